@@ -13,4 +13,6 @@ pub struct ConnectionInfo {
     pub server: Option<String>,
     pub port: Option<u16>,
     pub autoconnect: bool,
+    /// Nick used when joining channels, account localpart by default
+    pub nick: Option<String>,
 }
@@ -0,0 +1,504 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+use crypto::digest::Digest;
+use crypto::sha1::Sha1;
+use std::convert::TryInto;
+use termion::color;
+
+const PNG_SIGNATURE: [u8; 8] = [137, 80, 78, 71, 13, 10, 26, 10];
+/// Bigger images are most likely not avatars
+const MAX_PIXELS: usize = 4096 * 4096;
+
+/// Decoded image, as RGBA pixels row by row
+#[derive(Debug, Clone, PartialEq)]
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<[u8; 4]>,
+    /// Placeholder of an image that couldn't be decoded
+    pub placeholder: bool,
+}
+
+impl Image {
+    /// Opaque image standing for one that couldn't be decoded, it is always drawn with the
+    /// fallback color
+    pub fn placeholder(width: usize, height: usize) -> Image {
+        Image {
+            width,
+            height,
+            pixels: vec![[0, 0, 0, 255]; width * height],
+            placeholder: true,
+        }
+    }
+
+    fn pixel(&self, x: usize, y: usize) -> [u8; 4] {
+        match y < self.height {
+            true => self.pixels[y * self.width + x],
+            false => [0, 0, 0, 0],
+        }
+    }
+
+    /// Scale the image down by averaging the pixels covered by each resulting pixel
+    pub fn resize(&self, width: usize, height: usize) -> Image {
+        let mut pixels = Vec::with_capacity(width * height);
+        for y in 0..height {
+            let y0 = y * self.height / height;
+            let y1 = ((y + 1) * self.height / height).max(y0 + 1);
+            for x in 0..width {
+                let x0 = x * self.width / width;
+                let x1 = ((x + 1) * self.width / width).max(x0 + 1);
+                let mut sum = [0usize; 4];
+                for sy in y0..y1 {
+                    for sx in x0..x1 {
+                        let pixel = self.pixels[sy * self.width + sx];
+                        for (total, value) in sum.iter_mut().zip(pixel.iter()) {
+                            *total += *value as usize;
+                        }
+                    }
+                }
+                let count = (y1 - y0) * (x1 - x0);
+                pixels.push([
+                    (sum[0] / count) as u8,
+                    (sum[1] / count) as u8,
+                    (sum[2] / count) as u8,
+                    (sum[3] / count) as u8,
+                ]);
+            }
+        }
+        Image {
+            width,
+            height,
+            pixels,
+            placeholder: self.placeholder,
+        }
+    }
+
+    /// Render the image with one upper half block per pair of pixel rows, transparent pixels
+    /// are left to the terminal background. Without truecolor support, or for placeholders,
+    /// the image is drawn with the given color only.
+    pub fn render(&self, truecolor: bool, fallback: (u8, u8, u8)) -> Vec<String> {
+        let truecolor = truecolor && !self.placeholder;
+        let (r, g, b) = fallback;
+        let fallback = color::AnsiValue::rgb(
+            (r as u16 * 5 / 255) as u8,
+            (g as u16 * 5 / 255) as u8,
+            (b as u16 * 5 / 255) as u8,
+        );
+
+        let mut lines = Vec::new();
+        for y in (0..self.height).step_by(2) {
+            let mut line = String::new();
+            for x in 0..self.width {
+                let top = self.pixel(x, y);
+                let bottom = self.pixel(x, y + 1);
+                let cell = match (top[3] >= 128, bottom[3] >= 128) {
+                    (false, false) => format!("{} ", color::Bg(color::Reset)),
+                    (_, _) if !truecolor => {
+                        format!("{}{}█", color::Bg(color::Reset), color::Fg(fallback))
+                    }
+                    (true, true) => format!(
+                        "{}{}▀",
+                        color::Fg(color::Rgb(top[0], top[1], top[2])),
+                        color::Bg(color::Rgb(bottom[0], bottom[1], bottom[2]))
+                    ),
+                    (true, false) => format!(
+                        "{}{}▀",
+                        color::Fg(color::Rgb(top[0], top[1], top[2])),
+                        color::Bg(color::Reset)
+                    ),
+                    (false, true) => format!(
+                        "{}{}▄",
+                        color::Fg(color::Rgb(bottom[0], bottom[1], bottom[2])),
+                        color::Bg(color::Reset)
+                    ),
+                };
+                line.push_str(&cell);
+            }
+            line.push_str(&format!(
+                "{}{}",
+                color::Fg(color::Reset),
+                color::Bg(color::Reset)
+            ));
+            lines.push(line);
+        }
+        lines
+    }
+}
+
+/// Whether the terminal advertises 24-bit color support
+pub fn truecolor() -> bool {
+    matches!(
+        std::env::var("COLORTERM").as_deref(),
+        Ok("truecolor") | Ok("24bit")
+    )
+}
+
+/// Check an avatar id is a hex encoded SHA-1, before using it as a file name
+pub fn is_sha1_hex(hash: &str) -> bool {
+    hash.len() == 40
+        && hash
+            .bytes()
+            .all(|c| c.is_ascii_digit() || (b'a'..=b'f').contains(&c))
+}
+
+/// Hex encoded SHA-1 of image data, as used to identify avatars
+pub fn sha1_hex(data: &[u8]) -> String {
+    let mut hasher = Sha1::new();
+    hasher.input(data);
+    hasher.result_str()
+}
+
+/// Decode a non interlaced PNG image. Avatars are small and mostly PNG (the only format
+/// XEP-0084 requires), this decoder covers them without depending on a full image library.
+pub fn decode_png(data: &[u8]) -> Result<Image, String> {
+    if data.len() < PNG_SIGNATURE.len() || data[..PNG_SIGNATURE.len()] != PNG_SIGNATURE {
+        return Err("not a PNG image".to_string());
+    }
+
+    let mut header = None;
+    let mut palette = Vec::new();
+    let mut transparency = Vec::new();
+    let mut compressed = Vec::new();
+    let mut position = PNG_SIGNATURE.len();
+    while position + 8 <= data.len() {
+        let length = u32::from_be_bytes(data[position..position + 4].try_into().unwrap());
+        let kind = &data[position + 4..position + 8];
+        let start = position + 8;
+        let end = start + length as usize;
+        // Chunks are followed by their CRC
+        if end + 4 > data.len() {
+            return Err("truncated PNG image".to_string());
+        }
+        let chunk = &data[start..end];
+        match kind {
+            b"IHDR" => header = Some(Header::parse(chunk)?),
+            b"PLTE" => {
+                palette = chunk
+                    .chunks_exact(3)
+                    .map(|rgb| [rgb[0], rgb[1], rgb[2]])
+                    .collect()
+            }
+            b"tRNS" => transparency = chunk.to_vec(),
+            b"IDAT" => compressed.extend_from_slice(chunk),
+            b"IEND" => break,
+            _ => {}
+        }
+        position = end + 4;
+    }
+
+    let header = header.ok_or("missing PNG header".to_string())?;
+    let bits = header.channels() * header.depth;
+    let stride = (header.width * bits).div_ceil(8);
+    // Filters work on whole bytes, at least one
+    let step = bits.div_ceil(8).max(1);
+
+    // Never inflate more than the image needs, a small payload can expand enormously
+    let raw = miniz_oxide::inflate::decompress_to_vec_zlib_with_limit(
+        &compressed,
+        header.height * (stride + 1),
+    )
+    .map_err(|_| "invalid PNG data".to_string())?;
+    if raw.len() < header.height * (stride + 1) {
+        return Err("truncated PNG data".to_string());
+    }
+
+    let mut pixels = Vec::with_capacity(header.width * header.height);
+    let mut previous = vec![0u8; stride];
+    for line in raw.chunks_exact(stride + 1).take(header.height) {
+        let mut row = line[1..].to_vec();
+        unfilter(line[0], &mut row, &previous, step)?;
+        for x in 0..header.width {
+            pixels.push(header.pixel(&row, x, &palette, &transparency)?);
+        }
+        previous = row;
+    }
+
+    Ok(Image {
+        width: header.width,
+        height: header.height,
+        pixels,
+        placeholder: false,
+    })
+}
+
+struct Header {
+    width: usize,
+    height: usize,
+    depth: usize,
+    color_type: u8,
+}
+
+impl Header {
+    fn parse(chunk: &[u8]) -> Result<Self, String> {
+        if chunk.len() < 13 {
+            return Err("invalid PNG header".to_string());
+        }
+        let header = Header {
+            width: u32::from_be_bytes(chunk[0..4].try_into().unwrap()) as usize,
+            height: u32::from_be_bytes(chunk[4..8].try_into().unwrap()) as usize,
+            depth: chunk[8] as usize,
+            color_type: chunk[9],
+        };
+        let valid_depth = match header.color_type {
+            0 => [1, 2, 4, 8, 16].contains(&header.depth),
+            3 => [1, 2, 4, 8].contains(&header.depth),
+            2 | 4 | 6 => [8, 16].contains(&header.depth),
+            _ => false,
+        };
+        if !valid_depth {
+            return Err("unsupported PNG color type".to_string());
+        }
+        if chunk[12] != 0 {
+            return Err("interlaced PNG images are not supported".to_string());
+        }
+        if header.width == 0 || header.height == 0 || header.width * header.height > MAX_PIXELS {
+            return Err("invalid PNG size".to_string());
+        }
+        Ok(header)
+    }
+
+    fn channels(&self) -> usize {
+        match self.color_type {
+            2 => 3,
+            4 => 2,
+            6 => 4,
+            _ => 1,
+        }
+    }
+
+    /// Raw value of the given sample of a row
+    fn sample(&self, row: &[u8], index: usize) -> u16 {
+        match self.depth {
+            16 => u16::from_be_bytes([row[index * 2], row[index * 2 + 1]]),
+            8 => row[index] as u16,
+            depth => {
+                let offset = index * depth;
+                let shift = 8 - depth - offset % 8;
+                ((row[offset / 8] >> shift) & ((1 << depth) - 1)) as u16
+            }
+        }
+    }
+
+    /// Sample value scaled to 8 bits
+    fn scale(&self, sample: u16) -> u8 {
+        match self.depth {
+            16 => (sample >> 8) as u8,
+            depth => (sample as usize * 255 / ((1 << depth) - 1)) as u8,
+        }
+    }
+
+    fn pixel(
+        &self,
+        row: &[u8],
+        x: usize,
+        palette: &[[u8; 3]],
+        transparency: &[u8],
+    ) -> Result<[u8; 4], String> {
+        let channels = self.channels();
+        let samples: Vec<u16> = (0..channels)
+            .map(|channel| self.sample(row, x * channels + channel))
+            .collect();
+        // Transparent color of images without alpha channel
+        let transparent = |index: usize| {
+            transparency
+                .get(index * 2..index * 2 + 2)
+                .map(|value| u16::from_be_bytes([value[0], value[1]]))
+        };
+        let alpha = |opaque: bool| match opaque {
+            true => 255,
+            false => 0,
+        };
+
+        Ok(match self.color_type {
+            0 => {
+                let gray = self.scale(samples[0]);
+                [gray, gray, gray, alpha(transparent(0) != Some(samples[0]))]
+            }
+            2 => {
+                let opaque = (0..3).any(|i| transparent(i) != Some(samples[i]));
+                let [r, g, b] = [
+                    self.scale(samples[0]),
+                    self.scale(samples[1]),
+                    self.scale(samples[2]),
+                ];
+                [r, g, b, alpha(opaque)]
+            }
+            3 => {
+                let index = samples[0] as usize;
+                let [r, g, b] = palette
+                    .get(index)
+                    .ok_or("invalid PNG palette index".to_string())?;
+                [*r, *g, *b, *transparency.get(index).unwrap_or(&255)]
+            }
+            4 => {
+                let gray = self.scale(samples[0]);
+                [gray, gray, gray, self.scale(samples[1])]
+            }
+            _ => [
+                self.scale(samples[0]),
+                self.scale(samples[1]),
+                self.scale(samples[2]),
+                self.scale(samples[3]),
+            ],
+        })
+    }
+}
+
+/// Revert the filter applied to a row, given the unfiltered previous row
+fn unfilter(filter: u8, row: &mut [u8], previous: &[u8], step: usize) -> Result<(), String> {
+    for i in 0..row.len() {
+        let left = match i >= step {
+            true => row[i - step],
+            false => 0,
+        };
+        let up = previous[i];
+        let up_left = match i >= step {
+            true => previous[i - step],
+            false => 0,
+        };
+        let prediction = match filter {
+            0 => 0,
+            1 => left,
+            2 => up,
+            3 => ((left as u16 + up as u16) / 2) as u8,
+            4 => paeth(left, up, up_left),
+            _ => return Err("invalid PNG filter".to_string()),
+        };
+        row[i] = row[i].wrapping_add(prediction);
+    }
+    Ok(())
+}
+
+fn paeth(left: u8, up: u8, up_left: u8) -> u8 {
+    let estimate = left as i16 + up as i16 - up_left as i16;
+    let distance_left = (estimate - left as i16).abs();
+    let distance_up = (estimate - up as i16).abs();
+    let distance_up_left = (estimate - up_left as i16).abs();
+    if distance_left <= distance_up && distance_left <= distance_up_left {
+        left
+    } else if distance_up <= distance_up_left {
+        up
+    } else {
+        up_left
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn png(header: &[u8], chunks: &[(&[u8; 4], Vec<u8>)], rows: &[u8]) -> Vec<u8> {
+        let mut data = PNG_SIGNATURE.to_vec();
+        let mut chunks = chunks.to_vec();
+        chunks.insert(0, (b"IHDR", header.to_vec()));
+        chunks.push((b"IDAT", miniz_oxide::deflate::compress_to_vec_zlib(rows, 6)));
+        chunks.push((b"IEND", Vec::new()));
+        for (kind, chunk) in chunks {
+            data.extend_from_slice(&(chunk.len() as u32).to_be_bytes());
+            data.extend_from_slice(kind);
+            data.extend_from_slice(&chunk);
+            data.extend_from_slice(&[0, 0, 0, 0]);
+        }
+        data
+    }
+
+    #[test]
+    fn test_decode_png_rgba_filters() {
+        // 2x2 RGBA, first row filtered with sub, second one with up
+        let header = [0, 0, 0, 2, 0, 0, 0, 2, 8, 6, 0, 0, 0];
+        let rows = [
+            1, 10, 20, 30, 255, 5, 5, 5, 0, //
+            2, 1, 1, 1, 0, 0, 0, 0, 0,
+        ];
+        let image = decode_png(&png(&header, &[], &rows)).unwrap();
+
+        assert_eq!(image.width, 2);
+        assert_eq!(image.height, 2);
+        assert_eq!(
+            image.pixels,
+            vec![
+                [10, 20, 30, 255],
+                [15, 25, 35, 255],
+                [11, 21, 31, 255],
+                [15, 25, 35, 255]
+            ]
+        );
+    }
+
+    #[test]
+    fn test_decode_png_palette() {
+        // 3x1 with 1 bit indexes into a palette whose first color is transparent
+        let header = [0, 0, 0, 3, 0, 0, 0, 1, 1, 3, 0, 0, 0];
+        let palette = vec![0, 0, 0, 255, 0, 0];
+        let image = decode_png(&png(
+            &header,
+            &[(b"PLTE", palette), (b"tRNS", vec![0])],
+            &[0, 0b0100_0000],
+        ))
+        .unwrap();
+
+        assert_eq!(
+            image.pixels,
+            vec![[0, 0, 0, 0], [255, 0, 0, 255], [0, 0, 0, 0]]
+        );
+    }
+
+    #[test]
+    fn test_decode_png_rejects_oversized_data() {
+        // 1x1 gray image followed by far more data than it needs
+        let header = [0, 0, 0, 1, 0, 0, 0, 1, 8, 0, 0, 0, 0];
+        let rows = vec![0; 1024 * 1024];
+
+        assert!(decode_png(&png(&header, &[], &rows)).is_err());
+    }
+
+    #[test]
+    fn test_is_sha1_hex() {
+        assert!(is_sha1_hex("111f4b3c50d7b0df729d299bc6f8e9ef9066971f"));
+        assert!(!is_sha1_hex("111F4B3C50D7B0DF729D299BC6F8E9EF9066971F"));
+        assert!(!is_sha1_hex("../../../../../../../../../../etc/passwd"));
+        assert!(!is_sha1_hex("111f4b3c50d7b0df729d299bc6f8e9ef9066971"));
+        assert!(!is_sha1_hex(""));
+    }
+
+    #[test]
+    fn test_render_placeholder_with_fallback() {
+        let image = Image::placeholder(1, 2);
+
+        assert_eq!(
+            image.render(true, (255, 255, 255)),
+            image.render(false, (255, 255, 255))
+        );
+    }
+
+    #[test]
+    fn test_render_half_blocks() {
+        let image = Image {
+            width: 1,
+            height: 2,
+            pixels: vec![[255, 0, 0, 255], [0, 0, 255, 255]],
+            placeholder: false,
+        };
+        let reset = format!("{}{}", color::Fg(color::Reset), color::Bg(color::Reset));
+
+        assert_eq!(
+            image.render(true, (0, 0, 0)),
+            vec![format!(
+                "{}{}▀{}",
+                color::Fg(color::Rgb(255, 0, 0)),
+                color::Bg(color::Rgb(0, 0, 255)),
+                reset
+            )]
+        );
+        assert_eq!(
+            image.render(false, (255, 255, 255)),
+            vec![format!(
+                "{}{}█{}",
+                color::Bg(color::Reset),
+                color::Fg(color::AnsiValue::rgb(5, 5, 5)),
+                reset
+            )]
+        );
+    }
+}
@@ -282,7 +282,7 @@ macro_rules! build_subcommand_map(
 #[macro_export]
 macro_rules! parse_command_args(
     ($aparte:ident, $command:ident, $index:ident, {}) => ();
-    ($aparte:ident, $command:ident, $index:ident, { $arg:ident: Password<$type:ty> }) => (
+    ($aparte:ident, $command:ident, $index:ident, { $arg:ident: Password<$type:ty> $(, $($tail:tt)*)? }) => (
         if $command.args.len() <= $index {
             $aparte.schedule(Event::ReadPassword($command.clone()));
             return Ok(())
@@ -294,6 +294,8 @@ macro_rules! parse_command_args(
         };
 
         $index += 1;
+
+        parse_command_args!($aparte, $command, $index, { $($($tail)*)? });
     );
     ($aparte:ident, $command:ident, $index:ident, { $arg:ident: Option<$type:ty> $(= $attr:tt)? $(, $($tail:tt)*)? }) => (
         let $arg: Option<$type> = {
@@ -311,6 +313,35 @@ macro_rules! parse_command_args(
 
         parse_command_args!($aparte, $command, $index, { $($($tail)*)? });
     );
+    ($aparte:ident, $command:ident, $index:ident, { $arg:ident: Flag<$type:ty> $(, $($tail:tt)*)? }) => (
+        let $arg: Option<$type> = {
+            let flag = format!("--{}", stringify!($arg));
+            match $command.args.iter().position(|arg| arg == &flag) {
+                Some(position) if position + 1 < $command.args.len() => {
+                    let value = $command.args.remove(position + 1);
+                    $command.args.remove(position);
+                    match <$type>::from_str(&value) {
+                        Ok(arg) => Some(arg),
+                        Err(e) => return Err(format!("Invalid format for {} argument: {}", stringify!($arg), e)),
+                    }
+                }
+                Some(_) => return Err(format!("Missing value for {}", flag)),
+                None => None,
+            }
+        };
+
+        parse_command_args!($aparte, $command, $index, { $($($tail)*)? });
+    );
+    ($aparte:ident, $command:ident, $index:ident, { $arg:ident: Flag $(, $($tail:tt)*)? }) => (
+        let $arg: bool = {
+            let flag = format!("--{}", stringify!($arg));
+            let count = $command.args.len();
+            $command.args.retain(|arg| arg != &flag);
+            count != $command.args.len()
+        };
+
+        parse_command_args!($aparte, $command, $index, { $($($tail)*)? });
+    );
     ($aparte:ident, $command:ident, $index:ident, { $arg:ident: Named<$type:ty> $(= $attr:tt)? $(, $($tail:tt)*)? }) => (
         let $arg: Option<$type> = {
             // Could use let matching = $command.args.drain_filter(|a| a.starts_with(stringify!($arg))).collect::<Vec<String>>();
@@ -376,6 +407,13 @@ macro_rules! parse_command_args(
 #[macro_export]
 macro_rules! generate_command_autocompletions(
     ($autocompletions:ident, {}) => ();
+    ($autocompletions:ident, { $argname:ident: Flag<$type:ty> $(, $($tail:tt)*)? }) => (
+        generate_command_autocompletions!($autocompletions, { $($($tail)*)? });
+    );
+    ($autocompletions:ident, { $argname:ident: Flag $(, $($tail:tt)*)? }) => (
+        // Flags can appear anywhere and don't take a positional completion
+        generate_command_autocompletions!($autocompletions, { $($($tail)*)? });
+    );
     ($autocompletions:ident, { $argname:ident: $type:ty = $attrs:tt $(, $($tail:tt)*)? }) => (
         let count = $autocompletions.len();
         generate_arg_autocompletion!($autocompletions, $type, $attrs);
@@ -497,6 +535,7 @@ macro_rules! command_def (
                 Command::new(account.clone(), context.to_string(), buf.to_string())
             }
 
+            #[allow(unused_mut)] // Password arguments are taken out of $command
             fn exec($aparte: &mut Aparte, mut $command: Command) -> Result<(), String> {
                 #[allow(unused_variables, unused_mut)]
                 let mut index = 1;
@@ -601,6 +640,28 @@ mod tests_command_macro {
         assert_eq!(cmd.help, "help");
         assert_eq!(cmd.autocompletions.len(), 2);
     }
+
+    command_def!(flag_and_arg, "help", { _flag: Flag, _first_arg: String }, |_aparte, _command| { Ok(()) });
+
+    #[test]
+    fn test_command_with_flag_and_arg() {
+        let cmd = flag_and_arg::new();
+
+        assert_eq!(cmd.name, "flag_and_arg");
+        assert_eq!(cmd.help, "help");
+        assert_eq!(cmd.autocompletions.len(), 1);
+    }
+
+    command_def!(flag_value_and_arg, "help", { _value: Flag<String>, _first_arg: String }, |_aparte, _command| { Ok(()) });
+
+    #[test]
+    fn test_command_with_flag_value_and_arg() {
+        let cmd = flag_value_and_arg::new();
+
+        assert_eq!(cmd.name, "flag_value_and_arg");
+        assert_eq!(cmd.help, "help");
+        assert_eq!(cmd.autocompletions.len(), 1);
+    }
 }
 
 #[cfg(test)]
@@ -7,6 +7,7 @@ use termion::color;
 
 use crate::account::ConnectionInfo;
 use crate::color::ColorTuple;
+use crate::conversation::NotificationLevel;
 
 fn true_() -> bool {
     true
@@ -18,9 +19,32 @@ pub struct Config {
     pub accounts: HashMap<String, ConnectionInfo>,
     #[serde(default = "true_")]
     pub bell: bool,
+    /// Show avatars next to contacts in the roster
+    pub roster_avatars: bool,
+    /// Default notification level of each type of conversation
+    pub notifications: Notifications,
+    pub langs: Vec<String>,
     pub theme: Theme,
 }
 
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Notifications {
+    pub chat: NotificationLevel,
+    pub channel: NotificationLevel,
+    pub private_chat: NotificationLevel,
+}
+
+impl Default for Notifications {
+    fn default() -> Self {
+        Notifications {
+            chat: NotificationLevel::All,
+            channel: NotificationLevel::Mentions,
+            private_chat: NotificationLevel::All,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct Theme {
     pub title_bar: ColorTuple,
@@ -1,10 +1,13 @@
 /* This Source Code Form is subject to the terms of the Mozilla Public
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+use serde::Deserialize;
 use std::cmp;
 use std::collections::HashMap;
+use std::fmt;
 use std::hash::{Hash, Hasher};
-use xmpp_parsers::BareJid;
+use std::str::FromStr;
+use xmpp_parsers::{BareJid, FullJid};
 
 use crate::account::Account;
 
@@ -64,6 +67,10 @@ impl Channel {
             None => self.jid.to_string(),
         }
     }
+
+    pub fn get_self_occupant<'a>(&'a self) -> Option<&'a Occupant> {
+        self.occupants.get(&self.nick)
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -72,10 +79,51 @@ pub struct Chat {
     pub contact: BareJid,
 }
 
+/// Private discussion with an occupant of a channel
+#[derive(Clone, Debug)]
+pub struct PrivateChat {
+    pub account: Account,
+    pub occupant: FullJid,
+}
+
+/// Which incoming messages of a conversation are notified
+#[derive(Clone, Debug, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NotificationLevel {
+    All,
+    /// Other messages are only shown as unread
+    Mentions,
+    None,
+}
+
+impl FromStr for NotificationLevel {
+    type Err = String;
+
+    fn from_str(level: &str) -> Result<Self, Self::Err> {
+        match level {
+            "all" => Ok(NotificationLevel::All),
+            "mentions" => Ok(NotificationLevel::Mentions),
+            "none" => Ok(NotificationLevel::None),
+            _ => Err(format!("Unknown notification level {}", level)),
+        }
+    }
+}
+
+impl fmt::Display for NotificationLevel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NotificationLevel::All => write!(f, "all"),
+            NotificationLevel::Mentions => write!(f, "mentions"),
+            NotificationLevel::None => write!(f, "none"),
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub enum Conversation {
     Chat(Chat),
     Channel(Channel),
+    PrivateChat(PrivateChat),
 }
 
 impl Conversation {
@@ -83,13 +131,33 @@ impl Conversation {
         match self {
             Conversation::Chat(chat) => &chat.account,
             Conversation::Channel(channel) => &channel.account,
+            Conversation::PrivateChat(chat) => &chat.account,
+        }
+    }
+
+    /// Get the bare jid of the conversation, for private chats it is the channel jid
+    pub fn get_jid(&self) -> BareJid {
+        match self {
+            Conversation::Chat(chat) => chat.contact.clone(),
+            Conversation::Channel(channel) => channel.jid.clone(),
+            Conversation::PrivateChat(chat) => chat.occupant.clone().into(),
         }
     }
 
-    pub fn get_jid<'a>(&'a self) -> &'a BareJid {
+    /// Get the name of the window of the conversation, displayed names may differ
+    pub fn get_window(&self) -> String {
         match self {
-            Conversation::Chat(chat) => &chat.contact,
-            Conversation::Channel(channel) => &channel.jid,
+            Conversation::Chat(chat) => chat.contact.to_string(),
+            Conversation::Channel(channel) => channel.jid.to_string(),
+            Conversation::PrivateChat(chat) => chat.occupant.to_string(),
+        }
+    }
+
+    pub fn get_name(&self) -> String {
+        match self {
+            Conversation::Chat(chat) => chat.contact.to_string(),
+            Conversation::Channel(channel) => channel.get_name(),
+            Conversation::PrivateChat(chat) => chat.occupant.to_string(),
         }
     }
 }
@@ -107,3 +175,24 @@ impl PartialEq for Occupant {
 }
 
 impl Eq for Occupant {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_notification_level_round_trip() {
+        for level in &[
+            NotificationLevel::All,
+            NotificationLevel::Mentions,
+            NotificationLevel::None,
+        ] {
+            assert_eq!(NotificationLevel::from_str(&level.to_string()), Ok(*level));
+        }
+    }
+
+    #[test]
+    fn test_notification_level_unknown() {
+        assert!(NotificationLevel::from_str("always").is_err());
+    }
+}
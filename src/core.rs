@@ -1,7 +1,9 @@
 /* This Source Code Form is subject to the terms of the Mozilla Public
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
-use chrono::{DateTime, FixedOffset, Local as LocalTz};
+use chrono::{
+    DateTime, FixedOffset, Local as LocalTz, NaiveDate, NaiveDateTime, NaiveTime, TimeZone,
+};
 use core::fmt::Debug;
 use futures::sink::SinkExt;
 use futures::stream::StreamExt;
@@ -13,29 +15,35 @@ use std::collections::{HashMap, VecDeque};
 use std::convert::TryFrom;
 use std::fmt;
 use std::fs::OpenOptions;
+use std::future::Future;
 use std::io::Read;
 use std::path::PathBuf;
 use std::rc::Rc;
 use std::str::FromStr;
+use std::time::Duration;
 use termion::event::Key;
 use tokio::runtime::Runtime as TokioRuntime;
 use tokio::signal::unix;
 use tokio::sync::mpsc;
 use tokio::task;
+use tokio::time;
 use tokio_xmpp::{
     AsyncClient as TokioXmppClient, Error as XmppError, Event as XmppEvent, Packet as XmppPacket,
 };
 use uuid::Uuid;
 use xmpp_parsers;
+use xmpp_parsers::data_forms::DataForm;
 use xmpp_parsers::delay::Delay;
 use xmpp_parsers::iq::{Iq, IqType};
 use xmpp_parsers::message::Message as XmppParsersMessage;
 use xmpp_parsers::muc::Muc;
 use xmpp_parsers::presence::{Presence, Show as PresenceShow, Type as PresenceType};
 use xmpp_parsers::pubsub::event::PubSubEvent;
-use xmpp_parsers::{iq, presence, BareJid, Element, FullJid, Jid};
+use xmpp_parsers::stanza_error::StanzaError;
+use xmpp_parsers::{disco, iq, presence, BareJid, Element, FullJid, Jid};
 
 use crate::account::{Account, ConnectionInfo};
+use crate::avatar;
 use crate::color;
 use crate::command::{Command, CommandParser};
 use crate::config::Config;
@@ -79,9 +87,14 @@ pub enum Event {
         account: Account,
         contact: BareJid,
     },
+    PrivateChat {
+        account: Account,
+        occupant: FullJid,
+    },
     Join {
         account: FullJid,
         channel: Jid,
+        password: Option<String>,
         user_request: bool,
     },
     Joined {
@@ -90,8 +103,47 @@ pub enum Event {
         user_request: bool,
     },
     Leave(Channel),
+    /// Time to check we are still joined to the given channel
+    ChannelSelfPing {
+        account: Account,
+        channel: BareJid,
+    },
     Iq(Account, iq::Iq),
     Disco(Account),
+    /// Identities, features and items of an entity browsed with /disco
+    DiscoBrowse {
+        account: Account,
+        jid: Jid,
+        node: Option<String>,
+        info: Option<disco::DiscoInfoResult>,
+        items: Vec<disco::Item>,
+    },
+    /// Ad-hoc commands offered by an entity
+    AdhocCommands {
+        account: Account,
+        jid: Jid,
+        commands: Vec<disco::Item>,
+    },
+    /// Public rooms of a conference service listed with /rooms, announced again with the same id
+    /// as their details are received
+    Rooms {
+        account: Account,
+        id: String,
+        service: BareJid,
+        filter: Option<String>,
+        rooms: Vec<mods::muc::Room>,
+    },
+    /// Avatar of a contact, scaled to a thumbnail
+    Avatar {
+        account: Account,
+        jid: BareJid,
+        avatar: avatar::Image,
+    },
+    /// Information about a contact queried with /info, sent again as answers arrive
+    ContactInfo {
+        account: Account,
+        info: mods::info::ContactInfo,
+    },
     PubSub(Account, PubSubEvent),
     Presence(Account, presence::Presence),
     ReadPassword(Command),
@@ -101,21 +153,53 @@ pub enum Event {
     ContactUpdate(Account, contact::Contact),
     Bookmark(contact::Bookmark),
     DeletedBookmark(BareJid),
+    /// Name displayed for a contact or a channel, resolved from the roster, bookmarks and nicks
+    DisplayName {
+        jid: BareJid,
+        name: String,
+    },
     Occupant {
         account: Account,
         conversation: BareJid,
         occupant: conversation::Occupant,
     },
+    /// An occupant changed its nick, the occupant with the new nick follows
+    NickChanged {
+        account: Account,
+        conversation: BareJid,
+        occupant: conversation::Occupant,
+        nick: String,
+    },
     WindowChange,
+    /// Load the page of history preceding the given archive id, or the given date. With a
+    /// start date, load the page following it instead
     LoadChannelHistory {
         account: Account,
         jid: BareJid,
         from: Option<DateTime<FixedOffset>>,
+        before: Option<String>,
+        start: Option<DateTime<FixedOffset>>,
     },
     LoadChatHistory {
         account: Account,
         contact: BareJid,
         from: Option<DateTime<FixedOffset>>,
+        before: Option<String>,
+        start: Option<DateTime<FixedOffset>>,
+    },
+    /// A page of history has been loaded, complete when there is nothing older
+    HistoryLoaded {
+        account: Account,
+        conversation: BareJid,
+        complete: bool,
+    },
+    /// Search the history of a conversation
+    Search(mods::messages::Search),
+    /// Messages matching a search, from the local store or from the archive
+    SearchResults {
+        search: mods::messages::Search,
+        messages: Vec<Message>,
+        archive: bool,
     },
     Quit,
     Key(Key),
@@ -133,6 +217,46 @@ pub enum Event {
         important: bool,
     },
     Subject(Account, Jid, HashMap<String, String>),
+    /// Open a window to fill the given data form, with the actions it can be submitted with
+    Form {
+        id: String,
+        title: String,
+        form: DataForm,
+        actions: Vec<FormAction>,
+        notes: Vec<String>,
+    },
+    SubmitForm {
+        id: String,
+        form: DataForm,
+        action: FormAction,
+    },
+    CancelForm(String),
+    /// Log message displayed in a conversation window
+    ConversationLog {
+        account: Account,
+        conversation: BareJid,
+        message: Message,
+    },
+}
+
+/// Action a data form is submitted with, multi-stage forms may allow going back and forth
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FormAction {
+    Submit,
+    Prev,
+    Next,
+    Complete,
+}
+
+impl fmt::Display for FormAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FormAction::Submit => write!(f, "submit"),
+            FormAction::Prev => write!(f, "prev"),
+            FormAction::Next => write!(f, "next"),
+            FormAction::Complete => write!(f, "complete"),
+        }
+    }
 }
 
 pub enum Mod {
@@ -146,6 +270,13 @@ pub enum Mod {
     UI(mods::ui::UIMod),
     Mam(mods::mam::MamMod),
     Correction(mods::correction::CorrectionMod),
+    Muc(mods::muc::MucMod),
+    Adhoc(mods::adhoc::AdhocMod),
+    Register(mods::register::RegisterMod),
+    Info(mods::info::InfoMod),
+    Avatar(mods::avatar::AvatarMod),
+    Profile(mods::profile::ProfileMod),
+    Names(mods::names::NamesMod),
 }
 
 macro_rules! from_mod {
@@ -180,6 +311,13 @@ from_mod!(UI, mods::ui::UIMod);
 from_mod!(Mam, mods::mam::MamMod);
 from_mod!(Messages, mods::messages::MessagesMod);
 from_mod!(Correction, mods::correction::CorrectionMod);
+from_mod!(Muc, mods::muc::MucMod);
+from_mod!(Adhoc, mods::adhoc::AdhocMod);
+from_mod!(Register, mods::register::RegisterMod);
+from_mod!(Info, mods::info::InfoMod);
+from_mod!(Avatar, mods::avatar::AvatarMod);
+from_mod!(Profile, mods::profile::ProfileMod);
+from_mod!(Names, mods::names::NamesMod);
 
 pub trait ModTrait: fmt::Display {
     fn init(&mut self, aparte: &mut Aparte) -> Result<(), ()>;
@@ -221,6 +359,13 @@ impl ModTrait for Mod {
             Mod::Mam(r#mod) => r#mod.init(aparte),
             Mod::Messages(r#mod) => r#mod.init(aparte),
             Mod::Correction(r#mod) => r#mod.init(aparte),
+            Mod::Muc(r#mod) => r#mod.init(aparte),
+            Mod::Adhoc(r#mod) => r#mod.init(aparte),
+            Mod::Register(r#mod) => r#mod.init(aparte),
+            Mod::Info(r#mod) => r#mod.init(aparte),
+            Mod::Avatar(r#mod) => r#mod.init(aparte),
+            Mod::Profile(r#mod) => r#mod.init(aparte),
+            Mod::Names(r#mod) => r#mod.init(aparte),
         }
     }
 
@@ -236,6 +381,13 @@ impl ModTrait for Mod {
             Mod::Mam(r#mod) => r#mod.on_event(aparte, event),
             Mod::Messages(r#mod) => r#mod.on_event(aparte, event),
             Mod::Correction(r#mod) => r#mod.on_event(aparte, event),
+            Mod::Muc(r#mod) => r#mod.on_event(aparte, event),
+            Mod::Adhoc(r#mod) => r#mod.on_event(aparte, event),
+            Mod::Register(r#mod) => r#mod.on_event(aparte, event),
+            Mod::Info(r#mod) => r#mod.on_event(aparte, event),
+            Mod::Avatar(r#mod) => r#mod.on_event(aparte, event),
+            Mod::Profile(r#mod) => r#mod.on_event(aparte, event),
+            Mod::Names(r#mod) => r#mod.on_event(aparte, event),
         }
     }
 
@@ -263,6 +415,13 @@ impl ModTrait for Mod {
             Mod::Correction(r#mod) => {
                 r#mod.can_handle_xmpp_message(aparte, account, message, delay)
             }
+            Mod::Muc(r#mod) => r#mod.can_handle_xmpp_message(aparte, account, message, delay),
+            Mod::Adhoc(r#mod) => r#mod.can_handle_xmpp_message(aparte, account, message, delay),
+            Mod::Register(r#mod) => r#mod.can_handle_xmpp_message(aparte, account, message, delay),
+            Mod::Info(r#mod) => r#mod.can_handle_xmpp_message(aparte, account, message, delay),
+            Mod::Avatar(r#mod) => r#mod.can_handle_xmpp_message(aparte, account, message, delay),
+            Mod::Profile(r#mod) => r#mod.can_handle_xmpp_message(aparte, account, message, delay),
+            Mod::Names(r#mod) => r#mod.can_handle_xmpp_message(aparte, account, message, delay),
         }
     }
 
@@ -301,6 +460,23 @@ impl ModTrait for Mod {
             Mod::Correction(r#mod) => {
                 r#mod.handle_xmpp_message(aparte, account, message, delay, archive)
             }
+            Mod::Muc(r#mod) => r#mod.handle_xmpp_message(aparte, account, message, delay, archive),
+            Mod::Adhoc(r#mod) => {
+                r#mod.handle_xmpp_message(aparte, account, message, delay, archive)
+            }
+            Mod::Register(r#mod) => {
+                r#mod.handle_xmpp_message(aparte, account, message, delay, archive)
+            }
+            Mod::Info(r#mod) => r#mod.handle_xmpp_message(aparte, account, message, delay, archive),
+            Mod::Avatar(r#mod) => {
+                r#mod.handle_xmpp_message(aparte, account, message, delay, archive)
+            }
+            Mod::Profile(r#mod) => {
+                r#mod.handle_xmpp_message(aparte, account, message, delay, archive)
+            }
+            Mod::Names(r#mod) => {
+                r#mod.handle_xmpp_message(aparte, account, message, delay, archive)
+            }
         }
     }
 }
@@ -318,6 +494,13 @@ impl fmt::Debug for Mod {
             Mod::Mam(_) => f.write_str("Mod::Mam"),
             Mod::Messages(_) => f.write_str("Mod::Messages"),
             Mod::Correction(_) => f.write_str("Mod::Correction"),
+            Mod::Muc(_) => f.write_str("Mod::Muc"),
+            Mod::Adhoc(_) => f.write_str("Mod::Adhoc"),
+            Mod::Register(_) => f.write_str("Mod::Register"),
+            Mod::Info(_) => f.write_str("Mod::Info"),
+            Mod::Avatar(_) => f.write_str("Mod::Avatar"),
+            Mod::Profile(_) => f.write_str("Mod::Profile"),
+            Mod::Names(_) => f.write_str("Mod::Names"),
         }
     }
 }
@@ -335,6 +518,13 @@ impl fmt::Display for Mod {
             Mod::Mam(r#mod) => r#mod.fmt(f),
             Mod::Messages(r#mod) => r#mod.fmt(f),
             Mod::Correction(r#mod) => r#mod.fmt(f),
+            Mod::Muc(r#mod) => r#mod.fmt(f),
+            Mod::Adhoc(r#mod) => r#mod.fmt(f),
+            Mod::Register(r#mod) => r#mod.fmt(f),
+            Mod::Info(r#mod) => r#mod.fmt(f),
+            Mod::Avatar(r#mod) => r#mod.fmt(f),
+            Mod::Profile(r#mod) => r#mod.fmt(f),
+            Mod::Names(r#mod) => r#mod.fmt(f),
         }
     }
 }
@@ -353,6 +543,15 @@ impl<T: FromStr> FromStr for Password<T> {
     }
 }
 
+/// Human readable description of a stanza error: its condition and optional text
+pub fn stanza_error_text(error: &StanzaError) -> String {
+    let condition = Element::from(error.defined_condition.clone());
+    match error.texts.get("en").or(error.texts.values().next()) {
+        Some(text) => format!("{} ({})", condition.name(), text),
+        None => condition.name().to_string(),
+    }
+}
+
 pub struct Connection {
     pub sink: mpsc::Sender<Element>,
     pub account: FullJid,
@@ -404,6 +603,7 @@ Examples:
                 server: None,
                 port: None,
                 autoconnect: false,
+                nick: None,
             }
         } else {
             return Err(format!("Unknown account or invalid jid {}", account_name));
@@ -529,30 +729,114 @@ Examples:
     Ok(())
 });
 
+/// Get the conversation associated to the window the command was typed in, if any
+pub fn get_current_conversation(aparte: &Aparte, command: &Command) -> Option<Conversation> {
+    let account = command.account.as_ref()?;
+    let conversations = aparte.get_mod::<mods::conversation::ConversationMod>();
+    match Jid::from_str(&command.context).ok()? {
+        Jid::Bare(jid) => conversations.get(account, &jid).cloned(),
+        Jid::Full(occupant) => conversations.get_private_chat(account, &occupant).cloned(),
+    }
+}
+
+/// Get the channel associated to the window the command was typed in, if any
+pub fn get_current_channel(aparte: &Aparte, command: &Command) -> Option<Channel> {
+    match get_current_conversation(aparte, command)? {
+        Conversation::Channel(channel) => Some(channel),
+        _ => None,
+    }
+}
+
+/// Start of the given day in local time
+pub fn local_day_start(date: NaiveDate) -> Option<DateTime<FixedOffset>> {
+    let start = LocalTz
+        .from_local_datetime(&date.and_hms_opt(0, 0, 0)?)
+        .earliest()?;
+    Some(start.into())
+}
+
+/// Parse a date (YYYY-MM-DD), a time of today (HH:MM) or both, in local time
+pub fn parse_datetime(value: &str) -> Result<DateTime<FixedOffset>, String> {
+    if let Ok(datetime) = DateTime::parse_from_rfc3339(value) {
+        return Ok(datetime);
+    }
+
+    let naive = NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M")
+        .or_else(|_| NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M"))
+        .or_else(|_| {
+            NaiveDate::parse_from_str(value, "%Y-%m-%d").map(|date| date.and_time(NaiveTime::MIN))
+        })
+        .or_else(|_| {
+            NaiveTime::parse_from_str(value, "%H:%M")
+                .map(|time| LocalTz::now().date_naive().and_time(time))
+        })
+        .map_err(|_| format!("Invalid date {}, expected YYYY-MM-DD, HH:MM or both", value))?;
+
+    match LocalTz.from_local_datetime(&naive).earliest() {
+        Some(datetime) => Ok(datetime.into()),
+        None => Err(format!("Invalid date {}", value)),
+    }
+}
+
 command_def!(msg,
-r#"/msg <contact> [<message>]
+r#"/msg [--private] <contact> [<message>]
 
+    --private     Don't copy the message to your other clients
     contact       Contact to send a message to
     message       Optionnal message to be sent
 
 Description:
     Open a window for a private discussion with a given contact and optionnaly
-    send a message.
+    send a message. Inside a channel window, contact can be the nick of an
+    occupant of the channel.
 
 Example:
     /msg contact@server.tld
     /msg contact@server.tld "Hi there!"
+    /msg nick "Hi there!"
+    /msg --private contact@server.tld "Only on this client"
 "#,
 {
+    private: Flag,
     contact: String = {
-        completion: (|aparte, _command| {
+        completion: (|aparte, command| {
+            let mut completions: Vec<String> = match get_current_channel(aparte, &command) {
+                Some(channel) => channel.occupants.keys().cloned().collect(),
+                None => Vec::new(),
+            };
             let contact = aparte.get_mod::<mods::contact::ContactMod>();
-            contact.contacts.iter().map(|(_, contact)| contact.jid.to_string()).collect()
+            completions.extend(contact.contacts.values().map(|contact| contact.jid.to_string()));
+            completions
         })
     },
     message: Option<String>
 },
-|aparte, _command| {
+|aparte, command| {
+    // Inside a channel a nick designates an occupant
+    if let Some(channel) = get_current_channel(aparte, &command) {
+        if channel.occupants.contains_key(&contact) {
+            let occupant = channel.jid.clone().with_resource(contact);
+            aparte.schedule(Event::PrivateChat { account: channel.account.clone(), occupant: occupant.clone() });
+            if let Some(body) = message {
+                let mut bodies = HashMap::new();
+                bodies.insert("".to_string(), body);
+                let id = Uuid::new_v4().to_string();
+                let from: Jid = channel.account.clone().into();
+                let timestamp = LocalTz::now();
+                let message = Message::outgoing_private_chat(id, timestamp.into(), &from, &occupant, &bodies, false);
+                match private {
+                    true => {
+                        aparte.schedule(Event::Message(Some(channel.account.clone()), message.clone()));
+                        let message = mods::carbons::make_private(Element::try_from(message).unwrap());
+                        aparte.send(&channel.account, message);
+                    }
+                    false => aparte.schedule(Event::SendMessage(channel.account.clone(), message)),
+                }
+            }
+            return Ok(());
+        }
+    }
+
     let account = aparte.current_account().ok_or(format!("No connection found"))?;
     match Jid::from_str(&contact.clone()) {
         Ok(jid) => {
@@ -570,7 +854,11 @@ Example:
                 let message = Message::outgoing_chat(id, timestamp.into(), &from, &jid, &bodies, false);
                 aparte.schedule(Event::Message(Some(account.clone()), message.clone()));
 
-                aparte.send(&account, Element::try_from(message).unwrap());
+                let mut message = Element::try_from(message).unwrap();
+                if private {
+                    message = mods::carbons::make_private(message);
+                }
+                aparte.send(&account, message);
             }
             Ok(())
         },
@@ -581,15 +869,19 @@ Example:
 });
 
 command_def!(join,
-r#"/join <channel>
+r#"/join [--configure] <channel>
 
+    --configure   Configure the channel once joined, use it to create a reserved channel
     channel       Channel JID to join
+
 Description:
     Open a window and join a given channel.
 
-Example:
-    /join channel@conference.server.tld"#,
+Examples:
+    /join channel@conference.server.tld
+    /join --configure new-channel@conference.server.tld"#,
 {
+    configure: Flag,
     muc: String = {
         completion: (|aparte, _command| {
             let bookmarks = aparte.get_mod::<mods::bookmarks::BookmarksMod>();
@@ -599,45 +891,54 @@ Example:
 },
 |aparte, _command| {
     let account = aparte.current_account().ok_or(format!("No connection found"))?;
-    match Jid::from_str(&muc) {
-        Ok(jid) => {
-            aparte.schedule(Event::Join {
-                account,
-                channel: jid,
-                user_request: true
-            });
-            Ok(())
+    let (channel, password) = match Jid::from_str(&muc) {
+        Ok(Jid::Bare(jid)) => {
+            // Use the nick remembered in the bookmark, if any
+            let bookmarks = aparte.get_mod::<mods::bookmarks::BookmarksMod>();
+            match bookmarks.bookmarks_by_jid.get(&Jid::Bare(jid.clone())) {
+                Some(index) => {
+                    let bookmark = &bookmarks.bookmarks[*index];
+                    let jid = match &bookmark.nick {
+                        Some(nick) => Jid::Full(jid.with_resource(nick.clone())),
+                        None => Jid::Bare(jid),
+                    };
+                    (jid, bookmark.password.clone())
+                },
+                None => (Jid::Bare(jid), None),
+            }
         },
+        Ok(jid) => (jid, None),
         Err(_) => {
-            let jid = {
-                let bookmarks = aparte.get_mod::<mods::bookmarks::BookmarksMod>();
-                match bookmarks.get_by_name(&muc) {
-                    Some(bookmark) => {
-                        match bookmark.nick {
-                            Some(nick) => Ok(Jid::Full(bookmark.jid.with_resource(nick))),
-                            None => Ok(Jid::Bare(bookmark.jid.clone())),
-                        }
-                    },
-                    None => match Jid::from_str(&muc) {
-                        Ok(jid) => Ok(jid),
-                        Err(e) => Err(e.to_string()),
-                    }
-                }
-            };
-
-            match jid {
-                Ok(jid) => {
-                    aparte.schedule(Event::Join {
-                        account,
-                        channel: jid,
-                        user_request: true
-                    });
-                    Ok(())
+            let bookmarks = aparte.get_mod::<mods::bookmarks::BookmarksMod>();
+            match bookmarks.get_by_name(&muc) {
+                Some(bookmark) => {
+                    let jid = match bookmark.nick {
+                        Some(nick) => Jid::Full(bookmark.jid.with_resource(nick)),
+                        None => Jid::Bare(bookmark.jid.clone()),
+                    };
+                    (jid, bookmark.password)
                 },
-                Err(e) => Err(e),
+                None => match Jid::from_str(&muc) {
+                    Ok(jid) => (jid, None),
+                    Err(e) => return Err(e.to_string()),
+                }
             }
         }
+    };
+
+    if configure {
+        let mut muc = aparte.get_mod_mut::<mods::muc::MucMod>();
+        muc.configure_on_join(&account, channel.clone().into());
     }
+
+    aparte.schedule(Event::Join {
+        account,
+        channel,
+        password,
+        user_request: true
+    });
+
+    Ok(())
 });
 
 command_def!(
@@ -657,6 +958,56 @@ Example:
     }
 );
 
+command_def!(search,
+r#"/search [--from <author>] [--since <date>] [--until <date>] <text>
+
+    --from        Nick or JID of the author of the messages
+    --since       First day to search, formatted as YYYY-MM-DD
+    --until       Last day to search, formatted as YYYY-MM-DD
+    text          Text to look for
+
+Description:
+    Search the history of the current conversation, both in the messages
+    already loaded and in the archive of the conversation when it supports
+    full-text search. Results are displayed in a dedicated window, use /jump
+    to show a result in its conversation.
+
+Examples:
+    /search aparte
+    /search --from nick --since 2021-01-01 "release date"
+"#,
+{
+    from: Flag<String>,
+    since: Flag<NaiveDate>,
+    until: Flag<NaiveDate>,
+    text: String
+},
+|aparte, command| {
+    let conversation = get_current_conversation(aparte, &command)
+        .ok_or("Search is only available in a conversation window".to_string())?;
+    let since = match since {
+        Some(since) => Some(local_day_start(since).ok_or(format!("Invalid date {}", since))?),
+        None => None,
+    };
+    let until = match until {
+        Some(until) => {
+            let end = until.succ_opt().and_then(local_day_start);
+            Some(end.ok_or(format!("Invalid date {}", until))?)
+        }
+        None => None,
+    };
+
+    aparte.schedule(Event::Search(mods::messages::Search {
+        id: Uuid::new_v4().to_string(),
+        conversation,
+        text,
+        from,
+        since,
+        until,
+    }));
+    Ok(())
+});
+
 command_def!(help,
 r#"/help [command]
 
@@ -694,7 +1045,7 @@ mod me {
     use std::collections::HashMap;
     use std::str::FromStr;
     use uuid::Uuid;
-    use xmpp_parsers::{BareJid, Jid};
+    use xmpp_parsers::Jid;
 
     use crate::account::Account;
     use crate::command::*;
@@ -716,11 +1067,15 @@ mod me {
         let account = command
             .account
             .ok_or("Can't use /me in non XMPP window".to_string())?;
-        let jid = BareJid::from_str(&command.context)
+        let jid = Jid::from_str(&command.context)
             .map_err(|_| "Can't use /me in non XMPP window".to_string())?;
         let message = {
             let conversation = aparte.get_mod::<mods::conversation::ConversationMod>();
-            if let Some(conversation) = conversation.get(&account, &jid) {
+            let conversation = match &jid {
+                Jid::Bare(jid) => conversation.get(&account, jid),
+                Jid::Full(occupant) => conversation.get_private_chat(&account, occupant),
+            };
+            if let Some(conversation) = conversation {
                 match conversation {
                     Conversation::Chat(chat) => {
                         let account = &chat.account;
@@ -759,6 +1114,21 @@ mod me {
                             false,
                         ))
                     }
+                    Conversation::PrivateChat(chat) => {
+                        let from: Jid = chat.account.clone().into();
+                        let id = Uuid::new_v4();
+                        let timestamp = LocalTz::now().into();
+                        let mut bodies = HashMap::new();
+                        bodies.insert("".to_string(), command.args[0].clone());
+                        Ok(Message::outgoing_private_chat(
+                            id.to_string(),
+                            timestamp,
+                            &from,
+                            &chat.occupant,
+                            &bodies,
+                            false,
+                        ))
+                    }
                 }
             } else {
                 Err(format!("Unknown context {}", command.context))
@@ -837,6 +1207,13 @@ impl Aparte {
         aparte.add_mod(Mod::Mam(mods::mam::MamMod::new()));
         aparte.add_mod(Mod::Messages(mods::messages::MessagesMod::new()));
         aparte.add_mod(Mod::Correction(mods::correction::CorrectionMod::new()));
+        aparte.add_mod(Mod::Muc(mods::muc::MucMod::new()));
+        aparte.add_mod(Mod::Adhoc(mods::adhoc::AdhocMod::new()));
+        aparte.add_mod(Mod::Register(mods::register::RegisterMod::new()));
+        aparte.add_mod(Mod::Info(mods::info::InfoMod::new()));
+        aparte.add_mod(Mod::Avatar(mods::avatar::AvatarMod::new()));
+        aparte.add_mod(Mod::Profile(mods::profile::ProfileMod::new()));
+        aparte.add_mod(Mod::Names(mods::names::NamesMod::new()));
 
         aparte
     }
@@ -941,6 +1318,48 @@ impl Aparte {
                     RefCell::new(Mod::Correction(r#mod)),
                 );
             }
+            Mod::Muc(r#mod) => {
+                mods.insert(
+                    TypeId::of::<mods::muc::MucMod>(),
+                    RefCell::new(Mod::Muc(r#mod)),
+                );
+            }
+            Mod::Adhoc(r#mod) => {
+                mods.insert(
+                    TypeId::of::<mods::adhoc::AdhocMod>(),
+                    RefCell::new(Mod::Adhoc(r#mod)),
+                );
+            }
+            Mod::Register(r#mod) => {
+                mods.insert(
+                    TypeId::of::<mods::register::RegisterMod>(),
+                    RefCell::new(Mod::Register(r#mod)),
+                );
+            }
+            Mod::Info(r#mod) => {
+                mods.insert(
+                    TypeId::of::<mods::info::InfoMod>(),
+                    RefCell::new(Mod::Info(r#mod)),
+                );
+            }
+            Mod::Avatar(r#mod) => {
+                mods.insert(
+                    TypeId::of::<mods::avatar::AvatarMod>(),
+                    RefCell::new(Mod::Avatar(r#mod)),
+                );
+            }
+            Mod::Profile(r#mod) => {
+                mods.insert(
+                    TypeId::of::<mods::profile::ProfileMod>(),
+                    RefCell::new(Mod::Profile(r#mod)),
+                );
+            }
+            Mod::Names(r#mod) => {
+                mods.insert(
+                    TypeId::of::<mods::names::NamesMod>(),
+                    RefCell::new(Mod::Names(r#mod)),
+                );
+            }
         }
     }
 
@@ -976,6 +1395,20 @@ impl Aparte {
         self.current_connection = Some(account.clone());
     }
 
+    /// Nick to use in channels for the given account
+    pub fn default_nick(&self, account: &Account) -> String {
+        let bare: BareJid = account.clone().into();
+        self.config
+            .accounts
+            .values()
+            .find(|info| match BareJid::from_str(&info.jid) {
+                Ok(jid) => jid == bare,
+                Err(_) => false,
+            })
+            .and_then(|info| info.nick.clone())
+            .unwrap_or_else(|| account.node.clone().unwrap())
+    }
+
     pub fn current_account(&self) -> Option<Account> {
         self.current_connection.clone()
     }
@@ -990,6 +1423,7 @@ impl Aparte {
         self.add_command(join::new());
         self.add_command(quit::new());
         self.add_command(me::new());
+        self.add_command(search::new());
 
         let mods = Rc::clone(&self.mods);
         for (_, r#mod) in mods.iter() {
@@ -1270,21 +1704,23 @@ impl Aparte {
                 Event::Join {
                     account,
                     channel,
+                    password,
                     user_request,
                 } => {
                     let to = match channel.clone() {
                         Jid::Full(jid) => jid,
-                        Jid::Bare(jid) => {
-                            let node = account.node.clone().unwrap();
-                            jid.with_resource(node)
-                        }
+                        Jid::Bare(jid) => jid.with_resource(self.default_nick(&account)),
                     };
                     let from: Jid = account.clone().into();
 
                     let mut presence = Presence::new(PresenceType::None);
                     presence = presence.with_to(Jid::Full(to.clone()));
                     presence = presence.with_from(from);
-                    presence.add_payload(Muc::new());
+                    let mut muc = Muc::new();
+                    if let Some(password) = password {
+                        muc = muc.with_password(password);
+                    }
+                    presence.add_payload(muc);
                     self.send(&account, presence.into());
 
                     // Successful join
@@ -1318,11 +1754,49 @@ impl Aparte {
         self.event_queue.push(event);
     }
 
+    /// Schedule an event once the given delay is elapsed
+    pub fn schedule_in(&mut self, delay: Duration, event: Event) {
+        self.spawn(move |event_channel| async move {
+            time::sleep(delay).await;
+            if let Err(err) = event_channel.send(event).await {
+                error!("Cannot send event to internal channel: {}", err);
+            }
+        });
+    }
+
+    /// Run a task in the background, it can send events to the event loop through the given
+    /// channel
+    pub fn spawn<F, T>(&mut self, task: F)
+    where
+        F: FnOnce(mpsc::Sender<Event>) -> T,
+        T: Future<Output = ()> + 'static,
+    {
+        let event_channel = match &self.event_channel {
+            Some(event_channel) => event_channel.clone(),
+            None => {
+                warn!("Cannot spawn a task without event loop");
+                return;
+            }
+        };
+
+        task::spawn_local(task(event_channel));
+    }
+
     pub fn log(&mut self, message: String) {
         let message = Message::log(message);
         self.schedule(Event::Message(None, message));
     }
 
+    /// Log a message in the window of the given conversation
+    pub fn log_to(&mut self, account: &Account, conversation: &BareJid, message: String) {
+        let message = Message::log(message);
+        self.schedule(Event::ConversationLog {
+            account: account.clone(),
+            conversation: conversation.clone(),
+            message,
+        });
+    }
+
     fn handle_stanza(&mut self, account: Account, stanza: Element) {
         if let Ok(message) = XmppParsersMessage::try_from(stanza.clone()) {
             self.handle_xmpp_message(account, message, None, false);
@@ -9,7 +9,8 @@ use std::hash;
 use uuid::Uuid;
 use xmpp_parsers::delay::Delay;
 use xmpp_parsers::message::{Message as XmppParsersMessage, MessageType as XmppParsersMessageType};
-use xmpp_parsers::{BareJid, Jid};
+use xmpp_parsers::stanza_id::{OriginId, StanzaId};
+use xmpp_parsers::{ns, BareJid, FullJid, Jid};
 
 use crate::account::Account;
 use crate::i18n;
@@ -62,9 +63,37 @@ pub struct VersionedXmppMessage {
     pub type_: XmppMessageType,
     pub direction: Direction,
     pub archive: bool,
+    /// Id stamped by the archive of the conversation (XEP-0359)
+    pub stanza_id: Option<String>,
+    /// Id chosen by the sending client, kept even if the channel rewrites the message id
+    pub origin_id: Option<String>,
+    /// Name of the author displayed instead of its jid, resolved by the UI
+    pub from_name: Option<String>,
 }
 
 impl VersionedXmppMessage {
+    /// Id shared by every copy of this message (live, carbons, channel history, MAM), only unique
+    /// for a given sender
+    pub fn uid(&self) -> &str {
+        self.origin_id.as_ref().unwrap_or(&self.id)
+    }
+
+    /// Check the message has been sent by the given jid, channel occupants are identified by
+    /// their nick as our own messages are sent from our account and reflected by the channel
+    pub fn is_from(&self, jid: &Jid) -> bool {
+        match self.type_ {
+            XmppMessageType::Chat => {
+                let from: BareJid = jid.clone().into();
+                self.from == from
+            }
+            XmppMessageType::Channel => match (&self.from_full, jid) {
+                (Jid::Full(from), Jid::Full(jid)) => from.resource == jid.resource,
+                _ => false,
+            },
+            XmppMessageType::PrivateChat => &self.from_full == jid,
+        }
+    }
+
     pub fn get_last_bodies<'a>(&'a self) -> impl Iterator<Item = (&'a String, &'a String)> {
         let last = self.history.iter().max().unwrap();
         last.bodies.iter()
@@ -115,6 +144,8 @@ impl VersionedXmppMessage {
 pub enum XmppMessageType {
     Chat,
     Channel,
+    /// Chat with a channel occupant
+    PrivateChat,
 }
 
 #[derive(Debug, Clone, Eq, PartialEq)]
@@ -143,10 +174,43 @@ impl Message {
         delay: &Option<Delay>,
         archive: bool,
     ) -> Result<Self, ()> {
+        // Only trust stanza-id stamped by the archive of the conversation
+        let archive_jid: Option<BareJid> = match message.type_ {
+            XmppParsersMessageType::Groupchat => message.from.clone().map(|from| from.into()),
+            _ => Some(account.clone().into()),
+        };
+        let stanza_id = message
+            .payloads
+            .iter()
+            .filter_map(|payload| StanzaId::try_from(payload.clone()).ok())
+            .find(|stanza_id| Some(BareJid::from(stanza_id.by.clone())) == archive_jid)
+            .map(|stanza_id| stanza_id.id);
+        let origin_id = message
+            .payloads
+            .iter()
+            .find_map(|payload| OriginId::try_from(payload.clone()).ok())
+            .map(|origin_id| origin_id.id);
+
         let id = message
             .id
             .clone()
+            .or_else(|| stanza_id.clone())
             .unwrap_or_else(|| Uuid::new_v4().to_string());
+        let mut parsed = Message::from_xmpp_with_id(account, message, id, delay, archive)?;
+        if let Message::Xmpp(parsed) = &mut parsed {
+            parsed.stanza_id = stanza_id;
+            parsed.origin_id = origin_id;
+        }
+        Ok(parsed)
+    }
+
+    fn from_xmpp_with_id(
+        account: &Account,
+        message: &XmppParsersMessage,
+        id: String,
+        delay: &Option<Delay>,
+        archive: bool,
+    ) -> Result<Self, ()> {
         if let Some(from) = message.from.clone() {
             let bodies: HashMap<String, String> = message
                 .bodies
@@ -168,9 +232,28 @@ impl Message {
 
             match message.type_ {
                 XmppParsersMessageType::Chat => {
+                    // Private messages between channel occupants carry an empty MUC user payload
+                    let private = message
+                        .payloads
+                        .iter()
+                        .any(|payload| payload.is("x", ns::MUC_USER));
                     if from.clone().node() == account.node
                         && from.clone().domain() == account.domain
                     {
+                        if private {
+                            if let Jid::Full(to) = &to {
+                                return Ok(Message::outgoing_private_chat(
+                                    id,
+                                    delay
+                                        .map(|delay| delay.stamp.0)
+                                        .unwrap_or(LocalTz::now().into()),
+                                    &from,
+                                    to,
+                                    &bodies,
+                                    archive,
+                                ));
+                            }
+                        }
                         Ok(Message::outgoing_chat(
                             id,
                             delay
@@ -182,6 +265,20 @@ impl Message {
                             archive,
                         ))
                     } else {
+                        if private {
+                            if let Jid::Full(from) = &from {
+                                return Ok(Message::incoming_private_chat(
+                                    id,
+                                    delay
+                                        .map(|delay| delay.stamp.0)
+                                        .unwrap_or(LocalTz::now().into()),
+                                    from,
+                                    &to,
+                                    &bodies,
+                                    archive,
+                                ));
+                            }
+                        }
                         Ok(Message::incoming_chat(
                             id,
                             delay
@@ -297,6 +394,9 @@ impl Message {
             type_: XmppMessageType::Chat,
             direction: Direction::Incoming,
             archive,
+            stanza_id: None,
+            origin_id: None,
+            from_name: None,
         })
     }
 
@@ -336,9 +436,46 @@ impl Message {
             type_: XmppMessageType::Chat,
             direction: Direction::Outgoing,
             archive,
+            stanza_id: None,
+            origin_id: None,
+            from_name: None,
         })
     }
 
+    pub fn incoming_private_chat<I: Into<String>>(
+        id: I,
+        timestamp: DateTime<FixedOffset>,
+        from_full: &FullJid,
+        to_full: &Jid,
+        bodies: &HashMap<String, String>,
+        archive: bool,
+    ) -> Self {
+        let from_full = Jid::Full(from_full.clone());
+        let mut message =
+            Message::incoming_chat(id, timestamp, &from_full, to_full, bodies, archive);
+        if let Message::Xmpp(message) = &mut message {
+            message.type_ = XmppMessageType::PrivateChat;
+        }
+        message
+    }
+
+    pub fn outgoing_private_chat<I: Into<String>>(
+        id: I,
+        timestamp: DateTime<FixedOffset>,
+        from_full: &Jid,
+        to_full: &FullJid,
+        bodies: &HashMap<String, String>,
+        archive: bool,
+    ) -> Self {
+        let to_full = Jid::Full(to_full.clone());
+        let mut message =
+            Message::outgoing_chat(id, timestamp, from_full, &to_full, bodies, archive);
+        if let Message::Xmpp(message) = &mut message {
+            message.type_ = XmppMessageType::PrivateChat;
+        }
+        message
+    }
+
     pub fn incoming_channel<I: Into<String>>(
         id: I,
         timestamp: DateTime<FixedOffset>,
@@ -375,6 +512,9 @@ impl Message {
             type_: XmppMessageType::Channel,
             direction: Direction::Incoming,
             archive,
+            stanza_id: None,
+            origin_id: None,
+            from_name: None,
         })
     }
 
@@ -414,6 +554,9 @@ impl Message {
             type_: XmppMessageType::Channel,
             direction: Direction::Outgoing,
             archive,
+            stanza_id: None,
+            origin_id: None,
+            from_name: None,
         })
     }
 
@@ -448,17 +591,42 @@ impl Message {
             Message::Log(LogMessage { timestamp, .. }) => timestamp,
         }
     }
+
+    /// Keep the versions of another copy of this message when it has more, corrections are only
+    /// applied to the copy already known when they are received
+    pub fn keep_longest_history(&mut self, other: &Message) {
+        if let (Message::Xmpp(message), Message::Xmpp(other)) = (self, other) {
+            if other.history.len() > message.history.len() {
+                message.history = other.history.clone();
+            }
+        }
+    }
 }
 
 impl hash::Hash for Message {
     fn hash<H: hash::Hasher>(&self, state: &mut H) {
-        self.id().hash(state)
+        match self {
+            Message::Xmpp(message) => message.uid().hash(state),
+            Message::Log(message) => message.id.hash(state),
+        }
     }
 }
 
 impl PartialEq for Message {
     fn eq(&self, other: &Self) -> bool {
-        self.id() == other.id()
+        match (self, other) {
+            (Message::Xmpp(message), Message::Xmpp(other)) => {
+                message.type_ == other.type_
+                    && message.uid() == other.uid()
+                    && match (&message.stanza_id, &other.stanza_id) {
+                        // Both copies went through the archive
+                        (Some(stanza_id), Some(other)) => stanza_id == other,
+                        _ => message.is_from(&other.from_full),
+                    }
+            }
+            (Message::Log(message), Message::Log(other)) => message.id == other.id,
+            _ => false,
+        }
     }
 }
 
@@ -493,6 +661,12 @@ impl TryFrom<Message> for xmpp_parsers::Element {
                             Jid::Bare(message.to.clone()),
                         ));
                         xmpp_message.id = Some(message.id.clone());
+                        xmpp_message.payloads.push(
+                            OriginId {
+                                id: message.id.clone(),
+                            }
+                            .into(),
+                        );
                         xmpp_message.type_ = xmpp_parsers::message::MessageType::Chat;
                         xmpp_message.bodies = message
                             .get_last_bodies()
@@ -502,11 +676,39 @@ impl TryFrom<Message> for xmpp_parsers::Element {
                             .collect();
                         Ok(xmpp_message.into())
                     }
+                    XmppMessageType::PrivateChat => {
+                        let mut xmpp_message =
+                            xmpp_parsers::message::Message::new(Some(message.to_full.clone()));
+                        xmpp_message.id = Some(message.id.clone());
+                        xmpp_message.payloads.push(
+                            OriginId {
+                                id: message.id.clone(),
+                            }
+                            .into(),
+                        );
+                        xmpp_message.type_ = xmpp_parsers::message::MessageType::Chat;
+                        xmpp_message.bodies = message
+                            .get_last_bodies()
+                            .map(|(lang, body)| {
+                                (lang.clone(), xmpp_parsers::message::Body(body.clone()))
+                            })
+                            .collect();
+                        xmpp_message
+                            .payloads
+                            .push(xmpp_parsers::Element::builder("x", ns::MUC_USER).build());
+                        Ok(xmpp_message.into())
+                    }
                     XmppMessageType::Channel => {
                         let mut xmpp_message = xmpp_parsers::message::Message::new(Some(
                             Jid::Bare(message.to.clone()),
                         ));
                         xmpp_message.id = Some(message.id.clone());
+                        xmpp_message.payloads.push(
+                            OriginId {
+                                id: message.id.clone(),
+                            }
+                            .into(),
+                        );
                         xmpp_message.type_ = xmpp_parsers::message::MessageType::Groupchat;
                         xmpp_message.bodies = message
                             .get_last_bodies()
@@ -522,3 +724,139 @@ impl TryFrom<Message> for xmpp_parsers::Element {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use xmpp_parsers::Element;
+
+    fn chat(from: &str, to: &str, private: bool) -> Message {
+        let account = Account::from_str("me@example.org/aparte").unwrap();
+        let payload = match private {
+            true => "<x xmlns='http://jabber.org/protocol/muc#user'/>",
+            false => "",
+        };
+        let message: Element = format!(
+            "<message xmlns='jabber:client' type='chat' id='id' from='{}' to='{}'>
+                <body>hello</body>
+                {}
+            </message>",
+            from, to, payload
+        )
+        .parse()
+        .unwrap();
+        let message = XmppParsersMessage::try_from(message).unwrap();
+        Message::from_xmpp(&account, &message, &None, false).unwrap()
+    }
+
+    #[test]
+    fn test_incoming_private_chat() {
+        match chat(
+            "channel@conference.example.org/juliet",
+            "me@example.org/aparte",
+            true,
+        ) {
+            Message::Xmpp(message) => {
+                assert_eq!(message.type_, XmppMessageType::PrivateChat);
+                assert_eq!(message.direction, Direction::Incoming);
+                assert_eq!(
+                    message.from_full,
+                    Jid::from_str("channel@conference.example.org/juliet").unwrap()
+                );
+            }
+            _ => panic!("private message should be an xmpp message"),
+        }
+    }
+
+    #[test]
+    fn test_outgoing_private_chat() {
+        let message = chat(
+            "me@example.org/aparte",
+            "channel@conference.example.org/juliet",
+            true,
+        );
+        match &message {
+            Message::Xmpp(message) => {
+                assert_eq!(message.type_, XmppMessageType::PrivateChat);
+                assert_eq!(message.direction, Direction::Outgoing);
+            }
+            _ => panic!("private message should be an xmpp message"),
+        }
+
+        // The MUC user payload is kept when sending it
+        let element = Element::try_from(message).unwrap();
+        assert!(element.has_child("x", ns::MUC_USER));
+        assert_eq!(element.attr("type"), Some("chat"));
+        assert_eq!(
+            element.attr("to"),
+            Some("channel@conference.example.org/juliet")
+        );
+    }
+
+    #[test]
+    fn test_chat_without_muc_user_payload() {
+        match chat("juliet@example.org/phone", "me@example.org/aparte", false) {
+            Message::Xmpp(message) => assert_eq!(message.type_, XmppMessageType::Chat),
+            _ => panic!("chat message should be an xmpp message"),
+        }
+    }
+
+    fn echo() -> Message {
+        let mut bodies = HashMap::new();
+        bodies.insert("".to_string(), "hello".to_string());
+        Message::outgoing_channel(
+            "origin".to_string(),
+            LocalTz::now().into(),
+            &Jid::from_str("me@example.org/nick").unwrap(),
+            &Jid::from_str("channel@conference.example.org").unwrap(),
+            &bodies,
+            false,
+        )
+    }
+
+    fn reflection(from: &str, stanza_id: &str) -> Message {
+        let account = Account::from_str("me@example.org/aparte").unwrap();
+        let message: Element = format!(
+            "<message xmlns='jabber:client' type='groupchat' id='rewritten' from='{}' to='me@example.org/aparte'>
+                <body>hello</body>
+                <origin-id xmlns='urn:xmpp:sid:0' id='origin'/>
+                <stanza-id xmlns='urn:xmpp:sid:0' id='{}' by='channel@conference.example.org'/>
+            </message>",
+            from, stanza_id
+        )
+        .parse()
+        .unwrap();
+        let message = XmppParsersMessage::try_from(message).unwrap();
+        Message::from_xmpp(&account, &message, &None, false).unwrap()
+    }
+
+    #[test]
+    fn test_channel_echo_equals_reflection() {
+        assert_eq!(
+            echo(),
+            reflection("channel@conference.example.org/nick", "1")
+        );
+    }
+
+    #[test]
+    fn test_channel_message_from_other_occupant() {
+        assert_ne!(
+            echo(),
+            reflection("channel@conference.example.org/other", "1")
+        );
+    }
+
+    #[test]
+    fn test_archived_copies_compared_by_stanza_id() {
+        let archived = reflection("channel@conference.example.org/nick", "1");
+        assert_eq!(
+            archived,
+            reflection("channel@conference.example.org/nick", "1")
+        );
+        assert_ne!(
+            archived,
+            reflection("channel@conference.example.org/nick", "2")
+        );
+    }
+}
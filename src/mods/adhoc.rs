@@ -0,0 +1,534 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+use uuid::Uuid;
+use xmpp_parsers::data_forms::DataForm;
+use xmpp_parsers::disco::{DiscoItemsResult, Item as DiscoItem};
+use xmpp_parsers::iq::{Iq, IqType};
+use xmpp_parsers::ns;
+use xmpp_parsers::{BareJid, Element, Jid};
+
+use crate::account::Account;
+use crate::command::{Command, CommandParser};
+use crate::core::{self, Aparte, Event, FormAction, ModTrait};
+use crate::mods::disco;
+
+pub const NS_COMMANDS: &str = "http://jabber.org/protocol/commands";
+
+command_def!(adhoc,
+r#"/adhoc [<jid>]
+/adhoc exec <jid> <node>
+
+    jid     Entity offering the commands, the server of the current account by default
+    node    Node of the command to run, as displayed in the command list
+
+Description:
+    List the ad-hoc commands offered by an entity, or run one of them.
+    Each stage of a command is displayed as a data form, edit it with
+    /form set then use /form submit, /form prev, /form next,
+    /form complete or /form cancel as offered by the command.
+
+Examples:
+    /adhoc
+    /adhoc server.tld
+    /adhoc exec server.tld http://jabber.org/protocol/admin#get-online-users
+"#,
+{
+    target: Option<String> = {
+        completion: (|aparte, _command| {
+            let adhoc = aparte.get_mod::<AdhocMod>();
+            let mut targets = vec!["exec".to_string()];
+            targets.extend(adhoc.commands.keys().map(|(_, jid)| jid.to_string()));
+            targets
+        })
+    },
+    jid: Option<String> = {
+        completion: (|aparte, _command| {
+            let adhoc = aparte.get_mod::<AdhocMod>();
+            adhoc.commands.keys().map(|(_, jid)| jid.to_string()).collect()
+        })
+    },
+    node: Option<String> = {
+        completion: (|aparte, _command| {
+            let adhoc = aparte.get_mod::<AdhocMod>();
+            adhoc.commands.values().flatten().filter_map(|item| item.node.clone()).collect()
+        })
+    },
+},
+|aparte, _command| {
+    let account = aparte.current_account().ok_or("No connection found".to_string())?;
+    let parse_jid = |jid: &str| Jid::from_str(jid).map_err(|e| format!("Invalid JID {}: {}", jid, e));
+    let request = match (target.as_deref(), jid, node) {
+        (None, _, _) => {
+            let server = Jid::Bare(BareJid::domain(&account.domain));
+            let mut adhoc = aparte.get_mod_mut::<AdhocMod>();
+            adhoc.list(&account, server)
+        }
+        (Some("exec"), Some(jid), Some(node)) => {
+            let jid = parse_jid(&jid)?;
+            let mut adhoc = aparte.get_mod_mut::<AdhocMod>();
+            adhoc.execute(&account, jid, node)
+        }
+        (Some("exec"), _, _) => return Err("Missing JID or node of the command to run".to_string()),
+        (Some(target), _, _) => {
+            let jid = parse_jid(target)?;
+            let mut adhoc = aparte.get_mod_mut::<AdhocMod>();
+            adhoc.list(&account, jid)
+        }
+    };
+    aparte.send(&account, request);
+    Ok(())
+});
+
+/// An ad-hoc command being run
+#[derive(Debug, Clone)]
+struct Session {
+    account: Account,
+    jid: Jid,
+    node: String,
+    name: String,
+    /// Session id given by the entity once the command started
+    id: Option<String>,
+}
+
+/// Status of a command session
+#[derive(Debug, PartialEq)]
+enum Status {
+    Executing,
+    Completed,
+    Canceled,
+}
+
+impl FromStr for Status {
+    type Err = String;
+
+    fn from_str(status: &str) -> Result<Self, Self::Err> {
+        match status {
+            "executing" => Ok(Status::Executing),
+            "completed" => Ok(Status::Completed),
+            "canceled" => Ok(Status::Canceled),
+            _ => Err(format!("unknown status {}", status)),
+        }
+    }
+}
+
+/// Content of a `<command/>` answer
+struct Stage {
+    status: Status,
+    id: Option<String>,
+    actions: Vec<FormAction>,
+    notes: Vec<String>,
+    /// Whether a note reports an error
+    failed: bool,
+    form: Option<DataForm>,
+}
+
+impl Stage {
+    /// Text logged when the command ends without a result form
+    fn summary(&self, name: &str) -> String {
+        let outcome = match (&self.status, self.failed) {
+            (_, true) => "failed",
+            (Status::Canceled, false) => "canceled",
+            (_, false) => "completed",
+        };
+        match self.notes.is_empty() {
+            true => format!("Command {} {}", name, outcome),
+            false => format!("Command {} {}\n{}", name, outcome, self.notes.join("\n")),
+        }
+    }
+}
+
+impl TryFrom<&Element> for Stage {
+    type Error = String;
+
+    fn try_from(command: &Element) -> Result<Self, Self::Error> {
+        if !command.is("command", NS_COMMANDS) {
+            return Err("not an ad-hoc command".to_string());
+        }
+
+        let mut actions = vec![FormAction::Submit];
+        if let Some(available) = command.get_child("actions", NS_COMMANDS) {
+            for action in available.children() {
+                match action.name() {
+                    "prev" => actions.push(FormAction::Prev),
+                    "next" => actions.push(FormAction::Next),
+                    "complete" => actions.push(FormAction::Complete),
+                    _ => {}
+                }
+            }
+        }
+
+        let failed = command
+            .children()
+            .any(|child| child.is("note", NS_COMMANDS) && child.attr("type") == Some("error"));
+        let notes = command
+            .children()
+            .filter(|child| child.is("note", NS_COMMANDS))
+            .map(|note| match note.attr("type") {
+                Some("warn") => format!("Warning: {}", note.text()),
+                Some("error") => format!("Error: {}", note.text()),
+                _ => note.text(),
+            })
+            .collect();
+
+        let form = match command.get_child("x", ns::DATA_FORMS) {
+            Some(form) => Some(DataForm::try_from(form.clone()).map_err(|e| e.to_string())?),
+            None => None,
+        };
+
+        Ok(Stage {
+            status: Status::from_str(command.attr("status").unwrap_or("executing"))?,
+            id: command.attr("sessionid").map(str::to_string),
+            actions,
+            notes,
+            failed,
+            form,
+        })
+    }
+}
+
+pub struct AdhocMod {
+    /// Commands offered by entities, as last listed
+    commands: HashMap<(Account, Jid), Vec<DiscoItem>>,
+    /// Pending command list requests, indexed by iq id
+    list_requests: HashMap<String, (Account, Jid)>,
+    /// Pending command executions, indexed by iq id
+    requests: HashMap<String, Session>,
+    /// Sessions waiting for the user to fill their form, indexed by form id
+    forms: HashMap<String, Session>,
+}
+
+impl AdhocMod {
+    pub fn new() -> Self {
+        Self {
+            commands: HashMap::new(),
+            list_requests: HashMap::new(),
+            requests: HashMap::new(),
+            forms: HashMap::new(),
+        }
+    }
+
+    fn list(&mut self, account: &Account, jid: Jid) -> Element {
+        let id = Uuid::new_v4().to_hyphenated().to_string();
+        self.list_requests
+            .insert(id.clone(), (account.clone(), jid.clone()));
+        disco::items_request(id, jid, Some(NS_COMMANDS.to_string()))
+    }
+
+    fn execute(&mut self, account: &Account, jid: Jid, node: String) -> Element {
+        let index = (account.clone(), jid.clone());
+        let name = self
+            .commands
+            .get(&index)
+            .and_then(|items| items.iter().find(|item| item.node.as_ref() == Some(&node)))
+            .and_then(|item| item.name.clone())
+            .unwrap_or(node.clone());
+        let session = Session {
+            account: account.clone(),
+            jid,
+            node,
+            name,
+            id: None,
+        };
+        self.request(session, "execute", None)
+    }
+
+    /// Build a command request for the session, its answer isn't tracked
+    fn command(session: &Session, action: &str, form: Option<DataForm>) -> Iq {
+        let mut command = Element::builder("command", NS_COMMANDS)
+            .attr("node", session.node.clone())
+            .attr("action", action);
+        if let Some(id) = &session.id {
+            command = command.attr("sessionid", id.clone());
+        }
+        if let Some(form) = form {
+            command = command.append(Element::from(form));
+        }
+
+        Iq {
+            from: None,
+            to: Some(session.jid.clone()),
+            id: Uuid::new_v4().to_hyphenated().to_string(),
+            payload: IqType::Set(command.build()),
+        }
+    }
+
+    fn request(&mut self, session: Session, action: &str, form: Option<DataForm>) -> Element {
+        let iq = Self::command(&session, action, form);
+        self.requests.insert(iq.id.clone(), session);
+        iq.into()
+    }
+
+    fn handle_list(&mut self, aparte: &mut Aparte, account: Account, jid: Jid, iq: &Iq) {
+        match &iq.payload {
+            IqType::Result(Some(query)) => {
+                let commands = match DiscoItemsResult::try_from(query.clone()) {
+                    Ok(result) => result.items,
+                    Err(_) => Vec::new(),
+                };
+                self.commands
+                    .insert((account.clone(), jid.clone()), commands.clone());
+                aparte.schedule(Event::AdhocCommands {
+                    account,
+                    jid,
+                    commands,
+                });
+            }
+            IqType::Error(error) => aparte.log(format!(
+                "Cannot list commands of {}: {}",
+                jid,
+                core::stanza_error_text(error)
+            )),
+            _ => {}
+        }
+    }
+
+    fn handle_stage(&mut self, aparte: &mut Aparte, mut session: Session, iq: &Iq) {
+        let stage = match &iq.payload {
+            IqType::Result(Some(command)) => Stage::try_from(command),
+            IqType::Result(None) => Err("empty answer".to_string()),
+            IqType::Error(error) => Err(core::stanza_error_text(error)),
+            _ => return,
+        };
+        let mut stage = match stage {
+            Ok(stage) => stage,
+            Err(err) => {
+                aparte.log(format!("Command {} failed: {}", session.name, err));
+                return;
+            }
+        };
+
+        if stage.id.is_some() {
+            session.id = stage.id.take();
+        }
+        let title = format!("{} ({})", session.name, session.jid);
+        match (&stage.status, stage.form.take()) {
+            (Status::Executing, Some(form)) => {
+                let id = Uuid::new_v4().to_hyphenated().to_string();
+                self.forms.insert(id.clone(), session);
+                aparte.schedule(Event::Form {
+                    id,
+                    title,
+                    form,
+                    actions: stage.actions,
+                    notes: stage.notes,
+                });
+            }
+            (Status::Executing, None) => {
+                // Nothing can be submitted to continue, don't leave the session open
+                let mut text = format!("Command {} failed: no form to fill", session.name);
+                for note in stage.notes.iter() {
+                    text.push_str(&format!("\n{}", note));
+                }
+                aparte.log(text);
+                let cancel = Self::command(&session, "cancel", None);
+                aparte.send(&session.account, cancel.into());
+            }
+            // Results are displayed as read-only forms
+            (Status::Completed, Some(form)) if !stage.failed => {
+                aparte.schedule(Event::Form {
+                    id: Uuid::new_v4().to_hyphenated().to_string(),
+                    title,
+                    form,
+                    actions: Vec::new(),
+                    notes: stage.notes,
+                });
+            }
+            (Status::Completed, _) | (Status::Canceled, _) => {
+                aparte.log(stage.summary(&session.name))
+            }
+        }
+    }
+}
+
+impl ModTrait for AdhocMod {
+    fn init(&mut self, aparte: &mut Aparte) -> Result<(), ()> {
+        aparte.add_command(adhoc::new());
+        Ok(())
+    }
+
+    fn on_event(&mut self, aparte: &mut Aparte, event: &Event) {
+        match event {
+            Event::Iq(_, iq) => {
+                if let Some((account, jid)) = self.list_requests.remove(&iq.id) {
+                    self.handle_list(aparte, account, jid, iq);
+                } else if let Some(session) = self.requests.remove(&iq.id) {
+                    self.handle_stage(aparte, session, iq);
+                }
+            }
+            Event::SubmitForm { id, form, action } => {
+                if let Some(session) = self.forms.remove(id) {
+                    let account = session.account.clone();
+                    let request = match action {
+                        FormAction::Submit => self.request(session, "execute", Some(form.clone())),
+                        FormAction::Prev => self.request(session, "prev", None),
+                        FormAction::Next => self.request(session, "next", Some(form.clone())),
+                        FormAction::Complete => {
+                            self.request(session, "complete", Some(form.clone()))
+                        }
+                    };
+                    aparte.send(&account, request);
+                }
+            }
+            Event::CancelForm(id) => {
+                if let Some(session) = self.forms.remove(id) {
+                    let account = session.account.clone();
+                    let request = self.request(session, "cancel", None);
+                    aparte.send(&account, request);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl fmt::Display for AdhocMod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "XEP-0050: Ad-Hoc Commands")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stage_executing() {
+        let command: Element = r#"<command xmlns='http://jabber.org/protocol/commands'
+                    node='config' sessionid='session' status='executing'>
+                <actions execute='next'>
+                    <prev/>
+                    <next/>
+                </actions>
+                <note type='warn'>Be careful</note>
+                <x xmlns='jabber:x:data' type='form'>
+                    <field var='name' type='text-single'/>
+                </x>
+            </command>"#
+            .parse()
+            .unwrap();
+        let stage = Stage::try_from(&command).unwrap();
+
+        assert_eq!(stage.status, Status::Executing);
+        assert_eq!(stage.id.as_deref(), Some("session"));
+        assert_eq!(
+            stage.actions,
+            vec![FormAction::Submit, FormAction::Prev, FormAction::Next]
+        );
+        assert_eq!(stage.notes, vec!["Warning: Be careful".to_string()]);
+        assert_eq!(stage.form.unwrap().fields.len(), 1);
+    }
+
+    #[test]
+    fn test_stage_completed() {
+        let command: Element = r#"<command xmlns='http://jabber.org/protocol/commands'
+                    node='ping' status='completed'>
+                <note>Pong</note>
+            </command>"#
+            .parse()
+            .unwrap();
+        let stage = Stage::try_from(&command).unwrap();
+
+        assert_eq!(stage.status, Status::Completed);
+        assert_eq!(stage.id, None);
+        assert_eq!(stage.actions, vec![FormAction::Submit]);
+        assert_eq!(stage.notes, vec!["Pong".to_string()]);
+        assert!(stage.form.is_none());
+        assert_eq!(stage.summary("Ping"), "Command Ping completed\nPong");
+    }
+
+    #[test]
+    fn test_stage_error_note() {
+        let command: Element = r#"<command xmlns='http://jabber.org/protocol/commands'
+                    node='config' status='completed'>
+                <note type='error'>Not allowed</note>
+            </command>"#
+            .parse()
+            .unwrap();
+        let stage = Stage::try_from(&command).unwrap();
+
+        assert!(stage.failed);
+        assert_eq!(
+            stage.summary("Configure"),
+            "Command Configure failed\nError: Not allowed"
+        );
+    }
+
+    #[test]
+    fn test_stage_canceled() {
+        let command: Element = r#"<command xmlns='http://jabber.org/protocol/commands'
+                    node='config' status='canceled'/>"#
+            .parse()
+            .unwrap();
+        let stage = Stage::try_from(&command).unwrap();
+
+        assert_eq!(stage.status, Status::Canceled);
+        assert_eq!(stage.summary("Configure"), "Command Configure canceled");
+    }
+
+    #[test]
+    fn test_stage_unknown_status() {
+        let command: Element = r#"<command xmlns='http://jabber.org/protocol/commands'
+                    node='config' status='paused'/>"#
+            .parse()
+            .unwrap();
+        assert!(Stage::try_from(&command).is_err());
+    }
+
+    #[test]
+    fn test_stage_not_a_command() {
+        let query: Element = "<query xmlns='http://jabber.org/protocol/disco#items'/>"
+            .parse()
+            .unwrap();
+        assert!(Stage::try_from(&query).is_err());
+    }
+
+    #[test]
+    fn test_request_continues_session() {
+        let account = Account::from_str("me@example.org/aparte").unwrap();
+        let session = Session {
+            account,
+            jid: Jid::from_str("example.org").unwrap(),
+            node: "config".to_string(),
+            name: "Configure".to_string(),
+            id: Some("session".to_string()),
+        };
+        let mut adhoc = AdhocMod::new();
+        let iq = Iq::try_from(adhoc.request(session, "next", None)).unwrap();
+
+        let command = match &iq.payload {
+            IqType::Set(command) => command,
+            _ => panic!("ad-hoc command should be a set request"),
+        };
+        assert_eq!(command.attr("node"), Some("config"));
+        assert_eq!(command.attr("action"), Some("next"));
+        assert_eq!(command.attr("sessionid"), Some("session"));
+        assert!(adhoc.requests.contains_key(&iq.id));
+    }
+
+    #[test]
+    fn test_cancel_command_untracked() {
+        let account = Account::from_str("me@example.org/aparte").unwrap();
+        let session = Session {
+            account,
+            jid: Jid::from_str("example.org").unwrap(),
+            node: "config".to_string(),
+            name: "Configure".to_string(),
+            id: Some("session".to_string()),
+        };
+        let adhoc = AdhocMod::new();
+        let iq = AdhocMod::command(&session, "cancel", None);
+
+        let command = match &iq.payload {
+            IqType::Set(command) => command,
+            _ => panic!("ad-hoc command should be a set request"),
+        };
+        assert_eq!(command.attr("action"), Some("cancel"));
+        assert_eq!(command.attr("sessionid"), Some("session"));
+        assert!(adhoc.requests.is_empty());
+    }
+}
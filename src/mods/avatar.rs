@@ -0,0 +1,323 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use uuid::Uuid;
+use xmpp_parsers::avatar::{Data, Metadata};
+use xmpp_parsers::iq::{Iq, IqType};
+use xmpp_parsers::pubsub::{pubsub::Items, ItemId, NodeName, PubSub};
+use xmpp_parsers::{ns, BareJid, Element, Jid};
+
+use crate::account::Account;
+use crate::avatar::{self, Image};
+use crate::core::{Aparte, Event, ModTrait};
+use crate::mods;
+use crate::mods::info::{pubsub_payload, NS_VCARD_TEMP};
+
+/// Size in pixels of avatar thumbnails, drawn with two pixels per cell vertically
+pub const THUMBNAIL_SIZE: usize = 16;
+pub const NS_VCARD_UPDATE: &str = "vcard-temp:x:update";
+
+enum AvatarRequest {
+    Metadata,
+    Data(String),
+    VCard,
+}
+
+pub struct AvatarMod {
+    /// Directory where avatars are cached by hash
+    cache: PathBuf,
+    /// Thumbnails of known avatars
+    avatars: HashMap<BareJid, Image>,
+    /// Hash of the avatar of each contact
+    hashes: HashMap<BareJid, String>,
+    /// Pending requests, indexed by iq id
+    requests: HashMap<String, (BareJid, AvatarRequest)>,
+}
+
+impl AvatarMod {
+    pub fn new() -> Self {
+        Self {
+            cache: dirs::data_dir().unwrap().join("aparte").join("avatars"),
+            avatars: HashMap::new(),
+            hashes: HashMap::new(),
+            requests: HashMap::new(),
+        }
+    }
+
+    pub fn get(&self, jid: &BareJid) -> Option<&Image> {
+        self.avatars.get(jid)
+    }
+
+    /// XEP-0084 avatar metadata request, falling back to vcard-temp when unavailable
+    pub fn request(&mut self, jid: &BareJid) -> Element {
+        self.pubsub_request(jid, AvatarRequest::Metadata, ns::AVATAR_METADATA, None)
+    }
+
+    fn pubsub_request(
+        &mut self,
+        jid: &BareJid,
+        request: AvatarRequest,
+        node: &str,
+        item: Option<String>,
+    ) -> Element {
+        let id = Uuid::new_v4().to_hyphenated().to_string();
+        self.requests.insert(id.clone(), (jid.clone(), request));
+        let items = Items {
+            max_items: Some(1),
+            node: NodeName(node.to_string()),
+            subid: None,
+            items: item
+                .into_iter()
+                .map(|item| {
+                    xmpp_parsers::pubsub::pubsub::Item(xmpp_parsers::pubsub::Item {
+                        id: Some(ItemId(item)),
+                        publisher: None,
+                        payload: None,
+                    })
+                })
+                .collect(),
+        };
+        Iq::from_get(id, PubSub::Items(items))
+            .with_to(Jid::Bare(jid.clone()))
+            .into()
+    }
+
+    fn vcard_request(&mut self, jid: &BareJid) -> Element {
+        let id = Uuid::new_v4().to_hyphenated().to_string();
+        self.requests
+            .insert(id.clone(), (jid.clone(), AvatarRequest::VCard));
+        Iq {
+            from: None,
+            to: Some(Jid::Bare(jid.clone())),
+            id,
+            payload: IqType::Get(Element::builder("vCard", NS_VCARD_TEMP).build()),
+        }
+        .into()
+    }
+
+    fn load(&self, hash: &str) -> Option<Vec<u8>> {
+        if !avatar::is_sha1_hex(hash) {
+            return None;
+        }
+        fs::read(self.cache.join(hash)).ok()
+    }
+
+    fn store(&self, hash: &str, data: &[u8]) {
+        if !avatar::is_sha1_hex(hash) {
+            return;
+        }
+        if let Err(err) =
+            fs::create_dir_all(&self.cache).and_then(|_| fs::write(self.cache.join(hash), data))
+        {
+            warn!("Cannot cache avatar {}: {}", hash, err);
+        }
+    }
+
+    /// Use the avatar with the given hash, if cached, returns false otherwise
+    fn use_cached(
+        &mut self,
+        aparte: &mut Aparte,
+        account: &Account,
+        jid: &BareJid,
+        hash: &str,
+    ) -> bool {
+        if self.hashes.get(jid).map(String::as_str) == Some(hash) && self.avatars.contains_key(jid)
+        {
+            return true;
+        }
+        match self.load(hash) {
+            Some(data) => {
+                self.set_avatar(aparte, account, jid, hash, &data);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn set_avatar(
+        &mut self,
+        aparte: &mut Aparte,
+        account: &Account,
+        jid: &BareJid,
+        hash: &str,
+        data: &[u8],
+    ) {
+        let thumbnail = match avatar::decode_png(data) {
+            Ok(image) => image.resize(THUMBNAIL_SIZE, THUMBNAIL_SIZE),
+            Err(err) => {
+                // Other formats are drawn like on terminals without truecolor support
+                debug!("Cannot decode avatar of {}: {}", jid, err);
+                Image::placeholder(THUMBNAIL_SIZE, THUMBNAIL_SIZE)
+            }
+        };
+        self.hashes.insert(jid.clone(), hash.to_string());
+        self.avatars.insert(jid.clone(), thumbnail.clone());
+        aparte.schedule(Event::Avatar {
+            account: account.clone(),
+            jid: jid.clone(),
+            avatar: thumbnail,
+        });
+    }
+
+    fn handle_metadata(
+        &mut self,
+        aparte: &mut Aparte,
+        account: &Account,
+        jid: BareJid,
+        metadata: Metadata,
+    ) {
+        let info = metadata
+            .infos
+            .iter()
+            .find(|info| info.type_ == "image/png")
+            .or(metadata.infos.first());
+        if let Some(info) = info {
+            let hash = info.id.to_hex();
+            if !self.use_cached(aparte, account, &jid, &hash) {
+                let request = self.pubsub_request(
+                    &jid,
+                    AvatarRequest::Data(hash.clone()),
+                    ns::AVATAR_DATA,
+                    Some(hash),
+                );
+                aparte.send(account, request);
+            }
+        }
+    }
+
+    fn handle_data(
+        &mut self,
+        aparte: &mut Aparte,
+        account: &Account,
+        jid: BareJid,
+        hash: Option<String>,
+        data: Vec<u8>,
+    ) {
+        let actual = avatar::sha1_hex(&data);
+        if hash.is_some_and(|hash| hash != actual) {
+            warn!("Avatar of {} doesn't match its hash", jid);
+            return;
+        }
+        self.store(&actual, &data);
+        self.set_avatar(aparte, account, &jid, &actual, &data);
+    }
+
+    fn handle_response(
+        &mut self,
+        aparte: &mut Aparte,
+        account: &Account,
+        jid: BareJid,
+        request: AvatarRequest,
+        iq: &Iq,
+    ) {
+        let payload = match &iq.payload {
+            IqType::Result(Some(payload)) => Some(payload.clone()),
+            IqType::Result(None) | IqType::Error(_) => None,
+            _ => return,
+        };
+
+        match request {
+            AvatarRequest::Metadata => {
+                match payload
+                    .and_then(|payload| pubsub_payload(payload, "metadata", ns::AVATAR_METADATA))
+                {
+                    Some(metadata) => {
+                        if let Ok(metadata) = Metadata::try_from(metadata) {
+                            self.handle_metadata(aparte, account, jid, metadata);
+                        }
+                    }
+                    None => {
+                        let request = self.vcard_request(&jid);
+                        aparte.send(account, request);
+                    }
+                }
+            }
+            AvatarRequest::Data(hash) => {
+                let data = payload
+                    .and_then(|payload| pubsub_payload(payload, "data", ns::AVATAR_DATA))
+                    .and_then(|data| Data::try_from(data).ok());
+                if let Some(data) = data {
+                    self.handle_data(aparte, account, jid, Some(hash), data.data);
+                }
+            }
+            AvatarRequest::VCard => {
+                let photo = payload
+                    .filter(|vcard| vcard.is("vCard", NS_VCARD_TEMP))
+                    .and_then(|vcard| vcard.get_child("PHOTO", NS_VCARD_TEMP).cloned())
+                    .and_then(|photo| photo.get_child("BINVAL", NS_VCARD_TEMP).map(Element::text));
+                if let Some(photo) = photo {
+                    let photo: String = photo.split_whitespace().collect();
+                    match base64::decode(&photo) {
+                        Ok(data) => self.handle_data(aparte, account, jid, None, data),
+                        Err(err) => warn!("Invalid vCard photo of {}: {}", jid, err),
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl ModTrait for AvatarMod {
+    fn init(&mut self, _aparte: &mut Aparte) -> Result<(), ()> {
+        Ok(())
+    }
+
+    fn on_event(&mut self, aparte: &mut Aparte, event: &Event) {
+        match event {
+            Event::Iq(account, iq) => {
+                if let Some((jid, request)) = self.requests.remove(&iq.id) {
+                    self.handle_response(aparte, account, jid, request, iq);
+                }
+            }
+            Event::Contact(account, contact) if aparte.config.roster_avatars => {
+                let request = self.request(&contact.jid);
+                aparte.send(account, request);
+            }
+            // XEP-0153: vCard-Based Avatars
+            Event::Presence(account, presence) => {
+                let jid = match &presence.from {
+                    Some(from) => BareJid::from(from.clone()),
+                    None => return,
+                };
+                let hash = presence
+                    .payloads
+                    .iter()
+                    .find(|payload| payload.is("x", NS_VCARD_UPDATE))
+                    .and_then(|update| update.get_child("photo", NS_VCARD_UPDATE))
+                    .map(Element::text)
+                    // The hash names the cached file, anything else could escape the cache
+                    .filter(|hash| avatar::is_sha1_hex(hash));
+                let is_contact = {
+                    let contacts = aparte.get_mod::<mods::contact::ContactMod>();
+                    contacts.get(account, &jid).is_some()
+                };
+                let pending = self.requests.values().any(|(requested, request)| {
+                    requested == &jid && matches!(request, AvatarRequest::VCard)
+                });
+                match hash {
+                    Some(hash)
+                        if is_contact
+                            && !pending
+                            && !self.use_cached(aparte, account, &jid, &hash) =>
+                    {
+                        let request = self.vcard_request(&jid);
+                        aparte.send(account, request);
+                    }
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl fmt::Display for AvatarMod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "XEP-0084: User Avatar")
+    }
+}
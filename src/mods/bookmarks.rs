@@ -12,8 +12,8 @@ use xmpp_parsers::data_forms::{DataForm, DataFormType, Field, FieldType};
 use xmpp_parsers::iq::{Iq, IqType};
 use xmpp_parsers::ns;
 use xmpp_parsers::pubsub::{
-    owner as pubsubowner, pubsub, pubsub::Items, pubsub::Publish, pubsub::PublishOptions,
-    pubsub::Retract, Item, ItemId, NodeName, PubSub, PubSubEvent, PubSubOwner,
+    owner as pubsubowner, pubsub, pubsub::Items, pubsub::Publish, pubsub::Retract, Item, ItemId,
+    NodeName, PubSub, PubSubEvent, PubSubOwner,
 };
 use xmpp_parsers::Element;
 use xmpp_parsers::{BareJid, Jid};
@@ -21,8 +21,14 @@ use xmpp_parsers::{BareJid, Jid};
 use crate::account::Account;
 use crate::command::{Command, CommandParser};
 use crate::contact;
+use crate::conversation::NotificationLevel;
 use crate::core::{Aparte, Event, ModTrait};
+use crate::mods::conversation;
 use crate::mods::disco;
+use crate::mods::info::publish_options;
+
+/// XEP-0492: Chat notification settings
+pub const NS_NOTIFICATION_SETTINGS: &str = "urn:xmpp:notification-settings:0";
 
 command_def!(bookmark_add,
 r#"/bookmark add <bookmark> <conference> [autojoin=on|off]
@@ -177,7 +183,7 @@ impl Bookmarks {
                 jid: bookmark.jid.clone(),
                 name: Some(bookmark.name.clone().unwrap_or(bookmark.jid.to_string())),
                 nick: bookmark.nick.clone(),
-                password: None,
+                password: bookmark.password.clone(),
             })
             .collect();
         let storage = bookmarks::Storage {
@@ -193,36 +199,7 @@ impl Bookmarks {
             node: NodeName(String::from(ns::BOOKMARKS)),
             items: vec![pubsub::Item(item)],
         };
-        let options = PublishOptions {
-            form: Some(DataForm {
-                type_: DataFormType::Submit,
-                form_type: Some(String::from(
-                    "http://jabber.org/protocol/pubsub#publish-options",
-                )),
-                title: None,
-                instructions: None,
-                fields: vec![
-                    Field {
-                        var: String::from("pubsub#persist_items"),
-                        type_: FieldType::Boolean,
-                        label: None,
-                        required: false,
-                        media: vec![],
-                        options: vec![],
-                        values: vec![String::from("true")],
-                    },
-                    Field {
-                        var: String::from("pubsub#access_model"),
-                        type_: FieldType::TextSingle,
-                        label: None,
-                        required: false,
-                        media: vec![],
-                        options: vec![],
-                        values: vec![String::from("whitelist")],
-                    },
-                ],
-            }),
-        };
+        let options = publish_options("whitelist");
         let pubsub = PubSub::Publish {
             publish: publish,
             publish_options: Some(options),
@@ -379,8 +356,8 @@ impl Bookmarks2 {
                     },
                     name: bookmark.name,
                     nick: bookmark.nick,
-                    password: None,
-                    extensions: Some(vec![]),
+                    password: bookmark.password,
+                    extensions: Some(bookmark.extensions.into_iter().collect()),
                 }
                 .into(),
             ),
@@ -390,36 +367,7 @@ impl Bookmarks2 {
             node: NodeName(String::from(ns::BOOKMARKS2)),
             items: vec![pubsub::Item(item)],
         };
-        let options = PublishOptions {
-            form: Some(DataForm {
-                type_: DataFormType::Submit,
-                form_type: Some(String::from(
-                    "http://jabber.org/protocol/pubsub#publish-options",
-                )),
-                title: None,
-                instructions: None,
-                fields: vec![
-                    Field {
-                        var: String::from("pubsub#persist_items"),
-                        type_: FieldType::Boolean,
-                        label: None,
-                        required: false,
-                        media: vec![],
-                        options: vec![],
-                        values: vec![String::from("true")],
-                    },
-                    Field {
-                        var: String::from("pubsub#access_model"),
-                        type_: FieldType::TextSingle,
-                        label: None,
-                        required: false,
-                        media: vec![],
-                        options: vec![],
-                        values: vec![String::from("whitelist")],
-                    },
-                ],
-            }),
-        };
+        let options = publish_options("whitelist");
         let pubsub = PubSub::Publish {
             publish: publish,
             publish_options: Some(options),
@@ -458,7 +406,11 @@ impl Bookmarks2 {
                                 nick: conf.nick.clone(),
                                 password: conf.password.clone(),
                                 autojoin: conf.autojoin == bookmarks2::Autojoin::True,
-                                extensions: None,
+                                extensions: conf.extensions.map(|extensions| {
+                                    Element::builder("extensions", ns::BOOKMARKS2)
+                                        .append_all(extensions)
+                                        .build()
+                                }),
                             };
 
                             bookmarks.push(bookmark);
@@ -572,6 +524,84 @@ impl BookmarksMod {
         }
     }
 
+    /// Remember the nick used in a bookmarked channel
+    pub fn set_nick(&mut self, conference: &BareJid, nick: String) -> Option<Element> {
+        let index = self.bookmarks_by_jid.get(&Jid::Bare(conference.clone()))?;
+        let bookmark = self.bookmarks.get_mut(*index).unwrap();
+        if bookmark.nick.as_ref() == Some(&nick) {
+            return None;
+        }
+        bookmark.nick = Some(nick);
+
+        Some(match &self.backend {
+            Backend::Bookmarks(backend) => backend.update(&self.bookmarks),
+            Backend::Bookmarks2(backend) => backend.add(bookmark.clone()),
+        })
+    }
+
+    pub fn get(&self, conference: &BareJid) -> Option<&contact::Bookmark> {
+        let index = self.bookmarks_by_jid.get(&Jid::Bare(conference.clone()))?;
+        self.bookmarks.get(*index)
+    }
+
+    /// Notification level of a bookmarked channel, synced with the bookmark extensions
+    pub fn get_notification_level(&self, conference: &BareJid) -> Option<NotificationLevel> {
+        let notify = self
+            .get(conference)?
+            .extensions
+            .as_ref()?
+            .get_child("notify", NS_NOTIFICATION_SETTINGS)?;
+        notify.children().find_map(|level| match level.name() {
+            "always" => Some(NotificationLevel::All),
+            "on-mention" => Some(NotificationLevel::Mentions),
+            "never" => Some(NotificationLevel::None),
+            _ => None,
+        })
+    }
+
+    /// Whether bookmarks can carry extensions, legacy XEP-0048 storage drops them
+    pub fn stores_extensions(&self) -> bool {
+        matches!(self.backend, Backend::Bookmarks2(_))
+    }
+
+    pub fn set_notification_level(
+        &mut self,
+        conference: &BareJid,
+        level: NotificationLevel,
+    ) -> Option<Element> {
+        if !self.stores_extensions() || self.get_notification_level(conference) == Some(level) {
+            return None;
+        }
+        let index = self.bookmarks_by_jid.get(&Jid::Bare(conference.clone()))?;
+        let bookmark = self.bookmarks.get_mut(*index).unwrap();
+        let others = bookmark
+            .extensions
+            .iter()
+            .flat_map(|extensions| extensions.children())
+            .filter(|extension| !extension.is("notify", NS_NOTIFICATION_SETTINGS))
+            .cloned()
+            .collect::<Vec<Element>>();
+        let level = match level {
+            NotificationLevel::All => "always",
+            NotificationLevel::Mentions => "on-mention",
+            NotificationLevel::None => "never",
+        };
+        let notify = Element::builder("notify", NS_NOTIFICATION_SETTINGS)
+            .append(Element::builder(level, NS_NOTIFICATION_SETTINGS).build())
+            .build();
+        bookmark.extensions = Some(
+            Element::builder("extensions", ns::BOOKMARKS2)
+                .append_all(others)
+                .append(notify)
+                .build(),
+        );
+
+        Some(match &self.backend {
+            Backend::Bookmarks(backend) => backend.update(&self.bookmarks),
+            Backend::Bookmarks2(backend) => backend.add(bookmark.clone()),
+        })
+    }
+
     fn delete(&mut self, conference: BareJid) -> Option<(contact::Bookmark, Element)> {
         if let Some(index) = self.bookmarks.iter().position(|b| {
             (conference.node.is_none() && b.name == Some(conference.to_string()))
@@ -635,9 +665,18 @@ impl BookmarksMod {
         self.bookmarks = bookmarks;
         self.update_indexes();
 
+        // Open channels are rejoined by the muc mod on reconnection, don't join them twice
+        let joined: Vec<BareJid> = {
+            let conversation = aparte.get_mod::<conversation::ConversationMod>();
+            conversation
+                .get_channels(account)
+                .map(|channel| channel.jid.clone())
+                .collect()
+        };
+
         for bookmark in added.iter() {
             aparte.schedule(Event::Bookmark(bookmark.clone()));
-            if bookmark.autojoin {
+            if bookmark.autojoin && !joined.contains(&bookmark.jid) {
                 let jid = match &bookmark.nick {
                     Some(nick) => Jid::Full(bookmark.jid.clone().with_resource(nick)),
                     None => Jid::Bare(bookmark.jid.clone()),
@@ -646,6 +685,7 @@ impl BookmarksMod {
                 aparte.schedule(Event::Join {
                     account: account.clone(),
                     channel: jid,
+                    password: bookmark.password.clone(),
                     user_request: false,
                 });
             }
@@ -725,3 +765,171 @@ impl fmt::Display for BookmarksMod {
         write!(f, "XEP-0402: PEP Native Bookmarks")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bookmarks(backend: Backend, extensions: Option<Element>) -> BookmarksMod {
+        let mut bookmarks = BookmarksMod::new();
+        bookmarks.backend = backend;
+        bookmarks.bookmarks.push(contact::Bookmark {
+            jid: BareJid::from_str("room@conference.example.org").unwrap(),
+            name: Some("room".to_string()),
+            nick: None,
+            autojoin: true,
+            password: None,
+            extensions,
+        });
+        bookmarks.update_indexes();
+        bookmarks
+    }
+
+    #[test]
+    fn test_set_nick() {
+        let mut bookmarks = bookmarks(Backend::Bookmarks2(Bookmarks2 {}), None);
+        let jid = BareJid::from_str("room@conference.example.org").unwrap();
+
+        assert!(bookmarks.set_nick(&jid, "juliet".to_string()).is_some());
+        assert_eq!(bookmarks.bookmarks[0].nick.as_deref(), Some("juliet"));
+        // Same nick doesn't update the bookmark
+        assert!(bookmarks.set_nick(&jid, "juliet".to_string()).is_none());
+
+        let other = BareJid::from_str("other@conference.example.org").unwrap();
+        assert!(bookmarks.set_nick(&other, "juliet".to_string()).is_none());
+    }
+
+    /// Payloads of the items published by a bookmarks update
+    fn published(update: Element) -> Vec<Element> {
+        let iq = Iq::try_from(update).unwrap();
+        let pubsub = match iq.payload {
+            IqType::Set(payload) => PubSub::try_from(payload).unwrap(),
+            _ => panic!("bookmarks update should be a set request"),
+        };
+        match pubsub {
+            PubSub::Publish { publish, .. } => publish
+                .items
+                .into_iter()
+                .map(|item| item.0.payload.unwrap())
+                .collect(),
+            _ => panic!("bookmarks update should be a publish request"),
+        }
+    }
+
+    #[test]
+    fn test_set_nick_keeps_password() {
+        let extensions = Element::builder("extensions", ns::BOOKMARKS2)
+            .append(
+                "<other xmlns='urn:example:other'/>"
+                    .parse::<Element>()
+                    .unwrap(),
+            )
+            .build();
+        let mut bookmarks = bookmarks(Backend::Bookmarks2(Bookmarks2 {}), Some(extensions));
+        bookmarks.bookmarks[0].password = Some("secret".to_string());
+        let jid = BareJid::from_str("room@conference.example.org").unwrap();
+
+        let update = bookmarks.set_nick(&jid, "juliet".to_string()).unwrap();
+        let payloads = published(update);
+        let conference = bookmarks2::Conference::try_from(payloads[0].clone()).unwrap();
+
+        assert_eq!(conference.nick.as_deref(), Some("juliet"));
+        assert_eq!(conference.password.as_deref(), Some("secret"));
+        assert_eq!(conference.name.as_deref(), Some("room"));
+        assert_eq!(conference.autojoin, bookmarks2::Autojoin::True);
+        assert_eq!(conference.extensions.unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_set_nick_keeps_password_legacy_backend() {
+        let mut bookmarks = bookmarks(Backend::Bookmarks(Bookmarks {}), None);
+        bookmarks.bookmarks[0].password = Some("secret".to_string());
+        let jid = BareJid::from_str("room@conference.example.org").unwrap();
+
+        let update = bookmarks.set_nick(&jid, "juliet".to_string()).unwrap();
+        let payloads = published(update);
+        let storage = bookmarks::Storage::try_from(payloads[0].clone()).unwrap();
+
+        assert_eq!(storage.conferences[0].nick.as_deref(), Some("juliet"));
+        assert_eq!(storage.conferences[0].password.as_deref(), Some("secret"));
+    }
+
+    #[test]
+    fn test_notification_level_round_trip() {
+        let other = "<other xmlns='urn:example:other'/>"
+            .parse::<Element>()
+            .unwrap();
+        let extensions = Element::builder("extensions", ns::BOOKMARKS2)
+            .append(other.clone())
+            .build();
+        let mut bookmarks = bookmarks(Backend::Bookmarks2(Bookmarks2 {}), Some(extensions));
+        let jid = BareJid::from_str("room@conference.example.org").unwrap();
+
+        assert_eq!(bookmarks.get_notification_level(&jid), None);
+        assert!(bookmarks
+            .set_notification_level(&jid, NotificationLevel::Mentions)
+            .is_some());
+        assert_eq!(
+            bookmarks.get_notification_level(&jid),
+            Some(NotificationLevel::Mentions)
+        );
+
+        // Other extensions are kept and the level is replaced
+        assert!(bookmarks
+            .set_notification_level(&jid, NotificationLevel::None)
+            .is_some());
+        assert_eq!(
+            bookmarks.get_notification_level(&jid),
+            Some(NotificationLevel::None)
+        );
+        let extensions = bookmarks.get(&jid).unwrap().extensions.as_ref().unwrap();
+        assert_eq!(extensions.children().count(), 2);
+        assert!(extensions.children().any(|child| child == &other));
+
+        // Setting the same level doesn't update the bookmark
+        assert!(bookmarks
+            .set_notification_level(&jid, NotificationLevel::None)
+            .is_none());
+    }
+
+    #[test]
+    fn test_notification_level_keeps_password() {
+        let mut bookmarks = bookmarks(Backend::Bookmarks2(Bookmarks2 {}), None);
+        bookmarks.bookmarks[0].password = Some("secret".to_string());
+        bookmarks.bookmarks[0].nick = Some("juliet".to_string());
+        let jid = BareJid::from_str("room@conference.example.org").unwrap();
+
+        let update = bookmarks
+            .set_notification_level(&jid, NotificationLevel::Mentions)
+            .unwrap();
+        let payloads = published(update);
+        let conference = bookmarks2::Conference::try_from(payloads[0].clone()).unwrap();
+
+        assert_eq!(conference.password.as_deref(), Some("secret"));
+        assert_eq!(conference.nick.as_deref(), Some("juliet"));
+        let extensions = conference.extensions.unwrap();
+        assert!(extensions[0].is("notify", NS_NOTIFICATION_SETTINGS));
+    }
+
+    #[test]
+    fn test_notification_level_legacy_backend() {
+        let mut bookmarks = bookmarks(Backend::Bookmarks(Bookmarks {}), None);
+        let jid = BareJid::from_str("room@conference.example.org").unwrap();
+
+        assert!(!bookmarks.stores_extensions());
+        assert!(bookmarks
+            .set_notification_level(&jid, NotificationLevel::All)
+            .is_none());
+        assert_eq!(bookmarks.get_notification_level(&jid), None);
+    }
+
+    #[test]
+    fn test_notification_level_not_bookmarked() {
+        let mut bookmarks = bookmarks(Backend::Bookmarks2(Bookmarks2 {}), None);
+        let jid = BareJid::from_str("other@conference.example.org").unwrap();
+
+        assert!(bookmarks
+            .set_notification_level(&jid, NotificationLevel::All)
+            .is_none());
+    }
+}
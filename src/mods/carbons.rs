@@ -1,31 +1,109 @@
 /* This Source Code Form is subject to the terms of the Mozilla Public
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::fmt;
+use std::str::FromStr;
 use uuid::Uuid;
-use xmpp_parsers::carbons;
+use xmpp_parsers::carbons as xmpp_carbons;
 use xmpp_parsers::delay::Delay;
 use xmpp_parsers::forwarding::Forwarded;
-use xmpp_parsers::iq::Iq;
+use xmpp_parsers::iq::{Iq, IqType};
 use xmpp_parsers::message::Message as XmppParsersMessage;
 use xmpp_parsers::ns;
-use xmpp_parsers::Element;
+use xmpp_parsers::{BareJid, Element, Jid};
 
 use crate::account::Account;
-use crate::core::{Aparte, Event, ModTrait};
+use crate::command::{Command, CommandParser};
+use crate::core::{stanza_error_text, Aparte, Event, ModTrait};
 use crate::mods::disco;
 
-pub struct CarbonsMod {}
+/// XEP-0334: Message Processing Hints
+const NS_HINTS: &str = "urn:xmpp:hints";
+
+command_def!(carbons,
+r#"/carbons on|off
+
+Description:
+    Enable or disable message carbons for the current account. With carbons,
+    messages sent and received by your other clients are copied to this one.
+
+Examples:
+    /carbons on
+    /carbons off
+"#,
+{
+    state: String = {
+        completion: (|_aparte, _command| {
+            vec!["on".to_string(), "off".to_string()]
+        })
+    }
+},
+|aparte, _command| {
+    let account = aparte.current_account().ok_or("No connection found".to_string())?;
+    let enable = match state.as_str() {
+        "on" => true,
+        "off" => false,
+        _ => return Err(format!("Invalid state {}, expected on or off", state)),
+    };
+    let supported = {
+        let disco = aparte.get_mod::<disco::DiscoMod>();
+        disco.has_feature(&account, ns::CARBONS)
+    };
+    if !supported {
+        return Err(format!("The server of {} doesn't support message carbons", account));
+    }
+
+    let request = {
+        let mut carbons = aparte.get_mod_mut::<CarbonsMod>();
+        carbons.set(&account, enable, true)
+    };
+    aparte.send(&account, request);
+    Ok(())
+});
+
+/// Mark an outgoing message as private so that it isn't copied to our other clients
+pub fn make_private(mut message: Element) -> Element {
+    message.append_child(xmpp_carbons::Private.into());
+    message.append_child(Element::builder("no-copy", NS_HINTS).build());
+    message
+}
+
+/// Only our server can send us carbons, anyone else is trying to spoof messages. Stanzas
+/// without from are sent by our own account.
+fn is_authentic(account: &Account, from: &Option<Jid>) -> bool {
+    let us: BareJid = account.clone().into();
+    match from {
+        Some(Jid::Bare(from)) => from == &us,
+        Some(Jid::Full(_)) => false,
+        None => true,
+    }
+}
+
+pub struct CarbonsMod {
+    /// Whether carbons should be enabled for each account, enabled by default
+    enabled: HashMap<Account, bool>,
+    /// Enable and disable requests indexed by iq id, with whether the user asked for it
+    requests: HashMap<String, (Account, bool, bool)>,
+}
 
 impl CarbonsMod {
     pub fn new() -> Self {
-        Self {}
+        Self {
+            enabled: HashMap::new(),
+            requests: HashMap::new(),
+        }
     }
 
-    fn enable(&self) -> Element {
+    fn set(&mut self, account: &Account, enable: bool, user_request: bool) -> Element {
         let id = Uuid::new_v4().to_hyphenated().to_string();
-        let iq = Iq::from_set(id, carbons::Enable);
+        let iq = match enable {
+            true => Iq::from_set(id.clone(), xmpp_carbons::Enable),
+            false => Iq::from_set(id.clone(), xmpp_carbons::Disable),
+        };
+        self.requests
+            .insert(id, (account.clone(), enable, user_request));
         iq.into()
     }
 
@@ -33,9 +111,19 @@ impl CarbonsMod {
         &mut self,
         aparte: &mut Aparte,
         account: &Account,
+        from: &Option<Jid>,
         forwarded: Forwarded,
         archive: bool,
     ) {
+        if !is_authentic(account, from) {
+            let from = from
+                .as_ref()
+                .map_or("unknown".to_string(), |from| from.to_string());
+            warn!("Rejected carbon from {}", from);
+            aparte.log(format!("Rejected carbon copy sent by {}", from));
+            return;
+        }
+
         if let Some(message) = forwarded.stanza {
             aparte.schedule(Event::RawMessage {
                 account: account.clone(),
@@ -49,6 +137,7 @@ impl CarbonsMod {
 
 impl ModTrait for CarbonsMod {
     fn init(&mut self, aparte: &mut Aparte) -> Result<(), ()> {
+        aparte.add_command(carbons::new());
         let mut disco = aparte.get_mod_mut::<disco::DiscoMod>();
         disco.add_feature(ns::CARBONS)
     }
@@ -61,9 +150,9 @@ impl ModTrait for CarbonsMod {
         _delay: &Option<Delay>,
     ) -> f64 {
         for payload in message.payloads.iter().cloned() {
-            if carbons::Received::try_from(payload.clone()).is_ok() {
-                return 1f64;
-            } else if carbons::Sent::try_from(payload.clone()).is_ok() {
+            if xmpp_carbons::Received::try_from(payload.clone()).is_ok()
+                || xmpp_carbons::Sent::try_from(payload.clone()).is_ok()
+            {
                 return 1f64;
             }
         }
@@ -79,17 +168,53 @@ impl ModTrait for CarbonsMod {
         archive: bool,
     ) {
         for payload in message.payloads.iter().cloned() {
-            if let Ok(received) = carbons::Received::try_from(payload.clone()) {
-                self.handle_carbon(aparte, account, received.forwarded, archive);
-            } else if let Ok(sent) = carbons::Sent::try_from(payload.clone()) {
-                self.handle_carbon(aparte, account, sent.forwarded, archive);
+            if let Ok(received) = xmpp_carbons::Received::try_from(payload.clone()) {
+                self.handle_carbon(aparte, account, &message.from, received.forwarded, archive);
+            } else if let Ok(sent) = xmpp_carbons::Sent::try_from(payload.clone()) {
+                self.handle_carbon(aparte, account, &message.from, sent.forwarded, archive);
             }
         }
     }
 
     fn on_event(&mut self, aparte: &mut Aparte, event: &Event) {
         match event {
-            Event::Connected(account, _jid) => aparte.send(account, self.enable()),
+            Event::Disco(account) => {
+                let supported = {
+                    let disco = aparte.get_mod::<disco::DiscoMod>();
+                    disco.has_feature(account, ns::CARBONS)
+                };
+                if !supported {
+                    aparte.log(format!(
+                        "The server of {} doesn't support message carbons",
+                        account
+                    ));
+                } else if *self.enabled.get(account).unwrap_or(&true) {
+                    let request = self.set(account, true, false);
+                    aparte.send(account, request);
+                }
+            }
+            Event::Iq(_, iq) => {
+                if let Some((account, enable, user_request)) = self.requests.remove(&iq.id) {
+                    let state = match enable {
+                        true => "enabled",
+                        false => "disabled",
+                    };
+                    match &iq.payload {
+                        IqType::Error(err) => aparte.log(format!(
+                            "Cannot change message carbons of {}: {}",
+                            account,
+                            stanza_error_text(err)
+                        )),
+                        IqType::Result(_) => {
+                            self.enabled.insert(account.clone(), enable);
+                            if user_request {
+                                aparte.log(format!("Message carbons {} for {}", state, account));
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+            }
             _ => {}
         }
     }
@@ -100,3 +225,38 @@ impl fmt::Display for CarbonsMod {
         write!(f, "XEP-0280: Message Carbons")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_carbon_from_own_account() {
+        let account = Account::from_str("me@example.org/aparte").unwrap();
+
+        assert!(is_authentic(&account, &None));
+        assert!(is_authentic(
+            &account,
+            &Some(Jid::from_str("me@example.org").unwrap())
+        ));
+    }
+
+    #[test]
+    fn test_carbon_from_other_sender_rejected() {
+        let account = Account::from_str("me@example.org/aparte").unwrap();
+
+        assert!(!is_authentic(
+            &account,
+            &Some(Jid::from_str("evil@example.org").unwrap())
+        ));
+        assert!(!is_authentic(
+            &account,
+            &Some(Jid::from_str("me@example.org/other").unwrap())
+        ));
+        assert!(!is_authentic(
+            &account,
+            &Some(Jid::from_str("example.org").unwrap())
+        ));
+    }
+}
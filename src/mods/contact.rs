@@ -52,6 +52,13 @@ impl ContactMod {
         }
     }
 
+    pub fn get(&self, account: &Account, jid: &BareJid) -> Option<&contact::Contact> {
+        self.contacts.get(&ContactIndex {
+            account: account.clone(),
+            jid: jid.clone(),
+        })
+    }
+
     fn request(&self) -> Element {
         let id = Uuid::new_v4().to_hyphenated().to_string();
         let iq = Iq::from_get(
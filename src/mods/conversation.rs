@@ -4,29 +4,113 @@
 use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::fmt;
+use std::str::FromStr;
 use unicode_segmentation::UnicodeSegmentation;
-use xmpp_parsers::{muc, BareJid, Jid};
+use xmpp_parsers::{muc, BareJid, FullJid, Jid};
 
 use crate::account::Account;
-use crate::conversation;
-use crate::core::{Aparte, Event, ModTrait};
+use crate::command::{Command, CommandParser};
+use crate::conversation::{self, NotificationLevel};
+use crate::core::{self, Aparte, Event, ModTrait};
 use crate::message;
+use crate::mods::bookmarks::BookmarksMod;
+use crate::mods::names::NamesMod;
+
+command_def!(notify,
+r#"/notify all|mentions|none
+
+    all         Notify every message
+    mentions    Notify messages of channels mentioning you, others are only
+                shown as unread
+    none        Don't notify messages
+
+Description:
+    Set which messages of the current conversation are notified. The setting
+    of bookmarked channels is saved in their bookmark when the server supports
+    PEP Native Bookmarks, otherwise it is only kept by this client.
+
+Examples:
+    /notify mentions
+"#,
+{
+    level: String = {
+        completion: (|_aparte, _command| {
+            vec!["all".to_string(), "mentions".to_string(), "none".to_string()]
+        })
+    },
+},
+|aparte, command| {
+    let conversation = core::get_current_conversation(aparte, &command)
+        .ok_or("/notify can only be used in a conversation window".to_string())?;
+    let level = NotificationLevel::from_str(&level)?;
+    let mut local_only = false;
+    if let conversation::Conversation::Channel(channel) = &conversation {
+        let update = {
+            let mut bookmarks = aparte.get_mod_mut::<BookmarksMod>();
+            local_only = bookmarks.get(&channel.jid).is_some() && !bookmarks.stores_extensions();
+            bookmarks.set_notification_level(&channel.jid, level)
+        };
+        if let Some(update) = update {
+            aparte.send(&channel.account, update);
+        }
+    }
+    {
+        let mut conversations = aparte.get_mod_mut::<ConversationMod>();
+        conversations.notification_levels.insert(conversation.clone().into(), level);
+    }
+    if local_only {
+        aparte.log(format!(
+            "Notifications of {} set to {}, only on this client as the server bookmarks can't store it",
+            conversation.get_name(),
+            level
+        ));
+    } else {
+        aparte.log(format!("Notifications of {} set to {}", conversation.get_name(), level));
+    }
+    Ok(())
+});
 
 #[derive(Debug, Clone, Eq, PartialEq, Hash)]
 struct ConversationIndex {
     account: Account,
-    jid: BareJid,
+    /// Bare jid of the contact or channel, full jid of the occupant for private chats
+    jid: Jid,
 }
 
 pub struct ConversationMod {
     /// Collections of currently opened conversations.
     conversations: HashMap<ConversationIndex, conversation::Conversation>,
+    /// Notification levels set with /notify, bookmarked channels use their bookmark first
+    notification_levels: HashMap<ConversationIndex, NotificationLevel>,
 }
 
 impl ConversationMod {
     pub fn new() -> Self {
         Self {
             conversations: HashMap::new(),
+            notification_levels: HashMap::new(),
+        }
+    }
+
+    pub fn get_notification_level(
+        &self,
+        aparte: &Aparte,
+        conversation: &conversation::Conversation,
+    ) -> NotificationLevel {
+        if let conversation::Conversation::Channel(channel) = conversation {
+            let bookmarks = aparte.get_mod::<BookmarksMod>();
+            if let Some(level) = bookmarks.get_notification_level(&channel.jid) {
+                return level;
+            }
+        }
+        if let Some(level) = self.notification_levels.get(&conversation.clone().into()) {
+            return *level;
+        }
+        let defaults = &aparte.config.notifications;
+        match conversation {
+            conversation::Conversation::Chat(_) => defaults.chat,
+            conversation::Conversation::Channel(_) => defaults.channel,
+            conversation::Conversation::PrivateChat(_) => defaults.private_chat,
         }
     }
 
@@ -37,7 +121,34 @@ impl ConversationMod {
     ) -> Option<&'a conversation::Conversation> {
         let index = ConversationIndex {
             account: account.clone(),
-            jid: jid.clone(),
+            jid: Jid::Bare(jid.clone()),
+        };
+        self.conversations.get(&index)
+    }
+
+    /// Get channels joined with the given account
+    pub fn get_channels<'a>(
+        &'a self,
+        account: &'a Account,
+    ) -> impl Iterator<Item = &'a conversation::Channel> {
+        self.conversations
+            .values()
+            .filter_map(move |conversation| match conversation {
+                conversation::Conversation::Channel(channel) if &channel.account == account => {
+                    Some(channel)
+                }
+                _ => None,
+            })
+    }
+
+    pub fn get_private_chat<'a>(
+        &'a self,
+        account: &Account,
+        occupant: &FullJid,
+    ) -> Option<&'a conversation::Conversation> {
+        let index = ConversationIndex {
+            account: account.clone(),
+            jid: Jid::Full(occupant.clone()),
         };
         self.conversations.get(&index)
     }
@@ -67,7 +178,8 @@ impl From<muc::user::Affiliation> for conversation::Affiliation {
 }
 
 impl ModTrait for ConversationMod {
-    fn init(&mut self, _aparte: &mut Aparte) -> Result<(), ()> {
+    fn init(&mut self, aparte: &mut Aparte) -> Result<(), ()> {
+        aparte.add_command(notify::new());
         Ok(())
     }
 
@@ -81,29 +193,62 @@ impl ModTrait for ConversationMod {
 
                 let index = ConversationIndex {
                     account: account.clone(),
-                    jid: contact.clone(),
+                    jid: Jid::Bare(contact.clone()),
+                };
+                self.conversations.insert(index, conversation);
+            }
+            Event::PrivateChat { account, occupant } => {
+                let conversation =
+                    conversation::Conversation::PrivateChat(conversation::PrivateChat {
+                        account: account.clone(),
+                        occupant: occupant.clone(),
+                    });
+
+                let index = ConversationIndex {
+                    account: account.clone(),
+                    jid: Jid::Full(occupant.clone()),
                 };
                 self.conversations.insert(index, conversation);
             }
             Event::Message(account, message) => {
                 if let message::Message::Xmpp(message) = message {
                     let account = account.as_ref().unwrap();
-                    let index = ConversationIndex {
-                        account: account.clone(),
-                        jid: message.from.clone(),
+                    let index = match (&message.type_, &message.from_full) {
+                        (message::XmppMessageType::PrivateChat, Jid::Full(from)) => {
+                            ConversationIndex {
+                                account: account.clone(),
+                                jid: Jid::Full(from.clone()),
+                            }
+                        }
+                        _ => ConversationIndex {
+                            account: account.clone(),
+                            jid: Jid::Bare(message.from.clone()),
+                        },
                     };
 
                     // Create a conversation for incomming chat messages
-                    if message.type_ == message::XmppMessageType::Chat
-                        && message.direction == message::Direction::Incoming
+                    if message.direction == message::Direction::Incoming
+                        && !self.conversations.contains_key(&index)
                     {
-                        if self.conversations.get(&index).is_none() {
-                            let conversation =
-                                conversation::Conversation::Chat(conversation::Chat {
-                                    account: account.clone(),
-                                    contact: message.from.clone(),
-                                });
-                            self.conversations.insert(index.clone(), conversation);
+                        match (&message.type_, &message.from_full) {
+                            (message::XmppMessageType::Chat, _) => {
+                                let conversation =
+                                    conversation::Conversation::Chat(conversation::Chat {
+                                        account: account.clone(),
+                                        contact: message.from.clone(),
+                                    });
+                                self.conversations.insert(index.clone(), conversation);
+                            }
+                            (message::XmppMessageType::PrivateChat, Jid::Full(from)) => {
+                                let conversation = conversation::Conversation::PrivateChat(
+                                    conversation::PrivateChat {
+                                        account: account.clone(),
+                                        occupant: from.clone(),
+                                    },
+                                );
+                                self.conversations.insert(index.clone(), conversation);
+                            }
+                            _ => {}
                         }
                     }
 
@@ -111,8 +256,9 @@ impl ModTrait for ConversationMod {
                     if !message.archive && message.direction == message::Direction::Incoming {
                         let conversation = self.conversations.get(&index);
                         if let Some(conversation) = conversation {
-                            let important = match &conversation {
-                                conversation::Conversation::Chat(_) => true,
+                            let mention = match &conversation {
+                                conversation::Conversation::Chat(_)
+                                | conversation::Conversation::PrivateChat(_) => true,
                                 conversation::Conversation::Channel(channel) => {
                                     // Look for mentions
                                     let mut mention = false;
@@ -125,10 +271,18 @@ impl ModTrait for ConversationMod {
                                     mention
                                 }
                             };
-                            aparte.schedule(Event::Notification {
-                                conversation: conversation.clone(),
-                                important,
-                            });
+                            let important = match self.get_notification_level(aparte, conversation)
+                            {
+                                NotificationLevel::All => Some(true),
+                                NotificationLevel::Mentions => Some(mention),
+                                NotificationLevel::None => None,
+                            };
+                            if let Some(important) = important {
+                                aparte.schedule(Event::Notification {
+                                    conversation: conversation.clone(),
+                                    important,
+                                });
+                            }
                         }
                     }
                 }
@@ -137,31 +291,49 @@ impl ModTrait for ConversationMod {
                 account, channel, ..
             } => {
                 let channel_jid: BareJid = channel.clone().into();
+                let name = aparte.get_mod::<NamesMod>().get(&channel_jid);
                 let conversation = conversation::Conversation::Channel(conversation::Channel {
                     account: account.clone(),
                     jid: channel_jid.clone(),
                     nick: channel.resource.clone(),
-                    name: None,
+                    name,
                     occupants: HashMap::new(),
                 });
 
                 let index = ConversationIndex {
                     account: account.clone(),
-                    jid: channel_jid,
+                    jid: Jid::Bare(channel_jid),
                 };
                 self.conversations.insert(index, conversation);
             }
             Event::Presence(account, presence) => {
                 if let Some(Jid::Full(from)) = &presence.from {
+                    let channel_jid: BareJid = from.clone().into();
                     let index = ConversationIndex {
                         account: account.clone(),
-                        jid: from.clone().into(),
+                        jid: Jid::Bare(channel_jid.clone()),
                     };
                     if let Some(conversation::Conversation::Channel(channel)) =
                         self.conversations.get_mut(&index)
                     {
                         for payload in presence.clone().payloads {
                             if let Some(muc_user) = muc::user::MucUser::try_from(payload).ok() {
+                                if muc_user.status.contains(&muc::user::Status::NewNick) {
+                                    let nick = muc_user.items.iter().find_map(|i| i.nick.clone());
+                                    let occupant = channel.occupants.remove(&from.resource);
+                                    if let (Some(nick), Some(occupant)) = (nick, occupant) {
+                                        if channel.nick == occupant.nick {
+                                            channel.nick = nick.clone();
+                                        }
+                                        aparte.schedule(Event::NickChanged {
+                                            account: index.account.clone(),
+                                            conversation: channel_jid.clone(),
+                                            occupant,
+                                            nick,
+                                        });
+                                    }
+                                    continue;
+                                }
                                 for item in muc_user.items {
                                     let occupant_jid = match item.jid {
                                         Some(full) => Some(full.into()),
@@ -175,7 +347,7 @@ impl ModTrait for ConversationMod {
                                     };
                                     aparte.schedule(Event::Occupant {
                                         account: index.account.clone(),
-                                        conversation: index.jid.clone(),
+                                        conversation: channel_jid.clone(),
                                         occupant: occupant.clone(),
                                     });
                                     channel.occupants.insert(occupant.nick.clone(), occupant);
@@ -188,6 +360,16 @@ impl ModTrait for ConversationMod {
             Event::Leave(channel) => {
                 self.conversations.remove(&channel.clone().into());
             }
+            Event::DisplayName { jid, name } => {
+                for conversation in self.conversations.values_mut() {
+                    match conversation {
+                        conversation::Conversation::Channel(channel) if &channel.jid == jid => {
+                            channel.name = Some(name.clone());
+                        }
+                        _ => {}
+                    }
+                }
+            }
             _ => {}
         }
     }
@@ -197,7 +379,7 @@ impl Into<ConversationIndex> for conversation::Channel {
     fn into(self) -> ConversationIndex {
         ConversationIndex {
             account: self.account,
-            jid: self.jid,
+            jid: Jid::Bare(self.jid),
         }
     }
 }
@@ -206,7 +388,16 @@ impl Into<ConversationIndex> for conversation::Chat {
     fn into(self) -> ConversationIndex {
         ConversationIndex {
             account: self.account,
-            jid: self.contact,
+            jid: Jid::Bare(self.contact),
+        }
+    }
+}
+
+impl From<conversation::PrivateChat> for ConversationIndex {
+    fn from(chat: conversation::PrivateChat) -> Self {
+        ConversationIndex {
+            account: chat.account,
+            jid: Jid::Full(chat.occupant),
         }
     }
 }
@@ -216,6 +407,7 @@ impl Into<ConversationIndex> for conversation::Conversation {
         match self {
             conversation::Conversation::Channel(channel) => channel.into(),
             conversation::Conversation::Chat(chat) => chat.into(),
+            conversation::Conversation::PrivateChat(chat) => chat.into(),
         }
     }
 }
@@ -31,7 +31,9 @@ impl CorrectionMod {
     ) {
         let event = {
             let mut messages = aparte.get_mod_mut::<messages::MessagesMod>();
-            if let Some(original) = messages.get_mut(&Some(account.clone()), &replace.id) {
+            if let Some(original) =
+                messages.get_mut(&Some(account.clone()), &message.from, &replace.id)
+            {
                 match original {
                     Message::Xmpp(original) => {
                         original.add_version_from_xmpp(message);
@@ -1,21 +1,91 @@
 /* This Source Code Form is subject to the terms of the Mozilla Public
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::convert::TryFrom;
 use std::fmt;
 use std::str::FromStr;
 use uuid::Uuid;
-use xmpp_parsers::disco;
+use xmpp_parsers::disco as xmpp_disco;
 use xmpp_parsers::iq::{Iq, IqType};
-use xmpp_parsers::{Element, Jid};
+use xmpp_parsers::{BareJid, Element, Jid};
 
 use crate::account::Account;
-use crate::core::{Aparte, Event, ModTrait};
+use crate::command::{Command, CommandParser};
+use crate::core::{self, Aparte, Event, ModTrait};
+
+command_def!(disco,
+r#"/disco [<jid>] [<node>]
+
+    jid     Entity to discover, defaults to the server of the current account
+    node    Node of the entity to discover
+
+Description:
+    Query the identities, features and items of an entity and show them in
+    the disco window. Items are numbered, use their number as jid to browse
+    them.
+
+Examples:
+    /disco
+    /disco conference.server.tld
+    /disco pubsub.server.tld princely_musings
+    /disco 2
+"#,
+{
+    jid: Option<String> = {
+        completion: (|aparte, _command| {
+            let disco = aparte.get_mod::<DiscoMod>();
+            disco.items.values().flatten().map(|item| item.jid.to_string()).collect()
+        })
+    },
+    node: Option<String>,
+},
+|aparte, _command| {
+    let account = aparte.current_account().ok_or("No connection found".to_string())?;
+    let request = {
+        let mut disco = aparte.get_mod_mut::<DiscoMod>();
+        let (jid, node) = match jid {
+            None => (Jid::Bare(BareJid::domain(&account.domain)), node),
+            Some(jid) => match jid.parse::<usize>() {
+                Ok(position) => {
+                    let item = disco
+                        .get_item(&account, position)
+                        .ok_or(format!("No item {}", position))?;
+                    (item.jid.clone(), item.node.clone())
+                }
+                Err(_) => (
+                    Jid::from_str(&jid).map_err(|e| format!("Invalid JID {}: {}", jid, e))?,
+                    node,
+                ),
+            },
+        };
+        disco.browse(&account, jid, node)
+    };
+    for element in request {
+        aparte.send(&account, element);
+    }
+    Ok(())
+});
+
+/// Pending disco#info and disco#items queries of an entity browsed with /disco
+struct Browse {
+    account: Account,
+    jid: Jid,
+    node: Option<String>,
+    info: Option<Option<xmpp_disco::DiscoInfoResult>>,
+    items: Option<Vec<xmpp_disco::Item>>,
+}
 
 pub struct DiscoMod {
     client_features: Vec<String>,
     server_features: HashMap<Account, Vec<String>>,
+    /// Ids of pending server disco requests, other mods handle disco of other entities
+    requests: HashSet<String>,
+    /// Pending /disco queries by id of their disco#info (true) and disco#items requests
+    browse_requests: HashMap<String, (String, bool)>,
+    browses: HashMap<String, Browse>,
+    /// Items of the last entity browsed with each account
+    items: HashMap<Account, Vec<xmpp_disco::Item>>,
 }
 
 impl DiscoMod {
@@ -23,6 +93,10 @@ impl DiscoMod {
         Self {
             client_features: Vec::new(),
             server_features: HashMap::new(),
+            requests: HashSet::new(),
+            browse_requests: HashMap::new(),
+            browses: HashMap::new(),
+            items: HashMap::new(),
         }
     }
 
@@ -43,14 +117,92 @@ impl DiscoMod {
 
     pub fn disco(&mut self, jid: Jid) -> Element {
         let id = Uuid::new_v4().to_hyphenated().to_string();
-        let query = disco::DiscoInfoQuery { node: None };
-        let iq = Iq::from_get(id, query).with_to(Jid::from_str(&jid.domain()).unwrap());
-        iq.into()
+        self.requests.insert(id.clone());
+        info_request(id, Jid::from_str(&jid.domain()).unwrap(), None)
     }
+
+    /// Numbered item of the last entity browsed with the account
+    fn get_item(&self, account: &Account, position: usize) -> Option<&xmpp_disco::Item> {
+        let items = self.items.get(account)?;
+        position.checked_sub(1).and_then(|index| items.get(index))
+    }
+
+    fn browse(&mut self, account: &Account, jid: Jid, node: Option<String>) -> Vec<Element> {
+        let id = Uuid::new_v4().to_hyphenated().to_string();
+        let info_id = Uuid::new_v4().to_hyphenated().to_string();
+        let items_id = Uuid::new_v4().to_hyphenated().to_string();
+        self.browse_requests
+            .insert(info_id.clone(), (id.clone(), true));
+        self.browse_requests
+            .insert(items_id.clone(), (id.clone(), false));
+        self.browses.insert(
+            id,
+            Browse {
+                account: account.clone(),
+                jid: jid.clone(),
+                node: node.clone(),
+                info: None,
+                items: None,
+            },
+        );
+
+        vec![
+            info_request(info_id, jid.clone(), node.clone()),
+            items_request(items_id, jid, node),
+        ]
+    }
+
+    fn handle_browse(&mut self, aparte: &mut Aparte, browse_id: String, info: bool, iq: &Iq) {
+        let browse = match self.browses.get_mut(&browse_id) {
+            Some(browse) => browse,
+            None => return,
+        };
+
+        match (iq.payload.clone(), info) {
+            (IqType::Result(Some(el)), true) => {
+                browse.info = Some(xmpp_disco::DiscoInfoResult::try_from(el).ok());
+            }
+            (IqType::Result(Some(el)), false) => {
+                let items = xmpp_disco::DiscoItemsResult::try_from(el);
+                browse.items = Some(items.map(|result| result.items).unwrap_or_default());
+            }
+            (IqType::Error(err), true) => {
+                let text = core::stanza_error_text(&err);
+                aparte.log(format!("Cannot discover {}: {}", browse.jid, text));
+                browse.info = Some(None);
+            }
+            (IqType::Error(_), false) => browse.items = Some(Vec::new()),
+            _ => return,
+        }
+
+        if browse.info.is_some() && browse.items.is_some() {
+            let browse = self.browses.remove(&browse_id).unwrap();
+            let items = browse.items.unwrap();
+            self.items.insert(browse.account.clone(), items.clone());
+            aparte.schedule(Event::DiscoBrowse {
+                account: browse.account,
+                jid: browse.jid,
+                node: browse.node,
+                info: browse.info.unwrap(),
+                items,
+            });
+        }
+    }
+}
+
+pub fn info_request(id: String, jid: Jid, node: Option<String>) -> Element {
+    let query = xmpp_disco::DiscoInfoQuery { node };
+    Iq::from_get(id, query).with_to(jid).into()
+}
+
+pub fn items_request(id: String, jid: Jid, node: Option<String>) -> Element {
+    let query = xmpp_disco::DiscoItemsQuery { node };
+    Iq::from_get(id, query).with_to(jid).into()
 }
 
 impl ModTrait for DiscoMod {
-    fn init(&mut self, _aparte: &mut Aparte) -> Result<(), ()> {
+    fn init(&mut self, aparte: &mut Aparte) -> Result<(), ()> {
+        aparte.add_command(disco::new());
         Ok(())
     }
 
@@ -60,17 +212,20 @@ impl ModTrait for DiscoMod {
                 self.server_features.insert(account.clone(), Vec::new());
                 aparte.send(account, self.disco(jid.clone()));
             }
-            Event::Iq(account, iq) => match iq.payload.clone() {
-                IqType::Result(Some(el)) => {
-                    if let Ok(disco) = disco::DiscoInfoResult::try_from(el) {
+            Event::Iq(account, iq) if self.requests.remove(&iq.id) => {
+                if let IqType::Result(Some(el)) = iq.payload.clone() {
+                    if let Ok(disco) = xmpp_disco::DiscoInfoResult::try_from(el) {
                         if let Some(features) = self.server_features.get_mut(account) {
                             features.extend(disco.features.iter().map(|i| i.var.clone()));
                             aparte.schedule(Event::Disco(account.clone()));
                         }
                     }
                 }
-                _ => {}
-            },
+            }
+            Event::Iq(_, iq) if self.browse_requests.contains_key(&iq.id) => {
+                let (browse_id, info) = self.browse_requests.remove(&iq.id).unwrap();
+                self.handle_browse(aparte, browse_id, info, iq);
+            }
             _ => {}
         }
     }
@@ -81,3 +236,70 @@ impl fmt::Display for DiscoMod {
         write!(f, "XEP-0030: Service Discovery")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_browse_requests() {
+        let account = Account::from_str("me@example.org/aparte").unwrap();
+        let jid = Jid::from_str("pubsub.example.org").unwrap();
+        let mut disco = DiscoMod::new();
+        let requests = disco.browse(&account, jid.clone(), Some("node".to_string()));
+        assert_eq!(requests.len(), 2);
+
+        let info = Iq::try_from(requests[0].clone()).unwrap();
+        let items = Iq::try_from(requests[1].clone()).unwrap();
+        assert_eq!(info.to, Some(jid.clone()));
+        match &info.payload {
+            IqType::Get(query) => {
+                let query = xmpp_disco::DiscoInfoQuery::try_from(query.clone()).unwrap();
+                assert_eq!(query.node.as_deref(), Some("node"));
+            }
+            _ => panic!("disco#info should be a get request"),
+        }
+        match &items.payload {
+            IqType::Get(query) => {
+                let query = xmpp_disco::DiscoItemsQuery::try_from(query.clone()).unwrap();
+                assert_eq!(query.node.as_deref(), Some("node"));
+            }
+            _ => panic!("disco#items should be a get request"),
+        }
+
+        // Both answers are tracked for the same browse
+        let (info_browse, is_info) = disco.browse_requests.get(&info.id).unwrap().clone();
+        let (items_browse, is_items_info) = disco.browse_requests.get(&items.id).unwrap().clone();
+        assert_eq!(info_browse, items_browse);
+        assert!(is_info);
+        assert!(!is_items_info);
+        assert_eq!(disco.browses.get(&info_browse).unwrap().jid, jid);
+    }
+
+    #[test]
+    fn test_get_item() {
+        let account = Account::from_str("me@example.org/aparte").unwrap();
+        let items: Element = r#"<query xmlns='http://jabber.org/protocol/disco#items'>
+                <item jid='conference.example.org'/>
+                <item jid='pubsub.example.org' node='node'/>
+            </query>"#
+            .parse()
+            .unwrap();
+        let items = xmpp_disco::DiscoItemsResult::try_from(items).unwrap().items;
+        let mut disco = DiscoMod::new();
+        disco.items.insert(account.clone(), items);
+
+        assert_eq!(
+            disco.get_item(&account, 1).map(|item| item.jid.to_string()),
+            Some("conference.example.org".to_string())
+        );
+        assert_eq!(
+            disco
+                .get_item(&account, 2)
+                .and_then(|item| item.node.clone()),
+            Some("node".to_string())
+        );
+        assert!(disco.get_item(&account, 0).is_none());
+        assert!(disco.get_item(&account, 3).is_none());
+    }
+}
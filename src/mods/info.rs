@@ -0,0 +1,540 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+use chrono::{DateTime, FixedOffset};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+use uuid::Uuid;
+use xmpp_parsers::data_forms::{DataForm, DataFormType, Field, FieldType};
+use xmpp_parsers::iq::{Iq, IqType};
+use xmpp_parsers::nick::Nick;
+use xmpp_parsers::presence::{Presence, Type as PresenceType};
+use xmpp_parsers::pubsub::{pubsub::Items, pubsub::PublishOptions, NodeName, PubSub};
+use xmpp_parsers::time::{TimeQuery, TimeResult};
+use xmpp_parsers::version::{VersionQuery, VersionResult};
+use xmpp_parsers::{ns, BareJid, Element, FullJid, Jid};
+
+use crate::account::Account;
+use crate::command::{Command, CommandParser};
+use crate::contact;
+use crate::core::{self, Aparte, Event, ModTrait};
+use crate::mods;
+
+pub const NS_VCARD4: &str = "urn:ietf:params:xml:ns:vcard-4.0";
+pub const NODE_VCARD4: &str = "urn:xmpp:vcard4";
+pub const NS_VCARD_TEMP: &str = "vcard-temp";
+pub const NS_LAST: &str = "jabber:iq:last";
+
+command_def!(info,
+r#"/info <contact>
+
+    contact     JID of the contact, or nick of an occupant inside a channel
+
+Description:
+    Open a window showing what is known about a contact: its vCard,
+    nickname, client version, last activity, local time and roster
+    entry. Fields are filled as the contact answers.
+
+Examples:
+    /info contact@server.tld
+    /info contact@server.tld/resource
+    /info nick
+"#,
+{
+    contact: String = {
+        completion: (|aparte, command| {
+            let mut completions: Vec<String> = match core::get_current_channel(aparte, &command) {
+                Some(channel) => channel.occupants.keys().cloned().collect(),
+                None => Vec::new(),
+            };
+            let contact = aparte.get_mod::<mods::contact::ContactMod>();
+            completions.extend(contact.contacts.values().map(|contact| contact.jid.to_string()));
+            completions
+        })
+    },
+},
+|aparte, command| {
+    // Inside a channel a nick designates an occupant
+    let (account, jid, address) = match core::get_current_channel(aparte, &command) {
+        Some(channel) if channel.occupants.contains_key(&contact) => {
+            let occupant = channel.occupants.get(&contact).unwrap();
+            let address = occupant.jid.clone();
+            let jid = Jid::Full(channel.jid.clone().with_resource(contact));
+            (channel.account.clone(), jid, address)
+        }
+        _ => {
+            let account = aparte.current_account().ok_or("No connection found".to_string())?;
+            let jid = Jid::from_str(&contact).map_err(|e| format!("Invalid JID {}: {}", contact, e))?;
+            let address = Some(BareJid::from(jid.clone()));
+            (account, jid, address)
+        }
+    };
+
+    let roster = address.as_ref().and_then(|address| {
+        let contacts = aparte.get_mod::<mods::contact::ContactMod>();
+        contacts.get(&account, address).cloned()
+    });
+    let (info, requests) = {
+        let mut info = aparte.get_mod_mut::<InfoMod>();
+        info.query(&account, jid, address.clone(), roster)
+    };
+    aparte.schedule(Event::ContactInfo {
+        account: account.clone(),
+        info,
+    });
+    for request in requests {
+        aparte.send(&account, request);
+    }
+    if let Some(address) = address {
+        let request = {
+            let mut avatar = aparte.get_mod_mut::<mods::avatar::AvatarMod>();
+            avatar.request(&address)
+        };
+        aparte.send(&account, request);
+    }
+    Ok(())
+});
+
+/// Properties of a vCard, as label and value in display order
+#[derive(Debug, Clone)]
+pub struct VCard {
+    pub properties: Vec<(String, String)>,
+}
+
+impl VCard {
+    fn from_element(vcard: &Element, labels: &[(&str, &str)]) -> Self {
+        let mut properties = Vec::new();
+        for (name, label) in labels.iter() {
+            for property in vcard.children().filter(|child| child.name() == *name) {
+                let mut values = Vec::new();
+                property_values(property, &mut values);
+                if !values.is_empty() {
+                    properties.push((label.to_string(), values.join(", ")));
+                }
+            }
+        }
+        Self { properties }
+    }
+
+    /// XEP-0292: vCard4 Over XMPP
+    fn from_vcard4(vcard: &Element) -> Self {
+        Self::from_element(
+            vcard,
+            &[
+                ("fn", "Name"),
+                ("nickname", "Nickname"),
+                ("bday", "Birthday"),
+                ("email", "Email"),
+                ("tel", "Phone"),
+                ("impp", "Chat"),
+                ("url", "Website"),
+                ("adr", "Address"),
+                ("org", "Organization"),
+                ("title", "Title"),
+                ("role", "Role"),
+                ("tz", "Timezone"),
+                ("note", "Note"),
+            ],
+        )
+    }
+
+    /// XEP-0054: vcard-temp
+    fn from_vcard_temp(vcard: &Element) -> Self {
+        Self::from_element(
+            vcard,
+            &[
+                ("FN", "Name"),
+                ("NICKNAME", "Nickname"),
+                ("BDAY", "Birthday"),
+                ("EMAIL", "Email"),
+                ("TEL", "Phone"),
+                ("JABBERID", "Chat"),
+                ("URL", "Website"),
+                ("ADR", "Address"),
+                ("ORG", "Organization"),
+                ("TITLE", "Title"),
+                ("ROLE", "Role"),
+                ("TZ", "Timezone"),
+                ("DESC", "Note"),
+            ],
+        )
+    }
+}
+
+/// Collect the non empty texts of a vCard property, skipping its parameters
+fn property_values(property: &Element, values: &mut Vec<String>) {
+    if property.children().next().is_none() {
+        let text = property.text();
+        let text = text.trim();
+        if !text.is_empty() {
+            values.push(text.trim_start_matches("tel:").to_string());
+        }
+    }
+    for child in property.children() {
+        if child.name() != "parameters" {
+            property_values(child, values);
+        }
+    }
+}
+
+/// XEP-0012: Last Activity
+#[derive(Debug, Clone)]
+pub struct LastActivity {
+    /// Idle time of a client, or time since the last logout of an account
+    pub seconds: u64,
+    pub status: Option<String>,
+}
+
+/// What is known about a contact, filled as queries are answered
+#[derive(Debug, Clone)]
+pub struct ContactInfo {
+    pub id: String,
+    pub jid: Jid,
+    /// Real JID of the contact, unknown for some channel occupants
+    pub address: Option<BareJid>,
+    pub roster: Option<contact::Contact>,
+    /// Resource queried for client version, local time and idle time
+    pub client: Option<FullJid>,
+    pub vcard: Option<VCard>,
+    pub nick: Option<String>,
+    pub version: Option<VersionResult>,
+    pub last_activity: Option<LastActivity>,
+    pub time: Option<DateTime<FixedOffset>>,
+    /// Number of queries still waiting for an answer
+    pub pending: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum InfoQuery {
+    VCard4,
+    VCardTemp,
+    Nick,
+    Version,
+    LastActivity,
+    Time,
+}
+
+pub struct InfoMod {
+    /// Last available resource of each contact
+    resources: HashMap<(Account, BareJid), FullJid>,
+    /// Contact infos being filled, indexed by their id
+    infos: HashMap<String, (Account, ContactInfo)>,
+    /// Pending queries, indexed by iq id
+    requests: HashMap<String, (String, InfoQuery)>,
+}
+
+impl InfoMod {
+    pub fn new() -> Self {
+        Self {
+            resources: HashMap::new(),
+            infos: HashMap::new(),
+            requests: HashMap::new(),
+        }
+    }
+
+    fn query(
+        &mut self,
+        account: &Account,
+        jid: Jid,
+        address: Option<BareJid>,
+        roster: Option<contact::Contact>,
+    ) -> (ContactInfo, Vec<Element>) {
+        let info_id = Uuid::new_v4().to_hyphenated().to_string();
+
+        // Clients are queried on their last available resource
+        let client = match &jid {
+            Jid::Full(jid) => Some(jid.clone()),
+            Jid::Bare(jid) => self.resources.get(&(account.clone(), jid.clone())).cloned(),
+        };
+
+        let mut requests = Vec::new();
+        if let Some(address) = &address {
+            let address = Jid::Bare(address.clone());
+            requests.push(self.request(&info_id, address.clone(), InfoQuery::VCard4));
+            requests.push(self.request(&info_id, address, InfoQuery::Nick));
+        }
+        if let Some(client) = &client {
+            let client = Jid::Full(client.clone());
+            requests.push(self.request(&info_id, client.clone(), InfoQuery::Version));
+            requests.push(self.request(&info_id, client, InfoQuery::Time));
+        }
+        let last = client.clone().map(Jid::Full).unwrap_or(jid.clone());
+        requests.push(self.request(&info_id, last, InfoQuery::LastActivity));
+
+        let info = ContactInfo {
+            id: info_id.clone(),
+            jid,
+            address,
+            roster,
+            client,
+            vcard: None,
+            nick: None,
+            version: None,
+            last_activity: None,
+            time: None,
+            pending: requests.len(),
+        };
+        self.infos.insert(info_id, (account.clone(), info.clone()));
+        (info, requests)
+    }
+
+    fn request(&mut self, info_id: &str, jid: Jid, query: InfoQuery) -> Element {
+        let id = Uuid::new_v4().to_hyphenated().to_string();
+        self.requests
+            .insert(id.clone(), (info_id.to_string(), query));
+        let iq = match query {
+            InfoQuery::VCard4 | InfoQuery::Nick => {
+                let node = match query {
+                    InfoQuery::VCard4 => NODE_VCARD4,
+                    _ => ns::NICK,
+                };
+                let items = Items {
+                    max_items: Some(1),
+                    node: NodeName(node.to_string()),
+                    subid: None,
+                    items: vec![],
+                };
+                Iq::from_get(id, PubSub::Items(items))
+            }
+            InfoQuery::VCardTemp => Iq {
+                from: None,
+                to: None,
+                id,
+                payload: IqType::Get(Element::builder("vCard", NS_VCARD_TEMP).build()),
+            },
+            InfoQuery::Version => Iq::from_get(id, VersionQuery),
+            InfoQuery::LastActivity => Iq {
+                from: None,
+                to: None,
+                id,
+                payload: IqType::Get(Element::builder("query", NS_LAST).build()),
+            },
+            InfoQuery::Time => Iq::from_get(id, TimeQuery),
+        };
+        iq.with_to(jid).into()
+    }
+
+    fn update_resource(&mut self, account: &Account, from: &FullJid, presence: &Presence) {
+        let index: (Account, BareJid) = (account.clone(), from.clone().into());
+        match presence.type_ {
+            PresenceType::None => {
+                self.resources.insert(index, from.clone());
+            }
+            PresenceType::Unavailable if self.resources.get(&index) == Some(from) => {
+                self.resources.remove(&index);
+            }
+            _ => {}
+        }
+    }
+
+    fn handle_response(
+        &mut self,
+        aparte: &mut Aparte,
+        info_id: String,
+        query: InfoQuery,
+        response: Option<Element>,
+    ) {
+        let (account, mut info) = match self.infos.remove(&info_id) {
+            Some(info) => info,
+            None => return,
+        };
+
+        match (query, response) {
+            (InfoQuery::VCard4, response) => {
+                match response.and_then(|pubsub| pubsub_payload(pubsub, "vcard", NS_VCARD4)) {
+                    Some(vcard) => info.vcard = Some(VCard::from_vcard4(&vcard)),
+                    None => {
+                        // Fall back to vcard-temp for contacts without vCard4
+                        let address = Jid::Bare(info.address.clone().unwrap());
+                        let request = self.request(&info_id, address, InfoQuery::VCardTemp);
+                        aparte.send(&account, request);
+                        info.pending += 1;
+                    }
+                }
+            }
+            (InfoQuery::VCardTemp, Some(vcard)) if vcard.is("vCard", NS_VCARD_TEMP) => {
+                let vcard = VCard::from_vcard_temp(&vcard);
+                if !vcard.properties.is_empty() {
+                    info.vcard = Some(vcard);
+                }
+            }
+            (InfoQuery::Nick, Some(pubsub)) => {
+                info.nick = pubsub_payload(pubsub, "nick", ns::NICK)
+                    .and_then(|nick| Nick::try_from(nick).ok())
+                    .map(|nick| nick.0);
+            }
+            (InfoQuery::Version, Some(version)) => {
+                info.version = VersionResult::try_from(version).ok();
+            }
+            (InfoQuery::LastActivity, Some(last)) if last.is("query", NS_LAST) => {
+                info.last_activity = last
+                    .attr("seconds")
+                    .and_then(|seconds| seconds.parse().ok())
+                    .map(|seconds| LastActivity {
+                        seconds,
+                        status: match last.text().trim() {
+                            "" => None,
+                            status => Some(status.to_string()),
+                        },
+                    });
+            }
+            (InfoQuery::Time, Some(time)) => {
+                info.time = TimeResult::try_from(time).ok().map(|time| (time.0).0);
+            }
+            _ => {}
+        }
+
+        info.pending -= 1;
+        aparte.schedule(Event::ContactInfo {
+            account: account.clone(),
+            info: info.clone(),
+        });
+        if info.pending > 0 {
+            self.infos.insert(info_id, (account, info));
+        }
+    }
+}
+
+/// Options of items published to our PEP nodes, persisted with the given access model
+pub fn publish_options(access_model: &str) -> PublishOptions {
+    PublishOptions {
+        form: Some(DataForm {
+            type_: DataFormType::Submit,
+            form_type: Some(String::from(
+                "http://jabber.org/protocol/pubsub#publish-options",
+            )),
+            title: None,
+            instructions: None,
+            fields: vec![
+                Field {
+                    var: String::from("pubsub#persist_items"),
+                    type_: FieldType::Boolean,
+                    label: None,
+                    required: false,
+                    media: vec![],
+                    options: vec![],
+                    values: vec![String::from("true")],
+                },
+                Field {
+                    var: String::from("pubsub#access_model"),
+                    type_: FieldType::TextSingle,
+                    label: None,
+                    required: false,
+                    media: vec![],
+                    options: vec![],
+                    values: vec![String::from(access_model)],
+                },
+            ],
+        }),
+    }
+}
+
+/// Payload of the first item of a pubsub items result
+pub fn pubsub_payload(pubsub: Element, name: &str, ns: &str) -> Option<Element> {
+    match PubSub::try_from(pubsub) {
+        Ok(PubSub::Items(items)) => items
+            .items
+            .into_iter()
+            .filter_map(|item| item.0.payload)
+            .find(|payload| payload.is(name, ns)),
+        _ => None,
+    }
+}
+
+impl ModTrait for InfoMod {
+    fn init(&mut self, aparte: &mut Aparte) -> Result<(), ()> {
+        aparte.add_command(info::new());
+        Ok(())
+    }
+
+    fn on_event(&mut self, aparte: &mut Aparte, event: &Event) {
+        match event {
+            Event::Iq(_, iq) => {
+                if let Some((info_id, query)) = self.requests.remove(&iq.id) {
+                    let response = match &iq.payload {
+                        IqType::Result(payload) => payload.clone(),
+                        IqType::Error(_) => None,
+                        _ => return,
+                    };
+                    self.handle_response(aparte, info_id, query, response);
+                }
+            }
+            Event::Presence(account, presence) => {
+                if let Some(Jid::Full(from)) = &presence.from {
+                    self.update_resource(account, from, presence);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl fmt::Display for InfoMod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Contact information")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vcard4_properties() {
+        let vcard: Element = "<vcard xmlns='urn:ietf:params:xml:ns:vcard-4.0'>
+                <fn><text>Juliet Capulet</text></fn>
+                <tel>
+                    <parameters><type><text>cell</text></type></parameters>
+                    <uri>tel:+1-555-555-5555</uri>
+                </tel>
+                <email><text>juliet@example.org</text></email>
+                <note><text> </text></note>
+            </vcard>"
+            .parse()
+            .unwrap();
+
+        assert_eq!(
+            VCard::from_vcard4(&vcard).properties,
+            vec![
+                ("Name".to_string(), "Juliet Capulet".to_string()),
+                ("Email".to_string(), "juliet@example.org".to_string()),
+                ("Phone".to_string(), "+1-555-555-5555".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_vcard_temp_properties() {
+        let vcard: Element = "<vCard xmlns='vcard-temp'>
+                <FN>Juliet Capulet</FN>
+                <ADR><LOCALITY>Verona</LOCALITY><CTRY>Italy</CTRY></ADR>
+            </vCard>"
+            .parse()
+            .unwrap();
+
+        assert_eq!(
+            VCard::from_vcard_temp(&vcard).properties,
+            vec![
+                ("Name".to_string(), "Juliet Capulet".to_string()),
+                ("Address".to_string(), "Verona, Italy".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_pubsub_payload() {
+        let pubsub: Element = "<pubsub xmlns='http://jabber.org/protocol/pubsub'>
+                <items node='http://jabber.org/protocol/nick'>
+                    <item id='current'><nick xmlns='http://jabber.org/protocol/nick'>Jule</nick></item>
+                </items>
+            </pubsub>"
+            .parse()
+            .unwrap();
+
+        let nick = pubsub_payload(pubsub.clone(), "nick", ns::NICK).unwrap();
+        assert_eq!(nick.text(), "Jule");
+        assert!(pubsub_payload(pubsub, "vcard", NS_VCARD4).is_none());
+    }
+}
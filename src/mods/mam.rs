@@ -5,63 +5,255 @@ use chrono::{DateTime, FixedOffset};
 use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::fmt;
+use std::str::FromStr;
 use uuid::Uuid;
 use xmpp_parsers::data_forms::{DataForm, DataFormType, Field, FieldType};
 use xmpp_parsers::delay::Delay;
+use xmpp_parsers::disco::{DiscoInfoQuery, DiscoInfoResult};
 use xmpp_parsers::iq::{Iq, IqType};
-use xmpp_parsers::mam;
-use xmpp_parsers::message::Message as XmppParsersMessage;
+use xmpp_parsers::mam as xmpp_mam;
+use xmpp_parsers::message::{Message as XmppParsersMessage, MessageType};
 use xmpp_parsers::ns;
 use xmpp_parsers::rsm::SetQuery;
-use xmpp_parsers::{BareJid, Jid};
+use xmpp_parsers::stanza_id::StanzaId;
+use xmpp_parsers::{BareJid, Element, Jid};
 
 use crate::account::Account;
-use crate::core::{Aparte, Event, ModTrait};
+use crate::command::{Command, CommandParser};
+use crate::conversation::Conversation;
+use crate::core::{stanza_error_text, Aparte, Event, ModTrait};
+use crate::message::Message;
+use crate::mods::messages::Search;
+
+/// XEP-0431: Full Text Search in MAM
+const FULLTEXT_FIELD: &str = "{urn:xmpp:fulltext:0}fulltext";
+
+command_def!(mam_prefs,
+r#"/mam prefs [default|always|never|remove] [<value>]
+
+    default     Set the default mode to always, never or roster
+    always      Always archive messages exchanged with the given JID
+    never       Never archive messages exchanged with the given JID
+    remove      Remove the given JID from the always and never lists
+
+Description:
+    Show or edit the archiving preferences of the current account.
+    With roster as default mode, only messages exchanged with contacts of
+    the roster are archived.
+
+Examples:
+    /mam prefs
+    /mam prefs default roster
+    /mam prefs never contact@server.tld
+    /mam prefs remove contact@server.tld
+"#,
+{
+    action: Option<String> = {
+        completion: (|_aparte, _command| {
+            vec!["default".to_string(), "always".to_string(), "never".to_string(), "remove".to_string()]
+        })
+    },
+    value: Option<String>
+},
+|aparte, _command| {
+    let account = aparte.current_account().ok_or("No connection found".to_string())?;
+    let change = match (action.as_deref(), value) {
+        (None, _) => None,
+        (Some(action), None) => return Err(format!("Missing value for {}", action)),
+        (Some("default"), Some(value)) => Some(PrefsChange::Default(
+            xmpp_mam::DefaultPrefs::from_str(&value).map_err(|_| format!("Invalid mode {}, expected always, never or roster", value))?
+        )),
+        (Some(action), Some(value)) => {
+            let jid = Jid::from_str(&value).map_err(|e| format!("Invalid JID {}: {}", value, e))?;
+            match action {
+                "always" => Some(PrefsChange::Always(jid)),
+                "never" => Some(PrefsChange::Never(jid)),
+                "remove" => Some(PrefsChange::Remove(jid)),
+                _ => return Err(format!("Unknown action {}", action)),
+            }
+        }
+    };
+
+    let request = {
+        let mut mam = aparte.get_mod_mut::<MamMod>();
+        mam.prefs(&account, change)?
+    };
+    if let Some(request) = request {
+        aparte.send(&account, request);
+    }
+    Ok(())
+});
+
+command_def!(mam,
+r#"/mam prefs"#,
+{
+    action: Command = {
+        children: {
+            "prefs": mam_prefs,
+        }
+    },
+});
+
+/// Change to apply to the archiving preferences
+#[derive(Debug, Clone)]
+enum PrefsChange {
+    Default(xmpp_mam::DefaultPrefs),
+    Always(Jid),
+    Never(Jid),
+    Remove(Jid),
+}
+
+impl PrefsChange {
+    fn apply(self, prefs: &mut xmpp_mam::Prefs) {
+        match self {
+            PrefsChange::Default(default) => prefs.default_ = default,
+            PrefsChange::Always(jid) => {
+                prefs.never.retain(|never| never != &jid);
+                if !prefs.always.contains(&jid) {
+                    prefs.always.push(jid);
+                }
+            }
+            PrefsChange::Never(jid) => {
+                prefs.always.retain(|always| always != &jid);
+                if !prefs.never.contains(&jid) {
+                    prefs.never.push(jid);
+                }
+            }
+            PrefsChange::Remove(jid) => {
+                prefs.always.retain(|always| always != &jid);
+                prefs.never.retain(|never| never != &jid);
+            }
+        }
+    }
+}
+
+fn format_prefs(account: &Account, prefs: &xmpp_mam::Prefs) -> String {
+    let format_jids = |jids: &Vec<Jid>| match jids.is_empty() {
+        true => "-".to_string(),
+        false => jids
+            .iter()
+            .map(|jid| jid.to_string())
+            .collect::<Vec<String>>()
+            .join(", "),
+    };
+    let default = match prefs.default_ {
+        xmpp_mam::DefaultPrefs::Always => "always",
+        xmpp_mam::DefaultPrefs::Never => "never",
+        xmpp_mam::DefaultPrefs::Roster => "roster",
+    };
+    format!(
+        "Archiving preferences of {}:\n  default: {}\n  always: {}\n  never: {}",
+        account,
+        default,
+        format_jids(&prefs.always),
+        format_jids(&prefs.never)
+    )
+}
+
+/// Request waiting for the archive to be discovered
+enum Pending {
+    Query(Box<Query>),
+    Search(Box<Search>),
+    Prefs(Option<PrefsChange>),
+}
 
 struct Query {
     jid: BareJid,
     with: Option<BareJid>,
+    /// Retrieve messages older than the given date
     from: Option<DateTime<FixedOffset>>,
+    /// Retrieve messages newer than the given date
+    start: Option<DateTime<FixedOffset>>,
+    fulltext: Option<String>,
+    /// Search the results are collected for instead of being displayed
+    search: Option<Search>,
+    results: Vec<Message>,
+    /// Retrieve everything after the given archive id instead of the last messages
+    after: Option<String>,
+    /// Retrieve messages before the given archive id
+    before: Option<String>,
+    /// Only retrieve one page, as requested when scrolling back
+    scroll_back: bool,
+    /// Retrieve the first page after start instead of the last one
+    forward: bool,
     count: usize,
 }
 
 impl Query {
+    /// Query the last messages of an archive
+    fn new(jid: BareJid, with: Option<BareJid>) -> Self {
+        Self {
+            jid,
+            with,
+            from: None,
+            start: None,
+            fulltext: None,
+            search: None,
+            results: Vec::new(),
+            after: None,
+            before: None,
+            scroll_back: false,
+            forward: false,
+            count: 100,
+        }
+    }
+
+    /// Results must come from the queried archive, our own archive can omit its jid
+    fn is_authentic(&self, account: &Account, from: &Option<Jid>) -> bool {
+        let us: BareJid = account.clone().into();
+        match from {
+            Some(Jid::Bare(from)) => from == &self.jid,
+            Some(Jid::Full(_)) => false,
+            None => self.jid == us,
+        }
+    }
+
     pub fn start(&self) -> (String, Iq) {
-        // Start with before set to empty string in order to force xmpp_parser to generate a
-        // <before/> element and to ensure we get last page first
-        self.query(Some("".to_string()))
+        match &self.after {
+            Some(after) => self.query(None, Some(after.clone())),
+            None if self.forward => self.query(None, None),
+            // Start with before set to empty string in order to force xmpp_parser to generate a
+            // <before/> element and to ensure we get last page first
+            None => self.query(Some(self.before.clone().unwrap_or_default()), None),
+        }
     }
 
-    pub fn cont(&self, before: String) -> (String, Iq) {
-        self.query(Some(before))
+    pub fn cont(&self, fin: &xmpp_mam::Fin) -> Option<(String, Iq)> {
+        match &self.after {
+            Some(_) => Some(self.query(None, Some(fin.set.last.clone()?))),
+            None => Some(self.query(Some(fin.set.first.clone()?), None)),
+        }
+    }
+
+    fn field(var: &str, value: String) -> Field {
+        Field {
+            var: var.to_string(),
+            type_: FieldType::default(),
+            label: None,
+            required: false,
+            options: vec![],
+            values: vec![value],
+            media: vec![],
+        }
     }
 
-    fn query(&self, before: Option<String>) -> (String, Iq) {
+    fn query(&self, before: Option<String>, after: Option<String>) -> (String, Iq) {
         let mut fields = Vec::new();
 
         if let Some(end) = self.from {
-            let datetime = end.to_rfc3339();
-            fields.push(Field {
-                var: "end".to_string(),
-                type_: FieldType::default(),
-                label: None,
-                required: false,
-                options: vec![],
-                values: vec![datetime],
-                media: vec![],
-            });
+            fields.push(Self::field("end", end.to_rfc3339()));
+        }
+
+        if let Some(start) = self.start {
+            fields.push(Self::field("start", start.to_rfc3339()));
         }
 
         if let Some(with) = &self.with {
-            fields.push(Field {
-                var: "with".to_string(),
-                type_: FieldType::default(),
-                label: None,
-                required: false,
-                options: vec![],
-                values: vec![with.to_string()],
-                media: vec![],
-            });
+            fields.push(Self::field("with", with.to_string()));
+        }
+
+        if let Some(fulltext) = &self.fulltext {
+            fields.push(Self::field(FULLTEXT_FIELD, fulltext.clone()));
         }
 
         let form = DataForm {
@@ -74,14 +266,14 @@ impl Query {
 
         let set = SetQuery {
             max: Some(self.count),
-            after: None,
+            after,
             before,
             index: None,
         };
 
         let queryid = Uuid::new_v4().to_hyphenated().to_string();
-        let query = mam::Query {
-            queryid: Some(mam::QueryId(queryid.clone())),
+        let query = xmpp_mam::Query {
+            queryid: Some(xmpp_mam::QueryId(queryid.clone())),
             node: None,
             form: Some(form),
             set: Some(set),
@@ -95,12 +287,44 @@ impl Query {
     }
 }
 
+/// Identify an archive: the archive jid and the optional contact we are chatting with
+type ArchiveIndex = (Account, BareJid, Option<BareJid>);
+
+/// Last archive id seen in an archive
+struct LastId {
+    id: String,
+    /// Date stamped by the archive, unknown for live messages
+    timestamp: Option<DateTime<FixedOffset>>,
+}
+
 pub struct MamMod {
     /// Queries indexed by queryid
     queries: HashMap<String, Query>,
 
     /// Mapping between iq ids and query ids
     iq2id: HashMap<String, String>,
+
+    /// Last known archive id for each archive, used to catch up after a reconnection
+    last_ids: HashMap<ArchiveIndex, LastId>,
+
+    /// Searches waiting for the archive to tell whether it supports full-text search, indexed by
+    /// iq id
+    searches: HashMap<String, Search>,
+
+    /// Whether each archive exists, as advertised by disco
+    archives: HashMap<(Account, BareJid), bool>,
+
+    /// Requests waiting for the archive to be discovered
+    pending: HashMap<(Account, BareJid), Vec<Pending>>,
+
+    /// Disco requests indexed by iq id
+    disco_requests: HashMap<String, (Account, BareJid)>,
+
+    /// Preferences requests indexed by iq id, with the change to apply once retrieved
+    prefs_requests: HashMap<String, (Account, Option<PrefsChange>)>,
+
+    /// Preferences being set indexed by iq id
+    prefs_updates: HashMap<String, (Account, xmpp_mam::Prefs)>,
 }
 
 impl MamMod {
@@ -108,45 +332,455 @@ impl MamMod {
         Self {
             queries: HashMap::new(),
             iq2id: HashMap::new(),
+            last_ids: HashMap::new(),
+            searches: HashMap::new(),
+            archives: HashMap::new(),
+            pending: HashMap::new(),
+            disco_requests: HashMap::new(),
+            prefs_requests: HashMap::new(),
+            prefs_updates: HashMap::new(),
+        }
+    }
+
+    /// Live messages are the newest when they arrive, archived ones are only compared with each
+    /// other as the local clock can differ from the archive one
+    fn update_last_id(
+        &mut self,
+        index: ArchiveIndex,
+        id: String,
+        timestamp: Option<DateTime<FixedOffset>>,
+    ) {
+        match (self.last_ids.get(&index), timestamp) {
+            (
+                Some(LastId {
+                    timestamp: Some(last),
+                    ..
+                }),
+                Some(timestamp),
+            ) if last > &timestamp => {}
+            _ => {
+                self.last_ids.insert(index, LastId { id, timestamp });
+            }
+        }
+    }
+
+    /// Track stanza-id stamped by archives on live messages
+    fn handle_stanza(&mut self, account: &Account, message: &XmppParsersMessage) {
+        let us: BareJid = account.clone().into();
+        let from: Option<BareJid> = message.from.clone().map(|from| from.into());
+        let to: Option<BareJid> = message.to.clone().map(|to| to.into());
+        let index = match (&message.type_, from) {
+            (MessageType::Groupchat, Some(from)) => (account.clone(), from, None),
+            (MessageType::Chat, Some(from)) if from != us => (account.clone(), us, Some(from)),
+            (MessageType::Chat, _) => match to {
+                Some(to) => (account.clone(), us, Some(to)),
+                None => return,
+            },
+            _ => return,
+        };
+
+        for payload in message.payloads.iter() {
+            if let Ok(stanza_id) = StanzaId::try_from(payload.clone()) {
+                let by: BareJid = stanza_id.by.into();
+                if by == index.1 {
+                    self.update_last_id(index, stanza_id.id, None);
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Query the archive for the last messages, or for everything we missed if we know where we
+    /// stopped
+    fn catch_up(&mut self, aparte: &mut Aparte, index: ArchiveIndex) {
+        let after = self.last_ids.get(&index).map(|last| last.id.clone());
+        let (account, jid, with) = index;
+        let query = Query {
+            after,
+            ..Query::new(jid, with)
+        };
+        self.query(aparte, &account, query);
+    }
+
+    /// Archive holding the history of a conversation
+    fn get_archive(conversation: &Conversation) -> Option<(BareJid, Option<BareJid>)> {
+        match conversation {
+            Conversation::Chat(chat) => {
+                Some((chat.account.clone().into(), Some(chat.contact.clone())))
+            }
+            Conversation::Channel(channel) => Some((channel.jid.clone(), None)),
+            Conversation::PrivateChat(_) => None,
+        }
+    }
+
+    fn search(&mut self, aparte: &mut Aparte, search: &Search) {
+        if let Some((jid, _)) = Self::get_archive(&search.conversation) {
+            let account = search.conversation.get_account().clone();
+            self.with_archive(
+                aparte,
+                &account,
+                jid,
+                Pending::Search(Box::new(search.clone())),
+            );
+        }
+    }
+
+    /// Ask the archive for its query form, the search is run once we know full-text search is
+    /// supported
+    fn send_search(&mut self, aparte: &mut Aparte, search: Search) {
+        if let Some((jid, _)) = Self::get_archive(&search.conversation) {
+            let query = xmpp_mam::Query {
+                queryid: None,
+                node: None,
+                form: None,
+                set: None,
+            };
+            let id = Uuid::new_v4().to_hyphenated().to_string();
+            let iq = Iq::from_get(id.clone(), query).with_to(Jid::Bare(jid));
+            aparte.send(search.conversation.get_account(), iq.into());
+            self.searches.insert(id, search);
+        }
+    }
+
+    fn handle_search_form(&mut self, aparte: &mut Aparte, search: Search, iq: &Iq) {
+        let account = search.conversation.get_account().clone();
+        let (jid, with) = match Self::get_archive(&search.conversation) {
+            Some(archive) => archive,
+            None => return,
+        };
+
+        match &iq.payload {
+            IqType::Result(Some(payload)) => {
+                let supported = match xmpp_mam::Query::try_from(payload.clone()) {
+                    Ok(xmpp_mam::Query {
+                        form: Some(form), ..
+                    }) => form.fields.iter().any(|field| field.var == FULLTEXT_FIELD),
+                    _ => false,
+                };
+                if !supported {
+                    aparte.log(format!(
+                        "The archive of {} doesn't support full-text search, only loaded messages were searched",
+                        jid
+                    ));
+                    return;
+                }
+
+                let query = Query {
+                    from: search.until,
+                    start: search.since,
+                    fulltext: Some(search.text.clone()),
+                    search: Some(search),
+                    ..Query::new(jid, with)
+                };
+                self.send_query(aparte, &account, query);
+            }
+            IqType::Error(err) => {
+                aparte.log(format!(
+                    "Cannot search the archive of {}: {}",
+                    jid,
+                    stanza_error_text(err)
+                ));
+            }
+            _ => {}
+        }
+    }
+
+    /// Run the request once we know the archive exists
+    fn with_archive(
+        &mut self,
+        aparte: &mut Aparte,
+        account: &Account,
+        jid: BareJid,
+        pending: Pending,
+    ) {
+        let index = (account.clone(), jid);
+        match self.archives.get(&index) {
+            Some(true) => self.run(aparte, account, pending),
+            Some(false) => self.drop_pending(aparte, account, &index.1, pending),
+            None => {
+                if let Some(disco) = self.wait_for_archive(index, pending) {
+                    aparte.send(account, disco);
+                }
+            }
+        }
+    }
+
+    /// Queue the request until the archive is discovered, return the disco request to send if
+    /// it isn't already running
+    fn wait_for_archive(&mut self, index: (Account, BareJid), pending: Pending) -> Option<Element> {
+        let waiting = self.pending.entry(index.clone()).or_default();
+        waiting.push(pending);
+        if waiting.len() > 1 {
+            return None;
+        }
+
+        let id = Uuid::new_v4().to_hyphenated().to_string();
+        let iq = Iq::from_get(id.clone(), DiscoInfoQuery { node: None })
+            .with_to(Jid::Bare(index.1.clone()));
+        self.disco_requests.insert(id, index);
+        Some(iq.into())
+    }
+
+    fn run(&mut self, aparte: &mut Aparte, account: &Account, pending: Pending) {
+        match pending {
+            Pending::Query(query) => self.send_query(aparte, account, *query),
+            Pending::Search(search) => self.send_search(aparte, *search),
+            Pending::Prefs(change) => {
+                let request = self.prefs_request(account, change);
+                aparte.send(account, request);
+            }
+        }
+    }
+
+    fn drop_pending(
+        &mut self,
+        aparte: &mut Aparte,
+        account: &Account,
+        jid: &BareJid,
+        pending: Pending,
+    ) {
+        match pending {
+            Pending::Query(query) => {
+                debug!("No archive for {}, ignoring query", jid);
+                if query.scroll_back {
+                    aparte.schedule(Event::HistoryLoaded {
+                        account: account.clone(),
+                        conversation: query.with.unwrap_or(query.jid),
+                        complete: true,
+                    });
+                }
+            }
+            Pending::Search(_) => aparte.log(format!(
+                "{} has no archive, only loaded messages were searched",
+                jid
+            )),
+            Pending::Prefs(_) => aparte.log(format!(
+                "The server of {} doesn't support message archiving",
+                account
+            )),
+        }
+    }
+
+    fn handle_disco(&mut self, aparte: &mut Aparte, index: (Account, BareJid), iq: &Iq) {
+        let supported = match &iq.payload {
+            IqType::Result(Some(payload)) => match DiscoInfoResult::try_from(payload.clone()) {
+                Ok(disco) => disco.features.iter().any(|feature| feature.var == ns::MAM),
+                Err(_) => false,
+            },
+            _ => false,
+        };
+        self.archives.insert(index.clone(), supported);
+
+        let (account, jid) = &index;
+        for pending in self.pending.remove(&index).unwrap_or_default() {
+            match supported {
+                true => self.run(aparte, account, pending),
+                false => self.drop_pending(aparte, account, jid, pending),
+            }
+        }
+    }
+
+    /// Retrieve the archiving preferences of the account, and update them with the given change
+    fn prefs(
+        &mut self,
+        account: &Account,
+        change: Option<PrefsChange>,
+    ) -> Result<Option<Element>, String> {
+        let index = (account.clone(), account.clone().into());
+        match self.archives.get(&index) {
+            Some(true) => Ok(Some(self.prefs_request(account, change))),
+            Some(false) => Err(format!(
+                "The server of {} doesn't support message archiving",
+                account
+            )),
+            None => Ok(self.wait_for_archive(index, Pending::Prefs(change))),
+        }
+    }
+
+    fn prefs_request(&mut self, account: &Account, change: Option<PrefsChange>) -> Element {
+        let id = Uuid::new_v4().to_hyphenated().to_string();
+        let iq = Iq {
+            from: None,
+            to: None,
+            id: id.clone(),
+            payload: IqType::Get(Element::builder("prefs", ns::MAM).build()),
+        };
+        self.prefs_requests.insert(id, (account.clone(), change));
+        iq.into()
+    }
+
+    fn handle_prefs(
+        &mut self,
+        aparte: &mut Aparte,
+        account: &Account,
+        change: Option<PrefsChange>,
+        iq: &Iq,
+    ) {
+        match &iq.payload {
+            IqType::Result(Some(payload)) => match xmpp_mam::Prefs::try_from(payload.clone()) {
+                Ok(mut prefs) => match change {
+                    None => aparte.log(format_prefs(account, &prefs)),
+                    Some(change) => {
+                        change.apply(&mut prefs);
+                        let id = Uuid::new_v4().to_hyphenated().to_string();
+                        let iq = Iq::from_set(id.clone(), prefs.clone());
+                        self.prefs_updates.insert(id, (account.clone(), prefs));
+                        aparte.send(account, iq.into());
+                    }
+                },
+                Err(err) => aparte.log(format!("Invalid archiving preferences: {}", err)),
+            },
+            IqType::Error(err) => aparte.log(format!(
+                "Cannot retrieve archiving preferences: {}",
+                stanza_error_text(err)
+            )),
+            _ => {}
         }
     }
 
     fn query(&mut self, aparte: &mut Aparte, account: &Account, query: Query) {
+        let jid = query.jid.clone();
+        self.with_archive(aparte, account, jid, Pending::Query(Box::new(query)));
+    }
+
+    fn send_query(&mut self, aparte: &mut Aparte, account: &Account, query: Query) {
         let (queryid, iq) = query.start();
         self.queries.insert(queryid.clone(), query);
         self.iq2id.insert(iq.id.clone(), queryid);
         aparte.send(account, iq.into());
     }
 
-    fn handle_result(&mut self, aparte: &mut Aparte, account: &Account, result: mam::Result_) {
-        if let Some(id) = &result.queryid {
-            if let Some(query) = self.queries.get_mut(&id.0) {
-                query.count -= 1;
-                match (result.forwarded.delay, result.forwarded.stanza) {
-                    (Some(delay), Some(message)) => {
-                        aparte.schedule(Event::RawMessage {
-                            account: account.clone(),
-                            message,
-                            delay: Some(delay),
-                            archive: true,
-                        });
-                    }
-                    _ => {}
+    fn handle_result(
+        &mut self,
+        aparte: &mut Aparte,
+        account: &Account,
+        from: &Option<Jid>,
+        result: xmpp_mam::Result_,
+    ) {
+        match self.record_result(account, from, result) {
+            Ok(Some((message, delay))) => aparte.schedule(Event::RawMessage {
+                account: account.clone(),
+                message,
+                delay: Some(delay),
+                archive: true,
+            }),
+            Ok(None) => {}
+            Err(err) => aparte.log(err),
+        }
+    }
+
+    /// Record a result of a pending query, returns the archived message to display if any
+    fn record_result(
+        &mut self,
+        account: &Account,
+        from: &Option<Jid>,
+        result: xmpp_mam::Result_,
+    ) -> Result<Option<(XmppParsersMessage, Delay)>, String> {
+        let query = match &result.queryid {
+            Some(id) => match self.queries.get_mut(&id.0) {
+                Some(query) => query,
+                None => return Ok(None),
+            },
+            None => return Ok(None),
+        };
+        if !query.is_authentic(account, from) {
+            let from = from
+                .as_ref()
+                .map_or("unknown".to_string(), |from| from.to_string());
+            warn!("Rejected MAM result for {} from {}", query.jid, from);
+            return Err(format!(
+                "Rejected archived message for {} sent by {}",
+                query.jid, from
+            ));
+        }
+
+        // Catching up retrieves every missed message, count is then only the page size
+        if query.after.is_none() {
+            query.count = query.count.saturating_sub(1);
+        }
+        let index = (account.clone(), query.jid.clone(), query.with.clone());
+        let (delay, mut message) = match (result.forwarded.delay, result.forwarded.stanza) {
+            (Some(delay), Some(message)) => (delay, message),
+            _ => return Ok(None),
+        };
+
+        // Archived message might not carry the stanza-id the archive gave it
+        let archive_jid = Jid::Bare(query.jid.clone());
+        let has_stanza_id =
+            message
+                .payloads
+                .iter()
+                .any(|payload| match StanzaId::try_from(payload.clone()) {
+                    Ok(stanza_id) => stanza_id.by == archive_jid,
+                    Err(_) => false,
+                });
+        if !has_stanza_id {
+            message.payloads.push(
+                StanzaId {
+                    id: result.id.clone(),
+                    by: archive_jid,
                 }
+                .into(),
+            );
+        }
+        if query.search.is_some() {
+            let delay = Some(delay);
+            if let Ok(message) = Message::from_xmpp(account, &message, &delay, true) {
+                query.results.push(message);
             }
+            return Ok(None);
         }
+        // Pages loaded when scrolling back or jumping to a date are older than what was already
+        // seen, only catching up moves the last id forward
+        if !query.scroll_back {
+            self.update_last_id(index, result.id, Some(delay.stamp.0));
+        }
+        Ok(Some((message, delay)))
     }
 
-    fn handle_fin(&mut self, aparte: &mut Aparte, account: &Account, query: Query, fin: mam::Fin) {
-        if fin.complete == mam::Complete::False {
-            if let Some(start) = fin.set.first {
+    fn handle_fin(
+        &mut self,
+        aparte: &mut Aparte,
+        account: &Account,
+        query: Query,
+        fin: xmpp_mam::Fin,
+    ) {
+        if let Some(search) = query.search {
+            // Archive full-text matching can differ from ours, only check the other filters
+            let messages = query
+                .results
+                .into_iter()
+                .filter(|message| match message {
+                    Message::Xmpp(message) => search.matches_filters(message),
+                    Message::Log(_) => false,
+                })
+                .collect();
+            aparte.schedule(Event::SearchResults {
+                search,
+                messages,
+                archive: true,
+            });
+            return;
+        }
+
+        if query.scroll_back {
+            // A page following a date says nothing about older history
+            aparte.schedule(Event::HistoryLoaded {
+                account: account.clone(),
+                conversation: query.with.unwrap_or(query.jid),
+                complete: !query.forward && fin.complete == xmpp_mam::Complete::True,
+            });
+            return;
+        }
+
+        if fin.complete == xmpp_mam::Complete::False {
+            if let Some((queryid, iq)) = query.cont(&fin) {
                 info!(
                     "Continuing MAM retrieval for {} with {:?} from {:?}",
                     query.jid,
                     query.with.clone().map(|jid| jid.to_string()),
                     query.from
                 );
-                let (queryid, iq) = query.cont(start);
                 self.queries.insert(queryid.clone(), query);
                 self.iq2id.insert(iq.id.clone(), queryid);
                 aparte.send(account, iq.into());
@@ -156,7 +790,8 @@ impl MamMod {
 }
 
 impl ModTrait for MamMod {
-    fn init(&mut self, _aparte: &mut Aparte) -> Result<(), ()> {
+    fn init(&mut self, aparte: &mut Aparte) -> Result<(), ()> {
+        aparte.add_command(mam::new());
         Ok(())
     }
 
@@ -168,7 +803,7 @@ impl ModTrait for MamMod {
         _delay: &Option<Delay>,
     ) -> f64 {
         for payload in message.payloads.iter().cloned() {
-            if mam::Result_::try_from(payload.clone()).is_ok() {
+            if xmpp_mam::Result_::try_from(payload.clone()).is_ok() {
                 return 1f64;
             }
         }
@@ -184,8 +819,8 @@ impl ModTrait for MamMod {
         _archive: bool,
     ) {
         for payload in message.payloads.iter().cloned() {
-            if let Ok(result) = mam::Result_::try_from(payload.clone()) {
-                self.handle_result(aparte, account, result);
+            if let Ok(result) = xmpp_mam::Result_::try_from(payload.clone()) {
+                self.handle_result(aparte, account, &message.from, result);
             }
         }
     }
@@ -195,29 +830,51 @@ impl ModTrait for MamMod {
             Event::Join {
                 account, channel, ..
             } => {
-                let query = Query {
-                    jid: channel.clone().into(),
-                    with: None,
-                    from: None,
-                    count: 100,
-                };
-                self.query(aparte, account, query);
+                self.catch_up(aparte, (account.clone(), channel.clone().into(), None));
             }
             Event::Chat { account, contact } => {
-                let query = Query {
-                    jid: account.clone().into(),
-                    with: Some(contact.clone()),
-                    from: None,
-                    count: 100,
-                };
-                self.query(aparte, account, query);
+                let index = (
+                    account.clone(),
+                    account.clone().into(),
+                    Some(contact.clone()),
+                );
+                self.catch_up(aparte, index);
             }
-            Event::LoadChannelHistory { account, jid, from } => {
+            Event::Connected(account, _) => {
+                // Archives may have changed while we were away
+                self.archives
+                    .retain(|(archive_account, _), _| archive_account != account);
+                // Catch up chats we were following before the connection was lost, channels are
+                // handled once rejoined
+                let chats: Vec<ArchiveIndex> = self
+                    .last_ids
+                    .keys()
+                    .filter(|(last_account, _, with)| last_account == account && with.is_some())
+                    .cloned()
+                    .collect();
+                for index in chats {
+                    self.catch_up(aparte, index);
+                }
+            }
+            Event::Stanza(account, stanza) => {
+                if let Ok(message) = XmppParsersMessage::try_from(stanza.clone()) {
+                    self.handle_stanza(account, &message);
+                }
+            }
+            Event::LoadChannelHistory {
+                account,
+                jid,
+                from,
+                before,
+                start,
+            } => {
                 let query = Query {
-                    jid: jid.clone(),
-                    with: None,
                     from: from.clone(),
-                    count: 100,
+                    start: *start,
+                    before: before.clone(),
+                    scroll_back: true,
+                    forward: start.is_some(),
+                    ..Query::new(jid.clone(), None)
                 };
                 self.query(aparte, account, query);
             }
@@ -225,24 +882,64 @@ impl ModTrait for MamMod {
                 account,
                 contact,
                 from,
+                before,
+                start,
             } => {
                 let query = Query {
-                    jid: account.clone().into(),
-                    with: Some(contact.clone()),
                     from: from.clone(),
-                    count: 100,
+                    start: *start,
+                    before: before.clone(),
+                    scroll_back: true,
+                    forward: start.is_some(),
+                    ..Query::new(account.clone().into(), Some(contact.clone()))
                 };
                 self.query(aparte, account, query);
             }
+            Event::Search(search) => self.search(aparte, search),
             Event::Iq(account, iq) => {
+                if let Some(index) = self.disco_requests.remove(&iq.id) {
+                    self.handle_disco(aparte, index, iq);
+                }
+                if let Some((account, change)) = self.prefs_requests.remove(&iq.id) {
+                    self.handle_prefs(aparte, &account, change, iq);
+                }
+                if let Some((account, prefs)) = self.prefs_updates.remove(&iq.id) {
+                    match &iq.payload {
+                        IqType::Error(err) => aparte.log(format!(
+                            "Cannot change archiving preferences: {}",
+                            stanza_error_text(err)
+                        )),
+                        _ => aparte.log(format_prefs(&account, &prefs)),
+                    }
+                }
+                if let Some(search) = self.searches.remove(&iq.id) {
+                    self.handle_search_form(aparte, search, iq);
+                }
                 if let Some(id) = self.iq2id.remove(&iq.id) {
                     if let Some(query) = self.queries.remove(&id) {
-                        if let IqType::Result(Some(payload)) = &iq.payload {
-                            if let Ok(fin) = mam::Fin::try_from(payload.clone()) {
-                                self.handle_fin(aparte, account, query, fin);
-                            } else {
-                                warn!("Incorrect IQ response for MAM query");
+                        match &iq.payload {
+                            IqType::Result(Some(payload)) => {
+                                if let Ok(fin) = xmpp_mam::Fin::try_from(payload.clone()) {
+                                    self.handle_fin(aparte, account, query, fin);
+                                } else {
+                                    warn!("Incorrect IQ response for MAM query");
+                                }
                             }
+                            IqType::Error(err) if query.search.is_some() => {
+                                aparte.log(format!(
+                                    "Cannot search the archive of {}: {}",
+                                    query.jid,
+                                    stanza_error_text(err)
+                                ));
+                            }
+                            IqType::Error(_) if query.scroll_back => {
+                                aparte.schedule(Event::HistoryLoaded {
+                                    account: account.clone(),
+                                    conversation: query.with.unwrap_or(query.jid),
+                                    complete: false,
+                                });
+                            }
+                            _ => {}
                         }
                     }
                 }
@@ -257,3 +954,191 @@ impl fmt::Display for MamMod {
         write!(f, "XEP-0313: Message Archive Management")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn index() -> ArchiveIndex {
+        let account = Account::from_str("me@example.org/aparte").unwrap();
+        let archive = BareJid::from_str("channel@conference.example.org").unwrap();
+        (account, archive, None)
+    }
+
+    fn date(date: &str) -> Option<DateTime<FixedOffset>> {
+        Some(DateTime::parse_from_rfc3339(date).unwrap())
+    }
+
+    #[test]
+    fn test_update_last_id_keeps_newest_archived() {
+        let mut mam = MamMod::new();
+        mam.update_last_id(index(), "2".to_string(), date("2021-01-02T00:00:00Z"));
+        mam.update_last_id(index(), "1".to_string(), date("2021-01-01T00:00:00Z"));
+
+        assert_eq!(mam.last_ids.get(&index()).unwrap().id, "2");
+    }
+
+    #[test]
+    fn test_update_last_id_archived_replaces_live() {
+        // Live ids aren't compared with the local clock, a later catch up replaces them
+        let mut mam = MamMod::new();
+        mam.update_last_id(index(), "live".to_string(), None);
+        mam.update_last_id(
+            index(),
+            "archived".to_string(),
+            date("2000-01-01T00:00:00Z"),
+        );
+
+        assert_eq!(mam.last_ids.get(&index()).unwrap().id, "archived");
+
+        mam.update_last_id(index(), "live".to_string(), None);
+        assert_eq!(mam.last_ids.get(&index()).unwrap().id, "live");
+    }
+
+    fn archived_result(queryid: &str) -> xmpp_mam::Result_ {
+        let result: Element = format!(
+            "<result xmlns='urn:xmpp:mam:2' queryid='{}' id='archived'>
+                <forwarded xmlns='urn:xmpp:forward:0'>
+                    <delay xmlns='urn:xmpp:delay' stamp='2000-01-01T00:00:00Z'/>
+                    <message xmlns='jabber:client' type='groupchat'
+                        from='channel@conference.example.org/juliet' to='me@example.org/aparte'>
+                        <body>hello</body>
+                    </message>
+                </forwarded>
+            </result>",
+            queryid
+        )
+        .parse()
+        .unwrap();
+        xmpp_mam::Result_::try_from(result).unwrap()
+    }
+
+    #[test]
+    fn test_scroll_back_page_keeps_live_id() {
+        let (account, archive, _) = index();
+        let from = Some(Jid::Bare(archive.clone()));
+        let mut mam = MamMod::new();
+        mam.update_last_id(index(), "live".to_string(), None);
+        mam.queries.insert(
+            "scroll".to_string(),
+            Query {
+                scroll_back: true,
+                ..Query::new(archive.clone(), None)
+            },
+        );
+        mam.queries.insert(
+            "goto".to_string(),
+            Query {
+                start: date("1999-12-31T00:00:00Z"),
+                scroll_back: true,
+                forward: true,
+                ..Query::new(archive.clone(), None)
+            },
+        );
+
+        let scroll = mam.record_result(&account, &from, archived_result("scroll"));
+        let goto = mam.record_result(&account, &from, archived_result("goto"));
+
+        assert!(matches!(scroll, Ok(Some(_))));
+        assert!(matches!(goto, Ok(Some(_))));
+        assert_eq!(mam.last_ids.get(&index()).unwrap().id, "live");
+    }
+
+    #[test]
+    fn test_catch_up_page_updates_live_id() {
+        let (account, archive, _) = index();
+        let from = Some(Jid::Bare(archive.clone()));
+        let mut mam = MamMod::new();
+        mam.update_last_id(index(), "live".to_string(), None);
+        mam.queries.insert(
+            "catch-up".to_string(),
+            Query {
+                after: Some("live".to_string()),
+                ..Query::new(archive.clone(), None)
+            },
+        );
+
+        let result = mam.record_result(&account, &from, archived_result("catch-up"));
+
+        assert!(matches!(result, Ok(Some(_))));
+        assert_eq!(mam.last_ids.get(&index()).unwrap().id, "archived");
+    }
+
+    fn prefs() -> xmpp_mam::Prefs {
+        xmpp_mam::Prefs {
+            default_: xmpp_mam::DefaultPrefs::Roster,
+            always: vec![Jid::from_str("always@example.org").unwrap()],
+            never: vec![Jid::from_str("never@example.org").unwrap()],
+        }
+    }
+
+    #[test]
+    fn test_prefs_change_default() {
+        let mut prefs = prefs();
+        PrefsChange::Default(xmpp_mam::DefaultPrefs::Never).apply(&mut prefs);
+
+        assert_eq!(prefs.default_, xmpp_mam::DefaultPrefs::Never);
+        assert_eq!(prefs.always.len(), 1);
+        assert_eq!(prefs.never.len(), 1);
+    }
+
+    #[test]
+    fn test_prefs_change_moves_jid() {
+        let jid = Jid::from_str("never@example.org").unwrap();
+        let mut prefs = prefs();
+        PrefsChange::Always(jid.clone()).apply(&mut prefs);
+        PrefsChange::Always(jid.clone()).apply(&mut prefs);
+
+        assert!(prefs.never.is_empty());
+        assert_eq!(
+            prefs.always.iter().filter(|always| *always == &jid).count(),
+            1
+        );
+
+        PrefsChange::Never(jid.clone()).apply(&mut prefs);
+        assert_eq!(prefs.never, vec![jid.clone()]);
+        assert!(!prefs.always.contains(&jid));
+    }
+
+    #[test]
+    fn test_prefs_change_remove() {
+        let mut prefs = prefs();
+        PrefsChange::Remove(Jid::from_str("always@example.org").unwrap()).apply(&mut prefs);
+        PrefsChange::Remove(Jid::from_str("never@example.org").unwrap()).apply(&mut prefs);
+
+        assert!(prefs.always.is_empty());
+        assert!(prefs.never.is_empty());
+    }
+
+    #[test]
+    fn test_result_from_queried_archive() {
+        let account = Account::from_str("me@example.org/aparte").unwrap();
+        let channel = BareJid::from_str("channel@conference.example.org").unwrap();
+        let query = Query::new(channel.clone(), None);
+
+        assert!(query.is_authentic(&account, &Some(Jid::Bare(channel))));
+    }
+
+    #[test]
+    fn test_result_from_own_archive_without_from() {
+        let account = Account::from_str("me@example.org/aparte").unwrap();
+        let query = Query::new(account.clone().into(), None);
+
+        assert!(query.is_authentic(&account, &None));
+        assert!(query.is_authentic(&account, &Some(Jid::from_str("me@example.org").unwrap())));
+    }
+
+    #[test]
+    fn test_result_from_other_sender_rejected() {
+        let account = Account::from_str("me@example.org/aparte").unwrap();
+        let channel = BareJid::from_str("channel@conference.example.org").unwrap();
+        let query = Query::new(channel, None);
+
+        assert!(!query.is_authentic(&account, &None));
+        assert!(!query.is_authentic(&account, &Some(Jid::from_str("evil@example.org").unwrap())));
+        assert!(!query.is_authentic(
+            &account,
+            &Some(Jid::from_str("channel@conference.example.org/nick").unwrap())
+        ));
+    }
+}
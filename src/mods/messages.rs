@@ -1,20 +1,85 @@
 /* This Source Code Form is subject to the terms of the Mozilla Public
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+use chrono::{DateTime, FixedOffset};
 use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::fmt;
 use xmpp_parsers::delay::Delay;
 use xmpp_parsers::message::{Message as XmppParsersMessage, MessageType as XmppParsersMessageType};
-use xmpp_parsers::ns;
+use xmpp_parsers::{ns, BareJid, Jid};
 
 use crate::account::Account;
+use crate::conversation::Conversation;
 use crate::core::{Aparte, Event, ModTrait};
-use crate::message::Message;
+use crate::message::{Direction, Message, VersionedXmppMessage, XmppMessageType};
 use crate::mods::disco;
 
+/// History search in a conversation
+#[derive(Debug, Clone)]
+pub struct Search {
+    pub id: String,
+    pub conversation: Conversation,
+    pub text: String,
+    /// Nick or jid of the author
+    pub from: Option<String>,
+    pub since: Option<DateTime<FixedOffset>>,
+    pub until: Option<DateTime<FixedOffset>>,
+}
+
+impl Search {
+    /// Check the message belongs to the searched conversation and matches the author and date
+    /// filters, the text is left to the caller as archives can have their own full-text matching
+    pub fn matches_filters(&self, message: &VersionedXmppMessage) -> bool {
+        let remote = match message.direction {
+            Direction::Incoming => &message.from_full,
+            Direction::Outgoing => &message.to_full,
+        };
+        let remote_bare = BareJid::from(remote.clone());
+        let in_conversation = match (&self.conversation, &message.type_) {
+            (Conversation::Chat(chat), XmppMessageType::Chat) => remote_bare == chat.contact,
+            (Conversation::Channel(channel), XmppMessageType::Channel) => {
+                remote_bare == channel.jid
+            }
+            (Conversation::PrivateChat(chat), XmppMessageType::PrivateChat) => {
+                remote == &Jid::Full(chat.occupant.clone())
+            }
+            _ => false,
+        };
+
+        let from_author = match &self.from {
+            Some(from) => match &message.from_full {
+                Jid::Full(full) if message.type_ != XmppMessageType::Chat => &full.resource == from,
+                _ => &message.from.to_string() == from || message.from.node.as_ref() == Some(from),
+            },
+            None => true,
+        };
+
+        let timestamp = message.get_original_timestamp();
+        let after_since = match &self.since {
+            Some(since) => timestamp >= since,
+            None => true,
+        };
+        let before_until = match &self.until {
+            Some(until) => timestamp < until,
+            None => true,
+        };
+
+        in_conversation && from_author && after_since && before_until
+    }
+
+    pub fn matches(&self, message: &VersionedXmppMessage) -> bool {
+        self.matches_filters(message)
+            && message
+                .get_last_body()
+                .to_lowercase()
+                .contains(&self.text.to_lowercase())
+    }
+}
+
 pub struct MessagesMod {
-    messages: HashMap<Option<Account>, HashMap<String, Message>>,
+    /// Messages indexed by their uid, several senders can use the same id
+    messages: HashMap<Option<Account>, HashMap<String, Vec<Message>>>,
 }
 
 impl MessagesMod {
@@ -24,24 +89,79 @@ impl MessagesMod {
         }
     }
 
-    pub fn get<'a>(&'a self, account: &Option<Account>, id: &String) -> Option<&'a Message> {
-        self.messages.get(account)?.get(id)
+    fn is_from(message: &Message, from: &Option<Jid>) -> bool {
+        match (message, from) {
+            (Message::Xmpp(message), Some(from)) => message.is_from(from),
+            (Message::Log(_), None) => true,
+            _ => false,
+        }
+    }
+
+    pub fn get<'a>(
+        &'a self,
+        account: &Option<Account>,
+        from: &Option<Jid>,
+        id: &str,
+    ) -> Option<&'a Message> {
+        self.messages
+            .get(account)?
+            .get(id)?
+            .iter()
+            .find(|message| Self::is_from(message, from))
     }
 
     pub fn get_mut<'a>(
         &'a mut self,
         account: &Option<Account>,
-        id: &String,
+        from: &Option<Jid>,
+        id: &str,
     ) -> Option<&'a mut Message> {
-        self.messages.get_mut(account)?.get_mut(id)
+        self.messages
+            .get_mut(account)?
+            .get_mut(id)?
+            .iter_mut()
+            .find(|message| Self::is_from(message, from))
     }
 
+    /// Store a message, replacing any other copy of it
     pub fn handle_message(&mut self, account: &Option<Account>, message: &Message) {
         let messages = self
             .messages
             .entry(account.clone())
             .or_insert(HashMap::new());
-        messages.insert(message.id().to_string(), message.clone());
+        let uid = match message {
+            Message::Xmpp(message) => message.uid(),
+            Message::Log(_) => message.id(),
+        };
+        let copies = messages.entry(uid.to_string()).or_default();
+        let mut message = message.clone();
+        copies.retain(|copy| match copy == &message {
+            true => {
+                message.keep_longest_history(copy);
+                false
+            }
+            false => true,
+        });
+        copies.push(message);
+    }
+
+    /// Search the messages we know of, sorted by date
+    pub fn search(&self, search: &Search) -> Vec<Message> {
+        let account = Some(search.conversation.get_account().clone());
+        let mut results: Vec<Message> = match self.messages.get(&account) {
+            Some(messages) => messages
+                .values()
+                .flatten()
+                .filter(|message| match message {
+                    Message::Xmpp(message) => search.matches(message),
+                    Message::Log(_) => false,
+                })
+                .cloned()
+                .collect(),
+            None => Vec::new(),
+        };
+        results.sort_by(|a, b| a.timestamp().cmp(b.timestamp()));
+        results
     }
 
     fn handle_headline_message(
@@ -148,9 +268,14 @@ impl ModTrait for MessagesMod {
         };
     }
 
-    fn on_event(&mut self, _aparte: &mut Aparte, event: &Event) {
+    fn on_event(&mut self, aparte: &mut Aparte, event: &Event) {
         match event {
             Event::Message(account, message) => self.handle_message(account, message),
+            Event::Search(search) => aparte.schedule(Event::SearchResults {
+                search: search.clone(),
+                messages: self.search(search),
+                archive: false,
+            }),
             _ => {}
         }
     }
@@ -161,3 +286,57 @@ impl fmt::Display for MessagesMod {
         write!(f, "Message store")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::XmppMessageVersion;
+    use std::str::FromStr;
+
+    fn message(body: &str, archive: bool) -> Message {
+        let mut bodies = HashMap::new();
+        bodies.insert("".to_string(), body.to_string());
+        Message::incoming_chat(
+            "id",
+            DateTime::parse_from_rfc3339("2021-01-01T00:00:00Z").unwrap(),
+            &Jid::from_str("juliet@example.org/phone").unwrap(),
+            &Jid::from_str("me@example.org/aparte").unwrap(),
+            &bodies,
+            archive,
+        )
+    }
+
+    fn correct(message: &mut Message, body: &str) {
+        if let Message::Xmpp(message) = message {
+            let mut bodies = HashMap::new();
+            bodies.insert("".to_string(), body.to_string());
+            message.history.push(XmppMessageVersion {
+                id: "correction".to_string(),
+                timestamp: DateTime::parse_from_rfc3339("2021-01-01T00:01:00Z").unwrap(),
+                bodies,
+            });
+        }
+    }
+
+    #[test]
+    fn test_copy_keeps_corrections() {
+        let account = Some(Account::from_str("me@example.org/aparte").unwrap());
+        let from = Some(Jid::from_str("juliet@example.org/phone").unwrap());
+        let mut messages = MessagesMod::new();
+        let mut corrected = message("helo", false);
+        correct(&mut corrected, "hello");
+        messages.handle_message(&account, &corrected);
+
+        // Archived copy only knows the original version
+        messages.handle_message(&account, &message("helo", true));
+
+        match messages.get(&account, &from, "id") {
+            Some(Message::Xmpp(message)) => {
+                assert_eq!(message.history.len(), 2);
+                assert_eq!(message.get_last_body(), "hello");
+            }
+            _ => panic!("message should be stored"),
+        }
+        assert_eq!(messages.messages[&account]["id"].len(), 1);
+    }
+}
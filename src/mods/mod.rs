@@ -1,6 +1,8 @@
 /* This Source Code Form is subject to the terms of the Mozilla Public
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+pub mod adhoc;
+pub mod avatar;
 pub mod bookmarks;
 pub mod carbons;
 pub mod completion;
@@ -8,6 +10,11 @@ pub mod contact;
 pub mod conversation;
 pub mod correction;
 pub mod disco;
+pub mod info;
 pub mod mam;
 pub mod messages;
+pub mod muc;
+pub mod names;
+pub mod profile;
+pub mod register;
 pub mod ui;
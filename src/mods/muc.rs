@@ -0,0 +1,2344 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+use fuzzy_matcher::skim::SkimMatcherV2;
+use fuzzy_matcher::FuzzyMatcher;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+use xmpp_parsers::data_forms::{DataForm, DataFormType};
+use xmpp_parsers::delay::Delay;
+use xmpp_parsers::disco::{DiscoInfoResult, DiscoItemsResult};
+use xmpp_parsers::iq::{Iq, IqType};
+use xmpp_parsers::message::{Message as XmppParsersMessage, MessageType, Subject};
+use xmpp_parsers::muc::user::{Affiliation, MucUser, Role, Status};
+use xmpp_parsers::ping::Ping;
+use xmpp_parsers::presence::{Presence, Type as PresenceType};
+use xmpp_parsers::stanza_error::{DefinedCondition, StanzaError};
+use xmpp_parsers::{ns, BareJid, Element, Jid};
+
+use crate::account::Account;
+use crate::command::{Command, CommandParser};
+use crate::conversation::{self, Channel, Conversation};
+use crate::core::{self, Aparte, Event, FormAction, ModTrait};
+use crate::i18n;
+use crate::mods;
+use crate::mods::disco;
+
+pub const NS_MUC_ADMIN: &str = "http://jabber.org/protocol/muc#admin";
+pub const NS_MUC_OWNER: &str = "http://jabber.org/protocol/muc#owner";
+pub const NS_CONFERENCE: &str = "jabber:x:conference";
+pub const NS_MUC_ROOMINFO: &str = "http://jabber.org/protocol/muc#roominfo";
+
+/// Delay between two XEP-0410 self-pings of a joined channel
+const SELF_PING_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Delay after which an error answer to a sent invitation or topic change is no longer expected
+const MESSAGE_ERROR_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// Number of listed rooms whose details are requested
+const ROOM_INFO_MAX: usize = 100;
+/// Number of room details requested at the same time
+const ROOM_INFO_CONCURRENT: usize = 10;
+
+/// Get the channel associated to the window the command was typed in
+fn get_channel(aparte: &Aparte, command: &Command) -> Result<Channel, String> {
+    core::get_current_channel(aparte, command)
+        .ok_or_else(|| format!("/{} can only be used in a channel window", command.args[0]))
+}
+
+fn occupants_completion(aparte: &Aparte, command: &Command) -> Vec<String> {
+    match get_channel(aparte, command) {
+        Ok(channel) => channel.occupants.keys().cloned().collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+fn ensure_moderator(channel: &Channel) -> Result<(), String> {
+    match channel.get_self_occupant() {
+        Some(occupant) if occupant.role == conversation::Role::Moderator => Ok(()),
+        _ => Err(format!("You are not a moderator of {}", channel.jid)),
+    }
+}
+
+fn ensure_admin(channel: &Channel) -> Result<(), String> {
+    match channel.get_self_occupant() {
+        Some(occupant)
+            if occupant.affiliation == conversation::Affiliation::Admin
+                || occupant.affiliation == conversation::Affiliation::Owner =>
+        {
+            Ok(())
+        }
+        _ => Err(format!("You are not an admin of {}", channel.jid)),
+    }
+}
+
+fn ensure_owner(channel: &Channel) -> Result<(), String> {
+    match channel.get_self_occupant() {
+        Some(occupant) if occupant.affiliation == conversation::Affiliation::Owner => Ok(()),
+        _ => Err(format!("You are not an owner of {}", channel.jid)),
+    }
+}
+
+/// Resolve an affiliation target which can be either a nick of an occupant or a bare jid
+fn resolve_jid(channel: &Channel, target: &str) -> Result<BareJid, String> {
+    if let Some(occupant) = channel.occupants.get(target) {
+        occupant.jid.clone().ok_or(format!(
+            "Real jid of {} is not known, use its jid instead",
+            target
+        ))
+    } else {
+        match BareJid::from_str(target) {
+            Ok(jid) if jid.node.is_some() => Ok(jid),
+            _ => Err(format!("Unknown occupant {}", target)),
+        }
+    }
+}
+
+fn role_name(role: &Role) -> &'static str {
+    match role {
+        Role::Moderator => "moderator",
+        Role::Participant => "participant",
+        Role::Visitor => "visitor",
+        Role::None => "none",
+    }
+}
+
+fn affiliation_name(affiliation: &Affiliation) -> &'static str {
+    match affiliation {
+        Affiliation::Owner => "owner",
+        Affiliation::Admin => "admin",
+        Affiliation::Member => "member",
+        Affiliation::Outcast => "outcast",
+        Affiliation::None => "none",
+    }
+}
+
+fn set_role(
+    aparte: &mut Aparte,
+    channel: &Channel,
+    nick: &str,
+    role: Role,
+    reason: Option<String>,
+    description: String,
+) -> Result<(), String> {
+    if !channel.occupants.contains_key(nick) {
+        return Err(format!("Unknown occupant {}", nick));
+    }
+
+    match role {
+        Role::Moderator => ensure_admin(channel)?,
+        _ => ensure_moderator(channel)?,
+    }
+
+    let mut item = Element::builder("item", NS_MUC_ADMIN)
+        .attr("nick", nick)
+        .attr("role", role_name(&role));
+    if let Some(reason) = reason {
+        item = item.append(Element::builder("reason", NS_MUC_ADMIN).append(reason));
+    }
+
+    let iq = {
+        let mut muc = aparte.get_mod_mut::<MucMod>();
+        muc.admin_set(channel, item.build(), description)
+    };
+    aparte.send(&channel.account, iq);
+    Ok(())
+}
+
+fn set_affiliation(
+    aparte: &mut Aparte,
+    channel: &Channel,
+    target: &str,
+    affiliation: Affiliation,
+    reason: Option<String>,
+    description: String,
+) -> Result<(), String> {
+    let jid = resolve_jid(channel, target)?;
+
+    match affiliation {
+        Affiliation::Owner | Affiliation::Admin => ensure_owner(channel)?,
+        _ => ensure_admin(channel)?,
+    }
+
+    let mut item = Element::builder("item", NS_MUC_ADMIN)
+        .attr("jid", jid.to_string())
+        .attr("affiliation", affiliation_name(&affiliation));
+    if let Some(reason) = reason {
+        item = item.append(Element::builder("reason", NS_MUC_ADMIN).append(reason));
+    }
+
+    let iq = {
+        let mut muc = aparte.get_mod_mut::<MucMod>();
+        muc.admin_set(channel, item.build(), description)
+    };
+    aparte.send(&channel.account, iq);
+    Ok(())
+}
+
+command_def!(kick,
+r#"/kick <nick> [<reason>]
+
+    nick        Nick of the occupant to kick
+    reason      Optional reason sent to the occupant
+
+Description:
+    Kick an occupant out of the current channel. Requires to be moderator.
+
+Examples:
+    /kick troll
+    /kick troll "Stop it"
+"#,
+{
+    nick: String = {
+        completion: (|aparte, command| {
+            occupants_completion(aparte, &command)
+        })
+    },
+    reason: Option<String>
+},
+|aparte, command| {
+    let channel = get_channel(aparte, &command)?;
+    set_role(aparte, &channel, &nick, Role::None, reason, format!("Kick {}", nick))
+});
+
+command_def!(ban,
+r#"/ban <nick|jid> [<reason>]
+
+    nick|jid    Nick of an occupant or jid of the user to ban
+    reason      Optional reason sent to the user
+
+Description:
+    Ban a user from the current channel. Requires to be admin.
+
+Examples:
+    /ban troll
+    /ban troll@server.tld "Stop it"
+"#,
+{
+    target: String = {
+        completion: (|aparte, command| {
+            occupants_completion(aparte, &command)
+        })
+    },
+    reason: Option<String>
+},
+|aparte, command| {
+    let channel = get_channel(aparte, &command)?;
+    set_affiliation(aparte, &channel, &target, Affiliation::Outcast, reason, format!("Ban {}", target))
+});
+
+command_def!(voice,
+r#"/voice <nick>
+
+    nick        Nick of the occupant
+
+Description:
+    Grant voice to an occupant of the current channel. Requires to be moderator.
+
+Examples:
+    /voice visitor
+"#,
+{
+    nick: String = {
+        completion: (|aparte, command| {
+            occupants_completion(aparte, &command)
+        })
+    }
+},
+|aparte, command| {
+    let channel = get_channel(aparte, &command)?;
+    set_role(aparte, &channel, &nick, Role::Participant, None, format!("Grant voice to {}", nick))
+});
+
+command_def!(devoice,
+r#"/devoice <nick>
+
+    nick        Nick of the occupant
+
+Description:
+    Revoke voice from an occupant of the current channel. Requires to be moderator.
+
+Examples:
+    /devoice troll
+"#,
+{
+    nick: String = {
+        completion: (|aparte, command| {
+            occupants_completion(aparte, &command)
+        })
+    }
+},
+|aparte, command| {
+    let channel = get_channel(aparte, &command)?;
+    set_role(aparte, &channel, &nick, Role::Visitor, None, format!("Revoke voice from {}", nick))
+});
+
+command_def!(op,
+r#"/op <nick>
+
+    nick        Nick of the occupant
+
+Description:
+    Grant moderator role to an occupant of the current channel. Requires to be admin.
+
+Examples:
+    /op friend
+"#,
+{
+    nick: String = {
+        completion: (|aparte, command| {
+            occupants_completion(aparte, &command)
+        })
+    }
+},
+|aparte, command| {
+    let channel = get_channel(aparte, &command)?;
+    set_role(aparte, &channel, &nick, Role::Moderator, None, format!("Grant moderator role to {}", nick))
+});
+
+command_def!(deop,
+r#"/deop <nick>
+
+    nick        Nick of the occupant
+
+Description:
+    Revoke moderator role from an occupant of the current channel. Requires to be admin.
+
+Examples:
+    /deop friend
+"#,
+{
+    nick: String = {
+        completion: (|aparte, command| {
+            occupants_completion(aparte, &command)
+        })
+    }
+},
+|aparte, command| {
+    let channel = get_channel(aparte, &command)?;
+    set_role(aparte, &channel, &nick, Role::Participant, None, format!("Revoke moderator role from {}", nick))
+});
+
+command_def!(role,
+r#"/role <nick> <role> [<reason>]
+
+    nick        Nick of the occupant
+    role        One of moderator, participant, visitor or none
+    reason      Optional reason sent to the occupant
+
+Description:
+    Change the role of an occupant of the current channel.
+
+Examples:
+    /role friend moderator
+    /role troll visitor "Calm down"
+"#,
+{
+    nick: String = {
+        completion: (|aparte, command| {
+            occupants_completion(aparte, &command)
+        })
+    },
+    role: Role = {
+        completion: (|_aparte, _command| {
+            ["moderator", "participant", "visitor", "none"].iter().map(|r| r.to_string()).collect()
+        })
+    },
+    reason: Option<String>
+},
+|aparte, command| {
+    let channel = get_channel(aparte, &command)?;
+    let description = format!("Set role of {} to {}", nick, role_name(&role));
+    set_role(aparte, &channel, &nick, role, reason, description)
+});
+
+command_def!(affiliation,
+r#"/affiliation <nick|jid> <affiliation> [<reason>]
+
+    nick|jid    Nick of an occupant or jid of the user
+    affiliation One of owner, admin, member, outcast or none
+    reason      Optional reason sent to the user
+
+Description:
+    Change the affiliation of a user with the current channel.
+
+Examples:
+    /affiliation friend member
+    /affiliation friend@server.tld admin
+"#,
+{
+    target: String = {
+        completion: (|aparte, command| {
+            occupants_completion(aparte, &command)
+        })
+    },
+    affiliation: Affiliation = {
+        completion: (|_aparte, _command| {
+            ["owner", "admin", "member", "outcast", "none"].iter().map(|a| a.to_string()).collect()
+        })
+    },
+    reason: Option<String>
+},
+|aparte, command| {
+    let channel = get_channel(aparte, &command)?;
+    let description = format!("Set affiliation of {} to {}", target, affiliation_name(&affiliation));
+    set_affiliation(aparte, &channel, &target, affiliation, reason, description)
+});
+
+command_def!(affiliations_list,
+r#"/affiliations list <affiliation>
+
+    affiliation One of owner, admin, member or outcast
+
+Description:
+    List users having the given affiliation with the current channel.
+
+Examples:
+    /affiliations list member
+    /affiliations list outcast
+"#,
+{
+    affiliation: Affiliation = {
+        completion: (|_aparte, _command| {
+            ["owner", "admin", "member", "outcast"].iter().map(|a| a.to_string()).collect()
+        })
+    }
+},
+|aparte, command| {
+    let channel = get_channel(aparte, &command)?;
+    match affiliation {
+        Affiliation::Owner | Affiliation::Admin => ensure_owner(&channel)?,
+        Affiliation::Member | Affiliation::Outcast => ensure_admin(&channel)?,
+        Affiliation::None => return Err("Cannot list users without affiliation".to_string()),
+    }
+
+    let iq = {
+        let mut muc = aparte.get_mod_mut::<MucMod>();
+        muc.admin_list(&channel, affiliation)
+    };
+    aparte.send(&channel.account, iq);
+    Ok(())
+});
+
+command_def!(affiliations,
+r#"/affiliations list"#,
+{
+    action: Command = {
+        children: {
+            "list": affiliations_list,
+        }
+    },
+});
+
+command_def!(
+    room_config,
+    r#"/room config
+
+Description:
+    Edit the configuration of the current channel. Requires to be owner.
+
+Examples:
+    /room config
+"#,
+    {},
+    |aparte, command| {
+        let channel = get_channel(aparte, &command)?;
+        ensure_owner(&channel)?;
+
+        let iq = {
+            let mut muc = aparte.get_mod_mut::<MucMod>();
+            muc.owner_get_config(&channel.account, &channel.jid)
+        };
+        aparte.send(&channel.account, iq);
+        Ok(())
+    }
+);
+
+command_def!(room_destroy,
+r#"/room destroy [<reason>]
+
+    reason      Optional reason sent to the occupants
+
+Description:
+    Destroy the current channel. Requires to be owner.
+
+Examples:
+    /room destroy
+    /room destroy "Moved elsewhere"
+"#,
+{
+    reason: Option<String>
+},
+|aparte, command| {
+    let channel = get_channel(aparte, &command)?;
+    ensure_owner(&channel)?;
+
+    let mut destroy = Element::builder("destroy", NS_MUC_OWNER);
+    if let Some(reason) = reason {
+        destroy = destroy.append(Element::builder("reason", NS_MUC_OWNER).append(reason));
+    }
+    let query = Element::builder("query", NS_MUC_OWNER).append(destroy).build();
+
+    let iq = {
+        let mut muc = aparte.get_mod_mut::<MucMod>();
+        muc.iq(&channel.account, &channel.jid, IqType::Set(query), MucRequest::Destroy)
+    };
+    aparte.send(&channel.account, iq);
+    Ok(())
+});
+
+command_def!(room,
+r#"/room config|destroy"#,
+{
+    action: Command = {
+        children: {
+            "config": room_config,
+            "destroy": room_destroy,
+        }
+    },
+});
+
+command_def!(topic,
+r#"/topic [<text>]
+
+    text    New topic of the channel
+
+Description:
+    Show or change the topic of the current channel.
+
+Examples:
+    /topic
+    /topic "Welcome to this channel"
+"#,
+{
+    text: Option<String>
+},
+|aparte, command| {
+    let channel = get_channel(aparte, &command)?;
+    match text {
+        Some(text) => {
+            let message = {
+                let mut muc = aparte.get_mod_mut::<MucMod>();
+                muc.set_subject(&channel, text)
+            };
+            aparte.send(&channel.account, message);
+        }
+        None => {
+            let subject = {
+                let muc = aparte.get_mod::<MucMod>();
+                let langs = aparte.config.langs.iter().map(String::as_str).collect();
+                muc.get_subject(&channel, langs)
+            };
+            let text = match subject {
+                Some(subject) => format!("Topic: {}", subject),
+                None => "No topic set".to_string(),
+            };
+            aparte.log_to(&channel.account, &channel.jid, text);
+        }
+    }
+    Ok(())
+});
+
+command_def!(nick,
+r#"/nick <nick>
+
+    nick    New nick to use in the current channel
+
+Description:
+    Change your nick in the current channel. The nick is remembered in the
+    channel bookmark if any.
+
+Examples:
+    /nick Juliet
+"#,
+{
+    nick: String
+},
+|aparte, command| {
+    let channel = get_channel(aparte, &command)?;
+    if nick.is_empty() {
+        return Err("Nick cannot be empty".to_string());
+    }
+
+    let mut presence = Presence::new(PresenceType::None);
+    presence = presence.with_to(Jid::Full(channel.jid.clone().with_resource(nick.clone())));
+    presence = presence.with_from(channel.account.clone());
+
+    {
+        let mut muc = aparte.get_mod_mut::<MucMod>();
+        muc.nick_changes
+            .insert((channel.account.clone(), channel.jid.clone()), nick);
+    }
+    aparte.send(&channel.account, presence.into());
+    Ok(())
+});
+
+command_def!(invite,
+r#"/invite <jid> [<reason>]
+
+    jid         Jid of the contact to invite
+    reason      Optional reason sent with the invitation
+
+Description:
+    Invite a contact in the current channel.
+
+Examples:
+    /invite contact@server.tld
+    /invite contact@server.tld "Come and see"
+"#,
+{
+    jid: BareJid = {
+        completion: (|aparte, _command| {
+            let contact = aparte.get_mod::<mods::contact::ContactMod>();
+            contact.contacts.values().map(|contact| contact.jid.to_string()).collect()
+        })
+    },
+    reason: Option<String>
+},
+|aparte, command| {
+    let channel = get_channel(aparte, &command)?;
+    let message = {
+        let mut muc = aparte.get_mod_mut::<MucMod>();
+        muc.direct_invite(&channel, &jid, reason)
+    };
+    aparte.send(&channel.account, message);
+    aparte.log_to(&channel.account, &channel.jid, format!("{} has been invited", jid));
+    Ok(())
+});
+
+command_def!(accept,
+r#"/accept [<channel>]
+
+    channel     Channel of the invitation to accept, the last received by default
+
+Description:
+    Accept an invitation and join the channel.
+
+Examples:
+    /accept
+    /accept channel@conference.server.tld
+"#,
+{
+    channel: Option<BareJid> = {
+        completion: (|aparte, _command| {
+            let muc = aparte.get_mod::<MucMod>();
+            muc.invitations.iter().map(|invitation| invitation.channel.to_string()).collect()
+        })
+    }
+},
+|aparte, _command| {
+    let invitation = {
+        let mut muc = aparte.get_mod_mut::<MucMod>();
+        muc.take_invitation(channel.as_ref())?
+    };
+    aparte.schedule(Event::Join {
+        account: invitation.account,
+        channel: Jid::Bare(invitation.channel),
+        password: invitation.password,
+        user_request: true,
+    });
+    Ok(())
+});
+
+command_def!(decline,
+r#"/decline [<channel>] [<reason>]
+
+    channel     Channel of the invitation to decline, the last received by default
+    reason      Optional reason sent to the inviter
+
+Description:
+    Decline an invitation.
+
+Examples:
+    /decline
+    /decline channel@conference.server.tld "Not interested"
+"#,
+{
+    channel: Option<BareJid> = {
+        completion: (|aparte, _command| {
+            let muc = aparte.get_mod::<MucMod>();
+            muc.invitations.iter().map(|invitation| invitation.channel.to_string()).collect()
+        })
+    },
+    reason: Option<String>
+},
+|aparte, _command| {
+    let invitation = {
+        let mut muc = aparte.get_mod_mut::<MucMod>();
+        muc.take_invitation(channel.as_ref())?
+    };
+
+    let mut decline = Element::builder("decline", ns::MUC_USER)
+        .attr("to", invitation.from.to_string());
+    if let Some(reason) = reason {
+        decline = decline.append(Element::builder("reason", ns::MUC_USER).append(reason));
+    }
+    let mut message = XmppParsersMessage::new(Some(Jid::Bare(invitation.channel.clone())));
+    message.type_ = MessageType::Normal;
+    message.id = Some(Uuid::new_v4().to_hyphenated().to_string());
+    message.payloads.push(Element::builder("x", ns::MUC_USER).append(decline).build());
+
+    aparte.send(&invitation.account, message.into());
+    aparte.log(format!("Invitation to {} declined", invitation.channel));
+    Ok(())
+});
+
+command_def!(rooms,
+r#"/rooms [<service>] [<filter>]
+
+    service     Conference service to list, detected from the server by default
+    filter      Only list rooms fuzzy matching the filter
+
+Description:
+    List the public rooms of a conference service in the rooms window.
+    Rooms are numbered, use their number as service to join them.
+
+Examples:
+    /rooms
+    /rooms conference.server.tld
+    /rooms conference.server.tld rust
+    /rooms 3
+"#,
+{
+    service: Option<String> = {
+        completion: (|aparte, _command| {
+            let muc = aparte.get_mod::<MucMod>();
+            muc.conference_services.values().map(|service| service.to_string()).collect()
+        })
+    },
+    filter: Option<String>
+},
+|aparte, _command| {
+    let account = aparte.current_account().ok_or("No connection found".to_string())?;
+    if let Some(Ok(position)) = service.as_ref().map(|service| service.parse::<usize>()) {
+        let room = {
+            let muc = aparte.get_mod::<MucMod>();
+            muc.get_room(&account, position).ok_or(format!("No room {}", position))?
+        };
+        aparte.schedule(Event::Join {
+            account,
+            channel: Jid::Bare(room),
+            password: None,
+            user_request: true,
+        });
+        return Ok(());
+    }
+
+    let service = match service {
+        Some(service) => Some(BareJid::from_str(&service).map_err(|e| format!("Invalid service {}: {}", service, e))?),
+        None => None,
+    };
+    let request = {
+        let mut muc = aparte.get_mod_mut::<MucMod>();
+        muc.list_rooms(&account, service, filter)
+    };
+    aparte.send(&account, request);
+    Ok(())
+});
+
+struct Invitation {
+    account: Account,
+    channel: BareJid,
+    from: BareJid,
+    reason: Option<String>,
+    password: Option<String>,
+}
+
+impl Invitation {
+    /// Parse both XEP-0249 direct and XEP-0045 mediated invitations
+    fn from_xmpp(account: &Account, message: &XmppParsersMessage) -> Option<Self> {
+        let from: BareJid = message.from.clone()?.into();
+        for payload in message.payloads.iter() {
+            if payload.is("x", NS_CONFERENCE) {
+                return Some(Self {
+                    account: account.clone(),
+                    channel: BareJid::from_str(payload.attr("jid")?).ok()?,
+                    from,
+                    reason: payload.attr("reason").map(String::from),
+                    password: payload.attr("password").map(String::from),
+                });
+            } else if payload.is("x", ns::MUC_USER) {
+                let invite = payload.get_child("invite", ns::MUC_USER)?;
+                return Some(Self {
+                    account: account.clone(),
+                    channel: from,
+                    from: BareJid::from_str(invite.attr("from")?).ok()?,
+                    reason: invite
+                        .get_child("reason", ns::MUC_USER)
+                        .map(|reason| reason.text()),
+                    password: payload
+                        .get_child("password", ns::MUC_USER)
+                        .map(|password| password.text()),
+                });
+            }
+        }
+        None
+    }
+}
+
+/// Get a human readable error from the payloads of an error stanza
+fn get_error_text(payloads: &[Element]) -> Option<String> {
+    payloads
+        .iter()
+        .find(|payload| payload.is("error", ns::DEFAULT_NS))
+        .and_then(|payload| StanzaError::try_from(payload.clone()).ok())
+        .map(|error| core::stanza_error_text(&error))
+}
+
+/// Find the declined invitation notification a channel sent us
+fn get_decline(message: &XmppParsersMessage) -> Option<&Element> {
+    message
+        .payloads
+        .iter()
+        .filter(|payload| payload.is("x", ns::MUC_USER))
+        .find_map(|payload| payload.get_child("decline", ns::MUC_USER))
+}
+
+struct DirectInvitation {
+    account: Account,
+    channel: BareJid,
+    to: BareJid,
+    reason: Option<String>,
+    sent: Instant,
+}
+
+/// What an error answer to a XEP-0410 self-ping tells about our presence in the channel
+#[derive(Debug, PartialEq)]
+enum SelfPingStatus {
+    Joined,
+    Unreachable,
+    Left,
+}
+
+fn self_ping_status(error: &StanzaError) -> SelfPingStatus {
+    match error.defined_condition {
+        // Our occupant doesn't support pings but we are still joined
+        DefinedCondition::ServiceUnavailable | DefinedCondition::FeatureNotImplemented => {
+            SelfPingStatus::Joined
+        }
+        // Channel is unreachable, next ping will tell us more
+        DefinedCondition::RemoteServerNotFound | DefinedCondition::RemoteServerTimeout => {
+            SelfPingStatus::Unreachable
+        }
+        _ => SelfPingStatus::Left,
+    }
+}
+
+enum MucRequest {
+    Set(String),
+    List(Affiliation),
+    Config,
+    Destroy,
+}
+
+struct PendingRequest {
+    account: Account,
+    channel: BareJid,
+    request: MucRequest,
+}
+
+/// A public room of a conference service
+#[derive(Debug, Clone)]
+pub struct Room {
+    pub jid: BareJid,
+    pub name: Option<String>,
+    pub occupants: Option<u32>,
+    pub description: Option<String>,
+}
+
+/// Room listing in progress for an account
+struct RoomList {
+    id: String,
+    service: Option<BareJid>,
+    filter: Option<String>,
+    rooms: Vec<Room>,
+    /// Number of disco requests waiting for an answer
+    pending: usize,
+    /// Rooms whose details are still to be requested
+    queue: VecDeque<BareJid>,
+    /// Whether the rooms have been announced, details are announced as they arrive
+    listed: bool,
+}
+
+enum RoomsRequest {
+    /// Items of the server, candidates for the conference service
+    ServerItems,
+    ServiceInfo(BareJid),
+    Rooms,
+    RoomInfo(BareJid),
+}
+
+pub struct MucMod {
+    /// muc#admin and muc#owner requests waiting for an answer, indexed by iq id
+    pending: HashMap<String, PendingRequest>,
+    /// Configuration forms currently being filled, indexed by form id
+    forms: HashMap<String, (Account, BareJid)>,
+    /// Channels to configure once joined
+    configure_on_join: HashSet<(Account, BareJid)>,
+    /// Passwords used to join channels
+    passwords: HashMap<(Account, BareJid), String>,
+    /// Received invitations, the most recent last
+    invitations: Vec<Invitation>,
+    /// Sent direct invitations, indexed by message id, in case we must fallback to mediated ones
+    direct_invitations: HashMap<String, DirectInvitation>,
+    /// Current subject of joined channels, by lang
+    subjects: HashMap<(Account, BareJid), HashMap<String, String>>,
+    /// Channels for which we requested a subject change and when, indexed by message id
+    subject_changes: HashMap<String, (Account, BareJid, Instant)>,
+    /// Requested nick changes by channel
+    nick_changes: HashMap<(Account, BareJid), String>,
+    /// Channels with a running self-ping timer
+    self_ping: HashSet<(Account, BareJid)>,
+    /// Sent self-pings and when they were sent, indexed by iq id
+    self_ping_requests: HashMap<String, (Account, BareJid, Instant)>,
+    /// Conference service detected on the server of each account
+    conference_services: HashMap<Account, BareJid>,
+    /// Room listings in progress
+    room_lists: HashMap<Account, RoomList>,
+    /// Disco requests of room listings, indexed by iq id
+    rooms_requests: HashMap<String, (Account, RoomsRequest)>,
+    /// Rooms of the last listing of each account, as numbered in the rooms window
+    rooms: HashMap<Account, Vec<Room>>,
+}
+
+impl MucMod {
+    pub fn new() -> Self {
+        Self {
+            pending: HashMap::new(),
+            forms: HashMap::new(),
+            configure_on_join: HashSet::new(),
+            passwords: HashMap::new(),
+            invitations: Vec::new(),
+            direct_invitations: HashMap::new(),
+            subjects: HashMap::new(),
+            subject_changes: HashMap::new(),
+            nick_changes: HashMap::new(),
+            self_ping: HashSet::new(),
+            self_ping_requests: HashMap::new(),
+            conference_services: HashMap::new(),
+            room_lists: HashMap::new(),
+            rooms_requests: HashMap::new(),
+            rooms: HashMap::new(),
+        }
+    }
+
+    /// Request the configuration form of the channel once our own presence is received
+    pub fn configure_on_join(&mut self, account: &Account, channel: BareJid) {
+        self.configure_on_join.insert((account.clone(), channel));
+    }
+
+    fn direct_invite(
+        &mut self,
+        channel: &Channel,
+        to: &BareJid,
+        reason: Option<String>,
+    ) -> Element {
+        let mut x = Element::builder("x", NS_CONFERENCE).attr("jid", channel.jid.to_string());
+        if let Some(reason) = &reason {
+            x = x.attr("reason", reason.clone());
+        }
+        let index = (channel.account.clone(), channel.jid.clone());
+        if let Some(password) = self.passwords.get(&index) {
+            x = x.attr("password", password.clone());
+        }
+
+        let id = Uuid::new_v4().to_hyphenated().to_string();
+        let mut message = XmppParsersMessage::new(Some(Jid::Bare(to.clone())));
+        message.type_ = MessageType::Normal;
+        message.id = Some(id.clone());
+        message.payloads.push(x.build());
+
+        self.expire_sent_messages(Instant::now());
+        self.direct_invitations.insert(
+            id,
+            DirectInvitation {
+                account: channel.account.clone(),
+                channel: channel.jid.clone(),
+                to: to.clone(),
+                reason,
+                sent: Instant::now(),
+            },
+        );
+
+        message.into()
+    }
+
+    fn mediated_invite(&self, invitation: &DirectInvitation) -> Element {
+        let mut invite =
+            Element::builder("invite", ns::MUC_USER).attr("to", invitation.to.to_string());
+        if let Some(reason) = &invitation.reason {
+            invite = invite.append(Element::builder("reason", ns::MUC_USER).append(reason.clone()));
+        }
+
+        let mut message = XmppParsersMessage::new(Some(Jid::Bare(invitation.channel.clone())));
+        message.type_ = MessageType::Normal;
+        message.id = Some(Uuid::new_v4().to_hyphenated().to_string());
+        message
+            .payloads
+            .push(Element::builder("x", ns::MUC_USER).append(invite).build());
+        message.into()
+    }
+
+    fn set_subject(&mut self, channel: &Channel, subject: String) -> Element {
+        let id = Uuid::new_v4().to_hyphenated().to_string();
+        let mut message = XmppParsersMessage::new(Some(Jid::Bare(channel.jid.clone())));
+        message.type_ = MessageType::Groupchat;
+        message.id = Some(id.clone());
+        message.subjects.insert(String::new(), Subject(subject));
+
+        self.expire_sent_messages(Instant::now());
+        self.subject_changes.insert(
+            id,
+            (channel.account.clone(), channel.jid.clone(), Instant::now()),
+        );
+
+        message.into()
+    }
+
+    /// Forget invitations and topic changes sent too long ago to still get an error answer
+    fn expire_sent_messages(&mut self, now: Instant) {
+        self.direct_invitations
+            .retain(|_, invitation| now.duration_since(invitation.sent) < MESSAGE_ERROR_TIMEOUT);
+        self.subject_changes
+            .retain(|_, (_, _, sent)| now.duration_since(*sent) < MESSAGE_ERROR_TIMEOUT);
+    }
+
+    fn get_subject(&self, channel: &Channel, langs: Vec<&str>) -> Option<String> {
+        let index = (channel.account.clone(), channel.jid.clone());
+        let subjects = self.subjects.get(&index)?;
+        match i18n::get_best(subjects, langs) {
+            Some((_lang, subject)) if !subject.is_empty() => Some(subject.clone()),
+            _ => None,
+        }
+    }
+
+    /// Announce the subject received from a channel, either the initial one or a change
+    fn handle_subject(
+        &mut self,
+        aparte: &mut Aparte,
+        account: &Account,
+        jid: &Jid,
+        subjects: &HashMap<String, String>,
+    ) {
+        let channel: BareJid = jid.clone().into();
+        let index = (account.clone(), channel.clone());
+        let author = match jid {
+            Jid::Full(jid) => Some(jid.resource.clone()),
+            Jid::Bare(_) => None,
+        };
+
+        let langs = aparte.config.langs.iter().map(String::as_str).collect();
+        let subject = match i18n::get_best(subjects, langs) {
+            Some((_lang, subject)) if !subject.is_empty() => Some(subject.clone()),
+            _ => None,
+        };
+
+        let text = match (self.subjects.contains_key(&index), subject, author) {
+            (false, None, _) => None,
+            (false, Some(subject), Some(author)) => {
+                Some(format!("Topic: {} (set by {})", subject, author))
+            }
+            (false, Some(subject), None) => Some(format!("Topic: {}", subject)),
+            (true, Some(subject), Some(author)) => {
+                Some(format!("{} changed the topic to: {}", author, subject))
+            }
+            (true, Some(subject), None) => Some(format!("Topic changed to: {}", subject)),
+            (true, None, Some(author)) => Some(format!("{} cleared the topic", author)),
+            (true, None, None) => Some("Topic cleared".to_string()),
+        };
+
+        self.subjects.insert(index, subjects.clone());
+        if let Some(text) = text {
+            aparte.log_to(account, &channel, text);
+        }
+    }
+
+    fn take_invitation(&mut self, channel: Option<&BareJid>) -> Result<Invitation, String> {
+        let position = match channel {
+            Some(channel) => self
+                .invitations
+                .iter()
+                .rposition(|invitation| &invitation.channel == channel),
+            None => self.invitations.len().checked_sub(1),
+        };
+        match (position, channel) {
+            (Some(position), _) => Ok(self.invitations.remove(position)),
+            (None, Some(channel)) => Err(format!("No invitation to {}", channel)),
+            (None, None) => Err("No pending invitation".to_string()),
+        }
+    }
+
+    fn iq(
+        &mut self,
+        account: &Account,
+        channel: &BareJid,
+        payload: IqType,
+        request: MucRequest,
+    ) -> Element {
+        let id = Uuid::new_v4().to_hyphenated().to_string();
+        self.pending.insert(
+            id.clone(),
+            PendingRequest {
+                account: account.clone(),
+                channel: channel.clone(),
+                request,
+            },
+        );
+        let iq = Iq {
+            from: None,
+            to: Some(Jid::Bare(channel.clone())),
+            id,
+            payload,
+        };
+        iq.into()
+    }
+
+    fn admin_set(&mut self, channel: &Channel, item: Element, description: String) -> Element {
+        let query = Element::builder("query", NS_MUC_ADMIN).append(item).build();
+        self.iq(
+            &channel.account,
+            &channel.jid,
+            IqType::Set(query),
+            MucRequest::Set(description),
+        )
+    }
+
+    fn admin_list(&mut self, channel: &Channel, affiliation: Affiliation) -> Element {
+        let query = Element::builder("query", NS_MUC_ADMIN)
+            .append(
+                Element::builder("item", NS_MUC_ADMIN)
+                    .attr("affiliation", affiliation_name(&affiliation))
+                    .build(),
+            )
+            .build();
+        self.iq(
+            &channel.account,
+            &channel.jid,
+            IqType::Get(query),
+            MucRequest::List(affiliation),
+        )
+    }
+
+    fn owner_get_config(&mut self, account: &Account, channel: &BareJid) -> Element {
+        let query = Element::builder("query", NS_MUC_OWNER).build();
+        self.iq(account, channel, IqType::Get(query), MucRequest::Config)
+    }
+
+    fn owner_set_config(
+        &mut self,
+        account: &Account,
+        channel: &BareJid,
+        form: DataForm,
+        description: String,
+    ) -> Element {
+        let query = Element::builder("query", NS_MUC_OWNER)
+            .append(Element::from(form))
+            .build();
+        self.iq(
+            account,
+            channel,
+            IqType::Set(query),
+            MucRequest::Set(description),
+        )
+    }
+
+    fn handle_list(&self, affiliation: &Affiliation, query: Option<&Element>) -> String {
+        let mut items = Vec::new();
+        if let Some(query) = query {
+            for item in query.children().filter(|c| c.is("item", NS_MUC_ADMIN)) {
+                match (item.attr("jid"), item.attr("nick")) {
+                    (Some(jid), Some(nick)) => items.push(format!("  {} ({})", jid, nick)),
+                    (Some(jid), None) => items.push(format!("  {}", jid)),
+                    _ => {}
+                }
+            }
+        }
+
+        if items.is_empty() {
+            format!("No user with {} affiliation", affiliation_name(affiliation))
+        } else {
+            items.sort();
+            format!(
+                "Users with {} affiliation:\n{}",
+                affiliation_name(affiliation),
+                items.join("\n")
+            )
+        }
+    }
+
+    fn handle_config(
+        &mut self,
+        aparte: &mut Aparte,
+        pending: &PendingRequest,
+        query: Option<&Element>,
+    ) -> Result<(), String> {
+        let form = query
+            .and_then(|query| query.get_child("x", ns::DATA_FORMS))
+            .ok_or("no configuration form".to_string())?;
+        let form = DataForm::try_from(form.clone()).map_err(|e| e.to_string())?;
+
+        let id = Uuid::new_v4().to_hyphenated().to_string();
+        self.forms.insert(
+            id.clone(),
+            (pending.account.clone(), pending.channel.clone()),
+        );
+        aparte.schedule(Event::Form {
+            id,
+            title: format!("{} configuration", pending.channel),
+            form,
+            actions: vec![FormAction::Submit],
+            notes: Vec::new(),
+        });
+        Ok(())
+    }
+
+    fn handle_response(&mut self, aparte: &mut Aparte, pending: PendingRequest, iq: &Iq) {
+        let message = match (&pending.request, &iq.payload) {
+            (MucRequest::Set(description), IqType::Result(_)) => format!("{}: done", description),
+            (MucRequest::List(affiliation), IqType::Result(query)) => {
+                self.handle_list(affiliation, query.as_ref())
+            }
+            (MucRequest::Config, IqType::Result(query)) => {
+                match self.handle_config(aparte, &pending, query.as_ref()) {
+                    Ok(()) => return,
+                    Err(err) => format!("Cannot configure {}: {}", pending.channel, err),
+                }
+            }
+            (MucRequest::Destroy, IqType::Result(_)) => {
+                format!("{} has been destroyed", pending.channel)
+            }
+            (MucRequest::Set(description), IqType::Error(error)) => {
+                format!("{}: {}", description, core::stanza_error_text(error))
+            }
+            (MucRequest::List(affiliation), IqType::Error(error)) => format!(
+                "Cannot list users with {} affiliation: {}",
+                affiliation_name(affiliation),
+                core::stanza_error_text(error)
+            ),
+            (MucRequest::Config, IqType::Error(error)) => format!(
+                "Cannot configure {}: {}",
+                pending.channel,
+                core::stanza_error_text(error)
+            ),
+            (MucRequest::Destroy, IqType::Error(error)) => format!(
+                "Cannot destroy {}: {}",
+                pending.channel,
+                core::stanza_error_text(error)
+            ),
+            _ => return,
+        };
+
+        aparte.log_to(&pending.account, &pending.channel, message);
+    }
+
+    /// Ping our own occupant to check we are still joined (XEP-0410)
+    fn self_ping(&mut self, aparte: &mut Aparte, account: &Account, channel: &BareJid) {
+        let index = (account.clone(), channel.clone());
+        let joined = {
+            let conversation = aparte.get_mod::<mods::conversation::ConversationMod>();
+            match conversation.get(account, channel) {
+                Some(Conversation::Channel(channel)) => Some(channel.clone()),
+                _ => None,
+            }
+        };
+
+        let joined = match joined {
+            Some(joined) => joined,
+            None => {
+                // Channel has been left, stop pinging it
+                self.self_ping.remove(&index);
+                self.self_ping_requests
+                    .retain(|_, (pinged_account, pinged_channel, _)| {
+                        pinged_account != account || pinged_channel != channel
+                    });
+                return;
+            }
+        };
+
+        if self.expire_self_pings(account, channel, Instant::now()) {
+            // Pinging again would fail until we are back in the channel
+            self.rejoin(
+                aparte,
+                account,
+                channel,
+                "no answer to self-ping".to_string(),
+            );
+        } else {
+            let id = Uuid::new_v4().to_hyphenated().to_string();
+            let occupant = joined.jid.clone().with_resource(joined.nick.clone());
+            let iq = Iq::from_get(id.clone(), Ping).with_to(Jid::Full(occupant));
+            self.self_ping_requests
+                .insert(id, (account.clone(), channel.clone(), Instant::now()));
+            aparte.send(account, iq.into());
+        }
+
+        aparte.schedule_in(
+            SELF_PING_INTERVAL,
+            Event::ChannelSelfPing {
+                account: account.clone(),
+                channel: channel.clone(),
+            },
+        );
+    }
+
+    /// Forget self-pings of the channel unanswered for a whole interval, returns whether there
+    /// were some
+    fn expire_self_pings(&mut self, account: &Account, channel: &BareJid, now: Instant) -> bool {
+        let count = self.self_ping_requests.len();
+        self.self_ping_requests
+            .retain(|_, (pinged_account, pinged_channel, sent)| {
+                pinged_account != account
+                    || pinged_channel != channel
+                    || now.duration_since(*sent) < SELF_PING_INTERVAL
+            });
+        self.self_ping_requests.len() != count
+    }
+
+    /// Join again a channel we are no longer in, with the same nick and password
+    fn rejoin(&self, aparte: &mut Aparte, account: &Account, channel: &BareJid, reason: String) {
+        let nick = {
+            let conversation = aparte.get_mod::<mods::conversation::ConversationMod>();
+            match conversation.get(account, channel) {
+                Some(Conversation::Channel(channel)) => channel.nick.clone(),
+                _ => return,
+            }
+        };
+        let index = (account.clone(), channel.clone());
+        aparte.log_to(
+            account,
+            channel,
+            format!("You are no longer in {} ({}), rejoining", channel, reason),
+        );
+        aparte.schedule(Event::Join {
+            account: account.clone(),
+            channel: Jid::Full(channel.clone().with_resource(nick)),
+            password: self.passwords.get(&index).cloned(),
+            user_request: false,
+        });
+    }
+
+    fn handle_self_ping(
+        &mut self,
+        aparte: &mut Aparte,
+        account: &Account,
+        channel: &BareJid,
+        iq: &Iq,
+    ) {
+        let error = match &iq.payload {
+            IqType::Error(error) => error,
+            _ => return,
+        };
+
+        match self_ping_status(error) {
+            SelfPingStatus::Joined => {}
+            SelfPingStatus::Unreachable => {
+                aparte.log_to(
+                    account,
+                    channel,
+                    format!(
+                        "{} is unreachable: {}",
+                        channel,
+                        core::stanza_error_text(error)
+                    ),
+                );
+            }
+            SelfPingStatus::Left => {
+                self.rejoin(aparte, account, channel, core::stanza_error_text(error));
+            }
+        }
+    }
+
+    fn handle_presence(&mut self, aparte: &mut Aparte, account: &Account, presence: &Presence) {
+        let channel: BareJid = match &presence.from {
+            Some(Jid::Full(from)) => from.clone().into(),
+            _ => return,
+        };
+
+        let index = (account.clone(), channel);
+
+        if presence.type_ == PresenceType::Error {
+            if self.nick_changes.remove(&index).is_some() {
+                let (account, channel) = index;
+                let text = match get_error_text(&presence.payloads) {
+                    Some(error) => format!("Cannot change nick: {}", error),
+                    None => "Cannot change nick".to_string(),
+                };
+                aparte.log_to(&account, &channel, text);
+            }
+            return;
+        }
+
+        for payload in presence.payloads.iter() {
+            if let Ok(muc_user) = MucUser::try_from(payload.clone()) {
+                if muc_user.status.contains(&Status::NewNick) {
+                    let nick = muc_user.items.iter().find_map(|item| item.nick.clone());
+                    if let (Some(Jid::Full(from)), Some(nick)) = (&presence.from, nick) {
+                        let (account, channel) = &index;
+                        let text = format!("{} is now known as {}", from.resource, nick);
+                        aparte.log_to(account, channel, text);
+
+                        if muc_user.status.contains(&Status::SelfPresence)
+                            && self.nick_changes.remove(&index).is_some()
+                        {
+                            let update = {
+                                let mut bookmarks =
+                                    aparte.get_mod_mut::<mods::bookmarks::BookmarksMod>();
+                                bookmarks.set_nick(channel, nick)
+                            };
+                            if let Some(update) = update {
+                                aparte.send(account, update);
+                            }
+                        }
+                    }
+                    return;
+                }
+
+                if muc_user.status.contains(&Status::SelfPresence)
+                    && self.configure_on_join.contains(&index)
+                {
+                    self.configure_on_join.remove(&index);
+                    let (account, channel) = index;
+                    if muc_user.status.contains(&Status::RoomHasBeenCreated) {
+                        aparte.log_to(&account, &channel, format!("{} has been created", channel));
+                    }
+                    let iq = self.owner_get_config(&account, &channel);
+                    aparte.send(&account, iq);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn get_room(&self, account: &Account, position: usize) -> Option<BareJid> {
+        let rooms = self.rooms.get(account)?;
+        let room = position.checked_sub(1).and_then(|index| rooms.get(index))?;
+        Some(room.jid.clone())
+    }
+
+    fn rooms_request(&mut self, account: &Account, jid: BareJid, request: RoomsRequest) -> Element {
+        let id = Uuid::new_v4().to_hyphenated().to_string();
+        let element = match request {
+            RoomsRequest::ServerItems | RoomsRequest::Rooms => {
+                disco::items_request(id.clone(), Jid::Bare(jid), None)
+            }
+            RoomsRequest::ServiceInfo(_) | RoomsRequest::RoomInfo(_) => {
+                disco::info_request(id.clone(), Jid::Bare(jid), None)
+            }
+        };
+        self.rooms_requests.insert(id, (account.clone(), request));
+        element
+    }
+
+    /// Start listing the rooms of the service, detecting the conference service of the server
+    /// when none is given
+    fn list_rooms(
+        &mut self,
+        account: &Account,
+        service: Option<BareJid>,
+        filter: Option<String>,
+    ) -> Element {
+        // Forget answers to a previous listing
+        self.rooms_requests
+            .retain(|_, (requester, _)| requester != account);
+
+        let service = service.or_else(|| self.conference_services.get(account).cloned());
+        self.room_lists.insert(
+            account.clone(),
+            RoomList {
+                id: Uuid::new_v4().to_hyphenated().to_string(),
+                service: service.clone(),
+                filter,
+                rooms: Vec::new(),
+                pending: 1,
+                queue: VecDeque::new(),
+                listed: false,
+            },
+        );
+
+        match service {
+            Some(service) => self.rooms_request(account, service, RoomsRequest::Rooms),
+            None => {
+                let server = BareJid::domain(&account.domain);
+                self.rooms_request(account, server, RoomsRequest::ServerItems)
+            }
+        }
+    }
+
+    fn handle_rooms_response(
+        &mut self,
+        aparte: &mut Aparte,
+        account: &Account,
+        request: RoomsRequest,
+        iq: &Iq,
+    ) {
+        let mut list = match self.room_lists.remove(account) {
+            Some(list) => list,
+            None => return,
+        };
+
+        list.pending -= 1;
+        match (request, &iq.payload) {
+            (RoomsRequest::ServerItems, IqType::Result(Some(query))) => {
+                let items = match DiscoItemsResult::try_from(query.clone()) {
+                    Ok(result) => result.items,
+                    Err(_) => Vec::new(),
+                };
+                for item in items {
+                    let jid: BareJid = item.jid.into();
+                    let request =
+                        self.rooms_request(account, jid.clone(), RoomsRequest::ServiceInfo(jid));
+                    aparte.send(account, request);
+                    list.pending += 1;
+                }
+            }
+            (RoomsRequest::ServiceInfo(jid), IqType::Result(Some(query))) => {
+                let is_conference = match DiscoInfoResult::try_from(query.clone()) {
+                    Ok(info) => info.identities.iter().any(|identity| {
+                        identity.category == "conference" && identity.type_ == "text"
+                    }),
+                    Err(_) => false,
+                };
+                if is_conference && list.service.is_none() {
+                    self.conference_services
+                        .insert(account.clone(), jid.clone());
+                    list.service = Some(jid.clone());
+                    let request = self.rooms_request(account, jid, RoomsRequest::Rooms);
+                    aparte.send(account, request);
+                    list.pending += 1;
+                }
+            }
+            (RoomsRequest::Rooms, IqType::Result(Some(query))) => {
+                let items = match DiscoItemsResult::try_from(query.clone()) {
+                    Ok(result) => result.items,
+                    Err(_) => Vec::new(),
+                };
+                let rooms = items.into_iter().map(|item| Room {
+                    jid: item.jid.into(),
+                    name: item.name,
+                    occupants: None,
+                    description: None,
+                });
+                list.rooms = match &list.filter {
+                    Some(filter) => {
+                        let matcher = SkimMatcherV2::default();
+                        let mut rooms: Vec<(i64, Room)> = rooms
+                            .filter_map(|room| {
+                                let choice =
+                                    format!("{} {}", room.name.as_deref().unwrap_or(""), room.jid);
+                                Some((matcher.fuzzy_match(&choice, filter)?, room))
+                            })
+                            .collect();
+                        rooms.sort_by_key(|(score, _)| std::cmp::Reverse(*score));
+                        rooms.into_iter().map(|(_, room)| room).collect()
+                    }
+                    None => rooms.collect(),
+                };
+
+                // Rooms are listed right away, with details of the first ones filled as they are
+                // received
+                list.queue = list
+                    .rooms
+                    .iter()
+                    .take(ROOM_INFO_MAX)
+                    .map(|room| room.jid.clone())
+                    .collect();
+                for _ in 0..ROOM_INFO_CONCURRENT {
+                    self.request_room_info(aparte, account, &mut list);
+                }
+                list.listed = true;
+                self.announce_rooms(aparte, account, &list);
+            }
+            (RoomsRequest::RoomInfo(jid), payload) => {
+                if let (IqType::Result(Some(query)), Some(room)) =
+                    (payload, list.rooms.iter_mut().find(|room| room.jid == jid))
+                {
+                    if let Ok(info) = DiscoInfoResult::try_from(query.clone()) {
+                        update_room(room, info);
+                        self.announce_rooms(aparte, account, &list);
+                    }
+                }
+                self.request_room_info(aparte, account, &mut list);
+            }
+            (RoomsRequest::ServerItems, IqType::Error(error)) => {
+                aparte.log(format!(
+                    "Cannot find the conference service: {}",
+                    core::stanza_error_text(error)
+                ));
+                return;
+            }
+            (RoomsRequest::Rooms, IqType::Error(error)) => {
+                aparte.log(format!(
+                    "Cannot list rooms of {}: {}",
+                    list.service.as_ref().unwrap(),
+                    core::stanza_error_text(error)
+                ));
+                return;
+            }
+            _ => {}
+        }
+
+        if list.pending > 0 {
+            self.room_lists.insert(account.clone(), list);
+        } else if !list.listed {
+            aparte
+                .log("No conference service found on the server, use /rooms <service>".to_string());
+        }
+    }
+
+    /// Request the details of the next listed room
+    fn request_room_info(&mut self, aparte: &mut Aparte, account: &Account, list: &mut RoomList) {
+        if let Some(jid) = list.queue.pop_front() {
+            let request = self.rooms_request(account, jid.clone(), RoomsRequest::RoomInfo(jid));
+            aparte.send(account, request);
+            list.pending += 1;
+        }
+    }
+
+    fn announce_rooms(&mut self, aparte: &mut Aparte, account: &Account, list: &RoomList) {
+        if let Some(service) = &list.service {
+            self.rooms.insert(account.clone(), list.rooms.clone());
+            aparte.schedule(Event::Rooms {
+                account: account.clone(),
+                id: list.id.clone(),
+                service: service.clone(),
+                filter: list.filter.clone(),
+                rooms: list.rooms.clone(),
+            });
+        }
+    }
+}
+
+/// Fill the details of a room from its disco#info
+fn update_room(room: &mut Room, info: DiscoInfoResult) {
+    if let Some(name) = info
+        .identities
+        .iter()
+        .find_map(|identity| identity.name.clone())
+    {
+        room.name = Some(name);
+    }
+    let roominfo = info
+        .extensions
+        .iter()
+        .find(|form| form.form_type.as_deref() == Some(NS_MUC_ROOMINFO));
+    for field in roominfo.iter().flat_map(|form| form.fields.iter()) {
+        match (field.var.as_str(), field.values.first()) {
+            ("muc#roominfo_occupants", Some(value)) => room.occupants = value.parse().ok(),
+            ("muc#roominfo_description", Some(value)) if !value.is_empty() => {
+                room.description = Some(value.clone())
+            }
+            _ => {}
+        }
+    }
+}
+
+impl ModTrait for MucMod {
+    fn init(&mut self, aparte: &mut Aparte) -> Result<(), ()> {
+        aparte.add_command(topic::new());
+        aparte.add_command(nick::new());
+        aparte.add_command(invite::new());
+        aparte.add_command(accept::new());
+        aparte.add_command(decline::new());
+        aparte.add_command(kick::new());
+        aparte.add_command(ban::new());
+        aparte.add_command(voice::new());
+        aparte.add_command(devoice::new());
+        aparte.add_command(op::new());
+        aparte.add_command(deop::new());
+        aparte.add_command(role::new());
+        aparte.add_command(affiliation::new());
+        aparte.add_command(affiliations::new());
+        aparte.add_command(room::new());
+        aparte.add_command(rooms::new());
+
+        let mut disco = aparte.get_mod_mut::<disco::DiscoMod>();
+        disco.add_feature(NS_CONFERENCE)
+    }
+
+    fn can_handle_xmpp_message(
+        &mut self,
+        _aparte: &mut Aparte,
+        _account: &Account,
+        message: &XmppParsersMessage,
+        _delay: &Option<Delay>,
+    ) -> f64 {
+        match message.type_ {
+            MessageType::Error => match &message.id {
+                Some(id) if self.direct_invitations.contains_key(id) => 1f64,
+                Some(id) if self.subject_changes.contains_key(id) => 1f64,
+                _ => 0f64,
+            },
+            MessageType::Groupchat => 0f64,
+            _ => {
+                if message.payloads.iter().any(|payload| {
+                    payload.is("x", NS_CONFERENCE)
+                        || (payload.is("x", ns::MUC_USER)
+                            && (payload.has_child("invite", ns::MUC_USER)
+                                || payload.has_child("decline", ns::MUC_USER)))
+                }) {
+                    1f64
+                } else {
+                    0f64
+                }
+            }
+        }
+    }
+
+    fn handle_xmpp_message(
+        &mut self,
+        aparte: &mut Aparte,
+        account: &Account,
+        message: &XmppParsersMessage,
+        _delay: &Option<Delay>,
+        archive: bool,
+    ) {
+        if archive {
+            return;
+        }
+
+        if message.type_ == MessageType::Error {
+            // Contact doesn't support direct invitations, ask the channel to invite them
+            let id = message.id.as_ref().unwrap();
+            if let Some(invitation) = self.direct_invitations.remove(id) {
+                let mediated = self.mediated_invite(&invitation);
+                aparte.send(&invitation.account, mediated);
+            } else if let Some((account, channel, _)) = self.subject_changes.remove(id) {
+                let text = match get_error_text(&message.payloads) {
+                    Some(error) => format!("Cannot change topic: {}", error),
+                    None => "Cannot change topic".to_string(),
+                };
+                aparte.log_to(&account, &channel, text);
+            }
+        } else if let Some(decline) = get_decline(message) {
+            if let Some(from) = &message.from {
+                let channel: BareJid = from.clone().into();
+                let who = decline.attr("from").unwrap_or("Contact");
+                let text = match decline.get_child("reason", ns::MUC_USER) {
+                    Some(reason) => format!("{} declined the invitation: {}", who, reason.text()),
+                    None => format!("{} declined the invitation", who),
+                };
+                aparte.log_to(account, &channel, text);
+            }
+        } else if let Some(invitation) = Invitation::from_xmpp(account, message) {
+            let mut text = format!("{} invited you to {}", invitation.from, invitation.channel);
+            if let Some(reason) = &invitation.reason {
+                text.push_str(&format!(": {}", reason));
+            }
+            text.push_str(&format!(
+                "\nUse /accept {} to join or /decline {} [<reason>]",
+                invitation.channel, invitation.channel
+            ));
+            aparte.log(text);
+            self.invitations.retain(|existing| {
+                existing.account != invitation.account || existing.channel != invitation.channel
+            });
+            self.invitations.push(invitation);
+        }
+    }
+
+    fn on_event(&mut self, aparte: &mut Aparte, event: &Event) {
+        match event {
+            Event::Iq(_, iq) => {
+                if let Some((account, channel, _)) = self.self_ping_requests.remove(&iq.id) {
+                    self.handle_self_ping(aparte, &account, &channel, iq);
+                } else if let Some((account, request)) = self.rooms_requests.remove(&iq.id) {
+                    self.handle_rooms_response(aparte, &account, request, iq);
+                } else if let Some(pending) = self.pending.remove(&iq.id) {
+                    self.handle_response(aparte, pending, iq);
+                }
+            }
+            Event::Presence(account, presence) => {
+                self.handle_presence(aparte, account, presence);
+            }
+            Event::Join {
+                account,
+                channel,
+                password,
+                ..
+            } => {
+                let index = (account.clone(), channel.clone().into());
+                self.subjects.remove(&index);
+                match password {
+                    Some(password) => self.passwords.insert(index, password.clone()),
+                    None => self.passwords.remove(&index),
+                };
+            }
+            Event::Joined {
+                account, channel, ..
+            } => {
+                let index = (account.clone(), channel.clone().into());
+                if self.self_ping.insert(index) {
+                    aparte.schedule_in(
+                        SELF_PING_INTERVAL,
+                        Event::ChannelSelfPing {
+                            account: account.clone(),
+                            channel: channel.clone().into(),
+                        },
+                    );
+                }
+            }
+            Event::Connected(account, _) => {
+                // Rejoin channels we were in before the connection was lost, bookmark autojoin
+                // skips them
+                let channels: Vec<(BareJid, String)> = {
+                    let conversation = aparte.get_mod::<mods::conversation::ConversationMod>();
+                    conversation
+                        .get_channels(account)
+                        .map(|channel| (channel.jid.clone(), channel.nick.clone()))
+                        .collect()
+                };
+                for (channel, nick) in channels {
+                    let index = (account.clone(), channel.clone());
+                    aparte.schedule(Event::Join {
+                        account: account.clone(),
+                        channel: Jid::Full(channel.with_resource(nick)),
+                        password: self.passwords.get(&index).cloned(),
+                        user_request: false,
+                    });
+                }
+            }
+            Event::ChannelSelfPing { account, channel } => {
+                self.expire_sent_messages(Instant::now());
+                self.self_ping(aparte, account, channel);
+            }
+            Event::Subject(account, jid, subjects) => {
+                self.handle_subject(aparte, account, jid, subjects);
+            }
+            Event::SubmitForm { id, form, .. } => {
+                if let Some((account, channel)) = self.forms.remove(id) {
+                    let description = format!("Configure {}", channel);
+                    let iq = self.owner_set_config(&account, &channel, form.clone(), description);
+                    aparte.send(&account, iq);
+                }
+            }
+            Event::CancelForm(id) => {
+                if let Some((account, channel)) = self.forms.remove(id) {
+                    let form = DataForm {
+                        type_: DataFormType::Cancel,
+                        form_type: None,
+                        title: None,
+                        instructions: None,
+                        fields: vec![],
+                    };
+                    let description = format!("Cancel configuration of {}", channel);
+                    let iq = self.owner_set_config(&account, &channel, form, description);
+                    aparte.send(&account, iq);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl fmt::Display for MucMod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "XEP-0045: Multi-User Chat")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::terminus;
+    use std::convert::TryFrom;
+
+    fn occupant(
+        nick: &str,
+        jid: Option<&str>,
+        affiliation: conversation::Affiliation,
+        role: conversation::Role,
+    ) -> conversation::Occupant {
+        conversation::Occupant {
+            nick: nick.to_string(),
+            jid: jid.map(|jid| BareJid::from_str(jid).unwrap()),
+            affiliation,
+            role,
+        }
+    }
+
+    fn channel(affiliation: conversation::Affiliation, role: conversation::Role) -> Channel {
+        let mut occupants = HashMap::new();
+        for occupant in &[
+            occupant("me", Some("me@example.org"), affiliation, role),
+            occupant(
+                "troll",
+                Some("troll@example.org"),
+                conversation::Affiliation::None,
+                conversation::Role::Participant,
+            ),
+            occupant(
+                "ghost",
+                None,
+                conversation::Affiliation::None,
+                conversation::Role::Participant,
+            ),
+        ] {
+            occupants.insert(occupant.nick.clone(), occupant.clone());
+        }
+        Channel {
+            account: Account::from_str("me@example.org/aparte").unwrap(),
+            jid: BareJid::from_str("room@conference.example.org").unwrap(),
+            nick: "me".to_string(),
+            name: None,
+            occupants,
+        }
+    }
+
+    #[test]
+    fn test_resolve_jid() {
+        let channel = channel(
+            conversation::Affiliation::Admin,
+            conversation::Role::Moderator,
+        );
+
+        assert_eq!(
+            resolve_jid(&channel, "troll"),
+            Ok(BareJid::from_str("troll@example.org").unwrap())
+        );
+        assert_eq!(
+            resolve_jid(&channel, "someone@example.org"),
+            Ok(BareJid::from_str("someone@example.org").unwrap())
+        );
+        assert!(resolve_jid(&channel, "ghost").is_err());
+        assert!(resolve_jid(&channel, "unknown").is_err());
+    }
+
+    #[test]
+    fn test_ensure_privileges() {
+        let moderator = channel(
+            conversation::Affiliation::Member,
+            conversation::Role::Moderator,
+        );
+        assert!(ensure_moderator(&moderator).is_ok());
+        assert!(ensure_admin(&moderator).is_err());
+        assert!(ensure_owner(&moderator).is_err());
+
+        let admin = channel(
+            conversation::Affiliation::Admin,
+            conversation::Role::Participant,
+        );
+        assert!(ensure_moderator(&admin).is_err());
+        assert!(ensure_admin(&admin).is_ok());
+        assert!(ensure_owner(&admin).is_err());
+
+        let owner = channel(
+            conversation::Affiliation::Owner,
+            conversation::Role::Moderator,
+        );
+        assert!(ensure_admin(&owner).is_ok());
+        assert!(ensure_owner(&owner).is_ok());
+    }
+
+    #[test]
+    fn test_admin_list() {
+        let channel = channel(
+            conversation::Affiliation::Admin,
+            conversation::Role::Moderator,
+        );
+        let mut muc = MucMod::new();
+        let iq = Iq::try_from(muc.admin_list(&channel, Affiliation::Outcast)).unwrap();
+
+        assert_eq!(iq.to, Some(Jid::Bare(channel.jid.clone())));
+        let query = match &iq.payload {
+            IqType::Get(query) => query,
+            _ => panic!("admin list should be a get request"),
+        };
+        assert!(query.is("query", NS_MUC_ADMIN));
+        let item = query.get_child("item", NS_MUC_ADMIN).unwrap();
+        assert_eq!(item.attr("affiliation"), Some("outcast"));
+
+        match muc.pending.get(&iq.id) {
+            Some(PendingRequest {
+                request: MucRequest::List(Affiliation::Outcast),
+                ..
+            }) => {}
+            _ => panic!("admin list should be pending"),
+        }
+    }
+
+    #[test]
+    fn test_owner_set_config() {
+        let account = Account::from_str("me@example.org/aparte").unwrap();
+        let channel = BareJid::from_str("room@conference.example.org").unwrap();
+        let form: Element = r#"<x xmlns='jabber:x:data' type='form'>
+                <field var='FORM_TYPE' type='hidden'>
+                    <value>http://jabber.org/protocol/muc#roomconfig</value>
+                </field>
+                <field var='muc#roomconfig_roomname' type='text-single'/>
+            </x>"#
+            .parse()
+            .unwrap();
+        let mut form = DataForm::try_from(form).unwrap();
+        form.fields[0].values = vec!["My room".to_string()];
+        let submit = terminus::submit_form(&form).unwrap();
+
+        let mut muc = MucMod::new();
+        let description = format!("Configure {}", channel);
+        let iq =
+            Iq::try_from(muc.owner_set_config(&account, &channel, submit, description)).unwrap();
+
+        assert_eq!(iq.to, Some(Jid::Bare(channel.clone())));
+        let query = match &iq.payload {
+            IqType::Set(query) => query,
+            _ => panic!("configuration should be a set request"),
+        };
+        assert!(query.is("query", NS_MUC_OWNER));
+        let submitted =
+            DataForm::try_from(query.get_child("x", ns::DATA_FORMS).unwrap().clone()).unwrap();
+        assert_eq!(submitted.type_, DataFormType::Submit);
+        assert_eq!(
+            submitted.form_type.as_deref(),
+            Some("http://jabber.org/protocol/muc#roomconfig")
+        );
+        assert_eq!(submitted.fields[0].values, vec!["My room".to_string()]);
+
+        match muc.pending.get(&iq.id) {
+            Some(PendingRequest {
+                request: MucRequest::Set(description),
+                ..
+            }) => assert_eq!(description, "Configure room@conference.example.org"),
+            _ => panic!("configuration should be pending"),
+        }
+    }
+
+    #[test]
+    fn test_handle_list() {
+        let muc = MucMod::new();
+        let query: Element = r#"<query xmlns='http://jabber.org/protocol/muc#admin'>
+                <item affiliation='member' jid='bob@example.org' nick='bob'/>
+                <item affiliation='member' jid='alice@example.org'/>
+            </query>"#
+            .parse()
+            .unwrap();
+
+        assert_eq!(
+            muc.handle_list(&Affiliation::Member, Some(&query)),
+            "Users with member affiliation:\n  alice@example.org\n  bob@example.org (bob)"
+        );
+        assert_eq!(
+            muc.handle_list(&Affiliation::Outcast, None),
+            "No user with outcast affiliation"
+        );
+    }
+
+    #[test]
+    fn test_direct_invitation() {
+        let account = Account::from_str("me@example.org/aparte").unwrap();
+        let message: Element = r#"<message xmlns='jabber:client' from='alice@example.org/phone'>
+                <x xmlns='jabber:x:conference' jid='room@conference.example.org'
+                   reason='Join us' password='secret'/>
+            </message>"#
+            .parse()
+            .unwrap();
+        let message = XmppParsersMessage::try_from(message).unwrap();
+        let invitation = Invitation::from_xmpp(&account, &message).unwrap();
+
+        assert_eq!(
+            invitation.channel.to_string(),
+            "room@conference.example.org"
+        );
+        assert_eq!(invitation.from.to_string(), "alice@example.org");
+        assert_eq!(invitation.reason.as_deref(), Some("Join us"));
+        assert_eq!(invitation.password.as_deref(), Some("secret"));
+    }
+
+    #[test]
+    fn test_mediated_invitation() {
+        let account = Account::from_str("me@example.org/aparte").unwrap();
+        let message: Element =
+            r#"<message xmlns='jabber:client' from='room@conference.example.org'>
+                <x xmlns='http://jabber.org/protocol/muc#user'>
+                    <invite from='alice@example.org/phone'>
+                        <reason>Join us</reason>
+                    </invite>
+                    <password>secret</password>
+                </x>
+            </message>"#
+                .parse()
+                .unwrap();
+        let message = XmppParsersMessage::try_from(message).unwrap();
+        let invitation = Invitation::from_xmpp(&account, &message).unwrap();
+
+        assert_eq!(
+            invitation.channel.to_string(),
+            "room@conference.example.org"
+        );
+        assert_eq!(invitation.from.to_string(), "alice@example.org");
+        assert_eq!(invitation.reason.as_deref(), Some("Join us"));
+        assert_eq!(invitation.password.as_deref(), Some("secret"));
+        assert!(get_decline(&message).is_none());
+    }
+
+    #[test]
+    fn test_decline() {
+        let account = Account::from_str("me@example.org/aparte").unwrap();
+        let message: Element =
+            r#"<message xmlns='jabber:client' from='room@conference.example.org'>
+                <x xmlns='http://jabber.org/protocol/muc#user'>
+                    <decline from='alice@example.org'/>
+                </x>
+            </message>"#
+                .parse()
+                .unwrap();
+        let message = XmppParsersMessage::try_from(message).unwrap();
+
+        assert!(Invitation::from_xmpp(&account, &message).is_none());
+        assert_eq!(
+            get_decline(&message).and_then(|decline| decline.attr("from")),
+            Some("alice@example.org")
+        );
+    }
+
+    #[test]
+    fn test_take_invitation() {
+        let account = Account::from_str("me@example.org/aparte").unwrap();
+        let invitation = |channel: &str| Invitation {
+            account: account.clone(),
+            channel: BareJid::from_str(channel).unwrap(),
+            from: BareJid::from_str("alice@example.org").unwrap(),
+            reason: None,
+            password: None,
+        };
+        let first = BareJid::from_str("first@conference.example.org").unwrap();
+        let mut muc = MucMod::new();
+        muc.invitations
+            .push(invitation("first@conference.example.org"));
+        muc.invitations
+            .push(invitation("second@conference.example.org"));
+
+        assert_eq!(muc.take_invitation(Some(&first)).unwrap().channel, first);
+        assert!(muc.take_invitation(Some(&first)).is_err());
+        assert_eq!(
+            muc.take_invitation(None).unwrap().channel.to_string(),
+            "second@conference.example.org"
+        );
+        assert!(muc.take_invitation(None).is_err());
+    }
+
+    #[test]
+    fn test_mediated_invite_fallback() {
+        let channel = channel(
+            conversation::Affiliation::Member,
+            conversation::Role::Participant,
+        );
+        let to = BareJid::from_str("bob@example.org").unwrap();
+        let mut muc = MucMod::new();
+        let direct =
+            XmppParsersMessage::try_from(muc.direct_invite(&channel, &to, Some("Hi".to_string())))
+                .unwrap();
+        let x = direct.payloads[0].clone();
+        assert!(x.is("x", NS_CONFERENCE));
+        assert_eq!(x.attr("jid"), Some("room@conference.example.org"));
+
+        let invitation = muc.direct_invitations.get(&direct.id.unwrap()).unwrap();
+        let mediated = XmppParsersMessage::try_from(muc.mediated_invite(invitation)).unwrap();
+        assert_eq!(mediated.to, Some(Jid::Bare(channel.jid.clone())));
+        let invite = mediated.payloads[0]
+            .get_child("invite", ns::MUC_USER)
+            .unwrap();
+        assert_eq!(invite.attr("to"), Some("bob@example.org"));
+        assert_eq!(
+            invite
+                .get_child("reason", ns::MUC_USER)
+                .map(|reason| reason.text()),
+            Some("Hi".to_string())
+        );
+    }
+
+    #[test]
+    fn test_set_subject() {
+        let channel = channel(
+            conversation::Affiliation::Member,
+            conversation::Role::Participant,
+        );
+        let mut muc = MucMod::new();
+        let message =
+            XmppParsersMessage::try_from(muc.set_subject(&channel, "New topic".to_string()))
+                .unwrap();
+
+        assert_eq!(message.type_, MessageType::Groupchat);
+        assert_eq!(message.to, Some(Jid::Bare(channel.jid.clone())));
+        assert_eq!(
+            message.subjects.get(""),
+            Some(&Subject("New topic".to_string()))
+        );
+        let (account, jid, _) = muc.subject_changes.get(&message.id.unwrap()).unwrap();
+        assert_eq!(account, &channel.account);
+        assert_eq!(jid, &channel.jid);
+    }
+
+    #[test]
+    fn test_expire_sent_messages() {
+        let channel = channel(
+            conversation::Affiliation::Member,
+            conversation::Role::Participant,
+        );
+        let to = BareJid::from_str("bob@example.org").unwrap();
+        let mut muc = MucMod::new();
+        muc.direct_invite(&channel, &to, None);
+        muc.set_subject(&channel, "New topic".to_string());
+        let now = Instant::now();
+
+        muc.expire_sent_messages(now);
+        assert_eq!(muc.direct_invitations.len(), 1);
+        assert_eq!(muc.subject_changes.len(), 1);
+
+        muc.expire_sent_messages(now + MESSAGE_ERROR_TIMEOUT);
+        assert!(muc.direct_invitations.is_empty());
+        assert!(muc.subject_changes.is_empty());
+    }
+
+    #[test]
+    fn test_get_subject() {
+        let channel = channel(
+            conversation::Affiliation::Member,
+            conversation::Role::Participant,
+        );
+        let index = (channel.account.clone(), channel.jid.clone());
+        let mut muc = MucMod::new();
+        assert_eq!(muc.get_subject(&channel, vec!["en"]), None);
+
+        let mut subjects = HashMap::new();
+        subjects.insert("en".to_string(), "Topic".to_string());
+        subjects.insert("fr".to_string(), "Sujet".to_string());
+        muc.subjects.insert(index.clone(), subjects);
+        assert_eq!(
+            muc.get_subject(&channel, vec!["fr"]),
+            Some("Sujet".to_string())
+        );
+        assert_eq!(
+            muc.get_subject(&channel, vec!["en"]),
+            Some("Topic".to_string())
+        );
+
+        // An empty subject means the topic has been cleared
+        let mut subjects = HashMap::new();
+        subjects.insert(String::new(), String::new());
+        muc.subjects.insert(index, subjects);
+        assert_eq!(muc.get_subject(&channel, vec!["en"]), None);
+    }
+
+    #[test]
+    fn test_nick_change_error() {
+        let presence: Element = r#"<presence xmlns='jabber:client' type='error'
+                    from='room@conference.example.org/taken'>
+                <error type='cancel'>
+                    <conflict xmlns='urn:ietf:params:xml:ns:xmpp-stanzas'/>
+                </error>
+            </presence>"#
+            .parse()
+            .unwrap();
+        let presence = Presence::try_from(presence).unwrap();
+
+        assert!(get_error_text(&presence.payloads).is_some());
+        assert_eq!(get_error_text(&[]), None);
+    }
+
+    #[test]
+    fn test_expire_self_pings() {
+        let account = Account::from_str("me@example.org/aparte").unwrap();
+        let channel = BareJid::from_str("room@conference.example.org").unwrap();
+        let other = BareJid::from_str("other@conference.example.org").unwrap();
+        let sent = Instant::now();
+        let mut muc = MucMod::new();
+        muc.self_ping_requests
+            .insert("ping".to_string(), (account.clone(), channel.clone(), sent));
+        muc.self_ping_requests
+            .insert("other".to_string(), (account.clone(), other.clone(), sent));
+
+        // Pings are answered within an interval
+        assert!(!muc.expire_self_pings(&account, &channel, sent + SELF_PING_INTERVAL / 2));
+        assert_eq!(muc.self_ping_requests.len(), 2);
+
+        // A ping still unanswered at the next interval means we lost the channel
+        assert!(muc.expire_self_pings(&account, &channel, sent + SELF_PING_INTERVAL));
+        assert!(!muc.self_ping_requests.contains_key("ping"));
+        assert!(muc.self_ping_requests.contains_key("other"));
+        assert!(!muc.expire_self_pings(&account, &channel, sent + SELF_PING_INTERVAL * 2));
+    }
+
+    #[test]
+    fn test_self_ping_status() {
+        let status = |condition| {
+            let error = StanzaError::new(
+                xmpp_parsers::stanza_error::ErrorType::Cancel,
+                condition,
+                "en",
+                "",
+            );
+            self_ping_status(&error)
+        };
+
+        assert_eq!(
+            status(DefinedCondition::ServiceUnavailable),
+            SelfPingStatus::Joined
+        );
+        assert_eq!(
+            status(DefinedCondition::FeatureNotImplemented),
+            SelfPingStatus::Joined
+        );
+        assert_eq!(
+            status(DefinedCondition::RemoteServerTimeout),
+            SelfPingStatus::Unreachable
+        );
+        assert_eq!(
+            status(DefinedCondition::NotAcceptable),
+            SelfPingStatus::Left
+        );
+        assert_eq!(status(DefinedCondition::ItemNotFound), SelfPingStatus::Left);
+    }
+
+    fn room() -> Room {
+        Room {
+            jid: BareJid::from_str("room@conference.example.org").unwrap(),
+            name: Some("Listed name".to_string()),
+            occupants: None,
+            description: None,
+        }
+    }
+
+    #[test]
+    fn test_update_room_from_roominfo() {
+        let info: Element = r#"<query xmlns='http://jabber.org/protocol/disco#info'>
+                <identity category='conference' type='text' name='Room'/>
+                <feature var='http://jabber.org/protocol/disco#info'/>
+                <feature var='http://jabber.org/protocol/muc'/>
+                <x xmlns='jabber:x:data' type='result'>
+                    <field var='FORM_TYPE' type='hidden'>
+                        <value>http://jabber.org/protocol/muc#roominfo</value>
+                    </field>
+                    <field var='muc#roominfo_occupants'><value>42</value></field>
+                    <field var='muc#roominfo_description'><value>About rooms</value></field>
+                </x>
+            </query>"#
+            .parse()
+            .unwrap();
+        let mut room = room();
+        update_room(&mut room, DiscoInfoResult::try_from(info).unwrap());
+
+        assert_eq!(room.name.as_deref(), Some("Room"));
+        assert_eq!(room.occupants, Some(42));
+        assert_eq!(room.description.as_deref(), Some("About rooms"));
+    }
+
+    #[test]
+    fn test_update_room_without_roominfo() {
+        let info: Element = r#"<query xmlns='http://jabber.org/protocol/disco#info'>
+                <identity category='conference' type='text'/>
+                <feature var='http://jabber.org/protocol/disco#info'/>
+                <feature var='http://jabber.org/protocol/muc'/>
+            </query>"#
+            .parse()
+            .unwrap();
+        let mut room = room();
+        update_room(&mut room, DiscoInfoResult::try_from(info).unwrap());
+
+        assert_eq!(room.name.as_deref(), Some("Listed name"));
+        assert_eq!(room.occupants, None);
+        assert_eq!(room.description, None);
+    }
+}
@@ -0,0 +1,283 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::fmt;
+use uuid::Uuid;
+use xmpp_parsers::disco::DiscoInfoResult;
+use xmpp_parsers::iq::{Iq, IqType};
+use xmpp_parsers::nick::Nick;
+use xmpp_parsers::presence::Type as PresenceType;
+use xmpp_parsers::pubsub::{pubsub::Items, NodeName, PubSub};
+use xmpp_parsers::{ns, BareJid, Element, Jid};
+
+use crate::account::Account;
+use crate::core::{Aparte, Event, ModTrait};
+use crate::message::{Direction, Message, XmppMessageType};
+use crate::mods::disco;
+use crate::mods::info::pubsub_payload;
+
+enum NameRequest {
+    /// XEP-0172: User Nickname
+    Nick(BareJid),
+    /// Name of a channel from its disco#info identity
+    Room(BareJid),
+}
+
+/// Resolve the names displayed for contacts and channels, from the roster, bookmarks, published
+/// nicknames and channel identities, in that order
+pub struct NamesMod {
+    /// Roster contacts, whose published nicknames can be trusted
+    contacts: HashSet<BareJid>,
+    roster: HashMap<BareJid, String>,
+    bookmarks: HashMap<BareJid, String>,
+    nicks: HashMap<BareJid, String>,
+    rooms: HashMap<BareJid, String>,
+    /// Contacts whose nickname has been requested
+    requested: HashSet<BareJid>,
+    /// Pending requests, indexed by iq id
+    requests: HashMap<String, NameRequest>,
+}
+
+impl NamesMod {
+    pub fn new() -> Self {
+        Self {
+            contacts: HashSet::new(),
+            roster: HashMap::new(),
+            bookmarks: HashMap::new(),
+            nicks: HashMap::new(),
+            rooms: HashMap::new(),
+            requested: HashSet::new(),
+            requests: HashMap::new(),
+        }
+    }
+
+    /// Name of a contact or channel, if it has one. Anyone can publish a nickname, the ones of
+    /// strangers are shown along with their jid so that they can't pass for someone else.
+    pub fn get(&self, jid: &BareJid) -> Option<String> {
+        if let Some(name) = self.roster.get(jid).or_else(|| self.bookmarks.get(jid)) {
+            return Some(name.clone());
+        }
+        match self.nicks.get(jid) {
+            Some(nick) if self.contacts.contains(jid) => Some(nick.clone()),
+            Some(nick) => Some(format!("{} ({})", nick, jid)),
+            None => self.rooms.get(jid).cloned(),
+        }
+    }
+
+    /// Name of a contact or channel, its jid when it has none
+    pub fn get_name(&self, jid: &BareJid) -> String {
+        self.get(jid).unwrap_or_else(|| jid.to_string())
+    }
+
+    /// Update one of the names of a jid, announcing the change of its displayed name
+    fn update<F>(&mut self, aparte: &mut Aparte, jid: &BareJid, update: F)
+    where
+        F: FnOnce(&mut Self),
+    {
+        let before = self.get_name(jid);
+        update(self);
+        let after = self.get_name(jid);
+        if before != after {
+            aparte.schedule(Event::DisplayName {
+                jid: jid.clone(),
+                name: after,
+            });
+        }
+    }
+
+    /// Request the nickname of a contact without a name, once
+    fn request_nick(&mut self, aparte: &mut Aparte, account: &Account, jid: &BareJid) {
+        if self.get(jid).is_some() || !self.requested.insert(jid.clone()) {
+            return;
+        }
+        let id = Uuid::new_v4().to_hyphenated().to_string();
+        self.requests
+            .insert(id.clone(), NameRequest::Nick(jid.clone()));
+        let items = Items {
+            max_items: Some(1),
+            node: NodeName(String::from(ns::NICK)),
+            subid: None,
+            items: vec![],
+        };
+        let request: Element = Iq::from_get(id, PubSub::Items(items))
+            .with_to(Jid::Bare(jid.clone()))
+            .into();
+        aparte.send(account, request);
+    }
+
+    fn request_room(&mut self, aparte: &mut Aparte, account: &Account, jid: &BareJid) {
+        let id = Uuid::new_v4().to_hyphenated().to_string();
+        self.requests
+            .insert(id.clone(), NameRequest::Room(jid.clone()));
+        aparte.send(
+            account,
+            disco::info_request(id, Jid::Bare(jid.clone()), None),
+        );
+    }
+
+    fn handle_response(&mut self, aparte: &mut Aparte, request: NameRequest, iq: &Iq) {
+        let payload = match &iq.payload {
+            IqType::Result(Some(payload)) => payload.clone(),
+            _ => return,
+        };
+        match request {
+            NameRequest::Nick(jid) => {
+                let nick = pubsub_payload(payload, "nick", ns::NICK)
+                    .and_then(|nick| Nick::try_from(nick).ok())
+                    .map(|nick| nick.0);
+                self.update(aparte, &jid, |names| set(&mut names.nicks, &jid, nick));
+            }
+            NameRequest::Room(jid) => {
+                let name = DiscoInfoResult::try_from(payload)
+                    .ok()
+                    .and_then(|info| {
+                        info.identities
+                            .into_iter()
+                            .find(|identity| identity.category == "conference")
+                    })
+                    .and_then(|identity| identity.name);
+                self.update(aparte, &jid, |names| set(&mut names.rooms, &jid, name));
+            }
+        }
+    }
+}
+
+/// Set or remove the name of a jid, empty names are removed
+fn set(names: &mut HashMap<BareJid, String>, jid: &BareJid, name: Option<String>) {
+    match name {
+        Some(name) if !name.is_empty() => {
+            names.insert(jid.clone(), name);
+        }
+        _ => {
+            names.remove(jid);
+        }
+    }
+}
+
+impl ModTrait for NamesMod {
+    fn init(&mut self, _aparte: &mut Aparte) -> Result<(), ()> {
+        Ok(())
+    }
+
+    fn on_event(&mut self, aparte: &mut Aparte, event: &Event) {
+        match event {
+            Event::Connected(account, _) => {
+                let jid: BareJid = account.clone().into();
+                self.request_nick(aparte, account, &jid);
+            }
+            Event::Contact(_, contact) | Event::ContactUpdate(_, contact) => {
+                let name = contact.name.clone();
+                self.update(aparte, &contact.jid, |names| {
+                    names.contacts.insert(contact.jid.clone());
+                    set(&mut names.roster, &contact.jid, name)
+                });
+            }
+            Event::Bookmark(bookmark) => {
+                let name = bookmark.name.clone();
+                self.update(aparte, &bookmark.jid, |names| {
+                    set(&mut names.bookmarks, &bookmark.jid, name)
+                });
+            }
+            Event::DeletedBookmark(jid) => {
+                self.update(aparte, jid, |names| set(&mut names.bookmarks, jid, None));
+            }
+            Event::Chat { account, contact } => self.request_nick(aparte, account, contact),
+            Event::Message(Some(account), Message::Xmpp(message))
+                if message.type_ == XmppMessageType::Chat
+                    && message.direction == Direction::Incoming =>
+            {
+                self.request_nick(aparte, account, &message.from);
+            }
+            Event::Joined {
+                account, channel, ..
+            } => {
+                let jid: BareJid = channel.clone().into();
+                if self.get(&jid).is_none() {
+                    self.request_room(aparte, account, &jid);
+                }
+            }
+            // Nicknames are also included in subscription requests
+            Event::Presence(_, presence) if presence.type_ == PresenceType::Subscribe => {
+                let nick = presence
+                    .payloads
+                    .iter()
+                    .find(|payload| payload.is("nick", ns::NICK))
+                    .and_then(|nick| Nick::try_from(nick.clone()).ok());
+                if let (Some(from), Some(nick)) = (&presence.from, nick) {
+                    let jid: BareJid = from.clone().into();
+                    self.update(aparte, &jid, |names| {
+                        set(&mut names.nicks, &jid, Some(nick.0))
+                    });
+                }
+            }
+            Event::ContactInfo { info, .. } => {
+                if let (Some(address), Some(nick)) = (&info.address, &info.nick) {
+                    let nick = Some(nick.clone());
+                    self.update(aparte, address, |names| {
+                        set(&mut names.nicks, address, nick)
+                    });
+                }
+            }
+            Event::Iq(_, iq) => {
+                if let Some(request) = self.requests.remove(&iq.id) {
+                    self.handle_response(aparte, request, iq);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl fmt::Display for NamesMod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Contact and channel names")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn jid() -> BareJid {
+        BareJid::from_str("juliet@example.org").unwrap()
+    }
+
+    #[test]
+    fn test_names_resolution_order() {
+        let mut names = NamesMod::new();
+        names.contacts.insert(jid());
+        set(&mut names.rooms, &jid(), Some("Room".to_string()));
+        assert_eq!(names.get_name(&jid()), "Room");
+
+        set(&mut names.nicks, &jid(), Some("Jule".to_string()));
+        assert_eq!(names.get_name(&jid()), "Jule");
+
+        set(&mut names.bookmarks, &jid(), Some("Bookmark".to_string()));
+        assert_eq!(names.get_name(&jid()), "Bookmark");
+
+        set(&mut names.roster, &jid(), Some("Juliet".to_string()));
+        assert_eq!(names.get_name(&jid()), "Juliet");
+
+        set(&mut names.roster, &jid(), Some("".to_string()));
+        assert_eq!(names.get_name(&jid()), "Bookmark");
+    }
+
+    #[test]
+    fn test_stranger_nick_shown_with_jid() {
+        let mut names = NamesMod::new();
+        set(&mut names.nicks, &jid(), Some("Romeo".to_string()));
+
+        assert_eq!(names.get_name(&jid()), "Romeo (juliet@example.org)");
+    }
+
+    #[test]
+    fn test_unnamed_jid() {
+        let names = NamesMod::new();
+
+        assert_eq!(names.get(&jid()), None);
+        assert_eq!(names.get_name(&jid()), "juliet@example.org");
+    }
+}
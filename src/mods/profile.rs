@@ -0,0 +1,431 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+use uuid::Uuid;
+use xmpp_parsers::avatar::{Data, Info, Metadata};
+use xmpp_parsers::hashes::Sha1HexAttribute;
+use xmpp_parsers::iq::{Iq, IqType};
+use xmpp_parsers::nick::Nick;
+use xmpp_parsers::pubsub::{
+    pubsub, pubsub::Items, pubsub::Publish, pubsub::Retract, Item, ItemId, NodeName, PubSub,
+};
+use xmpp_parsers::stanza_error::DefinedCondition;
+use xmpp_parsers::{ns, Element};
+
+use crate::account::Account;
+use crate::avatar;
+use crate::command::{Command, CommandParser};
+use crate::core::{self, Aparte, Event, ModTrait};
+use crate::mods::info::{publish_options, pubsub_payload, NODE_VCARD4, NS_VCARD4};
+
+/// Editable fields, with their vCard4 property and value type
+const FIELDS: &[(&str, &str, &str)] = &[
+    ("nickname", "nickname", "text"),
+    ("fullname", "fn", "text"),
+    ("email", "email", "text"),
+    ("phone", "tel", "uri"),
+    ("url", "url", "uri"),
+    ("birthday", "bday", "date"),
+    ("organization", "org", "text"),
+    ("title", "title", "text"),
+    ("role", "role", "text"),
+    ("timezone", "tz", "text"),
+    ("note", "note", "text"),
+];
+
+/// Item id of single item nodes
+const CURRENT: &str = "current";
+
+command_def!(profile_set,
+r#"/profile set <field> [<value>]
+
+    field       nickname, fullname, email, phone, url, birthday, organization,
+                title, role, timezone or note
+    value       New value of the field, the field is removed when omitted
+
+Description:
+    Update your vCard, the nickname is also published on its own for
+    clients that don't read vCards.
+
+Examples:
+    /profile set fullname "Juliet Capulet"
+    /profile set birthday 1996-07-31
+    /profile set email
+"#,
+{
+    field: String = {
+        completion: (|_aparte, _command| {
+            FIELDS.iter().map(|(field, _, _)| field.to_string()).collect()
+        })
+    },
+    value: Option<String>
+},
+|aparte, _command| {
+    let account = aparte.current_account().ok_or("No connection found".to_string())?;
+    let property = FIELDS
+        .iter()
+        .find(|(name, _, _)| *name == field)
+        .ok_or(format!("Unknown field {}", field))?;
+    let requests = {
+        let mut profile = aparte.get_mod_mut::<ProfileMod>();
+        profile.set(&account, property, value)
+    };
+    for request in requests {
+        aparte.send(&account, request);
+    }
+    Ok(())
+});
+
+command_def!(profile_avatar,
+r#"/profile avatar <path>
+
+    path        PNG, JPEG or GIF image of at most 64KiB
+
+Description:
+    Publish your avatar, PNG images are recommended as all clients can
+    display them.
+
+Examples:
+    /profile avatar ~/avatar.png
+"#,
+{
+    path: String
+},
+|aparte, _command| {
+    let account = aparte.current_account().ok_or("No connection found".to_string())?;
+    let path = match path.strip_prefix("~/") {
+        Some(relative) => dirs::home_dir().ok_or("Unknown home directory".to_string())?.join(relative),
+        None => path.into(),
+    };
+    let data = std::fs::read(&path).map_err(|e| format!("Cannot read {}: {}", path.display(), e))?;
+    let requests = {
+        let mut profile = aparte.get_mod_mut::<ProfileMod>();
+        profile.publish_avatar(&account, data)?
+    };
+    for request in requests {
+        aparte.send(&account, request);
+    }
+    Ok(())
+});
+
+command_def!(profile,
+r#"/profile set|avatar"#,
+{
+    action: Command = {
+        children: {
+            "set": profile_set,
+            "avatar": profile_avatar,
+        }
+    },
+});
+
+enum ProfileRequest {
+    /// Retrieval of our vCard to update the given property
+    VCard {
+        property: &'static str,
+        kind: &'static str,
+        value: Option<String>,
+    },
+    /// Publication of the named part of our profile
+    Publish(&'static str),
+}
+
+pub struct ProfileMod {
+    /// Pending requests, indexed by iq id
+    requests: HashMap<String, (Account, ProfileRequest)>,
+}
+
+impl ProfileMod {
+    pub fn new() -> Self {
+        Self {
+            requests: HashMap::new(),
+        }
+    }
+
+    fn set(
+        &mut self,
+        account: &Account,
+        field: &(&'static str, &'static str, &'static str),
+        value: Option<String>,
+    ) -> Vec<Element> {
+        let (name, property, kind) = *field;
+        let mut requests = Vec::new();
+
+        // The vCard is updated once retrieved to keep its other properties
+        let id = Uuid::new_v4().to_hyphenated().to_string();
+        let items = Items {
+            max_items: Some(1),
+            node: NodeName(String::from(NODE_VCARD4)),
+            subid: None,
+            items: vec![],
+        };
+        requests.push(Iq::from_get(id.clone(), PubSub::Items(items)).into());
+        self.requests.insert(
+            id,
+            (
+                account.clone(),
+                ProfileRequest::VCard {
+                    property,
+                    kind,
+                    value: value.clone(),
+                },
+            ),
+        );
+
+        // XEP-0172: User Nickname
+        if name == "nickname" {
+            let request = match value {
+                Some(nick) => self.publish(ns::NICK, CURRENT, Nick(nick).into(), "presence"),
+                None => self.retract(ns::NICK, CURRENT),
+            };
+            self.requests.insert(
+                request.0,
+                (account.clone(), ProfileRequest::Publish("Nickname")),
+            );
+            requests.push(request.1);
+        }
+
+        requests
+    }
+
+    /// XEP-0084: User Avatar, data is published before the metadata referencing it
+    fn publish_avatar(&mut self, account: &Account, data: Vec<u8>) -> Result<Vec<Element>, String> {
+        let type_ = match data.as_slice() {
+            [0x89, b'P', b'N', b'G', ..] => "image/png",
+            [0xff, 0xd8, 0xff, ..] => "image/jpeg",
+            [b'G', b'I', b'F', b'8', ..] => "image/gif",
+            _ => return Err("Avatar must be a PNG, JPEG or GIF image".to_string()),
+        };
+        let bytes =
+            u16::try_from(data.len()).map_err(|_| "Avatar must be at most 64KiB".to_string())?;
+        let (width, height) = match avatar::decode_png(&data) {
+            Ok(image) => (
+                u16::try_from(image.width).ok(),
+                u16::try_from(image.height).ok(),
+            ),
+            Err(_) => (None, None),
+        };
+        let hash = avatar::sha1_hex(&data);
+        let info = Info {
+            bytes,
+            width,
+            height,
+            id: Sha1HexAttribute::from_str(&hash).unwrap(),
+            type_: type_.to_string(),
+            url: None,
+        };
+
+        let data = self.publish(ns::AVATAR_DATA, &hash, Data { data }.into(), "open");
+        let metadata = self.publish(
+            ns::AVATAR_METADATA,
+            &hash,
+            Metadata { infos: vec![info] }.into(),
+            "open",
+        );
+        self.requests.insert(
+            data.0,
+            (account.clone(), ProfileRequest::Publish("Avatar data")),
+        );
+        self.requests.insert(
+            metadata.0,
+            (account.clone(), ProfileRequest::Publish("Avatar")),
+        );
+        Ok(vec![data.1, metadata.1])
+    }
+
+    /// Publish an item to one of our PEP nodes, returns the id of the request with the request
+    fn publish(
+        &self,
+        node: &str,
+        item: &str,
+        payload: Element,
+        access_model: &str,
+    ) -> (String, Element) {
+        let id = Uuid::new_v4().to_hyphenated().to_string();
+        let item = Item {
+            id: Some(ItemId(String::from(item))),
+            payload: Some(payload),
+            publisher: None,
+        };
+        let publish = Publish {
+            node: NodeName(String::from(node)),
+            items: vec![pubsub::Item(item)],
+        };
+        let pubsub = PubSub::Publish {
+            publish,
+            publish_options: Some(publish_options(access_model)),
+        };
+        (id.clone(), Iq::from_set(id, pubsub).into())
+    }
+
+    fn retract(&self, node: &str, item: &str) -> (String, Element) {
+        let id = Uuid::new_v4().to_hyphenated().to_string();
+        let item = Item {
+            id: Some(ItemId(String::from(item))),
+            payload: None,
+            publisher: None,
+        };
+        let retract = Retract {
+            node: NodeName(String::from(node)),
+            items: vec![pubsub::Item(item)],
+            notify: pubsub::Notify::True,
+        };
+        (
+            id.clone(),
+            Iq::from_set(id, PubSub::Retract(retract)).into(),
+        )
+    }
+
+    /// XEP-0292: vCard4 Over XMPP, our current vCard with the given property replaced
+    fn update_vcard(
+        &self,
+        vcard: Option<Element>,
+        property: &str,
+        kind: &str,
+        value: Option<String>,
+    ) -> Element {
+        let properties = vcard
+            .iter()
+            .flat_map(|vcard| vcard.children())
+            .filter(|child| child.name() != property)
+            .cloned()
+            .collect::<Vec<Element>>();
+        let mut vcard = Element::builder("vcard", NS_VCARD4).append_all(properties);
+        if let Some(value) = value {
+            let value = match property {
+                "tel" if !value.starts_with("tel:") => format!("tel:{}", value),
+                _ => value,
+            };
+            vcard = vcard.append(
+                Element::builder(property, NS_VCARD4)
+                    .append(Element::builder(kind, NS_VCARD4).append(value))
+                    .build(),
+            );
+        }
+        vcard.build()
+    }
+
+    fn handle_response(
+        &mut self,
+        aparte: &mut Aparte,
+        account: Account,
+        request: ProfileRequest,
+        iq: &Iq,
+    ) {
+        match (request, &iq.payload) {
+            (
+                ProfileRequest::VCard {
+                    property,
+                    kind,
+                    value,
+                },
+                IqType::Result(_) | IqType::Error(_),
+            ) => {
+                let vcard = match &iq.payload {
+                    IqType::Result(Some(pubsub)) => {
+                        pubsub_payload(pubsub.clone(), "vcard", NS_VCARD4)
+                    }
+                    // A missing node is an empty vCard
+                    IqType::Error(error)
+                        if error.defined_condition != DefinedCondition::ItemNotFound =>
+                    {
+                        aparte.log(format!(
+                            "Cannot retrieve vCard: {}",
+                            core::stanza_error_text(error)
+                        ));
+                        return;
+                    }
+                    _ => None,
+                };
+                let vcard = self.update_vcard(vcard, property, kind, value);
+                let (id, request) = self.publish(NODE_VCARD4, CURRENT, vcard, "open");
+                self.requests
+                    .insert(id, (account.clone(), ProfileRequest::Publish("vCard")));
+                aparte.send(&account, request);
+            }
+            (ProfileRequest::Publish(what), IqType::Result(_)) => {
+                aparte.log(format!("{} published", what))
+            }
+            (ProfileRequest::Publish(what), IqType::Error(error)) => aparte.log(format!(
+                "Cannot publish {}: {}",
+                what.to_lowercase(),
+                core::stanza_error_text(error)
+            )),
+            _ => {}
+        }
+    }
+}
+
+impl ModTrait for ProfileMod {
+    fn init(&mut self, aparte: &mut Aparte) -> Result<(), ()> {
+        aparte.add_command(profile::new());
+        Ok(())
+    }
+
+    fn on_event(&mut self, aparte: &mut Aparte, event: &Event) {
+        if let Event::Iq(_, iq) = event {
+            if let Some((account, request)) = self.requests.remove(&iq.id) {
+                self.handle_response(aparte, account, request, iq);
+            }
+        }
+    }
+}
+
+impl fmt::Display for ProfileMod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Profile publication")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vcard() -> Element {
+        "<vcard xmlns='urn:ietf:params:xml:ns:vcard-4.0'>
+            <fn><text>Juliet Capulet</text></fn>
+            <tel><uri>tel:+1-555-555-5555</uri></tel>
+        </vcard>"
+            .parse()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_update_vcard_replaces_property() {
+        let profile = ProfileMod::new();
+        let vcard = profile.update_vcard(Some(vcard()), "tel", "uri", Some("+33".to_string()));
+
+        let tels: Vec<String> = vcard
+            .children()
+            .filter(|child| child.name() == "tel")
+            .map(|tel| tel.get_child("uri", NS_VCARD4).unwrap().text())
+            .collect();
+        assert_eq!(tels, vec!["tel:+33".to_string()]);
+        assert!(vcard.get_child("fn", NS_VCARD4).is_some());
+    }
+
+    #[test]
+    fn test_update_vcard_removes_property() {
+        let profile = ProfileMod::new();
+        let vcard = profile.update_vcard(Some(vcard()), "fn", "text", None);
+
+        assert!(vcard.get_child("fn", NS_VCARD4).is_none());
+        assert!(vcard.get_child("tel", NS_VCARD4).is_some());
+    }
+
+    #[test]
+    fn test_update_empty_vcard() {
+        let profile = ProfileMod::new();
+        let vcard = profile.update_vcard(None, "nickname", "text", Some("Jule".to_string()));
+
+        let nickname = vcard.get_child("nickname", NS_VCARD4).unwrap();
+        assert_eq!(
+            nickname.get_child("text", NS_VCARD4).unwrap().text(),
+            "Jule"
+        );
+    }
+}
@@ -0,0 +1,571 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+use futures::sink::SinkExt;
+use futures::stream::StreamExt;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio_native_tls::{native_tls, TlsConnector};
+use tokio_xmpp::xmpp_stream::XMPPStream;
+use tokio_xmpp::Packet;
+use trust_dns_resolver::TokioAsyncResolver;
+use uuid::Uuid;
+use xmpp_parsers::data_forms::{DataForm, DataFormType, Field, FieldType};
+use xmpp_parsers::ibr::Query;
+use xmpp_parsers::iq::{Iq, IqType};
+use xmpp_parsers::{ns, BareJid, Element, Jid};
+
+use crate::account::{Account, ConnectionInfo};
+use crate::command::{Command, CommandParser};
+use crate::core::{self, Aparte, Event, FormAction, ModTrait, Password};
+use crate::message::Message;
+
+/// Default port of client to server connections
+const CLIENT_PORT: u16 = 5222;
+
+command_def!(register,
+r#"/register <account>
+
+    account       Account name or JID of the account to create
+
+Description:
+    Create an account on a server allowing in-band registration. The
+    password of the new account is asked first, then the registration
+    form of the server is displayed to be filled and submitted.
+
+Examples:
+    /register myaccount
+    /register account@server.tld
+"#,
+{
+    account_name: String = {
+        completion: (|aparte, _command| {
+            aparte.config.accounts.keys().cloned().collect()
+        })
+    },
+    password: Password<String>
+},
+|aparte, _command| {
+    let info = match aparte.config.accounts.iter().find(|(name, _)| *name == &account_name) {
+        Some((_, info)) => info.clone(),
+        None => ConnectionInfo {
+            jid: account_name.clone(),
+            server: None,
+            port: None,
+            autoconnect: false,
+            nick: None,
+        },
+    };
+    let jid = match Jid::from_str(&info.jid) {
+        Ok(jid) if jid.clone().node().is_some() => BareJid::from(jid),
+        _ => return Err(format!("Unknown account or invalid jid {}", account_name)),
+    };
+
+    let (id, forms) = {
+        let mut register = aparte.get_mod_mut::<RegisterMod>();
+        register.add_registration()
+    };
+    aparte.log(format!("Registering {}", jid));
+    aparte.spawn(move |events| async move {
+        let registration = Registration {
+            id,
+            jid: jid.clone(),
+            password: password.0,
+            events: events.clone(),
+            forms,
+        };
+        let text = match registration.run(info).await {
+            Ok(()) => format!("Account {} created, use /connect {} to connect", jid, jid),
+            Err(err) => format!("Cannot register {}: {}", jid, err),
+        };
+        if let Err(err) = events.send(Event::Message(None, Message::log(text))).await {
+            error!("Cannot send event to internal channel: {}", err);
+        }
+    });
+    Ok(())
+});
+
+command_def!(account_password,
+r#"/account password
+
+Description:
+    Change the password of the current account, the new password is asked
+    twice.
+
+Examples:
+    /account password
+"#,
+{
+    password: Password<String>,
+    confirmation: Password<String>
+},
+|aparte, _command| {
+    let account = aparte.current_account().ok_or("No connection found".to_string())?;
+    if password.0 != confirmation.0 {
+        return Err("Passwords don't match".to_string());
+    }
+    let request = {
+        let mut register = aparte.get_mod_mut::<RegisterMod>();
+        register.change_password(&account, password.0)
+    };
+    aparte.send(&account, request);
+    Ok(())
+});
+
+command_def!(account_delete,
+r#"/account delete [<jid>]
+
+    jid           JID of the current account, to confirm its deletion
+
+Description:
+    Delete the current account and all its data from the server, this
+    cannot be undone and needs the JID of the account as confirmation.
+
+Examples:
+    /account delete
+    /account delete account@server.tld
+"#,
+{
+    confirmation: Option<String>
+},
+|aparte, _command| {
+    let account = aparte.current_account().ok_or("No connection found".to_string())?;
+    let jid: BareJid = account.clone().into();
+    if confirmation != Some(jid.to_string()) {
+        aparte.log(format!(
+            "This will delete {} and all its data from the server, use /account delete {} to confirm",
+            jid, jid
+        ));
+        return Ok(());
+    }
+    let request = {
+        let mut register = aparte.get_mod_mut::<RegisterMod>();
+        register.delete(&account)
+    };
+    aparte.send(&account, request);
+    Ok(())
+});
+
+command_def!(account,
+r#"/account password|delete"#,
+{
+    action: Command = {
+        children: {
+            "password": account_password,
+            "delete": account_delete,
+        }
+    },
+});
+
+/// In-band registration of a new account, on its own unauthenticated stream
+struct Registration {
+    /// Id of the registration form
+    id: String,
+    jid: BareJid,
+    password: String,
+    events: mpsc::Sender<Event>,
+    /// Registration form once submitted, None when it is canceled
+    forms: mpsc::Receiver<Option<DataForm>>,
+}
+
+impl Registration {
+    async fn run(self, info: ConnectionInfo) -> Result<(), String> {
+        let tcp = match info.server {
+            Some(server) => {
+                let port = info.port.unwrap_or(CLIENT_PORT);
+                TcpStream::connect((server.as_str(), port))
+                    .await
+                    .map_err(|e| e.to_string())?
+            }
+            None => connect_with_srv(&self.jid.domain).await?,
+        };
+        let domain = Jid::Bare(BareJid::domain(&self.jid.domain));
+        let mut stream = XMPPStream::start(tcp, domain.clone(), ns::JABBER_CLIENT.to_string())
+            .await
+            .map_err(|e| e.to_string())?;
+
+        // Never send the password in clear
+        if !stream.stream_features.can_starttls() {
+            return Err("server doesn't support TLS".to_string());
+        }
+        stream
+            .send(Packet::Stanza(
+                Element::builder("starttls", ns::TLS).build(),
+            ))
+            .await
+            .map_err(|e| e.to_string())?;
+        match next_stanza(&mut stream).await? {
+            proceed if proceed.is("proceed", ns::TLS) => {}
+            _ => return Err("TLS negotiation failed".to_string()),
+        }
+
+        let connector = native_tls::TlsConnector::new().map_err(|e| e.to_string())?;
+        let tls = TlsConnector::from(connector)
+            .connect(&self.jid.domain, stream.into_inner())
+            .await
+            .map_err(|e| e.to_string())?;
+        let mut stream = XMPPStream::start(tls, domain, ns::JABBER_CLIENT.to_string())
+            .await
+            .map_err(|e| e.to_string())?;
+
+        let query = request(&mut stream, IqType::Get(empty_query().into())).await?;
+        if query.registered {
+            return Err("account already exists".to_string());
+        }
+        self.register(&mut stream, query).await
+    }
+
+    async fn register<S>(mut self, stream: &mut XMPPStream<S>, query: Query) -> Result<(), String>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let legacy = query.form.is_none();
+        let (form, notes) = self.registration_form(query);
+        let event = Event::Form {
+            id: self.id.clone(),
+            title: format!("Register {}", self.jid),
+            form,
+            actions: vec![FormAction::Submit],
+            notes,
+        };
+        self.events.send(event).await.map_err(|e| e.to_string())?;
+
+        let form = match self.forms.recv().await {
+            Some(Some(form)) => form,
+            _ => return Err("registration canceled".to_string()),
+        };
+
+        let mut query = empty_query();
+        if legacy {
+            for field in form.fields.into_iter() {
+                query.fields.insert(field.var, field.values.join(""));
+            }
+        } else {
+            query.form = Some(form);
+        }
+        request(stream, IqType::Set(query.into())).await?;
+        Ok(())
+    }
+
+    /// Form to fill to register, either given by the server or built from legacy fields
+    fn registration_form(&self, query: Query) -> (DataForm, Vec<String>) {
+        let mut notes = Vec::new();
+        let mut form = match query.form {
+            Some(form) => {
+                if let Some(instructions) = query.fields.get("instructions") {
+                    notes.push(instructions.trim().to_string());
+                }
+                form
+            }
+            None => {
+                let mut fields: Vec<Field> = query
+                    .fields
+                    .iter()
+                    .filter(|(var, _)| var.as_str() != "instructions")
+                    .map(|(var, value)| Field {
+                        var: var.clone(),
+                        type_: match var.as_str() {
+                            "password" => FieldType::TextPrivate,
+                            _ => FieldType::TextSingle,
+                        },
+                        label: None,
+                        required: true,
+                        options: vec![],
+                        values: match value.is_empty() {
+                            true => vec![],
+                            false => vec![value.clone()],
+                        },
+                        media: vec![],
+                    })
+                    .collect();
+                fields.sort_by(|a, b| a.var.cmp(&b.var));
+                DataForm {
+                    type_: DataFormType::Form,
+                    form_type: None,
+                    title: None,
+                    instructions: query
+                        .fields
+                        .get("instructions")
+                        .map(|instructions| instructions.trim().to_string()),
+                    fields,
+                }
+            }
+        };
+
+        for field in form.fields.iter_mut() {
+            match field.var.as_str() {
+                "username" => field.values = vec![self.jid.node.clone().unwrap_or_default()],
+                "password" => field.values = vec![self.password.clone()],
+                _ => {}
+            }
+        }
+
+        (form, notes)
+    }
+}
+
+/// Connect to the server of the domain as advertised by its SRV records, like the client does
+async fn connect_with_srv(domain: &str) -> Result<TcpStream, String> {
+    let resolver = TokioAsyncResolver::tokio_from_system_conf().map_err(|e| e.to_string())?;
+    let srv_domain = format!("_xmpp-client._tcp.{}.", domain);
+    match resolver.srv_lookup(srv_domain).await {
+        Ok(lookup) => {
+            let mut records: Vec<_> = lookup.iter().collect();
+            records.sort_by_key(|srv| (srv.priority(), std::cmp::Reverse(srv.weight())));
+            for srv in records {
+                let target = srv.target().to_ascii();
+                match TcpStream::connect((target.trim_end_matches('.'), srv.port())).await {
+                    Ok(tcp) => return Ok(tcp),
+                    Err(err) => debug!("Cannot connect to {}:{}: {}", target, srv.port(), err),
+                }
+            }
+            Err(format!("cannot connect to any server of {}", domain))
+        }
+        // No SRV records, the domain is the server
+        Err(_) => TcpStream::connect((domain, CLIENT_PORT))
+            .await
+            .map_err(|e| e.to_string()),
+    }
+}
+
+fn empty_query() -> Query {
+    Query {
+        fields: HashMap::new(),
+        registered: false,
+        remove: false,
+        form: None,
+    }
+}
+
+async fn next_stanza<S>(stream: &mut XMPPStream<S>) -> Result<Element, String>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    loop {
+        match stream.next().await {
+            Some(Ok(Packet::Stanza(stanza))) => return Ok(stanza),
+            Some(Ok(Packet::StreamEnd)) | None => return Err("connection closed".to_string()),
+            Some(Ok(_)) => {}
+            Some(Err(err)) => return Err(err.to_string()),
+        }
+    }
+}
+
+/// Send a registration iq and wait for its answer
+async fn request<S>(stream: &mut XMPPStream<S>, payload: IqType) -> Result<Query, String>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let id = Uuid::new_v4().to_hyphenated().to_string();
+    let iq = Iq {
+        from: None,
+        to: None,
+        id: id.clone(),
+        payload,
+    };
+    stream
+        .send(Packet::Stanza(iq.into()))
+        .await
+        .map_err(|e| e.to_string())?;
+
+    loop {
+        let iq = match Iq::try_from(next_stanza(stream).await?) {
+            Ok(iq) if iq.id == id => iq,
+            _ => continue,
+        };
+        return match iq.payload {
+            IqType::Result(Some(query)) => Query::try_from(query).map_err(|e| e.to_string()),
+            IqType::Result(None) => Ok(empty_query()),
+            IqType::Error(error) => Err(core::stanza_error_text(&error)),
+            _ => Err("unexpected answer".to_string()),
+        };
+    }
+}
+
+enum AccountRequest {
+    Password,
+    Delete,
+}
+
+pub struct RegisterMod {
+    /// Registrations waiting for their form, indexed by form id
+    registrations: HashMap<String, mpsc::Sender<Option<DataForm>>>,
+    /// Password changes and account deletions, indexed by iq id
+    requests: HashMap<String, (Account, AccountRequest)>,
+}
+
+impl RegisterMod {
+    pub fn new() -> Self {
+        Self {
+            registrations: HashMap::new(),
+            requests: HashMap::new(),
+        }
+    }
+
+    fn add_registration(&mut self) -> (String, mpsc::Receiver<Option<DataForm>>) {
+        let id = Uuid::new_v4().to_hyphenated().to_string();
+        let (tx, rx) = mpsc::channel(1);
+        self.registrations.insert(id.clone(), tx);
+        (id, rx)
+    }
+
+    fn request(&mut self, account: &Account, query: Query, request: AccountRequest) -> Element {
+        let id = Uuid::new_v4().to_hyphenated().to_string();
+        self.requests.insert(id.clone(), (account.clone(), request));
+        Iq::from_set(id, query)
+            .with_to(Jid::Bare(BareJid::domain(&account.domain)))
+            .into()
+    }
+
+    fn change_password(&mut self, account: &Account, password: String) -> Element {
+        let mut query = empty_query();
+        query.fields.insert(
+            "username".to_string(),
+            account.node.clone().unwrap_or_default(),
+        );
+        query.fields.insert("password".to_string(), password);
+        self.request(account, query, AccountRequest::Password)
+    }
+
+    fn delete(&mut self, account: &Account) -> Element {
+        let mut query = empty_query();
+        query.remove = true;
+        self.request(account, query, AccountRequest::Delete)
+    }
+}
+
+impl ModTrait for RegisterMod {
+    fn init(&mut self, aparte: &mut Aparte) -> Result<(), ()> {
+        aparte.add_command(register::new());
+        aparte.add_command(account::new());
+        Ok(())
+    }
+
+    fn on_event(&mut self, aparte: &mut Aparte, event: &Event) {
+        match event {
+            Event::SubmitForm { id, form, .. } => {
+                if let Some(registration) = self.registrations.remove(id) {
+                    if registration.try_send(Some(form.clone())).is_err() {
+                        aparte.log("Registration is no longer in progress".to_string());
+                    }
+                }
+            }
+            Event::CancelForm(id) => {
+                if let Some(registration) = self.registrations.remove(id) {
+                    let _ = registration.try_send(None);
+                }
+            }
+            Event::Iq(_, iq) if self.requests.contains_key(&iq.id) => {
+                let (account, request) = self.requests.remove(&iq.id).unwrap();
+                let jid: BareJid = account.into();
+                let text = match (request, &iq.payload) {
+                    // The running connection can't be given the new password
+                    (AccountRequest::Password, IqType::Result(_)) => format!(
+                        "Password of {} changed, this connection keeps using the old one to reconnect until you /connect again",
+                        jid
+                    ),
+                    (AccountRequest::Delete, IqType::Result(_)) => format!("{} deleted", jid),
+                    (AccountRequest::Password, IqType::Error(error)) => format!(
+                        "Cannot change password of {}: {}",
+                        jid,
+                        core::stanza_error_text(error)
+                    ),
+                    (AccountRequest::Delete, IqType::Error(error)) => {
+                        format!("Cannot delete {}: {}", jid, core::stanza_error_text(error))
+                    }
+                    _ => return,
+                };
+                aparte.log(text);
+            }
+            _ => {}
+        }
+    }
+}
+
+impl fmt::Display for RegisterMod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "XEP-0077: In-Band Registration")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registration() -> Registration {
+        let (events, _) = mpsc::channel(1);
+        let (_, forms) = mpsc::channel(1);
+        Registration {
+            id: "registration".to_string(),
+            jid: BareJid::from_str("me@example.org").unwrap(),
+            password: "secret".to_string(),
+            events,
+            forms,
+        }
+    }
+
+    #[test]
+    fn test_registration_form_from_legacy_fields() {
+        let query: Element = "<query xmlns='jabber:iq:register'>
+                <instructions>Choose a username and password</instructions>
+                <username/>
+                <password/>
+                <email/>
+            </query>"
+            .parse()
+            .unwrap();
+        let (form, notes) = registration().registration_form(Query::try_from(query).unwrap());
+
+        assert!(notes.is_empty());
+        assert_eq!(
+            form.instructions.as_deref(),
+            Some("Choose a username and password")
+        );
+        let fields: Vec<(&str, &FieldType, Vec<String>)> = form
+            .fields
+            .iter()
+            .map(|field| (field.var.as_str(), &field.type_, field.values.clone()))
+            .collect();
+        assert_eq!(
+            fields,
+            vec![
+                ("email", &FieldType::TextSingle, vec![]),
+                (
+                    "password",
+                    &FieldType::TextPrivate,
+                    vec!["secret".to_string()]
+                ),
+                ("username", &FieldType::TextSingle, vec!["me".to_string()]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_registration_form_from_server_form() {
+        let query: Element = "<query xmlns='jabber:iq:register'>
+                <instructions>Fill the form</instructions>
+                <x xmlns='jabber:x:data' type='form'>
+                    <field var='FORM_TYPE' type='hidden'><value>jabber:iq:register</value></field>
+                    <field var='username' type='text-single'/>
+                    <field var='password' type='text-private'/>
+                    <field var='captcha' type='text-single'/>
+                </x>
+            </query>"
+            .parse()
+            .unwrap();
+        let (form, notes) = registration().registration_form(Query::try_from(query).unwrap());
+
+        assert_eq!(notes, vec!["Fill the form".to_string()]);
+        let username = form.fields.iter().find(|field| field.var == "username");
+        assert_eq!(username.unwrap().values, vec!["me".to_string()]);
+        let password = form.fields.iter().find(|field| field.var == "password");
+        assert_eq!(password.unwrap().values, vec!["secret".to_string()]);
+        let captcha = form.fields.iter().find(|field| field.var == "captcha");
+        assert!(captcha.unwrap().values.is_empty());
+    }
+}
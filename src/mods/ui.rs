@@ -4,10 +4,11 @@
 use backtrace::Backtrace;
 use chrono::offset::{Local, TimeZone};
 use chrono::Local as LocalTz;
+use chrono::{DateTime, Duration as ChronoDuration, FixedOffset};
 use futures::task::{AtomicWaker, Context, Poll};
 use futures::Stream;
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::hash::{Hash, Hasher};
 use std::io::{Error as IoError, ErrorKind as IoErrorKind};
@@ -15,6 +16,7 @@ use std::io::{Read, Stdout, Write};
 use std::panic;
 use std::pin::Pin;
 use std::rc::Rc;
+use std::str::FromStr;
 use std::sync::mpsc;
 use std::sync::{Arc, Mutex};
 use std::thread;
@@ -24,19 +26,29 @@ use termion::get_tty;
 use termion::raw::IntoRawMode;
 use termion::screen::AlternateScreen;
 use uuid::Uuid;
+use xmpp_parsers::data_forms::{DataForm, FieldType};
+use xmpp_parsers::disco::{DiscoInfoResult, Item as DiscoItem};
+use xmpp_parsers::roster::Subscription;
 use xmpp_parsers::{BareJid, Jid};
 
+use crate::account::Account;
+use crate::avatar::{self, Image};
 use crate::color::{id_to_rgb, ColorTuple};
-use crate::command::Command;
+use crate::command::{Command, CommandParser};
 use crate::config::Config;
-use crate::conversation::{Channel, Chat, Conversation};
-use crate::core::{Aparte, Event, ModTrait};
+use crate::conversation::{Channel, Chat, Conversation, PrivateChat};
+use crate::core::{parse_datetime, Aparte, Event, FormAction, ModTrait};
 use crate::cursor::Cursor;
 use crate::i18n;
-use crate::message::{Direction, Message, XmppMessageType};
+use crate::message::{Direction, LogMessage, Message, VersionedXmppMessage, XmppMessageType};
+use crate::mods::avatar::AvatarMod;
+use crate::mods::info::ContactInfo;
+use crate::mods::messages::Search;
+use crate::mods::muc::Room;
+use crate::mods::names::NamesMod;
 use crate::terminus::{
-    self, BufferedWin, Dimension, FrameLayout, Input, Layout, Layouts, LinearLayout, ListView,
-    Orientation, Screen, View, Window as _,
+    self, BufferedWin, Dimension, FormView, FrameLayout, Input, Layout, Layouts, LinearLayout,
+    ListView, Orientation, Screen, View, Window as _,
 };
 use crate::{contact, conversation};
 
@@ -45,20 +57,38 @@ enum UIEvent {
     Validate(Rc<RefCell<Option<(String, bool)>>>),
     GetInput(Rc<RefCell<Option<(String, Cursor, bool)>>>),
     AddWindow(String, Option<Box<dyn View<UIEvent, Stdout>>>),
+    /// Set a field of a form, the selected one by default
+    SetFormField {
+        id: String,
+        var: Option<String>,
+        value: String,
+        result: Rc<RefCell<Option<Result<(), String>>>>,
+    },
+    GetForm(String, Rc<RefCell<Option<DataForm>>>),
+    GetSearchResult(String, usize, Rc<RefCell<Option<Message>>>),
+    /// Show a message in the given conversation window
+    ShowMessage(String, Message),
+    /// Scroll the given conversation window to a date
+    Goto(String, DateTime<FixedOffset>),
 }
 
 struct TitleBar {
     name: Option<String>,
+    /// Names displayed instead of window names
+    names: HashMap<String, String>,
     subjects: HashMap<String, HashMap<String, String>>,
+    langs: Vec<String>,
     dirty: bool,
     pub color: ColorTuple,
 }
 
 impl TitleBar {
-    fn new(color: &ColorTuple) -> Self {
+    fn new(color: &ColorTuple, langs: &[String]) -> Self {
         Self {
             name: None,
+            names: HashMap::new(),
             subjects: HashMap::new(),
+            langs: langs.to_vec(),
             dirty: true,
             color: color.clone(),
         }
@@ -110,7 +140,7 @@ where
 
         if let Some(name) = &self.name {
             let clean_name = terminus::term_string_visible_truncate(
-                name,
+                self.names.get(name).unwrap_or(name),
                 dimension.w.unwrap().into(),
                 Some("…"),
             );
@@ -122,7 +152,8 @@ where
             if remaining > 0 {
                 let subjects = self.subjects.get(name).unwrap();
                 if !subjects.is_empty() {
-                    if let Some((_lang, subject)) = i18n::get_best(subjects, vec![]) {
+                    let langs = self.langs.iter().map(String::as_str).collect();
+                    if let Some((_lang, subject)) = i18n::get_best(subjects, langs) {
                         let clean_subject = terminus::term_string_visible_truncate(
                             subject,
                             remaining.into(),
@@ -156,6 +187,13 @@ where
             UIEvent::Core(Event::ChangeWindow(name)) => {
                 self.set_name(name);
             }
+            UIEvent::Core(Event::DisplayName { jid, name }) => {
+                let window = jid.to_string();
+                if Some(&window) == self.name.as_ref() {
+                    self.dirty = true;
+                }
+                self.names.insert(window, terminus::clean(name));
+            }
             UIEvent::Core(Event::Subject(_, jid, subjects)) => {
                 let window: BareJid = jid.clone().into();
                 self.add_subjects(
@@ -183,6 +221,8 @@ struct WinBar {
     windows: Vec<String>,
     current_window: Option<String>,
     highlighted: HashMap<String, (u64, u64)>,
+    /// Names displayed instead of window names
+    names: HashMap<String, String>,
     dirty: bool,
     pub color: ColorTuple,
 }
@@ -194,6 +234,7 @@ impl WinBar {
             windows: Vec::new(),
             current_window: None,
             highlighted: HashMap::new(),
+            names: HashMap::new(),
             dirty: true,
             color: color.clone(),
         }
@@ -264,6 +305,7 @@ where
         sorted.sort_by(|(_, (_, a)), (_, (_, b))| b.partial_cmp(a).unwrap());
 
         for (window, state) in sorted {
+            let window = self.names.get(window).unwrap_or(window);
             // Keep space for at least ", +X]"
             let remaining_len = if remaining > 1 {
                 format!("{}", remaining).len() + 4
@@ -347,11 +389,18 @@ where
                 self.connection = Some(terminus::clean(&account.to_string()));
                 self.dirty = true;
             }
+            UIEvent::Core(Event::DisplayName { jid, name }) => {
+                let window = jid.to_string();
+                if self.highlighted.contains_key(&window) {
+                    self.dirty = true;
+                }
+                self.names.insert(window, terminus::clean(name));
+            }
             UIEvent::Core(Event::Notification {
                 conversation,
                 important,
             }) => {
-                self.highlight_window(&conversation.get_jid().to_string(), *important);
+                self.highlight_window(&conversation.get_window(), *important);
             }
             _ => {}
         }
@@ -384,16 +433,26 @@ impl fmt::Display for Message {
                 Ok(())
             }
             Message::Xmpp(message) => {
-                let author = terminus::clean(
+                let id = terminus::clean(
                     &match &message.type_ {
                         XmppMessageType::Channel => match &message.from_full {
                             Jid::Full(from) => from.resource.clone(),
                             Jid::Bare(from) => from.to_string(),
                         },
                         XmppMessageType::Chat => message.from.to_string(),
+                        XmppMessageType::PrivateChat => {
+                            match (&message.direction, &message.from_full) {
+                                (Direction::Incoming, Jid::Full(from)) => from.resource.clone(),
+                                _ => message.from.to_string(),
+                            }
+                        }
                     }
                     .to_string(),
                 );
+                let author = match &message.from_name {
+                    Some(name) => terminus::clean(name),
+                    None => id.clone(),
+                };
 
                 let timestamp =
                     Local.from_utc_datetime(&message.get_original_timestamp().naive_local());
@@ -405,7 +464,7 @@ impl fmt::Display for Message {
                 };
                 let padding = " ".repeat(padding_len);
 
-                let (r, g, b) = id_to_rgb(&author);
+                let (r, g, b) = id_to_rgb(&id);
 
                 let mut attributes = "".to_string();
                 if message.has_multiple_version() {
@@ -471,17 +530,19 @@ impl fmt::Display for contact::Group {
 
 #[derive(Clone, Debug, Ord, PartialOrd)]
 pub enum RosterItem {
-    Contact(contact::Contact),
+    /// Contact with its rendered avatar, if any
+    Contact(contact::Contact, Option<String>),
     Bookmark(contact::Bookmark),
-    Window(String),
+    /// Window with its displayed name, if any
+    Window(String, Option<String>),
 }
 
 impl Hash for RosterItem {
     fn hash<H: Hasher>(&self, state: &mut H) {
         match self {
-            Self::Contact(contact) => contact.jid.hash(state),
+            Self::Contact(contact, _) => contact.jid.hash(state),
             Self::Bookmark(bookmark) => bookmark.jid.hash(state),
-            Self::Window(window) => window.hash(state),
+            Self::Window(window, _) => window.hash(state),
         };
     }
 }
@@ -489,9 +550,9 @@ impl Hash for RosterItem {
 impl PartialEq for RosterItem {
     fn eq(&self, other: &Self) -> bool {
         match (self, other) {
-            (Self::Contact(a), Self::Contact(b)) => a.eq(b),
+            (Self::Contact(a, _), Self::Contact(b, _)) => a.eq(b),
             (Self::Bookmark(a), Self::Bookmark(b)) => a.eq(b),
-            (Self::Window(a), Self::Window(b)) => a.eq(b),
+            (Self::Window(a, _), Self::Window(b, _)) => a.eq(b),
             _ => false,
         }
     }
@@ -502,7 +563,11 @@ impl Eq for RosterItem {}
 impl fmt::Display for RosterItem {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match &self {
-            Self::Contact(contact) => {
+            Self::Contact(contact, avatar) => {
+                if let Some(avatar) = avatar {
+                    write!(f, "{} ", avatar)?;
+                }
+
                 match contact.presence {
                     contact::Presence::Available | contact::Presence::Chat => {
                         write!(f, "{}", color::Fg(color::Green))?
@@ -533,8 +598,8 @@ impl fmt::Display for RosterItem {
 
                 write!(f, "{}{}", disp, color::Fg(color::Reset))
             }
-            Self::Window(window) => {
-                let disp = terminus::clean(window);
+            Self::Window(window, name) => {
+                let disp = terminus::clean(name.as_ref().unwrap_or(window));
 
                 write!(f, "{}", disp)
             }
@@ -588,6 +653,537 @@ impl fmt::Display for conversation::Role {
     }
 }
 
+/// A line of a search results or listing window, ordered by its position
+#[derive(Clone)]
+struct Line {
+    position: usize,
+    text: String,
+}
+
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+impl Hash for Line {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.position.hash(state);
+    }
+}
+
+impl PartialEq for Line {
+    fn eq(&self, other: &Self) -> bool {
+        self.position == other.position
+    }
+}
+
+impl Eq for Line {}
+
+impl PartialOrd for Line {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Line {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.position.cmp(&other.position)
+    }
+}
+
+fn format_form_help(actions: &[FormAction], notes: &[String]) -> String {
+    let mut help: Vec<String> = notes.iter().map(|note| terminus::clean(note)).collect();
+    if actions.is_empty() {
+        help.push("Use /close to close this form".to_string());
+    } else {
+        let mut commands: Vec<String> = actions
+            .iter()
+            .map(|action| format!("/form {}", action))
+            .collect();
+        commands.push("/form cancel".to_string());
+        help.push(
+            "Use Ctrl-n and Ctrl-p to select a field and type its value, or /form set <field> <value>"
+                .to_string(),
+        );
+        help.push(format!("Then use {}", commands.join(", ")));
+    }
+    help.join("\n")
+}
+
+command_def!(form_set,
+r#"/form set <field> <value>
+
+    field       Name of the field, as displayed between parenthesis
+    value       New value of the field, separate multiple values with commas
+
+Description:
+    Edit a field of the data form displayed in the current window.
+
+Examples:
+    /form set muc#roomconfig_roomname "My channel"
+    /form set muc#roomconfig_persistentroom true
+"#,
+{
+    field: String = {
+        completion: (|aparte, command| {
+            let mut ui = aparte.get_mod_mut::<UIMod>();
+            ui.get_form_fields(&command.context)
+        })
+    },
+    value: String
+},
+|aparte, command| {
+    let mut ui = aparte.get_mod_mut::<UIMod>();
+    ui.set_form_field(&command.context, Some(&field), &value)
+});
+
+/// Submit the form of the current window with the given action and close it
+fn submit_form_action(
+    aparte: &mut Aparte,
+    command: &Command,
+    action: FormAction,
+) -> Result<(), String> {
+    let (id, form) = {
+        let mut ui = aparte.get_mod_mut::<UIMod>();
+        ui.take_form(&command.context, action)?
+    };
+    aparte.schedule(Event::SubmitForm { id, form, action });
+    aparte.schedule(Event::Close(command.context.clone()));
+    Ok(())
+}
+
+command_def!(
+    form_submit,
+    r#"/form submit
+
+Description:
+    Submit the data form displayed in the current window and close it.
+"#,
+    {},
+    |aparte, command| { submit_form_action(aparte, &command, FormAction::Submit) }
+);
+
+command_def!(
+    form_prev,
+    r#"/form prev
+
+Description:
+    Go back to the previous stage of a multi-stage data form.
+"#,
+    {},
+    |aparte, command| { submit_form_action(aparte, &command, FormAction::Prev) }
+);
+
+command_def!(
+    form_next,
+    r#"/form next
+
+Description:
+    Submit the data form displayed in the current window and go to its next stage.
+"#,
+    {},
+    |aparte, command| { submit_form_action(aparte, &command, FormAction::Next) }
+);
+
+command_def!(
+    form_complete,
+    r#"/form complete
+
+Description:
+    Submit the data form displayed in the current window as its last stage.
+"#,
+    {},
+    |aparte, command| { submit_form_action(aparte, &command, FormAction::Complete) }
+);
+
+command_def!(
+    form_cancel,
+    r#"/form cancel
+
+Description:
+    Cancel the data form displayed in the current window and close it.
+"#,
+    {},
+    |aparte, command| {
+        let is_form = {
+            let ui = aparte.get_mod::<UIMod>();
+            ui.forms.contains_key(&command.context)
+        };
+        match is_form {
+            true => {
+                aparte.schedule(Event::Close(command.context.clone()));
+                Ok(())
+            }
+            false => Err("No form in the current window".to_string()),
+        }
+    }
+);
+
+command_def!(form,
+r#"/form set|submit|prev|next|complete|cancel"#,
+{
+    action: Command = {
+        children: {
+            "set": form_set,
+            "submit": form_submit,
+            "prev": form_prev,
+            "next": form_next,
+            "complete": form_complete,
+            "cancel": form_cancel,
+        }
+    },
+});
+
+fn format_search_header(search: &Search, count: usize) -> String {
+    let mut header = format!(
+        "{}{} results for \"{}\" in {}{}",
+        termion::style::Bold,
+        count,
+        terminus::clean(&search.text),
+        terminus::clean(&search.conversation.get_name()),
+        termion::style::NoBold
+    );
+    if let Some(from) = &search.from {
+        header.push_str(&format!(" from {}", terminus::clean(from)));
+    }
+    header.push_str("\nUse /jump <result> to show a result in its conversation\n");
+    header
+}
+
+fn format_search_result(position: usize, message: &Message) -> String {
+    let date = Local.from_utc_datetime(&message.timestamp().naive_local());
+    format!(
+        "{}[{}]{} {} {}",
+        termion::style::Bold,
+        position,
+        termion::style::NoBold,
+        date.format("%F"),
+        message
+    )
+}
+
+fn format_disco(
+    jid: &Jid,
+    node: &Option<String>,
+    info: &Option<DiscoInfoResult>,
+    items: &[DiscoItem],
+) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut header = format!(
+        "{}{}",
+        termion::style::Bold,
+        terminus::clean(&jid.to_string())
+    );
+    if let Some(node) = node {
+        header.push_str(&format!(" ({})", terminus::clean(node)));
+    }
+    header.push_str(&format!("{}", termion::style::NoBold));
+    lines.push(header);
+
+    match info {
+        Some(info) => {
+            lines.push(format!(
+                "\n{}Identities{}",
+                termion::style::Bold,
+                termion::style::NoBold
+            ));
+            for identity in info.identities.iter() {
+                let mut line = format!("  {}/{}", identity.category, identity.type_);
+                if let Some(name) = &identity.name {
+                    line.push_str(&format!(" {}", terminus::clean(name)));
+                }
+                lines.push(line);
+            }
+            lines.push(format!(
+                "\n{}Features{}",
+                termion::style::Bold,
+                termion::style::NoBold
+            ));
+            for feature in info.features.iter() {
+                lines.push(format!("  {}", terminus::clean(&feature.var)));
+            }
+        }
+        None => lines.push("\nNo information available".to_string()),
+    }
+
+    lines.push(format!(
+        "\n{}Items{}",
+        termion::style::Bold,
+        termion::style::NoBold
+    ));
+    for (index, item) in items.iter().enumerate() {
+        let mut line = format!(
+            "  {}[{}]{} {}",
+            termion::style::Bold,
+            index + 1,
+            termion::style::NoBold,
+            terminus::clean(&item.jid.to_string())
+        );
+        if let Some(node) = &item.node {
+            line.push_str(&format!(" ({})", terminus::clean(node)));
+        }
+        if let Some(name) = &item.name {
+            line.push_str(&format!(" {}", terminus::clean(name)));
+        }
+        lines.push(line);
+    }
+    if !items.is_empty() {
+        lines.push("\nUse /disco <item> to browse an item".to_string());
+    }
+    lines
+}
+
+fn format_adhoc_commands(jid: &Jid, commands: &[DiscoItem]) -> Vec<String> {
+    let mut header = format!(
+        "{}{} commands on {}{}",
+        termion::style::Bold,
+        commands.len(),
+        terminus::clean(&jid.to_string()),
+        termion::style::NoBold
+    );
+    if !commands.is_empty() {
+        header.push_str(&format!(
+            "\nUse /adhoc exec {} <node> to run a command\n",
+            terminus::clean(&jid.to_string())
+        ));
+    }
+
+    let mut lines = vec![header];
+    for command in commands.iter() {
+        let node = command.node.as_deref().unwrap_or("");
+        let line = match &command.name {
+            Some(name) => format!("  {} ({})", terminus::clean(name), terminus::clean(node)),
+            None => format!("  {}", terminus::clean(node)),
+        };
+        lines.push(line);
+    }
+    lines
+}
+
+fn format_rooms(service: &BareJid, filter: &Option<String>, rooms: &[Room]) -> Vec<String> {
+    let mut header = format!(
+        "{}{} rooms on {}",
+        termion::style::Bold,
+        rooms.len(),
+        terminus::clean(&service.to_string())
+    );
+    if let Some(filter) = filter {
+        header.push_str(&format!(" matching \"{}\"", terminus::clean(filter)));
+    }
+    header.push_str(&format!("{}", termion::style::NoBold));
+    if !rooms.is_empty() {
+        header.push_str("\nUse /rooms <room> to join a room\n");
+    }
+
+    let mut lines = vec![header];
+    for (index, room) in rooms.iter().enumerate() {
+        let mut line = format!(
+            "{}[{}]{} {}",
+            termion::style::Bold,
+            index + 1,
+            termion::style::NoBold,
+            terminus::clean(room.name.as_deref().unwrap_or(&room.jid.to_string()))
+        );
+        if room.name.is_some() {
+            line.push_str(&format!(" ({})", room.jid));
+        }
+        if let Some(occupants) = room.occupants {
+            line.push_str(&format!(" - {} occupants", occupants));
+        }
+        if let Some(description) = &room.description {
+            line.push_str(&format!("\n    {}", terminus::clean(description)));
+        }
+        lines.push(line);
+    }
+    lines
+}
+
+fn insert_roster_contact(
+    view: &mut ListView<UIEvent, Stdout, contact::Group, RosterItem>,
+    contact: &contact::Contact,
+    name: Option<&String>,
+    avatar: Option<String>,
+) {
+    let mut contact = contact.clone();
+    if let Some(name) = name.filter(|name| **name != contact.jid.to_string()) {
+        contact.name = Some(name.clone());
+    }
+    let item = RosterItem::Contact(contact.clone(), avatar);
+    if !contact.groups.is_empty() {
+        for group in &contact.groups {
+            view.insert(item.clone(), Some(group.clone()));
+        }
+    } else {
+        let group = contact::Group(String::from("Contacts"));
+        view.insert(item, Some(group));
+    }
+}
+
+/// Replace the content of a read-only window
+fn fill_lines(view: &mut BufferedWin<UIEvent, Stdout, Line>, lines: Vec<String>) {
+    view.clear();
+    for (position, text) in lines.into_iter().enumerate() {
+        view.insert(Line { position, text });
+    }
+}
+
+fn format_elapsed(seconds: u64) -> String {
+    match seconds {
+        0..=59 => format!("{} seconds", seconds),
+        60..=3599 => format!("{} minutes", seconds / 60),
+        3600..=86399 => format!("{} hours", seconds / 3600),
+        _ => format!("{} days", seconds / 86400),
+    }
+}
+
+fn format_contact_info(info: &ContactInfo, avatar: Option<&Image>) -> Vec<String> {
+    let mut header = format!(
+        "{}{}{}",
+        termion::style::Bold,
+        terminus::clean(&info.jid.to_string()),
+        termion::style::NoBold
+    );
+    if let Some(nick) = &info.nick {
+        header.push_str(&format!(" ({})", terminus::clean(nick)));
+    }
+    let mut lines = vec![header];
+    if let Some(avatar) = avatar {
+        let fallback = id_to_rgb(&info.jid.to_string());
+        lines.extend(avatar.render(avatar::truecolor(), fallback));
+    }
+    if let Some(address) = &info.address {
+        if Jid::Bare(address.clone()) != info.jid {
+            lines.push(format!(
+                "  Real JID: {}",
+                terminus::clean(&address.to_string())
+            ));
+        }
+    }
+
+    if let Some(contact) = &info.roster {
+        lines.push(format!(
+            "\n{}Roster{}",
+            termion::style::Bold,
+            termion::style::NoBold
+        ));
+        if let Some(name) = &contact.name {
+            lines.push(format!("  Name: {}", terminus::clean(name)));
+        }
+        let subscription = match contact.subscription {
+            Subscription::None => "none",
+            Subscription::From => "from",
+            Subscription::To => "to",
+            Subscription::Both => "both",
+            Subscription::Remove => "remove",
+        };
+        lines.push(format!("  Subscription: {}", subscription));
+        if !contact.groups.is_empty() {
+            let groups: Vec<String> = contact
+                .groups
+                .iter()
+                .map(|group| terminus::clean(&group.0))
+                .collect();
+            lines.push(format!("  Groups: {}", groups.join(", ")));
+        }
+    }
+
+    if let Some(vcard) = &info.vcard {
+        lines.push(format!(
+            "\n{}vCard{}",
+            termion::style::Bold,
+            termion::style::NoBold
+        ));
+        for (label, value) in vcard.properties.iter() {
+            lines.push(format!("  {}: {}", label, terminus::clean(value)));
+        }
+    }
+
+    if info.version.is_some() || info.last_activity.is_some() || info.time.is_some() {
+        lines.push(format!(
+            "\n{}Client{}",
+            termion::style::Bold,
+            termion::style::NoBold
+        ));
+        if let Some(client) = &info.client {
+            lines.push(format!("  Resource: {}", terminus::clean(&client.resource)));
+        }
+    }
+    if let Some(version) = &info.version {
+        let mut line = format!(
+            "  Software: {} {}",
+            terminus::clean(&version.name),
+            terminus::clean(&version.version)
+        );
+        if let Some(os) = &version.os {
+            line.push_str(&format!(" ({})", terminus::clean(os)));
+        }
+        lines.push(line);
+    }
+    if let Some(last) = &info.last_activity {
+        let mut line = match (&info.client, last.seconds) {
+            (Some(_), seconds) => format!("  Idle: {}", format_elapsed(seconds)),
+            (None, 0) => "  Last seen: online".to_string(),
+            (None, seconds) => format!("  Last seen: {} ago", format_elapsed(seconds)),
+        };
+        if let Some(status) = &last.status {
+            line.push_str(&format!(" ({})", terminus::clean(status)));
+        }
+        lines.push(line);
+    }
+    if let Some(time) = &info.time {
+        lines.push(format!("  Local time: {}", time.format("%F %R %:z")));
+    }
+
+    if info.pending > 0 {
+        lines.push(format!("\nWaiting for {} answers", info.pending));
+    }
+    lines
+}
+
+command_def!(goto,
+r#"/goto <date>
+
+    date        Day (YYYY-MM-DD), time of today (HH:MM) or both
+
+Description:
+    Scroll the current conversation to the first message at or after the
+    given date, loading its history from the archive if needed.
+
+Examples:
+    /goto 2021-01-01
+    /goto 14:30
+    /goto "2021-01-01 14:30"
+"#,
+{
+    date: String
+},
+|aparte, command| {
+    let date = parse_datetime(&date)?;
+    let mut ui = aparte.get_mod_mut::<UIMod>();
+    ui.goto(&command.context, date)
+});
+
+command_def!(jump,
+r#"/jump <result>
+
+    result      Number of the search result
+
+Description:
+    Show a result of the search displayed in the current window in its
+    conversation window.
+
+Examples:
+    /jump 3
+"#,
+{
+    result: usize
+},
+|aparte, command| {
+    let mut ui = aparte.get_mod_mut::<UIMod>();
+    ui.jump(&command.context, result)
+});
+
 pub struct Scheduler {
     queue: Rc<RefCell<Vec<Event>>>,
 }
@@ -599,6 +1195,170 @@ impl Scheduler {
     }
 }
 
+/// History loading state of a conversation window, older pages are loaded when scrolling back
+#[derive(Default)]
+struct ScrollBack {
+    loading: Option<Message>,
+    complete: bool,
+    /// Date to scroll to once history is loaded
+    goto: Option<DateTime<FixedOffset>>,
+    /// Oldest message loaded before jumping to an older date, history is loaded forward from
+    /// that date until reaching it
+    gap_end: Option<DateTime<FixedOffset>>,
+    /// Newest archived message loaded while filling the gap
+    gap_start: Option<DateTime<FixedOffset>>,
+    /// Date the last page was loaded from
+    gap_from: Option<DateTime<FixedOffset>>,
+}
+
+impl ScrollBack {
+    fn mark_loading(
+        &mut self,
+        view: &mut BufferedWin<UIEvent, Stdout, Message>,
+        timestamp: DateTime<FixedOffset>,
+    ) {
+        if self.loading.is_none() {
+            let loading = Message::Log(LogMessage {
+                id: Uuid::new_v4().to_string(),
+                timestamp,
+                body: "Loading history…".to_string(),
+            });
+            view.insert(loading.clone());
+            self.loading = Some(loading);
+        }
+    }
+
+    fn scroll_to_date(
+        view: &mut BufferedWin<UIEvent, Stdout, Message>,
+        date: &DateTime<FixedOffset>,
+    ) {
+        let first = view
+            .history
+            .iter()
+            .find(|message| match message {
+                Message::Xmpp(message) => message.get_original_timestamp() >= date,
+                Message::Log(_) => false,
+            })
+            .cloned();
+        match first {
+            Some(message) => view.scroll_to(&message),
+            None => {
+                view.view = 0;
+                view.dirty = true;
+            }
+        }
+    }
+
+    /// Scroll to the first message at or after the given date. Return the date to load history
+    /// from if it is older than the loaded messages.
+    fn goto(
+        &mut self,
+        view: &mut BufferedWin<UIEvent, Stdout, Message>,
+        date: DateTime<FixedOffset>,
+    ) -> Option<DateTime<FixedOffset>> {
+        let oldest = view.history.iter().find_map(|message| match message {
+            Message::Xmpp(message) => Some(*message.get_original_timestamp()),
+            Message::Log(_) => None,
+        });
+        match oldest {
+            Some(oldest) if oldest <= date || self.complete => {
+                Self::scroll_to_date(view, &date);
+                None
+            }
+            _ => {
+                self.mark_loading(view, date - ChronoDuration::milliseconds(1));
+                self.goto = Some(date);
+                self.gap_end = oldest;
+                self.gap_start = None;
+                self.gap_from = Some(date);
+                Some(date)
+            }
+        }
+    }
+
+    /// Track the archived messages loaded after a jump, the gap is filled once they reach the
+    /// messages loaded before
+    fn received(&mut self, message: &VersionedXmppMessage) {
+        if let (Some(end), true) = (self.gap_end, message.archive) {
+            let timestamp = *message.get_original_timestamp();
+            if timestamp >= end {
+                self.gap_end = None;
+            } else if self.gap_start < Some(timestamp) {
+                self.gap_start = Some(timestamp);
+            }
+        }
+    }
+
+    /// Mark the window as loading and return the oldest archive id and date to load history from.
+    /// Return None if history is already loading or fully loaded.
+    fn start(
+        &mut self,
+        view: &mut BufferedWin<UIEvent, Stdout, Message>,
+    ) -> Option<(Option<String>, Option<DateTime<FixedOffset>>)> {
+        if self.loading.is_some() || self.complete {
+            return None;
+        }
+
+        let oldest = view.history.iter().find_map(|message| match message {
+            Message::Xmpp(message) => Some(message),
+            Message::Log(_) => None,
+        });
+        let before = oldest.and_then(|message| message.stanza_id.clone());
+        let from = oldest.map(|message| *message.get_original_timestamp());
+
+        let timestamp = match from {
+            Some(from) => from - ChronoDuration::milliseconds(1),
+            None => LocalTz::now().into(),
+        };
+        self.mark_loading(view, timestamp);
+
+        Some((before, from))
+    }
+
+    /// Return the date to continue loading history from when a page loaded after a jump
+    /// doesn't reach the messages loaded before
+    fn done(
+        &mut self,
+        view: &mut BufferedWin<UIEvent, Stdout, Message>,
+        complete: bool,
+    ) -> Option<DateTime<FixedOffset>> {
+        if let Some(loading) = self.loading.take() {
+            view.remove(&loading);
+        }
+        self.complete = complete;
+        if self.gap_end.is_some() {
+            // Stop when a page brings nothing new
+            match self.gap_start {
+                Some(start) if Some(start) > self.gap_from => {
+                    self.gap_from = Some(start);
+                    self.mark_loading(view, start);
+                    return Some(start);
+                }
+                _ => self.gap_end = None,
+            }
+        }
+        if let Some(date) = self.goto.take() {
+            Self::scroll_to_date(view, &date);
+        }
+        None
+    }
+}
+
+/// Separator displayed between messages of different days
+fn day_separator(previous: &Message, next: &Message) -> Option<String> {
+    let previous = previous.timestamp().with_timezone(&Local).date_naive();
+    let next = next.timestamp().with_timezone(&Local).date_naive();
+    match previous == next {
+        true => None,
+        false => Some(format!(
+            "{}─── {} ───{}",
+            color::Fg(color::LightBlack),
+            next.format("%A %-d %B %Y"),
+            color::Fg(color::Reset)
+        )),
+    }
+}
+
 struct PanicHandler {
     panic: Arc<Mutex<Option<String>>>,
     backtrace: Arc<Mutex<Option<Backtrace>>>,
@@ -660,6 +1420,16 @@ pub struct UIMod {
     current_window: Option<String>,
     unread_windows: HashMap<String, u64>,
     conversations: HashMap<String, Conversation>,
+    /// Id of the data form displayed in each form window, and the actions it can be submitted with
+    forms: HashMap<String, (String, Vec<FormAction>)>,
+    /// Search displayed in each search results window
+    searches: HashMap<String, Search>,
+    /// Id of the last contact information shown in each info window, answers to
+    /// this query don't reopen the window once it is closed
+    contact_infos: HashMap<String, String>,
+    /// Id of the room listing shown in the rooms window, its updates don't reopen the window
+    /// once it is closed
+    rooms_listing: Option<String>,
     root: LinearLayout<UIEvent, Stdout>,
     dimension: Option<Dimension>,
     password_command: Option<Command>,
@@ -683,7 +1453,7 @@ impl UIMod {
             },
         );
 
-        let title_bar = TitleBar::new(&config.theme.title_bar);
+        let title_bar = TitleBar::new(&config.theme.title_bar, &config.langs);
         let frame =
             FrameLayout::<UIEvent, Stdout, String>::new().with_event(|frame, event| match event {
                 UIEvent::Core(Event::ChangeWindow(name)) => {
@@ -709,7 +1479,9 @@ impl UIMod {
                     }
                 }
                 UIEvent::Core(Event::Key(Key::PageUp))
-                | UIEvent::Core(Event::Key(Key::PageDown)) => {
+                | UIEvent::Core(Event::Key(Key::PageDown))
+                | UIEvent::Core(Event::Key(Key::Ctrl('n')))
+                | UIEvent::Core(Event::Key(Key::Ctrl('p'))) => {
                     if let Some(current) = frame.get_current_mut() {
                         current.event(event);
                     }
@@ -769,6 +1541,10 @@ impl UIMod {
             unread_windows: HashMap::new(),
             current_window: None,
             conversations: HashMap::new(),
+            forms: HashMap::new(),
+            searches: HashMap::new(),
+            contact_infos: HashMap::new(),
+            rooms_listing: None,
             password_command: None,
             outgoing_event_queue: Rc::new(RefCell::new(Vec::new())),
             panic_handler,
@@ -790,32 +1566,87 @@ impl UIMod {
         match &conversation {
             Conversation::Chat(chat) => {
                 let chat_for_event = chat.clone();
-                let chatwin = BufferedWin::<UIEvent, Stdout, Message>::new().with_event(
-                    move |view, event| {
+                let mut scroll_back = ScrollBack::default();
+                let chatwin = BufferedWin::<UIEvent, Stdout, Message>::new()
+                    .with_separator(day_separator)
+                    .with_merge(Message::keep_longest_history)
+                    .with_event(move |view, event| {
                         match event {
-                            UIEvent::Core(Event::Message(_, Message::Xmpp(message))) => {
+                            UIEvent::ShowMessage(window, message)
+                                if window == &chat_for_event.contact.to_string() =>
+                            {
+                                view.insert(message.clone());
+                                view.scroll_to(message);
+                            }
+                            UIEvent::Goto(window, date)
+                                if window == &chat_for_event.contact.to_string() =>
+                            {
+                                if let Some(start) = scroll_back.goto(view, *date) {
+                                    scheduler.schedule(Event::LoadChatHistory {
+                                        account: chat_for_event.account.clone(),
+                                        contact: chat_for_event.contact.clone(),
+                                        from: None,
+                                        before: None,
+                                        start: Some(start),
+                                    });
+                                }
+                            }
+                            UIEvent::Core(Event::Message(_, Message::Xmpp(message)))
+                                if message.type_ != XmppMessageType::PrivateChat =>
+                            {
                                 match message.direction {
                                     // TODO check to == us
                                     Direction::Incoming => {
                                         if message.from == chat_for_event.contact {
+                                            scroll_back.received(message);
                                             view.insert(Message::Xmpp(message.clone()));
                                         }
                                     }
                                     Direction::Outgoing => {
                                         // TODO check from == us
                                         if message.to == chat_for_event.contact {
+                                            scroll_back.received(message);
                                             view.insert(Message::Xmpp(message.clone()));
                                         }
                                     }
                                 }
                             }
+                            UIEvent::Core(Event::ConversationLog {
+                                conversation,
+                                message,
+                                ..
+                            }) => {
+                                if conversation == &chat_for_event.contact {
+                                    view.insert(message.clone());
+                                }
+                            }
                             UIEvent::Core(Event::Key(Key::PageUp)) => {
                                 if view.page_up() {
-                                    let from = view.first().map(|message| message.timestamp());
+                                    if let Some((before, from)) = scroll_back.start(view) {
+                                        scheduler.schedule(Event::LoadChatHistory {
+                                            account: chat_for_event.account.clone(),
+                                            contact: chat_for_event.contact.clone(),
+                                            from,
+                                            before,
+                                            start: None,
+                                        });
+                                    }
+                                }
+                            }
+                            UIEvent::Core(Event::HistoryLoaded {
+                                account,
+                                conversation,
+                                complete,
+                            }) if account == &chat_for_event.account
+                                && conversation == &chat_for_event.contact =>
+                            {
+                                if let Some(start) = scroll_back.done(view, *complete) {
                                     scheduler.schedule(Event::LoadChatHistory {
                                         account: chat_for_event.account.clone(),
                                         contact: chat_for_event.contact.clone(),
-                                        from: from.cloned(),
+                                        from: None,
+                                        before: None,
+                                        start: Some(start),
                                     });
                                 }
                             }
@@ -824,13 +1655,52 @@ impl UIMod {
                             }
                             _ => {}
                         }
-                    },
-                );
+                    });
 
                 self.add_window(chat.contact.to_string(), Box::new(chatwin));
                 self.conversations
                     .insert(chat.contact.to_string(), conversation.clone());
             }
+            Conversation::PrivateChat(chat) => {
+                let occupant = Jid::Full(chat.occupant.clone());
+                let chatwin = BufferedWin::<UIEvent, Stdout, Message>::new()
+                    .with_separator(day_separator)
+                    .with_merge(Message::keep_longest_history)
+                    .with_event(move |view, event| match event {
+                        UIEvent::ShowMessage(window, message)
+                            if window == &occupant.to_string() =>
+                        {
+                            view.insert(message.clone());
+                            view.scroll_to(message);
+                        }
+                        UIEvent::Goto(window, date) if window == &occupant.to_string() => {
+                            // Private messages are not archived
+                            ScrollBack::scroll_to_date(view, date);
+                        }
+                        UIEvent::Core(Event::Message(_, Message::Xmpp(message)))
+                            if message.type_ == XmppMessageType::PrivateChat =>
+                        {
+                            let remote = match message.direction {
+                                Direction::Incoming => &message.from_full,
+                                Direction::Outgoing => &message.to_full,
+                            };
+                            if remote == &occupant {
+                                view.insert(Message::Xmpp(message.clone()));
+                            }
+                        }
+                        UIEvent::Core(Event::Key(Key::PageUp)) => {
+                            view.page_up();
+                        }
+                        UIEvent::Core(Event::Key(Key::PageDown)) => {
+                            view.page_down();
+                        }
+                        _ => {}
+                    });
+
+                self.add_window(chat.occupant.to_string(), Box::new(chatwin));
+                self.conversations
+                    .insert(chat.occupant.to_string(), conversation.clone());
+            }
             Conversation::Channel(channel) => {
                 let mut layout = LinearLayout::<UIEvent, Stdout>::new(Orientation::Horizontal)
                     .with_event(|layout, event| {
@@ -840,32 +1710,87 @@ impl UIMod {
                     });
 
                 let channel_for_event = channel.clone();
-                let chanwin = BufferedWin::<UIEvent, Stdout, Message>::new().with_event(
-                    move |view, event| {
+                let mut scroll_back = ScrollBack::default();
+                let chanwin = BufferedWin::<UIEvent, Stdout, Message>::new()
+                    .with_separator(day_separator)
+                    .with_merge(Message::keep_longest_history)
+                    .with_event(move |view, event| {
                         match event {
-                            UIEvent::Core(Event::Message(_, Message::Xmpp(message))) => {
+                            UIEvent::ShowMessage(window, message)
+                                if window == &channel_for_event.jid.to_string() =>
+                            {
+                                view.insert(message.clone());
+                                view.scroll_to(message);
+                            }
+                            UIEvent::Goto(window, date)
+                                if window == &channel_for_event.jid.to_string() =>
+                            {
+                                if let Some(start) = scroll_back.goto(view, *date) {
+                                    scheduler.schedule(Event::LoadChannelHistory {
+                                        account: channel_for_event.account.clone(),
+                                        jid: channel_for_event.jid.clone(),
+                                        from: None,
+                                        before: None,
+                                        start: Some(start),
+                                    });
+                                }
+                            }
+                            UIEvent::Core(Event::Message(_, Message::Xmpp(message)))
+                                if message.type_ != XmppMessageType::PrivateChat =>
+                            {
                                 match message.direction {
                                     // TODO check to == us
                                     Direction::Incoming => {
                                         if message.from == channel_for_event.jid {
+                                            scroll_back.received(message);
                                             view.insert(Message::Xmpp(message.clone()));
                                         }
                                     }
                                     Direction::Outgoing => {
                                         // TODO check from == us
                                         if message.to == channel_for_event.jid {
+                                            scroll_back.received(message);
                                             view.insert(Message::Xmpp(message.clone()));
                                         }
                                     }
                                 }
                             }
+                            UIEvent::Core(Event::ConversationLog {
+                                conversation,
+                                message,
+                                ..
+                            }) => {
+                                if conversation == &channel_for_event.jid {
+                                    view.insert(message.clone());
+                                }
+                            }
                             UIEvent::Core(Event::Key(Key::PageUp)) => {
                                 if view.page_up() {
-                                    let from = view.first().map(|message| message.timestamp());
+                                    if let Some((before, from)) = scroll_back.start(view) {
+                                        scheduler.schedule(Event::LoadChannelHistory {
+                                            account: channel_for_event.account.clone(),
+                                            jid: channel_for_event.jid.clone(),
+                                            from,
+                                            before,
+                                            start: None,
+                                        });
+                                    }
+                                }
+                            }
+                            UIEvent::Core(Event::HistoryLoaded {
+                                account,
+                                conversation,
+                                complete,
+                            }) if account == &channel_for_event.account
+                                && conversation == &channel_for_event.jid =>
+                            {
+                                if let Some(start) = scroll_back.done(view, *complete) {
                                     scheduler.schedule(Event::LoadChannelHistory {
                                         account: channel_for_event.account.clone(),
                                         jid: channel_for_event.jid.clone(),
-                                        from: from.cloned(),
+                                        from: None,
+                                        before: None,
+                                        start: Some(start),
                                     });
                                 }
                             }
@@ -874,8 +1799,7 @@ impl UIMod {
                             }
                             _ => {}
                         }
-                    },
-                );
+                    });
                 layout.push(chanwin);
 
                 let roster_jid = channel.jid.clone();
@@ -898,17 +1822,309 @@ impl UIMod {
                                     view.insert(occupant.clone(), Some(occupant.role));
                                 }
                             }
+                            UIEvent::Core(Event::NickChanged {
+                                conversation,
+                                occupant,
+                                ..
+                            }) if roster_jid == *conversation => {
+                                let _ = view.remove(occupant.clone(), Some(occupant.role));
+                            }
                             _ => {}
                         });
                 layout.push(roster);
 
-                self.add_window(channel.get_name(), Box::new(layout));
+                self.add_window(channel.jid.to_string(), Box::new(layout));
                 self.conversations
-                    .insert(channel.get_name(), conversation.clone());
+                    .insert(channel.jid.to_string(), conversation.clone());
             }
         }
     }
 
+    fn add_form(
+        &mut self,
+        id: String,
+        title: String,
+        form: DataForm,
+        actions: Vec<FormAction>,
+        notes: &[String],
+    ) {
+        let form_id = id.clone();
+        let formwin = FormView::new(&title, form)
+            .with_header(format_form_help(&actions, notes))
+            .with_event(move |view, event| match event {
+                UIEvent::SetFormField {
+                    id,
+                    var,
+                    value,
+                    result,
+                } if id == &form_id => {
+                    let set = match var {
+                        Some(var) => view.set(var, value),
+                        None if value.is_empty() => view.toggle_selected(),
+                        None => view.set_selected(value),
+                    };
+                    result.borrow_mut().replace(set);
+                }
+                UIEvent::GetForm(id, result) if id == &form_id => {
+                    result.borrow_mut().replace(view.form().clone());
+                }
+                UIEvent::Core(Event::Key(Key::Ctrl('n'))) => view.next(),
+                UIEvent::Core(Event::Key(Key::Ctrl('p'))) => view.previous(),
+                UIEvent::Core(Event::Key(Key::PageUp)) => view.page_up(),
+                UIEvent::Core(Event::Key(Key::PageDown)) => view.page_down(),
+                _ => {}
+            });
+
+        self.add_window(title.clone(), Box::new(formwin));
+        self.forms.insert(title, (id, actions));
+    }
+
+    fn add_search(&mut self, title: String, search: Search) {
+        let search_id = search.id.clone();
+        let search_for_event = search.clone();
+        let mut results: Vec<Message> = Vec::new();
+        let mut searchwin = BufferedWin::<UIEvent, Stdout, Line>::new().with_event(
+            move |view, event| match event {
+                UIEvent::Core(Event::SearchResults {
+                    search, messages, ..
+                }) if search.id == search_id => {
+                    for message in messages.iter() {
+                        if !results.contains(message) {
+                            results.push(message.clone());
+                        }
+                    }
+                    results.sort_by(|a, b| a.timestamp().cmp(b.timestamp()));
+
+                    view.clear();
+                    view.insert(Line {
+                        position: 0,
+                        text: format_search_header(&search_for_event, results.len()),
+                    });
+                    for (index, message) in results.iter().enumerate() {
+                        view.insert(Line {
+                            position: index + 1,
+                            text: format_search_result(index + 1, message),
+                        });
+                    }
+                }
+                UIEvent::GetSearchResult(id, position, result) if id == &search_id => {
+                    if let Some(message) = position.checked_sub(1).and_then(|i| results.get(i)) {
+                        result.borrow_mut().replace(message.clone());
+                    }
+                }
+                UIEvent::Core(Event::Key(Key::PageUp)) => {
+                    view.page_up();
+                }
+                UIEvent::Core(Event::Key(Key::PageDown)) => {
+                    view.page_down();
+                }
+                _ => {}
+            },
+        );
+
+        searchwin.insert(Line {
+            position: 0,
+            text: format_search_header(&search, 0),
+        });
+
+        self.add_window(title.clone(), Box::new(searchwin));
+        self.searches.insert(title, search);
+    }
+
+    fn add_contact_info(&mut self, title: String, info: &ContactInfo, avatar: Option<Image>) {
+        let lines = format_contact_info(info, avatar.as_ref());
+        let mut shown = info.clone();
+        let mut avatar = avatar;
+        let mut infowin = BufferedWin::<UIEvent, Stdout, Line>::new().with_event(
+            move |view, event| match event {
+                UIEvent::Core(Event::ContactInfo { info, .. }) if info.id == shown.id => {
+                    shown = info.clone();
+                    fill_lines(view, format_contact_info(&shown, avatar.as_ref()));
+                }
+                UIEvent::Core(Event::Avatar {
+                    jid, avatar: image, ..
+                }) if shown.address.as_ref() == Some(jid) => {
+                    avatar = Some(image.clone());
+                    fill_lines(view, format_contact_info(&shown, avatar.as_ref()));
+                }
+                UIEvent::Core(Event::Key(Key::PageUp)) => {
+                    view.page_up();
+                }
+                UIEvent::Core(Event::Key(Key::PageDown)) => {
+                    view.page_down();
+                }
+                _ => {}
+            },
+        );
+
+        fill_lines(&mut infowin, lines);
+
+        self.add_window(title.clone(), Box::new(infowin));
+        self.contact_infos.insert(title, info.id.clone());
+    }
+
+    /// Add the window listing rooms, refreshed as their details are received
+    fn add_rooms(&mut self, title: String, id: String, lines: Vec<String>) {
+        let mut roomswin = BufferedWin::<UIEvent, Stdout, Line>::new().with_event(
+            move |view, event| match event {
+                UIEvent::Core(Event::Rooms {
+                    id: listing,
+                    service,
+                    filter,
+                    rooms,
+                    ..
+                }) if listing == &id => {
+                    fill_lines(view, format_rooms(service, filter, rooms));
+                }
+                UIEvent::Core(Event::Key(Key::PageUp)) => {
+                    view.page_up();
+                }
+                UIEvent::Core(Event::Key(Key::PageDown)) => {
+                    view.page_down();
+                }
+                _ => {}
+            },
+        );
+
+        fill_lines(&mut roomswin, lines);
+
+        self.add_window(title, Box::new(roomswin));
+    }
+
+    /// Add a read-only window showing the given lines
+    fn add_listing(&mut self, title: String, lines: Vec<String>) {
+        let mut listwin =
+            BufferedWin::<UIEvent, Stdout, Line>::new().with_event(|view, event| match event {
+                UIEvent::Core(Event::Key(Key::PageUp)) => {
+                    view.page_up();
+                }
+                UIEvent::Core(Event::Key(Key::PageDown)) => {
+                    view.page_down();
+                }
+                _ => {}
+            });
+
+        fill_lines(&mut listwin, lines);
+
+        self.add_window(title, Box::new(listwin));
+    }
+
+    pub fn goto(&mut self, window: &str, date: DateTime<FixedOffset>) -> Result<(), String> {
+        if !self.conversations.contains_key(window) {
+            return Err("Not a conversation window".to_string());
+        }
+        self.root
+            .event(&mut UIEvent::Goto(window.to_string(), date));
+        Ok(())
+    }
+
+    /// Show the given result of the search displayed in a window in its conversation
+    pub fn jump(&mut self, window: &str, position: usize) -> Result<(), String> {
+        let search = self
+            .searches
+            .get(window)
+            .ok_or("No search results in the current window".to_string())?;
+        let conversation = search.conversation.get_window();
+        if !self.windows.contains(&conversation) {
+            return Err(format!("Window {} is closed", conversation));
+        }
+
+        let result = Rc::new(RefCell::new(None));
+        self.root.event(&mut UIEvent::GetSearchResult(
+            search.id.clone(),
+            position,
+            Rc::clone(&result),
+        ));
+        let message = result
+            .borrow_mut()
+            .take()
+            .ok_or(format!("No result {}", position))?;
+
+        self.root
+            .event(&mut UIEvent::ShowMessage(conversation.clone(), message));
+        self.change_window(&conversation);
+        Ok(())
+    }
+
+    fn get_form(&mut self, window: &str) -> Result<(String, DataForm), String> {
+        let (id, _) = self
+            .forms
+            .get(window)
+            .cloned()
+            .ok_or("No form in the current window".to_string())?;
+        let result = Rc::new(RefCell::new(None));
+        self.root
+            .event(&mut UIEvent::GetForm(id.clone(), Rc::clone(&result)));
+        let form = result.borrow_mut().take();
+        match form {
+            Some(form) => Ok((id, form)),
+            None => Err("No form in the current window".to_string()),
+        }
+    }
+
+    pub fn get_form_fields(&mut self, window: &str) -> Vec<String> {
+        match self.get_form(window) {
+            Ok((_, form)) => form
+                .fields
+                .iter()
+                .filter(|field| field.type_ != FieldType::Fixed && field.type_ != FieldType::Hidden)
+                .map(|field| field.var.clone())
+                .collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    /// Set a field of the form displayed in the window, the selected one when no var is given
+    pub fn set_form_field(
+        &mut self,
+        window: &str,
+        var: Option<&str>,
+        value: &str,
+    ) -> Result<(), String> {
+        let (id, actions) = self
+            .forms
+            .get(window)
+            .cloned()
+            .ok_or("No form in the current window".to_string())?;
+        if actions.is_empty() {
+            return Err("This form is read-only".to_string());
+        }
+        let result = Rc::new(RefCell::new(None));
+        self.root.event(&mut UIEvent::SetFormField {
+            id,
+            var: var.map(str::to_string),
+            value: value.to_string(),
+            result: Rc::clone(&result),
+        });
+        self.render();
+
+        let set = result.borrow_mut().take();
+        set.unwrap_or(Err("No form in the current window".to_string()))
+    }
+
+    /// Get the submit form of the given window, the form is then no longer tracked
+    pub fn take_form(
+        &mut self,
+        window: &str,
+        action: FormAction,
+    ) -> Result<(String, DataForm), String> {
+        let (id, form) = self.get_form(window)?;
+        let allowed = match self.forms.get(window) {
+            Some((_, actions)) => actions.contains(&action),
+            None => false,
+        };
+        if !allowed {
+            return Err(format!("This form cannot be submitted with {}", action));
+        }
+        // Going back doesn't require the current stage to be complete
+        let form = match action {
+            FormAction::Prev => form,
+            _ => terminus::submit_form(&form)?,
+        };
+        self.forms.remove(window);
+        Ok((id, form))
+    }
+
     fn add_window(&mut self, name: String, window: Box<dyn View<UIEvent, Stdout>>) {
         self.windows.push(name.clone());
         self.root.event(&mut UIEvent::AddWindow(name, Some(window)));
@@ -951,10 +2167,28 @@ impl UIMod {
     pub fn current_window<'a>(&'a self) -> Option<&'a String> {
         self.current_window.as_ref()
     }
+
+    fn render(&mut self) {
+        if self.root.is_layout_dirty() {
+            let (width, height) = termion::terminal_size().unwrap();
+            let mut dimension = Dimension::new();
+            self.root.measure(&mut dimension, Some(width), Some(height));
+            self.root.layout(&mut dimension, 1, 1);
+            self.root.render(&dimension, &mut self.screen);
+            self.dimension = Some(dimension);
+        } else if self.root.is_dirty() {
+            let dimension: &Dimension = self.dimension.as_ref().unwrap();
+            self.root.render(dimension, &mut self.screen);
+        }
+    }
 }
 
 impl ModTrait for UIMod {
-    fn init(&mut self, _aparte: &mut Aparte) -> Result<(), ()> {
+    fn init(&mut self, aparte: &mut Aparte) -> Result<(), ()> {
+        aparte.add_command(form::new());
+        aparte.add_command(goto::new());
+        aparte.add_command(jump::new());
+
         vprint!(&mut self.screen, "{}", termion::clear::All);
 
         let (width, height) = termion::terminal_size().unwrap();
@@ -985,6 +2219,11 @@ impl ModTrait for UIMod {
                 _ => {}
             }),
         );
+        let roster_avatars = aparte.config.roster_avatars;
+        let mut avatars: HashMap<BareJid, String> = HashMap::new();
+        let mut contacts: HashMap<BareJid, contact::Contact> = HashMap::new();
+        let mut names: HashMap<String, String> = HashMap::new();
+        let mut windows: HashSet<String> = HashSet::new();
         let roster = ListView::<UIEvent, Stdout, contact::Group, RosterItem>::new()
             .with_layouts(Layouts {
                 width: Layout::wrap_content().with_relative_max(0.3),
@@ -992,7 +2231,7 @@ impl ModTrait for UIMod {
             })
             .with_none_group()
             .with_sort_item()
-            .with_event(|view, event| match event {
+            .with_event(move |view, event| match event {
                 UIEvent::Core(Event::Connected(_, _)) => {
                     view.add_group(contact::Group(String::from("Windows")));
                     view.add_group(contact::Group(String::from("Contacts")));
@@ -1000,13 +2239,33 @@ impl ModTrait for UIMod {
                 }
                 UIEvent::Core(Event::Contact(_, contact))
                 | UIEvent::Core(Event::ContactUpdate(_, contact)) => {
-                    if contact.groups.len() > 0 {
-                        for group in &contact.groups {
-                            view.insert(RosterItem::Contact(contact.clone()), Some(group.clone()));
-                        }
-                    } else {
-                        let group = contact::Group(String::from("Contacts"));
-                        view.insert(RosterItem::Contact(contact.clone()), Some(group));
+                    contacts.insert(contact.jid.clone(), contact.clone());
+                    insert_roster_contact(
+                        view,
+                        contact,
+                        names.get(&contact.jid.to_string()),
+                        avatars.get(&contact.jid).cloned(),
+                    );
+                }
+                UIEvent::Core(Event::Avatar { jid, avatar, .. }) if roster_avatars => {
+                    // Two cells wide, on a single line
+                    let fallback = id_to_rgb(&jid.to_string());
+                    let rendered = avatar.resize(2, 2).render(avatar::truecolor(), fallback);
+                    avatars.insert(jid.clone(), rendered[0].clone());
+                    if let Some(contact) = contacts.get(jid) {
+                        let name = names.get(&jid.to_string());
+                        insert_roster_contact(view, contact, name, avatars.get(jid).cloned());
+                    }
+                }
+                UIEvent::Core(Event::DisplayName { jid, name }) => {
+                    let window = jid.to_string();
+                    names.insert(window.clone(), name.clone());
+                    if let Some(contact) = contacts.get(jid) {
+                        insert_roster_contact(view, contact, Some(name), avatars.get(jid).cloned());
+                    }
+                    if windows.contains(&window) {
+                        let group = contact::Group(String::from("Windows"));
+                        view.insert(RosterItem::Window(window, Some(name.clone())), Some(group));
                     }
                 }
                 UIEvent::Core(Event::Bookmark(bookmark)) => {
@@ -1027,11 +2286,14 @@ impl ModTrait for UIMod {
                 }
                 UIEvent::AddWindow(name, _) => {
                     let group = contact::Group(String::from("Windows"));
-                    view.insert(RosterItem::Window(name.clone()), Some(group));
+                    windows.insert(name.clone());
+                    let item = RosterItem::Window(name.clone(), names.get(name).cloned());
+                    view.insert(item, Some(group));
                 }
                 UIEvent::Core(Event::Close(window)) => {
                     let group = contact::Group(String::from("Windows"));
-                    let _ = view.remove(RosterItem::Window(window.clone()), Some(group));
+                    windows.remove(window);
+                    let _ = view.remove(RosterItem::Window(window.clone(), None), Some(group));
                 }
                 _ => {}
             });
@@ -1059,9 +2321,15 @@ impl ModTrait for UIMod {
             Event::Message(account, message) => {
                 match message {
                     Message::Xmpp(message) => {
-                        let window_name = match message.direction {
-                            Direction::Incoming => message.from.to_string(),
-                            Direction::Outgoing => message.to.to_string(),
+                        let window_name = match (&message.type_, &message.direction) {
+                            (XmppMessageType::PrivateChat, Direction::Incoming) => {
+                                message.from_full.to_string()
+                            }
+                            (XmppMessageType::PrivateChat, Direction::Outgoing) => {
+                                message.to_full.to_string()
+                            }
+                            (_, Direction::Incoming) => message.from.to_string(),
+                            (_, Direction::Outgoing) => message.to.to_string(),
                         };
 
                         if !self.conversations.contains_key(&window_name) {
@@ -1092,37 +2360,47 @@ impl ModTrait for UIMod {
                                         occupants: HashMap::new(),
                                     }),
                                 },
+                                XmppMessageType::PrivateChat => {
+                                    let occupant = match message.direction {
+                                        Direction::Incoming => &message.from_full,
+                                        Direction::Outgoing => &message.to_full,
+                                    };
+                                    match occupant {
+                                        Jid::Full(occupant) => {
+                                            Conversation::PrivateChat(PrivateChat {
+                                                account: account.clone().unwrap(),
+                                                occupant: occupant.clone(),
+                                            })
+                                        }
+                                        Jid::Bare(_) => unreachable!(),
+                                    }
+                                }
                             };
 
                             self.add_conversation(aparte, conversation);
                         }
 
-                        if message.direction == Direction::Incoming {
-                            let mut window = None;
-                            for existing in &self.windows {
-                                if &message.from.to_string() == existing
-                                    && Some(existing) != self.current_window.as_ref()
-                                {
-                                    window = Some(existing.clone());
-                                }
-                            }
-
-                            if window != self.current_window {
-                                if let Some(window) = window {
-                                    let important =
-                                        self.unread_windows.entry(window.clone()).or_insert(0);
-                                    *important += 1;
-                                }
-                            }
+                        // Every incoming message of a background window is counted for Alt-a,
+                        // notification levels only apply to the bell and highlight
+                        if message.direction == Direction::Incoming
+                            && self.windows.contains(&window_name)
+                            && Some(&window_name) != self.current_window.as_ref()
+                        {
+                            *self.unread_windows.entry(window_name).or_insert(0) += 1;
                         }
                     }
                     Message::Log(_message) => {}
                 };
 
-                self.root.event(&mut UIEvent::Core(Event::Message(
-                    account.clone(),
-                    message.clone(),
-                )));
+                let mut message = message.clone();
+                if let Message::Xmpp(message) = &mut message {
+                    if message.type_ == XmppMessageType::Chat {
+                        let names = aparte.get_mod::<NamesMod>();
+                        message.from_name = names.get(&message.from);
+                    }
+                }
+                self.root
+                    .event(&mut UIEvent::Core(Event::Message(account.clone(), message)));
             }
             Event::Chat { account, contact } => {
                 // Should we store account association?
@@ -1138,6 +2416,19 @@ impl ModTrait for UIMod {
                 }
                 self.change_window(&win_name);
             }
+            Event::PrivateChat { account, occupant } => {
+                let win_name = occupant.to_string();
+                if !self.windows.contains(&win_name) {
+                    self.add_conversation(
+                        aparte,
+                        Conversation::PrivateChat(PrivateChat {
+                            account: account.clone(),
+                            occupant: occupant.clone(),
+                        }),
+                    );
+                }
+                self.change_window(&win_name);
+            }
             Event::Joined {
                 account,
                 channel,
@@ -1146,13 +2437,14 @@ impl ModTrait for UIMod {
                 let bare: BareJid = channel.clone().into();
                 let win_name = bare.to_string();
                 if !self.windows.contains(&win_name) {
+                    let name = aparte.get_mod::<NamesMod>().get(&bare);
                     self.add_conversation(
                         aparte,
                         Conversation::Channel(Channel {
                             account: account.clone(),
-                            jid: channel.clone().into(),
+                            jid: bare,
                             nick: channel.resource.clone(),
-                            name: None, // TODO use name from bookmark
+                            name,
                             occupants: HashMap::new(),
                         }),
                     );
@@ -1161,6 +2453,14 @@ impl ModTrait for UIMod {
                     self.change_window(&win_name);
                 }
             }
+            Event::DisplayName { jid, name } => {
+                if let Some(Conversation::Channel(channel)) =
+                    self.conversations.get_mut(&jid.to_string())
+                {
+                    channel.name = Some(name.clone());
+                }
+                self.root.event(&mut UIEvent::Core(event.clone()));
+            }
             Event::Win(window) => {
                 if self.windows.contains(window) {
                     self.change_window(&window);
@@ -1178,6 +2478,10 @@ impl ModTrait for UIMod {
             }
             Event::Close(window) => {
                 if window != "console" {
+                    if let Some((id, _)) = self.forms.remove(window) {
+                        aparte.schedule(Event::CancelForm(id));
+                    }
+                    self.searches.remove(window);
                     self.windows.retain(|win| win != window);
                     self.unread_windows.remove(window);
                     if Some(window) == self.current_window.as_ref() {
@@ -1206,13 +2510,10 @@ impl ModTrait for UIMod {
                             aparte.schedule(Event::Key(Key::Char('\t')));
                         } else {
                             let window = self.current_window.clone().unwrap();
-                            let account = match self.conversations.get(&window) {
-                                Some(Conversation::Chat(chat)) => Some(chat.account.clone()),
-                                Some(Conversation::Channel(channel)) => {
-                                    Some(channel.account.clone())
-                                }
-                                _ => None,
-                            };
+                            let account = self
+                                .conversations
+                                .get(&window)
+                                .map(|conversation| conversation.get_account().clone());
                             aparte.schedule(Event::AutoComplete {
                                 account,
                                 context: window,
@@ -1235,14 +2536,20 @@ impl ModTrait for UIMod {
                             aparte.schedule(Event::Command(command));
                         } else if raw_buf.starts_with("/") {
                             let window = self.current_window.clone().unwrap();
-                            let account = match self.conversations.get(&window) {
-                                Some(Conversation::Chat(chat)) => Some(chat.account.clone()),
-                                Some(Conversation::Channel(channel)) => {
-                                    Some(channel.account.clone())
-                                }
-                                _ => None,
-                            };
+                            let account = self
+                                .conversations
+                                .get(&window)
+                                .map(|conversation| conversation.get_account().clone());
                             aparte.schedule(Event::RawCommand(account, window, raw_buf.clone()));
+                        } else if let Some(window) = self
+                            .current_window
+                            .clone()
+                            .filter(|window| self.forms.contains_key(window))
+                        {
+                            // Input is the new value of the selected field
+                            if let Err(err) = self.set_form_field(&window, None, &raw_buf) {
+                                aparte.log(err);
+                            }
                         } else if raw_buf.len() > 0 {
                             if let Some(current_window) = self.current_window.clone() {
                                 if let Some(conversation) = self.conversations.get(&current_window)
@@ -1293,6 +2600,25 @@ impl ModTrait for UIMod {
                                                 message,
                                             ));
                                         }
+                                        Conversation::PrivateChat(chat) => {
+                                            let from: Jid = chat.account.clone().into();
+                                            let id = Uuid::new_v4();
+                                            let timestamp = LocalTz::now().into();
+                                            let mut bodies = HashMap::new();
+                                            bodies.insert("".to_string(), raw_buf.clone());
+                                            let message = Message::outgoing_private_chat(
+                                                id.to_string(),
+                                                timestamp,
+                                                &from,
+                                                &chat.occupant,
+                                                &bodies,
+                                                false,
+                                            );
+                                            aparte.schedule(Event::SendMessage(
+                                                chat.account.clone(),
+                                                message,
+                                            ));
+                                        }
                                     }
                                 }
                             }
@@ -1335,22 +2661,146 @@ impl ModTrait for UIMod {
                     important: important.clone(),
                 }));
             }
+            Event::Form {
+                id,
+                title,
+                form,
+                actions,
+                notes,
+            } => {
+                if self.windows.contains(title) {
+                    // Replace previous form
+                    if let Some((id, _)) = self.forms.remove(title) {
+                        aparte.schedule(Event::CancelForm(id));
+                    }
+                    self.windows.retain(|win| win != title);
+                    self.root
+                        .event(&mut UIEvent::Core(Event::Close(title.clone())));
+                }
+                self.add_form(
+                    id.clone(),
+                    title.clone(),
+                    form.clone(),
+                    actions.clone(),
+                    notes,
+                );
+                self.change_window(title);
+            }
+            Event::DiscoBrowse {
+                jid,
+                node,
+                info,
+                items,
+                ..
+            } => {
+                let title = "disco".to_string();
+                if self.windows.contains(&title) {
+                    // Replace previously browsed entity
+                    self.windows.retain(|win| win != &title);
+                    self.root
+                        .event(&mut UIEvent::Core(Event::Close(title.clone())));
+                }
+                self.add_listing(title.clone(), format_disco(jid, node, info, items));
+                self.change_window(&title);
+            }
+            Event::AdhocCommands { jid, commands, .. } => {
+                let title = "adhoc".to_string();
+                if self.windows.contains(&title) {
+                    // Replace previous listing
+                    self.windows.retain(|win| win != &title);
+                    self.root
+                        .event(&mut UIEvent::Core(Event::Close(title.clone())));
+                }
+                self.add_listing(title.clone(), format_adhoc_commands(jid, commands));
+                self.change_window(&title);
+            }
+            Event::Rooms {
+                id,
+                service,
+                filter,
+                rooms,
+                ..
+            } => {
+                let title = "rooms".to_string();
+                if self.rooms_listing.as_ref() != Some(id) {
+                    if self.windows.contains(&title) {
+                        // Replace previous listing
+                        self.windows.retain(|win| win != &title);
+                        self.root
+                            .event(&mut UIEvent::Core(Event::Close(title.clone())));
+                    }
+                    self.add_rooms(
+                        title.clone(),
+                        id.clone(),
+                        format_rooms(service, filter, rooms),
+                    );
+                    self.rooms_listing = Some(id.clone());
+                    self.change_window(&title);
+                } else {
+                    self.root.event(&mut UIEvent::Core(event.clone()));
+                }
+            }
+            Event::ContactInfo { info, .. } => {
+                let title = format!("info:{}", info.jid);
+                if self.contact_infos.get(&title) != Some(&info.id) {
+                    // Replace the window of a previous query of the same contact
+                    if self.windows.contains(&title) {
+                        self.windows.retain(|win| win != &title);
+                        self.root
+                            .event(&mut UIEvent::Core(Event::Close(title.clone())));
+                    }
+                    let avatar = info.address.as_ref().and_then(|address| {
+                        let avatars = aparte.get_mod::<AvatarMod>();
+                        avatars.get(address).cloned()
+                    });
+                    self.add_contact_info(title.clone(), info, avatar);
+                    self.change_window(&title);
+                } else {
+                    self.root.event(&mut UIEvent::Core(event.clone()));
+                }
+            }
+            Event::SearchResults {
+                search, archive, ..
+            } => {
+                let title = format!("search:{}", search.conversation.get_window());
+                let current = self.searches.get(&title).map(|existing| &existing.id);
+                if current != Some(&search.id) {
+                    // Results of a replaced search are dropped
+                    if *archive {
+                        return;
+                    }
+                    if self.windows.contains(&title) {
+                        self.windows.retain(|win| win != &title);
+                        self.root
+                            .event(&mut UIEvent::Core(Event::Close(title.clone())));
+                    }
+                    self.add_search(title.clone(), search.clone());
+                    self.change_window(&title);
+                }
+                self.root.event(&mut UIEvent::Core(event.clone()));
+            }
+            Event::ConversationLog {
+                account,
+                conversation,
+                message,
+            } => {
+                let has_window = self.conversations.values().any(|existing| {
+                    existing.get_account() == account && &existing.get_jid() == conversation
+                });
+                if has_window {
+                    self.root.event(&mut UIEvent::Core(event.clone()));
+                } else {
+                    self.root.event(&mut UIEvent::Core(Event::Message(
+                        Some(account.clone()),
+                        message.clone(),
+                    )));
+                }
+            }
             // Forward all unknown events
             event => self.root.event(&mut UIEvent::Core(event.clone())),
         }
 
-        // Update rendering
-        if self.root.is_layout_dirty() {
-            let (width, height) = termion::terminal_size().unwrap();
-            let mut dimension = Dimension::new();
-            self.root.measure(&mut dimension, Some(width), Some(height));
-            self.root.layout(&mut dimension, 1, 1);
-            self.root.render(&dimension, &mut self.screen);
-            self.dimension = Some(dimension);
-        } else if self.root.is_dirty() {
-            let dimension: &Dimension = self.dimension.as_ref().unwrap();
-            self.root.render(dimension, &mut self.screen);
-        }
+        self.render();
 
         // Handle queued outgoing event
         for event in self.outgoing_event_queue.borrow_mut().drain(..) {
@@ -1521,3 +2971,69 @@ impl Stream for EventStream {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn archived(stanza_id: &str, timestamp: &str) -> Message {
+        let mut bodies = HashMap::new();
+        bodies.insert("".to_string(), "hello".to_string());
+        let mut message = Message::incoming_chat(
+            stanza_id,
+            DateTime::parse_from_rfc3339(timestamp).unwrap(),
+            &Jid::from_str("juliet@example.org/phone").unwrap(),
+            &Jid::from_str("me@example.org/aparte").unwrap(),
+            &bodies,
+            true,
+        );
+        if let Message::Xmpp(message) = &mut message {
+            message.stanza_id = Some(stanza_id.to_string());
+        }
+        message
+    }
+
+    #[test]
+    fn test_scroll_back_empty_window() {
+        let mut view = BufferedWin::<UIEvent, Stdout, Message>::new();
+        let mut scroll_back = ScrollBack::default();
+
+        assert_eq!(scroll_back.start(&mut view), Some((None, None)));
+        // Loading indicator is displayed until the page is loaded
+        assert_eq!(view.history.len(), 1);
+        assert_eq!(scroll_back.start(&mut view), None);
+
+        assert_eq!(scroll_back.done(&mut view, false), None);
+        assert!(view.history.is_empty());
+        assert_eq!(scroll_back.start(&mut view), Some((None, None)));
+    }
+
+    #[test]
+    fn test_scroll_back_before_oldest_message() {
+        let mut view = BufferedWin::<UIEvent, Stdout, Message>::new();
+        view.insert(archived("2", "2021-01-02T00:00:00Z"));
+        view.insert(archived("1", "2021-01-01T00:00:00Z"));
+        let mut scroll_back = ScrollBack::default();
+
+        assert_eq!(
+            scroll_back.start(&mut view),
+            Some((
+                Some("1".to_string()),
+                Some(DateTime::parse_from_rfc3339("2021-01-01T00:00:00Z").unwrap())
+            ))
+        );
+        // Loading indicator is displayed before the oldest message
+        assert!(matches!(view.history.iter().next(), Some(Message::Log(_))));
+    }
+
+    #[test]
+    fn test_scroll_back_complete() {
+        let mut view = BufferedWin::<UIEvent, Stdout, Message>::new();
+        let mut scroll_back = ScrollBack::default();
+
+        assert!(scroll_back.start(&mut view).is_some());
+        scroll_back.done(&mut view, true);
+        assert_eq!(scroll_back.start(&mut view), None);
+        assert!(view.history.is_empty());
+    }
+}
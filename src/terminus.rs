@@ -10,9 +10,12 @@ use std::fmt;
 use std::hash::Hash;
 use std::io::Write;
 use std::rc::Rc;
+use std::str::FromStr;
 use termion::raw::RawTerminal;
 use termion::screen::AlternateScreen;
 use unicode_segmentation::UnicodeSegmentation;
+use xmpp_parsers::data_forms::{DataForm, DataFormType, Field, FieldType};
+use xmpp_parsers::Jid;
 
 pub type Screen<W> = AlternateScreen<RawTerminal<W>>;
 
@@ -1264,15 +1267,26 @@ where
     fn page_down(&mut self) -> bool;
 }
 
+/// Line to render between two consecutive items, if any
+type Separator<I> = Box<dyn Fn(&I, &I) -> Option<String>>;
+
+/// Update an inserted item with the equal item it replaces
+type Merge<I> = Box<dyn Fn(&mut I, &I)>;
+
 pub struct BufferedWin<E, W, I>
 where
-    I: fmt::Display + Hash + Eq + Ord,
+    I: fmt::Display + Hash + Eq + Ord + Clone,
 {
     pub next_line: u16,
     pub history: BTreeSet<I>,
+    /// Copy of the items of the history, to find the one equal to an inserted item regardless
+    /// of its position
+    index: HashSet<I>,
     pub view: usize,
     pub event_handler: Option<Rc<RefCell<Box<dyn FnMut(&mut Self, &mut E)>>>>,
     pub dirty: bool,
+    separator: Option<Separator<I>>,
+    merge: Option<Merge<I>>,
     width: usize,
     height: usize,
     layouts: Layouts,
@@ -1280,15 +1294,18 @@ where
 
 impl<E, W, I> BufferedWin<E, W, I>
 where
-    I: fmt::Display + Hash + Eq + Ord,
+    I: fmt::Display + Hash + Eq + Ord + Clone,
 {
     pub fn new() -> Self {
         Self {
             next_line: 0,
             history: BTreeSet::new(),
+            index: HashSet::new(),
             view: 0,
             event_handler: None,
             dirty: true,
+            separator: None,
+            merge: None,
             width: 0,
             height: 0,
             layouts: Layouts {
@@ -1306,6 +1323,22 @@ where
         self
     }
 
+    pub fn with_separator<F>(mut self, separator: F) -> Self
+    where
+        F: Fn(&I, &I) -> Option<String> + 'static,
+    {
+        self.separator = Some(Box::new(separator));
+        self
+    }
+
+    pub fn with_merge<F>(mut self, merge: F) -> Self
+    where
+        F: Fn(&mut I, &I) + 'static,
+    {
+        self.merge = Some(Box::new(merge));
+        self
+    }
+
     #[allow(unused)]
     pub fn with_layouts(mut self, layouts: Layouts) -> Self {
         self.layouts = layouts;
@@ -1313,99 +1346,114 @@ where
     }
 
     fn get_rendered_items(&self) -> Vec<String> {
+        let mut previous = None;
+        let mut buffers = Vec::new();
+        for item in self.history.iter() {
+            buffers.extend(self.render_item(previous, item));
+            previous = Some(item);
+        }
+        buffers
+    }
+
+    /// Render an item, preceded by its separator from the previous item
+    fn render_item(&self, previous: Option<&I>, item: &I) -> Vec<String> {
+        let mut formatted = format!("{}", item);
+        if let (Some(separator), Some(previous)) = (&self.separator, previous) {
+            if let Some(line) = separator(previous, item) {
+                formatted = format!("{}\n{}", line, formatted);
+            }
+        }
+
         let max_len = self.width;
         let mut buffers: Vec<String> = Vec::new();
 
-        for buf in &self.history {
-            let formatted = format!("{}", buf);
-            for line in formatted.lines() {
-                let mut words = line.split_word_bounds();
-
-                let mut line_len = 0;
-                let mut chunk = String::new();
-                while let Some(word) = words.next() {
-                    let visible_word;
-                    let mut remaining = String::new();
-
-                    // We can safely unwrap here because split_word_bounds produce non empty words
-                    let first_char = word.chars().next().unwrap();
-
-                    if first_char == '\x1b' {
-                        // Handle Escape sequence: see https://www.ecma-international.org/publications/files/ECMA-ST/Ecma-048.pdf
-                        // First char is a word boundary
-                        //
-                        // We must ignore them for the visible length count but include them in the
-                        // final chunk that will be written to the terminal
-
-                        if let Some(word) = words.next() {
-                            match word {
-                                "[" => {
-                                    // Control Sequence Introducer are accepted and can safely be
-                                    // written to terminal
-                                    let mut escape = String::from("\x1b[");
-                                    let mut end = false;
-
-                                    while let Some(word) = words.next() {
-                                        for c in word.chars() {
-                                            // Push all char belonging to escape sequence
-                                            // but keep remaining for wrap computation
-                                            if !end {
-                                                escape.push(c);
-                                                match c {
-                                                    '\x30'..='\x3f' => {} // parameter bytes
-                                                    '\x20'..='\x2f' => {} // intermediate bytes
-                                                    '\x40'..='\x7e' => {
-                                                        // final byte
-                                                        chunk.push_str(&escape);
-                                                        end = true;
-                                                    }
-                                                    _ => {
-                                                        // Invalid escape sequence, just ignore it
-                                                        end = true;
-                                                    }
+        for line in formatted.lines() {
+            let mut words = line.split_word_bounds();
+
+            let mut line_len = 0;
+            let mut chunk = String::new();
+            while let Some(word) = words.next() {
+                let visible_word;
+                let mut remaining = String::new();
+
+                // We can safely unwrap here because split_word_bounds produce non empty words
+                let first_char = word.chars().next().unwrap();
+
+                if first_char == '\x1b' {
+                    // Handle Escape sequence: see https://www.ecma-international.org/publications/files/ECMA-ST/Ecma-048.pdf
+                    // First char is a word boundary
+                    //
+                    // We must ignore them for the visible length count but include them in the
+                    // final chunk that will be written to the terminal
+
+                    if let Some(word) = words.next() {
+                        match word {
+                            "[" => {
+                                // Control Sequence Introducer are accepted and can safely be
+                                // written to terminal
+                                let mut escape = String::from("\x1b[");
+                                let mut end = false;
+
+                                while let Some(word) = words.next() {
+                                    for c in word.chars() {
+                                        // Push all char belonging to escape sequence
+                                        // but keep remaining for wrap computation
+                                        if !end {
+                                            escape.push(c);
+                                            match c {
+                                                '\x30'..='\x3f' => {} // parameter bytes
+                                                '\x20'..='\x2f' => {} // intermediate bytes
+                                                '\x40'..='\x7e' => {
+                                                    // final byte
+                                                    chunk.push_str(&escape);
+                                                    end = true;
+                                                }
+                                                _ => {
+                                                    // Invalid escape sequence, just ignore it
+                                                    end = true;
                                                 }
-                                            } else {
-                                                remaining.push(c);
                                             }
+                                        } else {
+                                            remaining.push(c);
                                         }
+                                    }
 
-                                        if end {
-                                            break;
-                                        }
+                                    if end {
+                                        break;
                                     }
                                 }
-                                _ => {
-                                    // Other sequence are not handled and just ignored
-                                }
                             }
-                        } else {
-                            // Nothing is following the escape char
-                            // We can simply ignore it
+                            _ => {
+                                // Other sequence are not handled and just ignored
+                            }
                         }
-                        visible_word = remaining.as_str();
                     } else {
-                        visible_word = word;
-                    }
-
-                    if visible_word.len() == 0 {
-                        continue;
+                        // Nothing is following the escape char
+                        // We can simply ignore it
                     }
+                    visible_word = remaining.as_str();
+                } else {
+                    visible_word = word;
+                }
 
-                    let grapheme_count = visible_word.graphemes(true).count();
+                if visible_word.len() == 0 {
+                    continue;
+                }
 
-                    if line_len + grapheme_count > max_len {
-                        // Wrap line
-                        buffers.push(chunk);
-                        chunk = String::new();
-                        line_len = 0;
-                    }
+                let grapheme_count = visible_word.graphemes(true).count();
 
-                    chunk.push_str(visible_word);
-                    line_len += grapheme_count;
+                if line_len + grapheme_count > max_len {
+                    // Wrap line
+                    buffers.push(chunk);
+                    chunk = String::new();
+                    line_len = 0;
                 }
 
-                buffers.push(chunk);
+                chunk.push_str(visible_word);
+                line_len += grapheme_count;
             }
+
+            buffers.push(chunk);
         }
 
         buffers
@@ -1415,14 +1463,51 @@ where
     pub fn first<'a>(&'a self) -> Option<&'a I> {
         self.history.iter().nth(0)
     }
+
+    /// Scroll the window so that the given item is at its top
+    pub fn scroll_to(&mut self, item: &I) {
+        let position = match self.history.iter().position(|existing| existing == item) {
+            Some(position) => position,
+            None => return,
+        };
+
+        let mut count = 0;
+        let mut below = 0;
+        let mut previous = None;
+        for (index, existing) in self.history.iter().enumerate() {
+            let lines = self.render_item(previous, existing).len();
+            count += lines;
+            if index >= position {
+                below += lines;
+            }
+            previous = Some(existing);
+        }
+
+        let max = count.saturating_sub(self.height);
+        self.view = below.saturating_sub(self.height).min(max);
+        self.dirty = true;
+    }
+
+    pub fn remove(&mut self, item: &I) {
+        if let Some(existing) = self.index.take(item) {
+            self.history.remove(&existing);
+            self.dirty = true;
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.history.clear();
+        self.index.clear();
+        self.dirty = true;
+    }
 }
 
 impl<E, W, I> Window<E, W, I> for BufferedWin<E, W, I>
 where
     W: Write,
-    I: fmt::Display + Hash + Eq + Ord,
+    I: fmt::Display + Hash + Eq + Ord + Clone,
 {
-    fn insert(&mut self, item: I) {
+    fn insert(&mut self, mut item: I) {
         // We don't care about rendered buffer, we avoid computation here at cost of false positive
         // (set dirty while in fact it shouldn't)
         let len = self.history.len();
@@ -1432,7 +1517,15 @@ where
                 .iter()
                 .position(|iter| iter > &item)
                 .unwrap_or(self.history.len());
-        self.history.replace(item);
+        // Ordering may not find an equal item with another position, remove it explicitly
+        if let Some(existing) = self.index.take(&item) {
+            self.history.remove(&existing);
+            if let Some(merge) = &self.merge {
+                merge(&mut item, &existing);
+            }
+        }
+        self.index.insert(item.clone());
+        self.history.insert(item);
         self.dirty |= position >= self.view && position <= self.view + self.height;
     }
 
@@ -1474,7 +1567,7 @@ where
 impl<E, W, I> View<E, W> for BufferedWin<E, W, I>
 where
     W: Write,
-    I: fmt::Display + Hash + Eq + Ord,
+    I: fmt::Display + Hash + Eq + Ord + Clone,
 {
     fn render(&mut self, dimension: &Dimension, screen: &mut Screen<W>) {
         save_cursor!(screen);
@@ -1531,6 +1624,408 @@ where
     }
 }
 
+fn is_true(value: &str) -> bool {
+    value == "1" || value == "true"
+}
+
+/// Fields that can be selected and edited in a form
+fn is_editable(field: &Field) -> bool {
+    field.type_ != FieldType::Fixed && field.type_ != FieldType::Hidden
+}
+
+fn format_form_field(field: &Field) -> String {
+    let label = clean(field.label.as_ref().unwrap_or(&field.var));
+    let required = match field.required {
+        true => "*",
+        false => "",
+    };
+    let option_label = |value: &String| {
+        field
+            .options
+            .iter()
+            .find(|option| &option.value == value)
+            .and_then(|option| option.label.clone())
+            .unwrap_or(value.clone())
+    };
+
+    match field.type_ {
+        FieldType::Fixed => clean(&field.values.join("\n")),
+        FieldType::Boolean => format!(
+            "[{}] {}{} ({})",
+            match field.values.iter().any(|value| is_true(value)) {
+                true => "x",
+                false => " ",
+            },
+            label,
+            required,
+            field.var
+        ),
+        FieldType::TextPrivate => format!(
+            "{}{} ({}): {}",
+            label,
+            required,
+            field.var,
+            "*".repeat(field.values.iter().map(|value| value.len()).sum())
+        ),
+        FieldType::TextMulti => format!(
+            "{}{} ({}):\n    {}",
+            label,
+            required,
+            field.var,
+            clean(&field.values.join("\n")).replace('\n', "\n    ")
+        ),
+        FieldType::ListSingle | FieldType::ListMulti => format!(
+            "{}{} ({}): {}\n    options: {}",
+            label,
+            required,
+            field.var,
+            clean(
+                &field
+                    .values
+                    .iter()
+                    .map(option_label)
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            ),
+            clean(
+                &field
+                    .options
+                    .iter()
+                    .map(|option| match &option.label {
+                        Some(label) if label != &option.value => {
+                            format!("{} ({})", option.value, label)
+                        }
+                        _ => option.value.clone(),
+                    })
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            )
+        ),
+        _ => format!(
+            "{}{} ({}): {}",
+            label,
+            required,
+            field.var,
+            clean(&field.values.join(", "))
+        ),
+    }
+}
+
+/// Set the value of a form field, multiple values are separated by commas, lines of text-multi
+/// fields by \n
+pub fn set_form_field(field: &mut Field, value: &str) -> Result<(), String> {
+    let values: Vec<String> = match field.type_ {
+        FieldType::Fixed | FieldType::Hidden => {
+            return Err(format!("Field {} cannot be edited", field.var))
+        }
+        FieldType::Boolean => match value {
+            "1" | "true" | "yes" | "on" => vec!["1".to_string()],
+            "0" | "false" | "no" | "off" => vec!["0".to_string()],
+            _ => return Err(format!("Invalid boolean value {}", value)),
+        },
+        FieldType::ListMulti | FieldType::JidMulti => value
+            .split(',')
+            .map(|value| value.trim().to_string())
+            .filter(|value| !value.is_empty())
+            .collect(),
+        FieldType::TextMulti => value.split("\\n").map(str::to_string).collect(),
+        _ => match value.is_empty() {
+            true => vec![],
+            false => vec![value.to_string()],
+        },
+    };
+
+    match field.type_ {
+        FieldType::ListSingle | FieldType::ListMulti if !field.options.is_empty() => {
+            for value in values.iter() {
+                if !field.options.iter().any(|option| &option.value == value) {
+                    return Err(format!("Invalid option {} for field {}", value, field.var));
+                }
+            }
+        }
+        FieldType::JidSingle | FieldType::JidMulti => {
+            for value in values.iter() {
+                Jid::from_str(value).map_err(|e| format!("Invalid JID {}: {}", value, e))?;
+            }
+        }
+        _ => {}
+    }
+
+    field.values = values;
+    Ok(())
+}
+
+/// Build the submit form answering the given form
+pub fn submit_form(form: &DataForm) -> Result<DataForm, String> {
+    let mut fields = Vec::new();
+    for field in form.fields.iter() {
+        if field.type_ == FieldType::Fixed {
+            continue;
+        }
+        if field.required && field.values.is_empty() {
+            return Err(format!("Field {} is required", field.var));
+        }
+        fields.push(Field {
+            var: field.var.clone(),
+            type_: field.type_.clone(),
+            label: None,
+            required: false,
+            options: vec![],
+            values: field.values.clone(),
+            media: vec![],
+        });
+    }
+
+    Ok(DataForm {
+        type_: DataFormType::Submit,
+        form_type: form.form_type.clone(),
+        title: None,
+        instructions: None,
+        fields,
+    })
+}
+
+/// View of a data form whose fields can be selected and edited
+pub struct FormView<E> {
+    title: String,
+    header: Option<String>,
+    form: DataForm,
+    /// Index of the selected field, if the form has editable fields
+    selected: Option<usize>,
+    /// First rendered line
+    top: usize,
+    /// Scroll to the selected field on next render
+    follow: bool,
+    height: usize,
+    event_handler: Option<Rc<RefCell<Box<dyn FnMut(&mut Self, &mut E)>>>>,
+    dirty: bool,
+    layouts: Layouts,
+}
+
+impl<E> FormView<E> {
+    pub fn new(title: &str, form: DataForm) -> Self {
+        let selected = form.fields.iter().position(is_editable);
+        Self {
+            title: form.title.clone().unwrap_or(title.to_string()),
+            header: None,
+            form,
+            selected,
+            top: 0,
+            follow: true,
+            height: 0,
+            event_handler: None,
+            dirty: true,
+            layouts: Layouts {
+                width: Layout::match_parent(),
+                height: Layout::match_parent(),
+            },
+        }
+    }
+
+    /// Text displayed below the title and instructions of the form
+    pub fn with_header(mut self, header: String) -> Self {
+        self.header = Some(header);
+        self
+    }
+
+    pub fn with_event<F>(mut self, event_handler: F) -> Self
+    where
+        F: FnMut(&mut Self, &mut E) + 'static,
+    {
+        self.event_handler = Some(Rc::new(RefCell::new(Box::new(event_handler))));
+        self
+    }
+
+    pub fn form(&self) -> &DataForm {
+        &self.form
+    }
+
+    /// Var of the selected field
+    pub fn selected(&self) -> Option<&str> {
+        self.selected
+            .map(|index| self.form.fields[index].var.as_str())
+    }
+
+    /// Select the next editable field
+    pub fn next(&mut self) {
+        if let Some(selected) = self.selected {
+            let next = self.form.fields[selected + 1..]
+                .iter()
+                .position(is_editable)
+                .map(|offset| selected + 1 + offset);
+            if next.is_some() {
+                self.selected = next;
+                self.follow = true;
+                self.dirty = true;
+            }
+        }
+    }
+
+    /// Select the previous editable field
+    pub fn previous(&mut self) {
+        if let Some(selected) = self.selected {
+            let previous = self.form.fields[..selected].iter().rposition(is_editable);
+            if previous.is_some() {
+                self.selected = previous;
+                self.follow = true;
+                self.dirty = true;
+            }
+        }
+    }
+
+    pub fn page_up(&mut self) {
+        self.top = self.top.saturating_sub(self.height);
+        self.follow = false;
+        self.dirty = true;
+    }
+
+    pub fn page_down(&mut self) {
+        self.top += self.height;
+        self.follow = false;
+        self.dirty = true;
+    }
+
+    pub fn set(&mut self, var: &str, value: &str) -> Result<(), String> {
+        let field = self
+            .form
+            .fields
+            .iter_mut()
+            .find(|field| field.var == var)
+            .ok_or(format!("Unknown field {}", var))?;
+        set_form_field(field, value)?;
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// Set the value of the selected field
+    pub fn set_selected(&mut self, value: &str) -> Result<(), String> {
+        let var = self
+            .selected()
+            .ok_or("No field to edit".to_string())?
+            .to_string();
+        self.set(&var, value)
+    }
+
+    /// Toggle the selected field, if it is a boolean
+    pub fn toggle_selected(&mut self) -> Result<(), String> {
+        let field = match self.selected {
+            Some(index) => &self.form.fields[index],
+            None => return Err("No field to edit".to_string()),
+        };
+        if field.type_ != FieldType::Boolean {
+            return Err(format!("Field {} is not a boolean", field.var));
+        }
+        let value = match field.values.iter().any(|value| is_true(value)) {
+            true => "0",
+            false => "1",
+        };
+        self.set_selected(value)
+    }
+
+    /// Lines of the form, with the range of lines of the selected field
+    fn get_rendered_lines(&self, width: usize) -> (Vec<String>, Option<(usize, usize)>) {
+        let mut lines = vec![format!(
+            "{}{}{}",
+            termion::style::Bold,
+            clean(&self.title),
+            termion::style::NoBold
+        )];
+        if let Some(instructions) = &self.form.instructions {
+            lines.extend(clean(instructions).lines().map(str::to_string));
+        }
+        if let Some(header) = &self.header {
+            lines.extend(header.lines().map(str::to_string));
+        }
+        lines.push(String::new());
+
+        let mut selected = None;
+        for (index, field) in self.form.fields.iter().enumerate() {
+            if field.type_ == FieldType::Hidden {
+                continue;
+            }
+            let start = lines.len();
+            let prefix = match Some(index) == self.selected {
+                true => "> ",
+                false => "  ",
+            };
+            for (i, line) in format_form_field(field).lines().enumerate() {
+                let line = match (i, Some(index) == self.selected) {
+                    (0, true) => format!(
+                        "{}{}{}{}",
+                        termion::style::Invert,
+                        prefix,
+                        line,
+                        termion::style::NoInvert
+                    ),
+                    (0, false) => format!("{}{}", prefix, line),
+                    _ => format!("  {}", line),
+                };
+                lines.push(term_string_visible_truncate(&line, width, Some("…")));
+            }
+            if Some(index) == self.selected {
+                selected = Some((start, lines.len()));
+            }
+        }
+
+        (lines, selected)
+    }
+}
+
+impl<E, W> View<E, W> for FormView<E>
+where
+    W: Write,
+{
+    fn render(&mut self, dimension: &Dimension, screen: &mut Screen<W>) {
+        save_cursor!(screen);
+        let width = dimension.w.unwrap() as usize;
+        self.height = dimension.h.unwrap() as usize;
+
+        let (lines, selected) = self.get_rendered_lines(width);
+        if let (true, Some((start, end))) = (self.follow, selected) {
+            if start < self.top {
+                self.top = start;
+            } else if end > self.top + self.height {
+                self.top = end.saturating_sub(self.height);
+            }
+        }
+        self.top = cmp::min(self.top, lines.len().saturating_sub(self.height));
+
+        let mut iter = lines.iter().skip(self.top);
+        for y in dimension.y..dimension.y + dimension.h.unwrap() {
+            goto!(screen, dimension.x, y);
+            for _ in dimension.x..dimension.x + dimension.w.unwrap() {
+                vprint!(screen, " ");
+            }
+
+            goto!(screen, dimension.x, y);
+            if let Some(line) = iter.next() {
+                vprint!(screen, "{}", line);
+            }
+        }
+
+        restore_cursor!(screen);
+        flush!(screen);
+
+        self.dirty = false;
+    }
+
+    fn event(&mut self, event: &mut E) {
+        if let Some(handler) = &self.event_handler {
+            let handler = Rc::clone(handler);
+            let handler = &mut *handler.borrow_mut();
+            handler(self, event);
+        }
+    }
+
+    fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    fn get_layouts(&self) -> Layouts {
+        self.layouts.clone()
+    }
+}
+
 pub struct ListView<E, W, G, V>
 where
     G: fmt::Display + Hash + Eq,
@@ -1921,4 +2416,261 @@ mod tests {
         // Then
         assert_eq!(truncated, "test …");
     }
+
+    fn form_field(var: &str, type_: FieldType, required: bool) -> Field {
+        Field {
+            var: var.to_string(),
+            type_,
+            label: None,
+            required,
+            options: vec![],
+            values: vec![],
+            media: vec![],
+        }
+    }
+
+    fn test_form() -> DataForm {
+        DataForm {
+            type_: DataFormType::Form,
+            form_type: None,
+            title: None,
+            instructions: None,
+            fields: vec![
+                form_field("hidden", FieldType::Hidden, false),
+                form_field("name", FieldType::TextSingle, true),
+                form_field("fixed", FieldType::Fixed, false),
+                form_field("public", FieldType::Boolean, false),
+            ],
+        }
+    }
+
+    #[test]
+    fn test_form_view_edit_and_submit() {
+        // Given
+        let mut view = FormView::<()>::new("form", test_form());
+        assert!(submit_form(view.form()).is_err());
+
+        // When
+        view.set("name", "My room").unwrap();
+        view.set("public", "yes").unwrap();
+        let submit = submit_form(view.form()).unwrap();
+
+        // Then
+        assert!(view.set("fixed", "value").is_err());
+        assert!(view.set("public", "maybe").is_err());
+        assert!(view.set("unknown", "value").is_err());
+        assert_eq!(submit.type_, DataFormType::Submit);
+        let values: Vec<(&str, &Vec<String>)> = submit
+            .fields
+            .iter()
+            .map(|field| (field.var.as_str(), &field.values))
+            .collect();
+        assert_eq!(
+            values,
+            vec![
+                ("hidden", &vec![]),
+                ("name", &vec!["My room".to_string()]),
+                ("public", &vec!["1".to_string()]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_form_view_navigation_skips_read_only_fields() {
+        // Given
+        let mut view = FormView::<()>::new("form", test_form());
+
+        // When
+        let first = view.selected().map(str::to_string);
+        view.next();
+        let second = view.selected().map(str::to_string);
+        view.next();
+        let last = view.selected().map(str::to_string);
+        view.previous();
+
+        // Then
+        assert_eq!(first.as_deref(), Some("name"));
+        assert_eq!(second.as_deref(), Some("public"));
+        assert_eq!(last.as_deref(), Some("public"));
+        assert_eq!(view.selected(), Some("name"));
+    }
+
+    #[test]
+    fn test_form_view_edit_selected_and_submit() {
+        // Given
+        let mut view = FormView::<()>::new("form", test_form());
+        assert!(submit_form(view.form()).is_err());
+
+        // When
+        view.set_selected("My room").unwrap();
+        view.next();
+        view.toggle_selected().unwrap();
+        let submit = submit_form(view.form()).unwrap();
+
+        // Then
+        assert!(view.set("fixed", "value").is_err());
+        assert_eq!(submit.type_, DataFormType::Submit);
+        let values: Vec<(&str, &Vec<String>)> = submit
+            .fields
+            .iter()
+            .map(|field| (field.var.as_str(), &field.values))
+            .collect();
+        assert_eq!(
+            values,
+            vec![
+                ("hidden", &vec![]),
+                ("name", &vec!["My room".to_string()]),
+                ("public", &vec!["1".to_string()]),
+            ]
+        );
+    }
+
+    /// Item equal by id but ordered by timestamp, like messages
+    #[derive(Clone)]
+    struct TimestampedItem {
+        id: u32,
+        timestamp: u32,
+    }
+
+    impl fmt::Display for TimestampedItem {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "{}", self.id)
+        }
+    }
+
+    impl Hash for TimestampedItem {
+        fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+            self.id.hash(state);
+        }
+    }
+
+    impl PartialEq for TimestampedItem {
+        fn eq(&self, other: &Self) -> bool {
+            self.id == other.id
+        }
+    }
+
+    impl Eq for TimestampedItem {}
+
+    impl Ord for TimestampedItem {
+        fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+            if self == other {
+                std::cmp::Ordering::Equal
+            } else {
+                self.timestamp.cmp(&other.timestamp)
+            }
+        }
+    }
+
+    impl PartialOrd for TimestampedItem {
+        fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+            Some(self.cmp(other))
+        }
+    }
+
+    #[test]
+    fn test_buffered_win_insert_replaces_copy_with_other_timestamp() {
+        // Given
+        let mut win = BufferedWin::<(), MockWriter, TimestampedItem>::new();
+        for id in 0..10 {
+            win.insert(TimestampedItem {
+                id,
+                timestamp: id * 10,
+            });
+        }
+
+        // When
+        win.insert(TimestampedItem {
+            id: 2,
+            timestamp: 75,
+        });
+
+        // Then
+        assert_eq!(win.history.len(), 10);
+        let ids: Vec<u32> = win.history.iter().map(|item| item.id).collect();
+        assert_eq!(ids, vec![0, 1, 3, 4, 5, 6, 7, 2, 8, 9]);
+    }
+
+    #[test]
+    fn test_buffered_win_insert_merges_copy() {
+        // Given
+        let mut win = BufferedWin::<(), MockWriter, TimestampedItem>::new()
+            .with_merge(|item, existing| item.timestamp = existing.timestamp);
+        for id in 0..3 {
+            win.insert(TimestampedItem {
+                id,
+                timestamp: id * 10,
+            });
+        }
+
+        // When
+        win.insert(TimestampedItem {
+            id: 1,
+            timestamp: 75,
+        });
+
+        // Then
+        let items: Vec<(u32, u32)> = win
+            .history
+            .iter()
+            .map(|item| (item.id, item.timestamp))
+            .collect();
+        assert_eq!(items, vec![(0, 0), (1, 10), (2, 20)]);
+    }
+
+    #[test]
+    fn test_buffered_win_separator() {
+        // Given
+        let mut win = BufferedWin::<(), MockWriter, TimestampedItem>::new().with_separator(
+            |previous, next| match previous.timestamp / 100 == next.timestamp / 100 {
+                true => None,
+                false => Some("--".to_string()),
+            },
+        );
+        win.width = 10;
+        for id in 0..4 {
+            win.insert(TimestampedItem {
+                id,
+                timestamp: id * 60,
+            });
+        }
+
+        // When
+        let rendered = win.get_rendered_items();
+
+        // Then
+        assert_eq!(rendered, vec!["0", "1", "--", "2", "3"]);
+    }
+
+    #[test]
+    fn test_buffered_win_scroll_to() {
+        // Given
+        let mut win = BufferedWin::<(), MockWriter, TimestampedItem>::new();
+        win.width = 10;
+        win.height = 3;
+        for id in 0..10 {
+            win.insert(TimestampedItem {
+                id,
+                timestamp: id * 10,
+            });
+        }
+
+        // When
+        win.scroll_to(&TimestampedItem {
+            id: 2,
+            timestamp: 20,
+        });
+
+        // Then
+        assert_eq!(win.view, 5);
+
+        // When
+        win.scroll_to(&TimestampedItem {
+            id: 9,
+            timestamp: 90,
+        });
+
+        // Then
+        assert_eq!(win.view, 0);
+    }
 }